@@ -0,0 +1,68 @@
+use std::{
+    fs::File,
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+/// Replaces the file at `path` with `bytes` through a temporary sibling, which is synced to
+/// disk before it's renamed over the file. Readers see either the old contents or the new
+/// ones, even after a crash or power loss.
+pub fn write(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    let parent = path
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty());
+    if let Some(parent) = parent {
+        std::fs::create_dir_all(parent)?;
+    }
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+
+    let mut file = File::create(&tmp_path)?;
+    file.write_all(bytes)?;
+    file.sync_all()?;
+    std::fs::rename(&tmp_path, path)?;
+
+    // The rename is only durable once the directory holding the file is synced too
+    #[cfg(unix)]
+    File::open(parent.unwrap_or_else(|| Path::new(".")))?.sync_all()?;
+    Ok(())
+}
+
+/// `write`, run on the blocking thread pool so it doesn't hold up the runtime
+pub async fn write_async(path: PathBuf, bytes: Vec<u8>) -> io::Result<()> {
+    tokio::task::spawn_blocking(move || write(&path, &bytes))
+        .await
+        .map_err(io::Error::other)?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{write, write_async};
+    use crate::test_utils::temp_dir;
+
+    #[test]
+    fn test_write_creates_and_replaces_file() {
+        let dir = temp_dir();
+        let path = dir.path().join("nested").join("state.json");
+
+        write(&path, b"old").unwrap();
+        write(&path, b"new").unwrap();
+
+        assert_eq!(std::fs::read(&path).unwrap(), b"new");
+        assert_eq!(
+            std::fs::read_dir(path.parent().unwrap()).unwrap().count(),
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn test_write_async_replaces_file() {
+        let dir = temp_dir();
+        let path = dir.path().join("queue.json");
+
+        write_async(path.clone(), b"old".to_vec()).await.unwrap();
+        write_async(path.clone(), b"new".to_vec()).await.unwrap();
+
+        assert_eq!(std::fs::read(&path).unwrap(), b"new");
+    }
+}
@@ -0,0 +1,568 @@
+use crate::key_derivation::derive_auth_key;
+use async_trait::async_trait;
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sodiumoxide::crypto::auth;
+use std::{
+    io::{ErrorKind, SeekFrom},
+    ops::Deref,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use thiserror::Error;
+use tokio::{
+    fs::{File, OpenOptions},
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+    sync::Mutex,
+};
+use warp::reject::Reject;
+
+/// Domain separation for the audit log signing key derived from the root signing key
+const AUDIT_KEY_CONTEXT: &[u8] = b"redact-client audit log signing key";
+
+/// The hash the first entry of a log is chained to
+const GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// How much of the end of the log is read at a time when listing recent entries
+const TAIL_CHUNK_BYTES: u64 = 16 * 1024;
+
+#[derive(Error, Debug)]
+pub enum AuditError {
+    #[error("Failed to read or write the audit log file")]
+    IoError { source: std::io::Error },
+    #[error("Failed to serialize an audit log entry")]
+    SerializationError { source: serde_json::Error },
+}
+
+impl Reject for AuditError {}
+
+/// What was done with the data at a path
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Operation {
+    View,
+    Edit,
+    Delete,
+    Relay,
+    Proxy,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Outcome {
+    Success,
+    Failure,
+}
+
+/// An access to the user's data by a website
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AuditEvent {
+    pub origin: String,
+    pub path: String,
+    pub operation: Operation,
+    pub outcome: Outcome,
+}
+
+impl AuditEvent {
+    pub fn new(origin: &str, path: &str, operation: Operation, succeeded: bool) -> Self {
+        AuditEvent {
+            origin: origin.to_owned(),
+            path: path.to_owned(),
+            operation,
+            outcome: if succeeded {
+                Outcome::Success
+            } else {
+                Outcome::Failure
+            },
+        }
+    }
+}
+
+/// An event as written to the log, chained to the entry before it by hash and signed
+/// with a key derived from the root signing key
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AuditEntry {
+    pub sequence: u64,
+    pub timestamp: String,
+    #[serde(flatten)]
+    pub event: AuditEvent,
+    pub previous_hash: String,
+    pub hash: String,
+    pub signature: String,
+}
+
+/// The most recent entries of the log, and whether the log as a whole is intact
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct AuditTrail {
+    /// Most recent first
+    pub entries: Vec<AuditEntry>,
+    pub total: u64,
+    /// The sequence number of the first entry which was altered, removed or reordered
+    pub broken_at: Option<u64>,
+}
+
+/// The fields of an entry covered by its hash
+#[derive(Serialize)]
+struct ChainedFields<'a> {
+    sequence: u64,
+    timestamp: &'a str,
+    event: &'a AuditEvent,
+    previous_hash: &'a str,
+}
+
+/// Records every access to the user's data, and lists the recorded accesses
+#[async_trait]
+pub trait Auditor: Clone + Send + Sync {
+    async fn record(&self, event: AuditEvent) -> Result<AuditEntry, AuditError>;
+    async fn recent(&self, limit: usize) -> Result<AuditTrail, AuditError>;
+}
+
+#[async_trait]
+impl<U> Auditor for Arc<U>
+where
+    U: Auditor,
+{
+    async fn record(&self, event: AuditEvent) -> Result<AuditEntry, AuditError> {
+        self.deref().record(event).await
+    }
+
+    async fn recent(&self, limit: usize) -> Result<AuditTrail, AuditError> {
+        self.deref().recent(limit).await
+    }
+}
+
+/// Records `event`, logging instead of failing the request if it can't be written
+pub async fn audit<A: Auditor>(auditor: &A, event: AuditEvent) {
+    if let Err(e) = auditor.record(event).await {
+        tracing::error!(error = %e, "failed to write audit log entry");
+    }
+}
+
+/// The sequence number and hash the next entry is chained to
+struct ChainHead {
+    sequence: u64,
+    hash: String,
+    /// Where the chain was found to be broken when the log was opened
+    broken_at: Option<u64>,
+}
+
+/// Appends entries to a file, one JSON entry per line. Altering, removing or reordering
+/// entries breaks the chain; entries removed from the end of the log can't be detected.
+#[derive(Clone)]
+pub struct FileAuditor {
+    path: PathBuf,
+    key: auth::Key,
+    head: Arc<Mutex<ChainHead>>,
+}
+
+impl FileAuditor {
+    /// Opens the log at `path`, continuing the chain from its last entry
+    pub fn open<P: AsRef<Path>>(path: P, root_key_bytes: &[u8]) -> Result<Self, AuditError> {
+        let path = path.as_ref().to_path_buf();
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent).map_err(|source| AuditError::IoError { source })?;
+        }
+
+        let key = derive_auth_key(AUDIT_KEY_CONTEXT, root_key_bytes);
+
+        let entries = read_entries(&path)?;
+        let broken_at = verify(&entries, 0, GENESIS_HASH, &key);
+        if let Some(sequence) = broken_at {
+            tracing::warn!(sequence, "audit log has been tampered with");
+        }
+        let head = match entries.iter().rev().find_map(|entry| entry.as_ref()) {
+            Some(last) => ChainHead {
+                sequence: last.sequence + 1,
+                hash: last.hash.clone(),
+                broken_at,
+            },
+            None => ChainHead {
+                sequence: 0,
+                hash: GENESIS_HASH.to_owned(),
+                broken_at,
+            },
+        };
+
+        Ok(FileAuditor {
+            path,
+            key,
+            head: Arc::new(Mutex::new(head)),
+        })
+    }
+}
+
+#[async_trait]
+impl Auditor for FileAuditor {
+    #[tracing::instrument(skip(self))]
+    async fn record(&self, event: AuditEvent) -> Result<AuditEntry, AuditError> {
+        let mut head = self.head.lock().await;
+        let timestamp = Utc::now().to_rfc3339();
+        let hash = entry_hash(head.sequence, &timestamp, &event, &head.hash)?;
+        let signature = base64::encode_config(
+            auth::authenticate(hash.as_bytes(), &self.key).as_ref(),
+            base64::URL_SAFE_NO_PAD,
+        );
+        let entry = AuditEntry {
+            sequence: head.sequence,
+            timestamp,
+            event,
+            previous_hash: head.hash.clone(),
+            hash,
+            signature,
+        };
+
+        let mut line = serde_json::to_vec(&entry)
+            .map_err(|source| AuditError::SerializationError { source })?;
+        line.push(b'\n');
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .await
+            .map_err(|source| AuditError::IoError { source })?;
+        file.write_all(&line)
+            .await
+            .map_err(|source| AuditError::IoError { source })?;
+        file.sync_data()
+            .await
+            .map_err(|source| AuditError::IoError { source })?;
+
+        head.sequence += 1;
+        head.hash = entry.hash.clone();
+        Ok(entry)
+    }
+
+    /// Only the listed entries are read and verified, along with the one before them, and
+    /// the last is checked against the hash kept in memory; the rest of the chain was
+    /// verified when the log was opened
+    #[tracing::instrument(skip(self))]
+    async fn recent(&self, limit: usize) -> Result<AuditTrail, AuditError> {
+        // Holding the head keeps a half-written entry from being read
+        let head = self.head.lock().await;
+        let (mut entries, from_start) = read_tail(&self.path, limit.max(1) + 1).await?;
+
+        let (first_sequence, previous_hash, anchor_broken) = if from_start {
+            (0, GENESIS_HASH.to_owned(), None)
+        } else {
+            let first_sequence = head.sequence.saturating_sub(entries.len() as u64 - 1);
+            match entries.remove(0) {
+                Some(anchor) => (first_sequence, anchor.hash, None),
+                None => (
+                    first_sequence,
+                    GENESIS_HASH.to_owned(),
+                    Some(first_sequence.saturating_sub(1)),
+                ),
+            }
+        };
+        let last_sequence = first_sequence + entries.len() as u64;
+        let last_hash = entries
+            .last()
+            .and_then(|entry| entry.as_ref())
+            .map_or(previous_hash.as_str(), |entry| entry.hash.as_str());
+        let tail_broken =
+            verify(&entries, first_sequence, &previous_hash, &self.key).or_else(|| {
+                if last_sequence != head.sequence || last_hash != head.hash {
+                    Some(last_sequence.min(head.sequence.saturating_sub(1)))
+                } else {
+                    None
+                }
+            });
+        let broken_at = [head.broken_at, anchor_broken, tail_broken]
+            .iter()
+            .flatten()
+            .min()
+            .copied();
+
+        Ok(AuditTrail {
+            total: head.sequence,
+            entries: entries.into_iter().rev().flatten().take(limit).collect(),
+            broken_at,
+        })
+    }
+}
+
+fn entry_hash(
+    sequence: u64,
+    timestamp: &str,
+    event: &AuditEvent,
+    previous_hash: &str,
+) -> Result<String, AuditError> {
+    let fields = serde_json::to_vec(&ChainedFields {
+        sequence,
+        timestamp,
+        event,
+        previous_hash,
+    })
+    .map_err(|source| AuditError::SerializationError { source })?;
+    Ok(format!("{:x}", Sha256::digest(&fields)))
+}
+
+/// Every line of the log, which is `None` where a line isn't an entry
+fn read_entries(path: &Path) -> Result<Vec<Option<AuditEntry>>, AuditError> {
+    match std::fs::read_to_string(path) {
+        Ok(contents) => Ok(parse_lines(&contents)),
+        Err(e) => match e.kind() {
+            ErrorKind::NotFound => Ok(vec![]),
+            _ => Err(AuditError::IoError { source: e }),
+        },
+    }
+}
+
+/// Up to the last `count` lines of the log, read from its end, and whether they start at
+/// the beginning of the log
+async fn read_tail(
+    path: &Path,
+    count: usize,
+) -> Result<(Vec<Option<AuditEntry>>, bool), AuditError> {
+    let mut file = match File::open(path).await {
+        Ok(file) => file,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok((vec![], true)),
+        Err(source) => return Err(AuditError::IoError { source }),
+    };
+    let mut end = file
+        .metadata()
+        .await
+        .map_err(|source| AuditError::IoError { source })?
+        .len();
+    let mut tail = Vec::new();
+
+    loop {
+        let start = end.saturating_sub(TAIL_CHUNK_BYTES);
+        let mut chunk = vec![0; (end - start) as usize];
+        file.seek(SeekFrom::Start(start))
+            .await
+            .map_err(|source| AuditError::IoError { source })?;
+        file.read_exact(&mut chunk)
+            .await
+            .map_err(|source| AuditError::IoError { source })?;
+        chunk.extend_from_slice(&tail);
+        tail = chunk;
+        end = start;
+
+        let contents = String::from_utf8_lossy(&tail);
+        let mut lines: Vec<&str> = contents
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .collect();
+        if start > 0 {
+            // The chunk may have started partway through a line
+            lines.remove(0);
+        }
+        if start == 0 || lines.len() >= count {
+            let from_start = start == 0 && lines.len() <= count;
+            let skip = lines.len().saturating_sub(count);
+            return Ok((parse_lines(&lines[skip..].join("\n")), from_start));
+        }
+    }
+}
+
+fn parse_lines(contents: &str) -> Vec<Option<AuditEntry>> {
+    contents
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| serde_json::from_str(line).ok())
+        .collect()
+}
+
+/// The sequence number of the first entry which doesn't follow from the one before it, if
+/// any, where the first entry should be numbered `first_sequence` and chained to
+/// `previous_hash`
+fn verify(
+    entries: &[Option<AuditEntry>],
+    first_sequence: u64,
+    previous_hash: &str,
+    key: &auth::Key,
+) -> Option<u64> {
+    let mut previous_hash = previous_hash.to_owned();
+    for (position, entry) in entries.iter().enumerate() {
+        let sequence = first_sequence + position as u64;
+        let entry = match entry {
+            Some(entry) => entry,
+            None => return Some(sequence),
+        };
+        let hash = entry_hash(
+            entry.sequence,
+            &entry.timestamp,
+            &entry.event,
+            &entry.previous_hash,
+        )
+        .ok();
+        let signature = base64::decode_config(&entry.signature, base64::URL_SAFE_NO_PAD)
+            .ok()
+            .and_then(|signature| auth::Tag::from_slice(&signature));
+        let signed = match signature {
+            Some(signature) => auth::verify(&signature, entry.hash.as_bytes(), key),
+            None => false,
+        };
+
+        if entry.sequence != sequence
+            || entry.previous_hash != previous_hash
+            || hash.as_deref() != Some(entry.hash.as_str())
+            || !signed
+        {
+            return Some(sequence);
+        }
+        previous_hash = entry.hash.clone();
+    }
+    None
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::{
+        AuditEntry, AuditError, AuditEvent, AuditTrail, Auditor, FileAuditor, Operation, Outcome,
+    };
+    use crate::test_utils::temp_dir;
+    use async_trait::async_trait;
+    use mockall::*;
+    use std::path::PathBuf;
+    use tempfile::TempDir;
+
+    mock! {
+    pub Auditor {}
+    impl Clone for Auditor {
+            fn clone(&self) -> Self;
+    }
+
+    #[async_trait]
+    impl Auditor for Auditor {
+        async fn record(&self, event: AuditEvent) -> Result<AuditEntry, AuditError>;
+        async fn recent(&self, limit: usize) -> Result<AuditTrail, AuditError>;
+    }
+    }
+
+    /// An auditor which expects the given operation to be recorded once with each of the
+    /// outcomes, in order
+    pub fn expect_audits(operation: Operation, outcomes: Vec<Outcome>) -> MockAuditor {
+        let mut auditor = MockAuditor::new();
+        let mut sequence = Sequence::new();
+        for outcome in outcomes {
+            auditor
+                .expect_record()
+                .times(1)
+                .in_sequence(&mut sequence)
+                .withf(move |event| event.operation == operation && event.outcome == outcome)
+                .returning(|event| {
+                    Ok(AuditEntry {
+                        sequence: 0,
+                        timestamp: "".to_owned(),
+                        event,
+                        previous_hash: "".to_owned(),
+                        hash: "".to_owned(),
+                        signature: "".to_owned(),
+                    })
+                });
+        }
+        auditor
+    }
+
+    fn temp_log_path() -> (TempDir, PathBuf) {
+        let dir = temp_dir();
+        let path = dir.path().join("audit.log");
+        (dir, path)
+    }
+
+    fn event(path: &str, operation: Operation) -> AuditEvent {
+        AuditEvent::new("https://example.com", path, operation, true)
+    }
+
+    #[tokio::test]
+    async fn test_chain_continues_after_reopening_log() {
+        let (_dir, path) = temp_log_path();
+        let auditor = FileAuditor::open(&path, b"root key").unwrap();
+        auditor
+            .record(event(".profile.name.", Operation::View))
+            .await
+            .unwrap();
+
+        let auditor = FileAuditor::open(&path, b"root key").unwrap();
+        let entry = auditor
+            .record(event(".profile.name.", Operation::Edit))
+            .await
+            .unwrap();
+        assert_eq!(entry.sequence, 1);
+
+        let trail = auditor.recent(10).await.unwrap();
+        assert_eq!(trail.total, 2);
+        assert_eq!(trail.broken_at, None);
+        assert_eq!(trail.entries[0].event.operation, Operation::Edit);
+        assert_eq!(trail.entries[1].event.operation, Operation::View);
+    }
+
+    #[tokio::test]
+    async fn test_altered_entry_breaks_chain() {
+        let (_dir, path) = temp_log_path();
+        let auditor = FileAuditor::open(&path, b"root key").unwrap();
+        for path in &[".profile.name.", ".profile.age.", ".profile.email."] {
+            auditor.record(event(path, Operation::View)).await.unwrap();
+        }
+
+        let log = std::fs::read_to_string(&path).unwrap();
+        std::fs::write(&path, log.replacen(".profile.age.", ".profile.bio.", 1)).unwrap();
+        assert_eq!(auditor.recent(10).await.unwrap().broken_at, Some(1));
+    }
+
+    #[tokio::test]
+    async fn test_removed_entry_breaks_chain() {
+        let (_dir, path) = temp_log_path();
+        let auditor = FileAuditor::open(&path, b"root key").unwrap();
+        for path in &[".profile.name.", ".profile.age."] {
+            auditor.record(event(path, Operation::View)).await.unwrap();
+        }
+
+        let log = std::fs::read_to_string(&path).unwrap();
+        let remaining: Vec<&str> = log.lines().skip(1).collect();
+        std::fs::write(&path, remaining.join("\n")).unwrap();
+        assert_eq!(auditor.recent(10).await.unwrap().broken_at, Some(0));
+    }
+
+    #[tokio::test]
+    async fn test_log_signed_with_other_key_breaks_chain() {
+        let (_dir, path) = temp_log_path();
+        let auditor = FileAuditor::open(&path, b"root key").unwrap();
+        auditor
+            .record(event(".profile.name.", Operation::View))
+            .await
+            .unwrap();
+
+        let other = FileAuditor::open(&path, b"other root key").unwrap();
+        assert_eq!(other.recent(10).await.unwrap().broken_at, Some(0));
+    }
+
+    #[tokio::test]
+    async fn test_recent_reads_only_the_end_of_a_long_log() {
+        let (_dir, path) = temp_log_path();
+        let auditor = FileAuditor::open(&path, b"root key").unwrap();
+        for i in 0..300 {
+            auditor
+                .record(event(&format!(".profile.{}.", i), Operation::View))
+                .await
+                .unwrap();
+        }
+
+        let trail = auditor.recent(3).await.unwrap();
+        assert_eq!(trail.total, 300);
+        assert_eq!(trail.broken_at, None);
+        let sequences: Vec<u64> = trail.entries.iter().map(|entry| entry.sequence).collect();
+        assert_eq!(sequences, vec![299, 298, 297]);
+
+        let log = std::fs::read_to_string(&path).unwrap();
+        std::fs::write(&path, log.replacen(".profile.298.", ".profile.bio.", 1)).unwrap();
+        assert_eq!(auditor.recent(3).await.unwrap().broken_at, Some(298));
+    }
+
+    #[tokio::test]
+    async fn test_truncated_log_breaks_chain() {
+        let (_dir, path) = temp_log_path();
+        let auditor = FileAuditor::open(&path, b"root key").unwrap();
+        for path in &[".profile.name.", ".profile.age.", ".profile.email."] {
+            auditor.record(event(path, Operation::View)).await.unwrap();
+        }
+
+        let log = std::fs::read_to_string(&path).unwrap();
+        let remaining: Vec<&str> = log.lines().take(2).collect();
+        std::fs::write(&path, remaining.join("\n")).unwrap();
+        assert_eq!(auditor.recent(10).await.unwrap().broken_at, Some(2));
+    }
+}
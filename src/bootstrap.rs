@@ -1,20 +1,79 @@
+pub mod pki;
+
 use crate::{
+    audit::FileAuditor,
+    cors::{CorsConfig, CorsPolicy, RouteGroup},
     error::ClientError,
+    key_rotation::RotationState,
+    key_selector::{KeyPolicy, KeySelector},
+    relay_queue::{FileRelayQueue, RelayQueueConfig},
+    relayer::{
+        identity::RelayIdentity,
+        policy::{CircuitBreakerPolicy, RelayPolicy, RetryPolicy, Timeouts},
+        MutualTLSRelayer,
+    },
     render::{HandlebarsRenderer, RenderError},
+    routes::{
+        cookie::{SameSite, SessionCookieConfig},
+        proxy::ProxyConfig,
+    },
+    session_store::{ClientSessionStore, FileSessionStore},
+    token::{FromThreadRng, SignedTokenIssuer},
 };
+use chrono::Duration;
 use redact_config::Configurator;
 use redact_crypto::{
-    Algorithm, Builder, CryptoError, Entry, HasBuilder, State, StorableType, TypeBuilderContainer,
+    key::sodiumoxide::{SodiumOxideEd25519SecretAsymmetricKey, SodiumOxideSymmetricKey},
+    storage::redact::ClientTlsConfig,
+    Algorithm, Builder, CryptoError, Entry, HasBuilder, HasByteSource, State, StorableType, Storer,
+    TypeBuilderContainer,
 };
-use std::{collections::HashMap, convert::TryInto};
+use reqwest::Certificate;
+use std::{collections::HashMap, convert::TryInto, io::ErrorKind};
+use warp_sessions::MemoryStore;
 
 pub fn setup_html_render_engine<'reg>() -> Result<HandlebarsRenderer<'reg>, RenderError> {
     let mut template_mapping = HashMap::new();
     template_mapping.insert("unsecure", "./static/unsecure.handlebars");
     template_mapping.insert("secure", "./static/secure.handlebars");
+    template_mapping.insert("consent", "./static/consent.handlebars");
+    template_mapping.insert("audit", "./static/audit.handlebars");
+    template_mapping.insert("relays", "./static/relays.handlebars");
+    template_mapping.insert("error", "./static/error.handlebars");
     HandlebarsRenderer::new(template_mapping)
 }
 
+/// Creates every key, certificate and identity bundle the client needs to run: its PKI,
+/// the default key and the keys named by the key policies, and the session store's key
+pub async fn initialize<T: Configurator, S: Storer>(
+    config: &T,
+    storer: &S,
+) -> Result<(), ClientError> {
+    pki::setup_pki(config).await?;
+    setup_storer_tls(config)?;
+    setup_key_selector(config, storer).await?;
+    setup_session_store(config).await?;
+    Ok(())
+}
+
+/// Reads the identity and CA used for mutual TLS with the storer and makes them current
+/// for every `RedactStorer`
+pub fn setup_storer_tls<T: Configurator>(config: &T) -> Result<ClientTlsConfig, ClientError> {
+    let pkcs12_path = config
+        .get_str("storage.tls.client.pkcs12.filepath")
+        .map_err(|e| ClientError::ConfigError { source: e })?;
+    let server_ca_path = config
+        .get_str("storage.tls.server.ca.filepath")
+        .ok()
+        .filter(|path| !path.is_empty());
+    let tls_config = ClientTlsConfig {
+        pkcs12_path,
+        server_ca_path,
+    };
+    tls_config.clone().make_current();
+    Ok(tls_config)
+}
+
 pub async fn setup_entry<Z: StorableType, T: Configurator>(
     config: &T,
     config_path: &str,
@@ -71,3 +130,318 @@ pub async fn setup_entry<Z: StorableType, T: Configurator>(
         },
     }
 }
+
+pub async fn setup_session_store<T: Configurator>(
+    config: &T,
+) -> Result<ClientSessionStore, ClientError> {
+    let store_type = match config.get_str("sessions.store.type") {
+        Ok(store_type) => store_type,
+        Err(redact_config::ConfigError::NotFound(_)) => "memory".to_owned(),
+        Err(e) => return Err(ClientError::ConfigError { source: e }),
+    };
+
+    match store_type.to_ascii_lowercase().as_ref() {
+        "memory" => Ok(ClientSessionStore::Memory(MemoryStore::new())),
+        "file" => {
+            let filepath = config
+                .get_str("sessions.store.filepath")
+                .map_err(|e| ClientError::ConfigError { source: e })?;
+            let key_entry: Entry<SodiumOxideSymmetricKey> =
+                setup_entry(config, "keys.encryption.symmetric.sessions").await?;
+            let key = key_entry
+                .resolve()
+                .await
+                .map_err(|e| ClientError::CryptoError { source: e })?;
+            let key_bs = key.byte_source();
+            let key_bytes = key_bs
+                .get()
+                .map_err(|e| ClientError::SourceError { source: e })?;
+            let store = FileSessionStore::new(filepath, key_bytes).map_err(|e| {
+                ClientError::InternalError {
+                    source: Box::new(e),
+                }
+            })?;
+            Ok(ClientSessionStore::File(store))
+        }
+        _ => Err(ClientError::InternalError {
+            source: format!(
+                "session store type '{}' is not one of 'memory' or 'file'",
+                store_type
+            )
+            .into(),
+        }),
+    }
+}
+
+/// Creates the default symmetric key along with every key named by the
+/// `keys.encryption.policies` list, and builds the selector which picks between them;
+/// keys which have been rotated are replaced by the key they were rotated to
+pub async fn setup_key_selector<T: Configurator, S: Storer>(
+    config: &T,
+    storer: &S,
+) -> Result<KeySelector, ClientError> {
+    let rotation_state = RotationState::load(rotation_state_filepath(config)?).map_err(|e| {
+        ClientError::InternalError {
+            source: Box::new(e),
+        }
+    })?;
+    let default_key = rotation_state.current_key("default");
+    let default_key_path = setup_symmetric_key(config, storer, default_key).await?;
+    let policies = match config.get::<Vec<KeyPolicy>>("keys.encryption.policies") {
+        Ok(policies) => policies,
+        Err(redact_config::ConfigError::NotFound(_)) => vec![],
+        Err(e) => return Err(ClientError::ConfigError { source: e }),
+    };
+
+    let mut key_paths: HashMap<String, String> = HashMap::new();
+    let mut selector_policies = Vec::with_capacity(policies.len());
+    for policy in policies {
+        let key = rotation_state.current_key(&policy.key);
+        let key_path = match key_paths.get(key) {
+            Some(key_path) => key_path.clone(),
+            None => {
+                let key_path = setup_symmetric_key(config, storer, key).await?;
+                key_paths.insert(key.to_owned(), key_path.clone());
+                key_path
+            }
+        };
+        selector_policies.push((policy.prefix, key_path));
+    }
+
+    Ok(KeySelector::new(default_key_path, selector_policies))
+}
+
+/// Creates the symmetric key configured under `keys.encryption.symmetric.<name>` if it
+/// doesn't exist and returns its storage path
+pub async fn setup_symmetric_key<T: Configurator, S: Storer>(
+    config: &T,
+    storer: &S,
+    name: &str,
+) -> Result<String, ClientError> {
+    let entry: Entry<SodiumOxideSymmetricKey> =
+        setup_entry(config, &format!("keys.encryption.symmetric.{}", name)).await?;
+    let path = entry.path.clone();
+    storer
+        .create(entry)
+        .await
+        .map_err(|e| ClientError::CryptoError { source: e })?;
+    Ok(path)
+}
+
+/// The storage path of the symmetric key configured under `keys.encryption.symmetric.<name>`
+pub fn symmetric_key_path<T: Configurator>(config: &T, name: &str) -> Result<String, ClientError> {
+    let entry = config
+        .get::<Entry<SodiumOxideSymmetricKey>>(&format!("keys.encryption.symmetric.{}", name))
+        .map_err(|e| ClientError::ConfigError { source: e })?;
+    Ok(entry.path)
+}
+
+/// The file recording the progress of symmetric key rotations
+pub fn rotation_state_filepath<T: Configurator>(config: &T) -> Result<String, ClientError> {
+    match config.get_str("keys.rotation.state_filepath") {
+        Ok(filepath) => Ok(filepath),
+        Err(redact_config::ConfigError::NotFound(_)) => Ok("keys/rotation.json".to_owned()),
+        Err(e) => Err(ClientError::ConfigError { source: e }),
+    }
+}
+
+/// Creates the issuer of iframe tokens, which signs them with a key derived from the root
+/// signing key and expires them after `tokens.lifetime` seconds
+pub async fn setup_token_issuer<T: Configurator>(
+    config: &T,
+    root_signing_key_entry: &Entry<SodiumOxideEd25519SecretAsymmetricKey>,
+) -> Result<SignedTokenIssuer<FromThreadRng>, ClientError> {
+    let lifetime = match config.get_int("tokens.lifetime") {
+        Ok(lifetime) if lifetime > 0 => lifetime,
+        Ok(_) | Err(redact_config::ConfigError::NotFound(_)) => 60,
+        Err(e) => return Err(ClientError::ConfigError { source: e }),
+    };
+    let root_signing_key = root_signing_key_entry
+        .resolve()
+        .await
+        .map_err(|e| ClientError::CryptoError { source: e })?;
+    let key_bs = root_signing_key.byte_source();
+    let key_bytes = key_bs
+        .get()
+        .map_err(|e| ClientError::SourceError { source: e })?;
+
+    Ok(SignedTokenIssuer::new(
+        FromThreadRng::new(),
+        key_bytes,
+        Duration::seconds(lifetime),
+    ))
+}
+
+/// Opens the audit log at `audit.filepath`, whose entries are signed with a key derived
+/// from the root signing key
+pub async fn setup_auditor<T: Configurator>(
+    config: &T,
+    root_signing_key_entry: &Entry<SodiumOxideEd25519SecretAsymmetricKey>,
+) -> Result<FileAuditor, ClientError> {
+    let filepath = match config.get_str("audit.filepath") {
+        Ok(filepath) => filepath,
+        Err(redact_config::ConfigError::NotFound(_)) => "audit/audit.log".to_owned(),
+        Err(e) => return Err(ClientError::ConfigError { source: e }),
+    };
+    let root_signing_key = root_signing_key_entry
+        .resolve()
+        .await
+        .map_err(|e| ClientError::CryptoError { source: e })?;
+    let key_bs = root_signing_key.byte_source();
+    let key_bytes = key_bs
+        .get()
+        .map_err(|e| ClientError::SourceError { source: e })?;
+
+    FileAuditor::open(filepath, key_bytes).map_err(|e| ClientError::InternalError {
+        source: Box::new(e),
+    })
+}
+
+/// Creates the identity sent with every relay, signed with the root signing key and
+/// naming the user by `relayer.user_id` or else by the key's fingerprint
+pub async fn setup_relay_identity<T: Configurator>(
+    config: &T,
+    root_signing_key_entry: &Entry<SodiumOxideEd25519SecretAsymmetricKey>,
+) -> Result<RelayIdentity, ClientError> {
+    let user_id = match config.get_str("relayer.user_id") {
+        Ok(user_id) => Some(user_id),
+        Err(redact_config::ConfigError::NotFound(_)) => None,
+        Err(e) => return Err(ClientError::ConfigError { source: e }),
+    };
+    let root_signing_key = root_signing_key_entry
+        .resolve()
+        .await
+        .map_err(|e| ClientError::CryptoError { source: e })?;
+    let key_bs = root_signing_key.byte_source();
+    let key_bytes = key_bs
+        .get()
+        .map_err(|e| ClientError::SourceError { source: e })?;
+
+    RelayIdentity::new(user_id, key_bytes).map_err(|e| ClientError::InternalError {
+        source: Box::new(e),
+    })
+}
+
+/// Reads the relayer's timeouts, retry policy and circuit breaker from `relayer.timeouts`,
+/// `relayer.retry` and `relayer.circuit_breaker`, keeping the defaults for any not set
+pub fn setup_relay_policy<T: Configurator>(config: &T) -> Result<RelayPolicy, ClientError> {
+    let timeouts = match config.get::<Timeouts>("relayer.timeouts") {
+        Ok(timeouts) => timeouts,
+        Err(redact_config::ConfigError::NotFound(_)) => Timeouts::default(),
+        Err(e) => return Err(ClientError::ConfigError { source: e }),
+    };
+    let retry = match config.get::<RetryPolicy>("relayer.retry") {
+        Ok(retry) => retry,
+        Err(redact_config::ConfigError::NotFound(_)) => RetryPolicy::default(),
+        Err(e) => return Err(ClientError::ConfigError { source: e }),
+    };
+    let circuit_breaker = match config.get::<CircuitBreakerPolicy>("relayer.circuit_breaker") {
+        Ok(circuit_breaker) => circuit_breaker,
+        Err(redact_config::ConfigError::NotFound(_)) => CircuitBreakerPolicy::default(),
+        Err(e) => return Err(ClientError::ConfigError { source: e }),
+    };
+    Ok(RelayPolicy {
+        timeouts,
+        retry,
+        circuit_breaker,
+    })
+}
+
+/// Reads the CA certificate a TLS server is verified with in place of the built-in roots,
+/// or none if no path is configured or nothing is at the path yet
+pub fn setup_ca_certs(filepath: Option<String>) -> Result<Option<Vec<Certificate>>, ClientError> {
+    let filepath = match filepath {
+        Some(filepath) => filepath,
+        None => return Ok(None),
+    };
+    let bytes = match std::fs::read(&filepath) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => {
+            return Err(ClientError::InternalError {
+                source: Box::new(e),
+            })
+        }
+    };
+    let cert = Certificate::from_pem(&bytes).map_err(|e| ClientError::InternalError {
+        source: Box::new(e),
+    })?;
+    Ok(Some(vec![cert]))
+}
+
+/// Creates a mutual TLS client identified by the PEM certificate and key at
+/// `identity_filepath`
+pub fn setup_relayer(
+    identity_filepath: String,
+    ca_certs: Option<&[Certificate]>,
+    identity: RelayIdentity,
+    policy: RelayPolicy,
+) -> Result<MutualTLSRelayer, ClientError> {
+    MutualTLSRelayer::new(identity_filepath, ca_certs, identity, policy).map_err(|e| {
+        ClientError::InternalError {
+            source: Box::new(e),
+        }
+    })
+}
+
+/// Opens the queue of relays waiting to be delivered, configured by `relayer.queue`
+pub async fn setup_relay_queue<T: Configurator>(config: &T) -> Result<FileRelayQueue, ClientError> {
+    let queue_config = match config.get::<RelayQueueConfig>("relayer.queue") {
+        Ok(queue_config) => queue_config,
+        Err(redact_config::ConfigError::NotFound(_)) => RelayQueueConfig::default(),
+        Err(e) => return Err(ClientError::ConfigError { source: e }),
+    };
+    FileRelayQueue::open(queue_config)
+        .await
+        .map_err(|e| ClientError::InternalError {
+            source: Box::new(e),
+        })
+}
+
+/// Reads which response headers the proxy passes through and how large a response it
+/// passes back from `proxy`, keeping the defaults for any which aren't set
+pub fn setup_proxy_config<T: Configurator>(config: &T) -> Result<ProxyConfig, ClientError> {
+    match config.get::<ProxyConfig>("proxy") {
+        Ok(proxy_config) => Ok(proxy_config),
+        Err(redact_config::ConfigError::NotFound(_)) => Ok(ProxyConfig::default()),
+        Err(e) => Err(ClientError::ConfigError { source: e }),
+    }
+}
+
+/// Reads the session cookie attributes from `sessions.cookie`, keeping the defaults for
+/// any which aren't set
+pub fn setup_session_cookie<T: Configurator>(
+    config: &T,
+) -> Result<SessionCookieConfig, ClientError> {
+    let cookie_config = match config.get::<SessionCookieConfig>("sessions.cookie") {
+        Ok(cookie_config) => cookie_config,
+        Err(redact_config::ConfigError::NotFound(_)) => SessionCookieConfig::default(),
+        Err(e) => return Err(ClientError::ConfigError { source: e }),
+    };
+    if cookie_config.same_site == SameSite::None && !cookie_config.secure {
+        tracing::warn!(
+            "session cookie is SameSite=None without Secure, which browsers reject for \
+             third-party iframes; set sessions.cookie.secure and enable server.tls"
+        );
+    }
+    Ok(cookie_config)
+}
+
+/// Reads the CORS policy of a route group from `cors.<group>`, falling back to the
+/// group's default policy; `self_origin` replaces the `self` origin keyword
+pub fn setup_cors_policy<T: Configurator>(
+    config: &T,
+    group: RouteGroup,
+    self_origin: &str,
+) -> Result<CorsPolicy, ClientError> {
+    let cors_config = match config.get::<CorsConfig>(group.config_key()) {
+        Ok(cors_config) => cors_config,
+        Err(redact_config::ConfigError::NotFound(_)) => group.default_config(),
+        Err(e) => return Err(ClientError::ConfigError { source: e }),
+    };
+    CorsPolicy::from_config(group, &cors_config, self_origin).map_err(|e| {
+        ClientError::InternalError {
+            source: Box::new(e),
+        }
+    })
+}
@@ -0,0 +1,115 @@
+use redact_crypto::Data;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// A composite value made up of the scalar `Data` types
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "t", content = "c")]
+pub enum CompoundData {
+    List(Vec<Data>),
+    Map(BTreeMap<String, Data>),
+}
+
+/// How a compound value is laid out in storage
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum CompoundLayout {
+    /// The whole value is serialized to JSON and sealed as a single string entry
+    #[default]
+    Entry,
+    /// Each element is sealed as its own entry at a child path of the value's path,
+    /// e.g. `.profile.address.street.` for the `street` key of `.profile.address.`
+    Children,
+}
+
+/// Either a single scalar value or a compound value
+#[derive(Debug, Clone, PartialEq)]
+pub enum DataValue {
+    Scalar(Data),
+    Compound(CompoundData),
+}
+
+impl CompoundData {
+    /// Creates an empty compound value for a `data_type` of `list` or `map`
+    pub fn empty(data_type: &str) -> Option<CompoundData> {
+        match data_type.to_ascii_lowercase().as_ref() {
+            "list" => Some(CompoundData::List(vec![])),
+            "map" => Some(CompoundData::Map(BTreeMap::new())),
+            _ => None,
+        }
+    }
+
+    /// Rebuilds a compound value of the same kind from its children, keyed by the last
+    /// segment of their path; list children are ordered by their numeric index
+    pub fn with_children(&self, children: Vec<(String, Data)>) -> CompoundData {
+        match self {
+            CompoundData::List(_) => {
+                let mut indexed: Vec<(usize, Data)> = children
+                    .into_iter()
+                    .filter_map(|(key, data)| key.parse::<usize>().ok().map(|i| (i, data)))
+                    .collect();
+                indexed.sort_by_key(|(i, _)| *i);
+                CompoundData::List(indexed.into_iter().map(|(_, data)| data).collect())
+            }
+            CompoundData::Map(_) => CompoundData::Map(children.into_iter().collect()),
+        }
+    }
+
+    /// The elements of this value paired with the key they are stored under
+    pub fn children(&self) -> Vec<(String, Data)> {
+        match self {
+            CompoundData::List(items) => items
+                .iter()
+                .enumerate()
+                .map(|(i, data)| (i.to_string(), data.clone()))
+                .collect(),
+            CompoundData::Map(items) => items
+                .iter()
+                .map(|(key, data)| (key.clone(), data.clone()))
+                .collect(),
+        }
+    }
+}
+
+/// The storage path of the child of `path` with the given key
+pub fn child_path(path: &str, key: &str) -> String {
+    format!("{}{}.", path, key)
+}
+
+/// The key of `child` if it is a direct child of `path`, as created by `child_path`
+pub fn child_key<'a>(path: &str, child: &'a str) -> Option<&'a str> {
+    child
+        .strip_prefix(path)
+        .and_then(|rest| rest.strip_suffix('.'))
+        .filter(|key| !key.is_empty() && !key.contains('.'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{child_key, child_path, CompoundData};
+    use redact_crypto::Data;
+
+    #[test]
+    fn test_child_path_round_trip() {
+        let path = child_path(".profile.address.", "street");
+        assert_eq!(path, ".profile.address.street.");
+        assert_eq!(child_key(".profile.address.", &path), Some("street"));
+    }
+
+    #[test]
+    fn test_child_key_ignores_grandchildren() {
+        assert_eq!(child_key(".profile.", ".profile.address.street."), None);
+        assert_eq!(child_key(".profile.", ".profile."), None);
+        assert_eq!(child_key(".profile.", ".other.name."), None);
+    }
+
+    #[test]
+    fn test_list_with_children_is_ordered_by_index() {
+        let list = CompoundData::List(vec![]).with_children(vec![
+            ("10".to_owned(), Data::U64(10)),
+            ("2".to_owned(), Data::U64(2)),
+            ("name".to_owned(), Data::U64(0)),
+        ]);
+        assert_eq!(list, CompoundData::List(vec![Data::U64(2), Data::U64(10)]));
+    }
+}
@@ -0,0 +1,581 @@
+use crate::routes::{error::CorsForbiddenRejection, proxy::parse_url_root};
+use serde::Deserialize;
+use std::{str::FromStr, sync::Arc};
+use thiserror::Error;
+use url::Url;
+use warp::{
+    filters::BoxedFilter,
+    http::{
+        header::{self, HeaderName},
+        Method,
+    },
+    path::Peek,
+    Filter, Rejection, Reply,
+};
+
+/// Pattern keyword which allows every origin
+const ANY_ORIGIN: &str = "*";
+
+/// Pattern keyword which is replaced by the client's own origin
+const SELF_ORIGIN: &str = "self";
+
+/// Prefix marking an origin pattern as a registrable domain whose subdomains are all allowed
+const ROOT_PREFIX: &str = "root:";
+
+#[derive(Error, Debug, PartialEq)]
+#[allow(clippy::enum_variant_names)]
+pub enum CorsError {
+    #[error("'{origin}' is not a valid origin pattern")]
+    InvalidOrigin { origin: String },
+    #[error("'{method}' is not a valid HTTP method")]
+    InvalidMethod { method: String },
+    #[error("'{header}' is not a valid HTTP header name")]
+    InvalidHeader { header: String },
+}
+
+/// An origin, or set of origins, allowed to make cross-origin requests
+#[derive(Debug, Clone, PartialEq)]
+pub enum OriginPattern {
+    /// Every origin, written as `*`
+    Any,
+    /// A single origin, written as `https://example.com`
+    Exact(String),
+    /// Every subdomain of a domain with the given scheme and port, written as
+    /// `https://*.example.com`
+    Subdomain {
+        scheme: String,
+        domain: String,
+        port: Option<u16>,
+    },
+    /// Every origin whose registrable domain is the one given, written as
+    /// `root:example.co.uk`, using the public suffix list as the proxy route does
+    Root(String),
+}
+
+impl FromStr for OriginPattern {
+    type Err = CorsError;
+
+    fn from_str(pattern: &str) -> Result<Self, Self::Err> {
+        let invalid = || CorsError::InvalidOrigin {
+            origin: pattern.to_owned(),
+        };
+        let pattern = pattern.trim();
+        if pattern == ANY_ORIGIN {
+            return Ok(OriginPattern::Any);
+        }
+        if let Some(root) = pattern.strip_prefix(ROOT_PREFIX) {
+            return match root {
+                "" => Err(invalid()),
+                root => Ok(OriginPattern::Root(root.to_ascii_lowercase())),
+            };
+        }
+        if let Some((scheme, domain)) = pattern.split_once("://*.") {
+            let url = Url::parse(&format!("{}://{}", scheme, domain)).map_err(|_| invalid())?;
+            return match (url.host_str(), url.path()) {
+                (Some(host), "/") => Ok(OriginPattern::Subdomain {
+                    scheme: url.scheme().to_owned(),
+                    domain: host.to_owned(),
+                    port: url.port_or_known_default(),
+                }),
+                _ => Err(invalid()),
+            };
+        }
+
+        // An exact origin may not carry a path, query or fragment
+        let url = Url::parse(pattern).map_err(|_| invalid())?;
+        match (url.origin(), url.path(), url.query(), url.fragment()) {
+            (origin, "/", None, None) if origin.is_tuple() => {
+                Ok(OriginPattern::Exact(origin.ascii_serialization()))
+            }
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl OriginPattern {
+    /// Whether the value of a request's `Origin` header is covered by this pattern
+    pub fn matches(&self, origin: &str) -> bool {
+        match self {
+            OriginPattern::Any => true,
+            OriginPattern::Exact(allowed) => parse_origin(origin)
+                .map(|url| &url.origin().ascii_serialization() == allowed)
+                .unwrap_or(false),
+            OriginPattern::Subdomain {
+                scheme,
+                domain,
+                port,
+            } => parse_origin(origin)
+                .map(|url| {
+                    url.scheme() == scheme
+                        && url.port_or_known_default() == *port
+                        && url
+                            .host_str()
+                            .map(|host| host.ends_with(&format!(".{}", domain)))
+                            .unwrap_or(false)
+                })
+                .unwrap_or(false),
+            OriginPattern::Root(root) => {
+                matches!(parse_url_root(origin), Ok(Some(origin_root)) if &origin_root == root)
+            }
+        }
+    }
+}
+
+/// Parses an `Origin` header value, which is `null` for opaque origins
+fn parse_origin(origin: &str) -> Option<Url> {
+    Url::parse(origin)
+        .ok()
+        .filter(|url| url.origin().is_tuple())
+}
+
+/// A route group's CORS settings, as found under `cors.<group>` in the config
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct CorsConfig {
+    pub origins: Vec<String>,
+    pub methods: Vec<String>,
+    #[serde(default)]
+    pub headers: Vec<String>,
+}
+
+/// The groups of routes which each have their own CORS policy
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RouteGroup {
+    Health,
+    Readiness,
+    Unsecure,
+    Secure,
+    Proxy,
+    Audit,
+    Relays,
+}
+
+impl RouteGroup {
+    /// The config key holding the group's `CorsConfig`
+    pub fn config_key(&self) -> &'static str {
+        match self {
+            RouteGroup::Health => "cors.health",
+            RouteGroup::Readiness => "cors.readiness",
+            RouteGroup::Unsecure => "cors.unsecure",
+            RouteGroup::Secure => "cors.secure",
+            RouteGroup::Proxy => "cors.proxy",
+            RouteGroup::Audit => "cors.audit",
+            RouteGroup::Relays => "cors.relays",
+        }
+    }
+
+    /// The first path segments of the routes in the group
+    pub fn route_names(&self) -> &'static [&'static str] {
+        match self {
+            RouteGroup::Health => &["healthz"],
+            RouteGroup::Readiness => &["readyz"],
+            RouteGroup::Unsecure => &["unsecure"],
+            RouteGroup::Secure => &["secure"],
+            RouteGroup::Proxy => &["proxy"],
+            RouteGroup::Audit => &["audit"],
+            RouteGroup::Relays => &["relays"],
+        }
+    }
+
+    /// The policy used when the group isn't configured; only the readiness, secure, audit
+    /// and relays routes are restricted to the client's own origin, as the readiness route
+    /// reports why the client's dependencies are failing
+    pub fn default_config(&self) -> CorsConfig {
+        let (origins, methods, headers): (&[&str], &[&str], &[&str]) = match self {
+            RouteGroup::Health | RouteGroup::Unsecure => (&[ANY_ORIGIN], &["GET"], &[]),
+            RouteGroup::Readiness => (&[SELF_ORIGIN], &["GET"], &[]),
+            RouteGroup::Secure => (&[SELF_ORIGIN], &["GET", "POST", "DELETE"], &[]),
+            RouteGroup::Audit => (&[SELF_ORIGIN], &["GET"], &[]),
+            RouteGroup::Relays => (&[SELF_ORIGIN], &["GET", "POST"], &[]),
+            RouteGroup::Proxy => (
+                &[ANY_ORIGIN],
+                &["GET", "POST", "OPTIONS"],
+                &["content-type"],
+            ),
+        };
+        CorsConfig {
+            origins: origins.iter().map(|s| s.to_string()).collect(),
+            methods: methods.iter().map(|s| s.to_string()).collect(),
+            headers: headers.iter().map(|s| s.to_string()).collect(),
+        }
+    }
+}
+
+/// The origins, methods and headers allowed to make cross-origin requests to a route group
+#[derive(Debug, Clone, PartialEq)]
+pub struct CorsPolicy {
+    group: RouteGroup,
+    origins: Vec<OriginPattern>,
+    methods: Vec<Method>,
+    headers: Vec<HeaderName>,
+}
+
+impl CorsPolicy {
+    /// Builds the policy of `group` from its config, replacing `self` with `self_origin`
+    pub fn from_config(
+        group: RouteGroup,
+        config: &CorsConfig,
+        self_origin: &str,
+    ) -> Result<Self, CorsError> {
+        let origins = config
+            .origins
+            .iter()
+            .map(|origin| match origin.trim() {
+                SELF_ORIGIN => self_origin.parse(),
+                origin => origin.parse(),
+            })
+            .collect::<Result<_, _>>()?;
+        let methods = config
+            .methods
+            .iter()
+            .map(|method| {
+                Method::from_bytes(method.trim().to_ascii_uppercase().as_bytes()).map_err(|_| {
+                    CorsError::InvalidMethod {
+                        method: method.to_owned(),
+                    }
+                })
+            })
+            .collect::<Result<_, _>>()?;
+        let headers = config
+            .headers
+            .iter()
+            .map(|header| {
+                HeaderName::from_bytes(header.trim().as_bytes()).map_err(|_| {
+                    CorsError::InvalidHeader {
+                        header: header.to_owned(),
+                    }
+                })
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(CorsPolicy {
+            group,
+            origins,
+            methods,
+            headers,
+        })
+    }
+
+    pub fn allows_origin(&self, origin: &str) -> bool {
+        self.origins.iter().any(|pattern| pattern.matches(origin))
+    }
+
+    fn allows_method(&self, method: &str) -> bool {
+        self.methods
+            .iter()
+            .any(|allowed| allowed.as_str() == method)
+    }
+
+    /// Whether every header in a comma-separated `Access-Control-Request-Headers` is allowed
+    fn allows_headers(&self, headers: &str) -> bool {
+        headers
+            .split(',')
+            .map(str::trim)
+            .filter(|header| !header.is_empty())
+            .all(|header| {
+                self.headers
+                    .iter()
+                    .any(|allowed| allowed.as_str().eq_ignore_ascii_case(header))
+            })
+    }
+
+    fn preflight(
+        &self,
+        origin: String,
+        method: &str,
+        headers: Option<&str>,
+    ) -> Result<Box<dyn Reply>, Rejection> {
+        if !self.allows_origin(&origin)
+            || !self.allows_method(method)
+            || !headers.map(|h| self.allows_headers(h)).unwrap_or(true)
+        {
+            return Err(warp::reject::custom(CorsForbiddenRejection));
+        }
+
+        let methods: Vec<&str> = self.methods.iter().map(Method::as_str).collect();
+        let headers: Vec<&str> = self.headers.iter().map(HeaderName::as_str).collect();
+        let reply = warp::reply::with_header(
+            warp::reply(),
+            header::ACCESS_CONTROL_ALLOW_METHODS,
+            methods.join(", "),
+        );
+        let reply = warp::reply::with_header(
+            reply,
+            header::ACCESS_CONTROL_ALLOW_HEADERS,
+            headers.join(", "),
+        );
+        Ok(with_allowed_origin(reply, origin))
+    }
+}
+
+fn with_allowed_origin<R: Reply + 'static>(reply: R, origin: String) -> Box<dyn Reply> {
+    let reply = warp::reply::with_header(reply, header::ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+    Box::new(warp::reply::with_header(reply, header::VARY, "origin"))
+}
+
+/// Applies `policy` to the routes of its group: preflight requests are answered directly,
+/// and requests from an origin the policy doesn't allow are rejected before reaching
+/// the routes
+pub fn wrap<F, R>(policy: CorsPolicy) -> impl Fn(F) -> BoxedFilter<(Box<dyn Reply>,)>
+where
+    F: Filter<Extract = (R,), Error = Rejection> + Clone + Send + Sync + 'static,
+    R: Reply + 'static,
+{
+    let group = policy.group;
+    let policy = Arc::new(policy);
+    move |filter: F| {
+        let preflight_policy = policy.clone();
+        let request_policy = policy.clone();
+        let preflight = in_group(group)
+            .and(warp::options())
+            .and(warp::header::<String>("origin"))
+            .and(warp::header::<String>("access-control-request-method"))
+            .and(warp::header::optional::<String>(
+                "access-control-request-headers",
+            ))
+            .and_then(
+                move |origin: String, method: String, headers: Option<String>| {
+                    let policy = preflight_policy.clone();
+                    async move { policy.preflight(origin, &method, headers.as_deref()) }
+                },
+            );
+        let request = in_group(group)
+            .and(warp::method())
+            .and(warp::header::optional::<String>(
+                "access-control-request-method",
+            ))
+            .and(warp::header::optional::<String>("origin"))
+            .and_then(
+                move |method: Method, request_method: Option<String>, origin: Option<String>| {
+                    let policy = request_policy.clone();
+                    async move {
+                        // Preflights are only ever answered by the preflight filter, so one it
+                        // forbids never reaches the routes
+                        if method == Method::OPTIONS && request_method.is_some() {
+                            return Err(warp::reject::not_found());
+                        }
+                        match origin {
+                            Some(origin) if !policy.allows_origin(&origin) => {
+                                tracing::debug!("cross-origin request from a disallowed origin");
+                                Err(warp::reject::custom(CorsForbiddenRejection))
+                            }
+                            origin => Ok(origin),
+                        }
+                    }
+                },
+            )
+            .and(filter)
+            .map(|origin: Option<String>, reply: R| match origin {
+                Some(origin) => with_allowed_origin(reply, origin),
+                None => Box::new(reply) as Box<dyn Reply>,
+            });
+
+        preflight.or(request).unify().boxed()
+    }
+}
+
+/// Only passes requests whose first path segment names a route in the group, so that a
+/// group's policy is never applied to another group's routes
+fn in_group(group: RouteGroup) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::path::peek()
+        .and_then(move |peek: Peek| async move {
+            match peek.segments().next() {
+                Some(segment) if group.route_names().contains(&segment) => Ok(()),
+                _ => Err(warp::reject::not_found()),
+            }
+        })
+        .untuple_one()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{wrap, CorsPolicy, OriginPattern, RouteGroup};
+    use crate::error_handler::recover;
+    use crate::render::tests::MockRenderer;
+    use std::sync::Arc;
+    use warp::Filter;
+
+    const SELF_ORIGIN: &str = "http://localhost:8080";
+
+    fn default_policy(group: RouteGroup) -> CorsPolicy {
+        CorsPolicy::from_config(group, &group.default_config(), SELF_ORIGIN).unwrap()
+    }
+
+    #[test]
+    fn test_origin_pattern_exact() {
+        let pattern: OriginPattern = "https://app.example.com".parse().unwrap();
+        assert!(pattern.matches("https://app.example.com"));
+        assert!(pattern.matches("https://app.example.com:443"));
+        assert!(!pattern.matches("http://app.example.com"));
+        assert!(!pattern.matches("https://evil.example.com"));
+        assert!("https://app.example.com/path"
+            .parse::<OriginPattern>()
+            .is_err());
+    }
+
+    #[test]
+    fn test_origin_pattern_subdomain() {
+        let pattern: OriginPattern = "https://*.example.com".parse().unwrap();
+        assert!(pattern.matches("https://app.example.com"));
+        assert!(pattern.matches("https://a.b.example.com"));
+        assert!(!pattern.matches("https://example.com"));
+        assert!(!pattern.matches("https://evilexample.com"));
+        assert!(!pattern.matches("http://app.example.com"));
+    }
+
+    #[test]
+    fn test_origin_pattern_root() {
+        let pattern: OriginPattern = "root:host.co.uk".parse().unwrap();
+        assert!(pattern.matches("https://www.abc.host.co.uk"));
+        assert!(pattern.matches("http://host.co.uk"));
+        assert!(!pattern.matches("https://other.co.uk"));
+        assert!(!pattern.matches("null"));
+        assert!("root:".parse::<OriginPattern>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_secure_routes_only_allow_self_origin() {
+        let routes = recover(
+            warp::path!("secure" / ..)
+                .map(warp::reply)
+                .with(warp::wrap_fn(wrap(default_policy(RouteGroup::Secure)))),
+            Arc::new(MockRenderer::new()),
+        );
+
+        let res = warp::test::request()
+            .path("/secure/data/.profile.name./abc")
+            .header("origin", SELF_ORIGIN)
+            .reply(&routes)
+            .await;
+        assert_eq!(res.status(), 200);
+        assert_eq!(
+            res.headers().get("access-control-allow-origin").unwrap(),
+            SELF_ORIGIN
+        );
+
+        let res = warp::test::request()
+            .path("/secure/data/.profile.name./abc")
+            .header("origin", "https://evil.com")
+            .reply(&routes)
+            .await;
+        assert_eq!(res.status(), 403);
+
+        let res = warp::test::request()
+            .method("OPTIONS")
+            .path("/secure/data/.profile.name./abc")
+            .header("origin", SELF_ORIGIN)
+            .header("access-control-request-method", "DELETE")
+            .reply(&routes)
+            .await;
+        assert_eq!(res.status(), 200);
+        assert_eq!(
+            res.headers().get("access-control-allow-methods").unwrap(),
+            "GET, POST, DELETE"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_unsecure_routes_allow_any_origin_to_get() {
+        let routes = recover(
+            warp::path!("unsecure" / ..)
+                .map(warp::reply)
+                .with(warp::wrap_fn(wrap(default_policy(RouteGroup::Unsecure)))),
+            Arc::new(MockRenderer::new()),
+        );
+
+        let res = warp::test::request()
+            .path("/unsecure/data/.profile.name.")
+            .header("origin", "https://example.com")
+            .reply(&routes)
+            .await;
+        assert_eq!(res.status(), 200);
+
+        let res = warp::test::request()
+            .method("OPTIONS")
+            .path("/unsecure/data/.profile.name.")
+            .header("origin", "https://example.com")
+            .header("access-control-request-method", "POST")
+            .reply(&routes)
+            .await;
+        assert_eq!(res.status(), 403);
+    }
+
+    #[tokio::test]
+    async fn test_proxy_route_allows_json_preflight() {
+        let routes = recover(
+            warp::path!("proxy")
+                .map(warp::reply)
+                .with(warp::wrap_fn(wrap(default_policy(RouteGroup::Proxy)))),
+            Arc::new(MockRenderer::new()),
+        );
+
+        let res = warp::test::request()
+            .method("OPTIONS")
+            .path("/proxy")
+            .header("origin", "https://example.com")
+            .header("access-control-request-method", "POST")
+            .header("access-control-request-headers", "Content-Type")
+            .reply(&routes)
+            .await;
+        assert_eq!(res.status(), 200);
+
+        let res = warp::test::request()
+            .method("OPTIONS")
+            .path("/proxy")
+            .header("origin", "https://example.com")
+            .header("access-control-request-method", "POST")
+            .header("access-control-request-headers", "authorization")
+            .reply(&routes)
+            .await;
+        assert_eq!(res.status(), 403);
+    }
+
+    #[tokio::test]
+    async fn test_health_policy_is_scoped_to_health_routes() {
+        let routes = recover(
+            warp::path!("healthz")
+                .map(warp::reply)
+                .with(warp::wrap_fn(wrap(default_policy(RouteGroup::Health)))),
+            Arc::new(MockRenderer::new()),
+        );
+
+        let res = warp::test::request()
+            .path("/healthz")
+            .header("origin", "https://example.com")
+            .reply(&routes)
+            .await;
+        assert_eq!(res.status(), 200);
+
+        let res = warp::test::request()
+            .method("OPTIONS")
+            .path("/secure/data/.profile.name./abc")
+            .header("origin", "https://example.com")
+            .header("access-control-request-method", "GET")
+            .reply(&routes)
+            .await;
+        assert_eq!(res.status(), 404);
+    }
+
+    #[tokio::test]
+    async fn test_readiness_is_restricted_to_own_origin_by_default() {
+        let routes = recover(
+            warp::path!("readyz")
+                .map(warp::reply)
+                .with(warp::wrap_fn(wrap(default_policy(RouteGroup::Readiness)))),
+            Arc::new(MockRenderer::new()),
+        );
+
+        let res = warp::test::request()
+            .path("/readyz")
+            .header("origin", SELF_ORIGIN)
+            .reply(&routes)
+            .await;
+        assert_eq!(res.status(), 200);
+
+        let res = warp::test::request()
+            .path("/readyz")
+            .header("origin", "https://example.com")
+            .reply(&routes)
+            .await;
+        assert_eq!(res.status(), 403);
+    }
+}
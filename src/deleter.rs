@@ -0,0 +1,112 @@
+use async_trait::async_trait;
+use redact_crypto::{
+    CryptoError, Data, DataBuilder, Entry, State, Storer, StringDataBuilder, TypeBuilder,
+};
+use std::ops::Deref;
+use std::sync::Arc;
+use thiserror::Error;
+use warp::reject::Reject;
+
+/// The path deleted entries are replaced by a reference to; nothing is ever stored there,
+/// so a deleted entry resolves as not found
+pub const DELETED_PATH: &str = ".deleted.";
+
+#[derive(Error, Debug)]
+pub enum DeleteError {
+    #[error("Failure happened while deleting from storage")]
+    CryptoError { source: CryptoError },
+}
+
+impl Reject for DeleteError {}
+
+/// Removes entries from storage
+#[async_trait]
+pub trait Deleter: Clone + Send + Sync {
+    async fn delete(&self, path: &str) -> Result<(), DeleteError>;
+}
+
+#[async_trait]
+impl<U> Deleter for Arc<U>
+where
+    U: Deleter,
+{
+    async fn delete(&self, path: &str) -> Result<(), DeleteError> {
+        self.deref().delete(path).await
+    }
+}
+
+/// Whether the entry was deleted, in which case it references `DELETED_PATH`
+pub fn is_deleted(state: &State) -> bool {
+    matches!(state, State::Referenced { path, .. } if path == DELETED_PATH)
+}
+
+/// Deletes entries through a storer; the `Storer` trait can only read and write entries,
+/// so an entry is deleted by overwriting it with a reference to `DELETED_PATH`
+#[derive(Debug, Clone)]
+pub struct StorerDeleter<H: Storer> {
+    storer: H,
+}
+
+impl<H: Storer> StorerDeleter<H> {
+    pub fn new(storer: H) -> StorerDeleter<H> {
+        StorerDeleter { storer }
+    }
+}
+
+#[async_trait]
+impl<H: Storer> Deleter for StorerDeleter<H> {
+    #[tracing::instrument(skip(self))]
+    async fn delete(&self, path: &str) -> Result<(), DeleteError> {
+        let entry: Entry<Data> = Entry::new(
+            path.to_owned(),
+            TypeBuilder::Data(DataBuilder::String(StringDataBuilder {})),
+            State::Referenced {
+                path: DELETED_PATH.to_owned(),
+                storer: self.storer.clone().into(),
+            },
+        );
+        self.storer
+            .create(entry)
+            .await
+            .map(|_| ())
+            .map_err(|source| DeleteError::CryptoError { source })
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::{is_deleted, DeleteError, Deleter, StorerDeleter};
+    use crate::test_utils::FakeStore;
+    use async_trait::async_trait;
+    use mockall::predicate::*;
+    use mockall::*;
+    use redact_crypto::{Data, Storer};
+
+    mock! {
+    pub Deleter {}
+    impl Clone for Deleter {
+            fn clone(&self) -> Self;
+    }
+
+    #[async_trait]
+    impl Deleter for Deleter {
+        async fn delete(&self, path: &str) -> Result<(), DeleteError>;
+    }
+    }
+
+    #[tokio::test]
+    async fn test_deleted_entry_resolves_as_not_found() {
+        let store = FakeStore::default();
+        let storer = store.serve().await;
+        store.insert_data(".profile.name.", Data::String("alice".to_owned()));
+
+        StorerDeleter::new(storer.clone())
+            .delete(".profile.name.")
+            .await
+            .unwrap();
+
+        let entry = storer.get::<Data>(".profile.name.").await.unwrap();
+        assert!(is_deleted(&entry.value));
+        assert!(entry.take_resolve().await.is_err());
+    }
+}
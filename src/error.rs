@@ -3,6 +3,7 @@ use std::fmt::{self, Display};
 
 /// All errors that the client will encounter
 #[derive(Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum ClientError {
     /// Represents an error which occurred in some internal system
     InternalError {
@@ -23,6 +24,14 @@ pub enum ClientError {
 
     /// Error happened when handling a source
     SourceError { source: redact_crypto::SourceError },
+
+    /// Error happened when issuing a certificate
+    X509Error {
+        source: redact_crypto::cert::X509Error,
+    },
+
+    /// The client was started with command line arguments it can't act on
+    ArgumentError { message: String },
 }
 
 impl Error for ClientError {
@@ -33,6 +42,8 @@ impl Error for ClientError {
             ClientError::ConfigError { ref source } => Some(source),
             ClientError::CryptoError { ref source } => Some(source),
             ClientError::SourceError { ref source } => Some(source),
+            ClientError::X509Error { ref source } => Some(source),
+            ClientError::ArgumentError { .. } => None,
         }
     }
 }
@@ -62,6 +73,12 @@ impl Display for ClientError {
             ClientError::SourceError { .. } => {
                 write!(f, "Error occured while handling a source")
             }
+            ClientError::X509Error { .. } => {
+                write!(f, "Error occured while issuing a certificate")
+            }
+            ClientError::ArgumentError { ref message } => {
+                write!(f, "Invalid command line arguments: {}", message)
+            }
         }
     }
 }
@@ -1,54 +1,467 @@
-use crate::routes::error::{QueryParamValidationRejection, RelayRejection};
+use crate::audit::AuditError;
+use crate::deleter::DeleteError;
+use crate::metrics;
+use crate::permissions::PermissionError;
+use crate::relay_queue::RelayQueueError;
+use crate::relayer::RelayError;
+use crate::render::{
+    ErrorTemplateValues, RenderError, RenderTemplate, Rendered, Renderer, TemplateValues,
+};
+use crate::routes::error::{
+    CorsForbiddenRejection, CrossSiteRequestRejection, InvalidTokenRejection, NoPathTokenProvided,
+    PermissionDeniedRejection, ProxyResponseTooLargeRejection, QueryParamValidationRejection,
+    RelayRejection,
+};
 use crate::routes::{
-    BadRequestRejection, IframeTokensDoNotMatchRejection, SessionTokenNotFoundRejection,
+    BadRequestRejection, CryptoErrorRejection, IframeTokensDoNotMatchRejection,
+    SerializationRejection, SessionTokenNotFoundRejection,
 };
-use serde::Serialize;
+use crate::token::{TokenGenerationError, TokenVerificationError};
+use redact_crypto::CryptoError;
+use serde::{Deserialize, Serialize};
 use std::convert::Infallible;
 use warp::http::StatusCode;
-use warp::{Rejection, Reply};
+use warp::{Filter, Rejection, Reply};
+
+/// Stable, machine-readable identifier of why a request was rejected. Codes are only ever
+/// added, so websites embedding the client may match on them.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    NotFound,
+    MethodNotAllowed,
+    BadRequest,
+    InvalidQueryParameters,
+    PayloadTooLarge,
+    UnsupportedMediaType,
+    SessionTokenNotFound,
+    NoPathToken,
+    IframeTokensDoNotMatch,
+    InvalidIframeToken,
+    CorsForbidden,
+    CrossSiteRequest,
+    PermissionDenied,
+    DataNotFound,
+    ProxyOriginNotAllowed,
+    ProxyDestinationNotAllowed,
+    RelayTimeout,
+    RelayFailed,
+    RelayPaused,
+    ProxyResponseTooLarge,
+    StorageError,
+    SerializationError,
+    RenderError,
+    AuditError,
+    RelayQueueError,
+    RelayError,
+    TokenError,
+    InternalError,
+}
+
+impl ErrorCode {
+    /// Classifies a rejection, looking at the most specific rejections first
+    pub fn of(err: &Rejection) -> ErrorCode {
+        if err.is_not_found() {
+            ErrorCode::NotFound
+        } else if err.find::<SessionTokenNotFoundRejection>().is_some() {
+            ErrorCode::SessionTokenNotFound
+        } else if err.find::<NoPathTokenProvided>().is_some() {
+            ErrorCode::NoPathToken
+        } else if err.find::<IframeTokensDoNotMatchRejection>().is_some() {
+            ErrorCode::IframeTokensDoNotMatch
+        } else if err.find::<InvalidTokenRejection>().is_some()
+            || err.find::<TokenVerificationError>().is_some()
+        {
+            ErrorCode::InvalidIframeToken
+        } else if err.find::<CorsForbiddenRejection>().is_some() {
+            ErrorCode::CorsForbidden
+        } else if err.find::<CrossSiteRequestRejection>().is_some() {
+            ErrorCode::CrossSiteRequest
+        } else if err.find::<PermissionDeniedRejection>().is_some() {
+            ErrorCode::PermissionDenied
+        } else if err.find::<BadRequestRejection>().is_some() {
+            ErrorCode::BadRequest
+        } else if err.find::<QueryParamValidationRejection>().is_some()
+            || err.find::<warp::reject::InvalidQuery>().is_some()
+        {
+            ErrorCode::InvalidQueryParameters
+        } else if let Some(e) = err.find::<RelayError>() {
+            match e {
+                RelayError::Timeout { .. } => ErrorCode::RelayTimeout,
+                RelayError::BlockedAddress { .. } => ErrorCode::ProxyDestinationNotAllowed,
+                RelayError::ConnectionRefused { .. }
+                | RelayError::TlsError { .. }
+                | RelayError::HttpStatus { .. }
+                | RelayError::ResolveError { .. }
+                | RelayError::RelayRequestError { .. } => ErrorCode::RelayFailed,
+                RelayError::CircuitOpen { .. } => ErrorCode::RelayPaused,
+                _ => ErrorCode::RelayError,
+            }
+        } else if err.find::<RelayRejection>().is_some() {
+            ErrorCode::ProxyOriginNotAllowed
+        } else if err.find::<ProxyResponseTooLargeRejection>().is_some() {
+            ErrorCode::ProxyResponseTooLarge
+        } else if let Some(CryptoErrorRejection(e)) = err.find::<CryptoErrorRejection>() {
+            match e {
+                CryptoError::NotFound { .. } => ErrorCode::DataNotFound,
+                _ => ErrorCode::StorageError,
+            }
+        } else if err.find::<PermissionError>().is_some() || err.find::<DeleteError>().is_some() {
+            ErrorCode::StorageError
+        } else if err.find::<SerializationRejection>().is_some() {
+            ErrorCode::SerializationError
+        } else if err.find::<RenderError>().is_some() {
+            ErrorCode::RenderError
+        } else if err.find::<AuditError>().is_some() {
+            ErrorCode::AuditError
+        } else if err.find::<RelayQueueError>().is_some() {
+            ErrorCode::RelayQueueError
+        } else if err.find::<TokenGenerationError>().is_some() {
+            ErrorCode::TokenError
+        } else if err.find::<warp::reject::MethodNotAllowed>().is_some() {
+            ErrorCode::MethodNotAllowed
+        } else if err.find::<warp::reject::MissingHeader>().is_some()
+            || err.find::<warp::reject::InvalidHeader>().is_some()
+            || err.find::<warp::reject::MissingCookie>().is_some()
+            || err
+                .find::<warp::filters::body::BodyDeserializeError>()
+                .is_some()
+        {
+            ErrorCode::BadRequest
+        } else if err.find::<warp::reject::PayloadTooLarge>().is_some() {
+            ErrorCode::PayloadTooLarge
+        } else if err.find::<warp::reject::UnsupportedMediaType>().is_some() {
+            ErrorCode::UnsupportedMediaType
+        } else {
+            ErrorCode::InternalError
+        }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ErrorCode::NotFound | ErrorCode::DataNotFound => StatusCode::NOT_FOUND,
+            ErrorCode::MethodNotAllowed => StatusCode::METHOD_NOT_ALLOWED,
+            ErrorCode::BadRequest | ErrorCode::InvalidQueryParameters => StatusCode::BAD_REQUEST,
+            ErrorCode::PayloadTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            ErrorCode::UnsupportedMediaType => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            ErrorCode::SessionTokenNotFound
+            | ErrorCode::NoPathToken
+            | ErrorCode::IframeTokensDoNotMatch
+            | ErrorCode::InvalidIframeToken => StatusCode::UNAUTHORIZED,
+            ErrorCode::CorsForbidden
+            | ErrorCode::CrossSiteRequest
+            | ErrorCode::PermissionDenied
+            | ErrorCode::ProxyOriginNotAllowed
+            | ErrorCode::ProxyDestinationNotAllowed => StatusCode::FORBIDDEN,
+            ErrorCode::RelayTimeout => StatusCode::GATEWAY_TIMEOUT,
+            ErrorCode::RelayFailed | ErrorCode::ProxyResponseTooLarge => StatusCode::BAD_GATEWAY,
+            ErrorCode::RelayPaused => StatusCode::SERVICE_UNAVAILABLE,
+            ErrorCode::StorageError
+            | ErrorCode::SerializationError
+            | ErrorCode::RenderError
+            | ErrorCode::AuditError
+            | ErrorCode::RelayQueueError
+            | ErrorCode::RelayError
+            | ErrorCode::TokenError
+            | ErrorCode::InternalError => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    pub fn message(&self) -> &'static str {
+        match self {
+            ErrorCode::NotFound => "NOT FOUND",
+            ErrorCode::MethodNotAllowed => "METHOD NOT ALLOWED",
+            ErrorCode::BadRequest => "BAD REQUEST",
+            ErrorCode::InvalidQueryParameters => "INVALID QUERY PARAMETERS",
+            ErrorCode::PayloadTooLarge => "PAYLOAD TOO LARGE",
+            ErrorCode::UnsupportedMediaType => "UNSUPPORTED MEDIA TYPE",
+            ErrorCode::SessionTokenNotFound => "SESSION TOKEN NOT FOUND",
+            ErrorCode::NoPathToken => "NO PATH TOKEN PROVIDED",
+            ErrorCode::IframeTokensDoNotMatch => "IFRAME TOKENS DO NOT MATCH",
+            ErrorCode::InvalidIframeToken => "INVALID IFRAME TOKEN",
+            ErrorCode::CorsForbidden => "CORS REQUEST FORBIDDEN",
+            ErrorCode::CrossSiteRequest => "FORBIDDEN - Cross-Site Request",
+            ErrorCode::PermissionDenied => "PERMISSION DENIED",
+            ErrorCode::DataNotFound => "DATA NOT FOUND",
+            ErrorCode::ProxyOriginNotAllowed => "FORBIDDEN - Proxy Origin Not Allowed",
+            ErrorCode::ProxyDestinationNotAllowed => "FORBIDDEN - Proxy Destination Not Allowed",
+            ErrorCode::RelayTimeout => "GATEWAY TIMEOUT - Relay Timed Out",
+            ErrorCode::RelayFailed => "BAD GATEWAY - Relay Failed",
+            ErrorCode::RelayPaused => "SERVICE UNAVAILABLE - Relay Paused",
+            ErrorCode::ProxyResponseTooLarge => "BAD GATEWAY - Proxied Response Too Large",
+            ErrorCode::StorageError => "INTERNAL SERVER ERROR - Storage Error",
+            ErrorCode::SerializationError => "INTERNAL SERVER ERROR - Serialization Error",
+            ErrorCode::RenderError => "INTERNAL SERVER ERROR - Render Error",
+            ErrorCode::AuditError => "INTERNAL SERVER ERROR - Audit Error",
+            ErrorCode::RelayQueueError => "INTERNAL SERVER ERROR - Relay Queue Error",
+            ErrorCode::RelayError => "INTERNAL SERVER ERROR - Relay Error",
+            ErrorCode::TokenError => "INTERNAL SERVER ERROR - Token Error",
+            ErrorCode::InternalError => "INTERNAL SERVER ERROR",
+        }
+    }
+}
 
 /// An API error serializable to JSON.
 #[derive(Serialize)]
 struct ErrorMessage {
     code: u16,
+    error: ErrorCode,
     message: String,
 }
 
-// This function receives a `Rejection` and tries to return a custom
-// value, otherwise simply passes the rejection along.
-pub async fn handle_rejection(err: Rejection) -> Result<impl Reply, Infallible> {
-    let code;
-    let message;
-
-    println!("{:?}", err);
-
-    if err.is_not_found() {
-        code = StatusCode::NOT_FOUND;
-        message = "NOT FOUND";
-    } else if err.find::<SessionTokenNotFoundRejection>().is_some() {
-        code = StatusCode::UNAUTHORIZED;
-        message = "SESSION TOKEN NOT FOUND";
-    } else if err.find::<IframeTokensDoNotMatchRejection>().is_some() {
-        code = StatusCode::UNAUTHORIZED;
-        message = "IFRAME TOKENS DO NOT MATCH";
-    } else if err.find::<BadRequestRejection>().is_some() {
-        code = StatusCode::BAD_REQUEST;
-        message = "BAD REQUEST";
-    } else if err.find::<RelayRejection>().is_some() {
-        code = StatusCode::INTERNAL_SERVER_ERROR;
-        message = "INTERNAL SERVER ERROR - Relay Error";
-    } else if err.find::<QueryParamValidationRejection>().is_some() {
-        code = StatusCode::BAD_REQUEST;
-        message = "INVALID QUERY PARAMETERS";
+/// Query parameters of the rejected request used to theme the HTML error page
+#[derive(Deserialize, Debug, Default)]
+struct ErrorPageParams {
+    css: Option<String>,
+}
+
+/// Whether the rejected request expects an HTML page, i.e. it is loading an iframe or, for
+/// browsers which don't send `Sec-Fetch-Dest`, asked for HTML rather than JSON
+pub fn wants_html(sec_fetch_dest: &Option<String>, accept: &Option<String>) -> bool {
+    match sec_fetch_dest.as_deref() {
+        Some(dest) => dest.eq_ignore_ascii_case("iframe") || dest.eq_ignore_ascii_case("frame"),
+        None => accept
+            .as_ref()
+            .map(|accept| {
+                let accept = accept.to_ascii_lowercase();
+                accept.contains("text/html") && !accept.contains("application/json")
+            })
+            .unwrap_or(false),
+    }
+}
+
+/// Records the rejection in the metrics and logs, returning its classification
+fn classify(err: &Rejection) -> ErrorCode {
+    let code = ErrorCode::of(err);
+
+    if let Some(CryptoErrorRejection(e)) = err.find::<CryptoErrorRejection>() {
+        metrics::STORER_ERRORS
+            .with_label_values(&[&metrics::crypto_error_kind(e)])
+            .inc();
+    }
+
+    // Only the classification is logged, the rejection itself may carry request contents;
+    // JSON errors are logged by where they happened, as their message quotes the input
+    if let Some(SerializationRejection(e)) = err.find::<SerializationRejection>() {
+        tracing::debug!(category = ?e.classify(), line = e.line(), column = e.column(), "failed to (de)serialize JSON");
+    }
+    if let Some(InvalidTokenRejection(e)) = err.find::<InvalidTokenRejection>() {
+        tracing::debug!(reason = %e, "iframe token rejected");
+    }
+    let status = code.status();
+    if status.is_server_error() {
+        tracing::error!(code = status.as_u16(), error = ?code, reason = code.message(), "request rejected");
     } else {
-        code = StatusCode::INTERNAL_SERVER_ERROR;
-        message = "INTERNAL SERVER ERROR";
+        tracing::warn!(code = status.as_u16(), error = ?code, reason = code.message(), "request rejected");
     }
 
+    code
+}
+
+fn json_reply(code: ErrorCode) -> Box<dyn Reply> {
     let json = warp::reply::json(&ErrorMessage {
-        code: code.as_u16(),
-        message: message.into(),
+        code: code.status().as_u16(),
+        error: code,
+        message: code.message().into(),
     });
 
-    Ok(warp::reply::with_status(json, code))
+    Box::new(warp::reply::with_status(json, code.status()))
+}
+
+/// Recovers from the rejections of `routes` by replying with their error as JSON, except
+/// that requests loading an iframe are shown the "error" page instead
+pub fn recover<F, T, R>(
+    routes: F,
+    render_engine: R,
+) -> impl Filter<Extract = (Box<dyn Reply>,), Error = Infallible> + Clone
+where
+    F: Filter<Extract = (T,), Error = Rejection> + Clone + Send + Sync + 'static,
+    T: Reply + 'static,
+    R: Renderer + Clone + Send + Sync + 'static,
+{
+    warp::header::optional::<String>("sec-fetch-dest")
+        .or(warp::any().map(|| None))
+        .unify()
+        .and(
+            warp::header::optional::<String>("accept")
+                .or(warp::any().map(|| None))
+                .unify(),
+        )
+        .and(
+            warp::query::<ErrorPageParams>()
+                .or(warp::any().map(ErrorPageParams::default))
+                .unify(),
+        )
+        .and(
+            routes
+                .map(|reply: T| Ok(Box::new(reply) as Box<dyn Reply>))
+                .or_else(|rejection| async move { Ok::<_, Infallible>((Err(rejection),)) }),
+        )
+        .and(warp::any().map(move || render_engine.clone()))
+        .and_then(
+            |sec_fetch_dest: Option<String>,
+             accept: Option<String>,
+             params: ErrorPageParams,
+             result: Result<Box<dyn Reply>, Rejection>,
+             render_engine: R| async move {
+                let rejection = match result {
+                    Ok(reply) => return Ok::<_, Infallible>(reply),
+                    Err(rejection) => rejection,
+                };
+                let code = classify(&rejection);
+                if !wants_html(&sec_fetch_dest, &accept) {
+                    return Ok(json_reply(code));
+                }
+
+                match Rendered::new(
+                    &render_engine,
+                    RenderTemplate {
+                        name: "error",
+                        value: TemplateValues::Error(ErrorTemplateValues {
+                            status: code.status().as_u16(),
+                            code,
+                            message: code.message().to_owned(),
+                            css: params.css,
+                        }),
+                    },
+                ) {
+                    Ok(rendered) => Ok(Box::new(warp::reply::with_status(rendered, code.status()))
+                        as Box<dyn Reply>),
+                    Err(e) => {
+                        tracing::error!(error = %e, "failed to render error page");
+                        Ok(json_reply(code))
+                    }
+                }
+            },
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{classify, json_reply, recover, wants_html, ErrorCode};
+    use crate::relayer::RelayError;
+    use crate::render::{tests::MockRenderer, RenderTemplate, TemplateValues};
+    use crate::routes::error::{NoPathTokenProvided, PermissionDeniedRejection, RelayRejection};
+    use crate::routes::secure::new_token_session;
+    use crate::token::{tests::MockTokenIssuer, TokenGenerationError};
+    use std::sync::Arc;
+    use warp::{Filter, Reply};
+
+    #[test]
+    fn test_error_code_of_rejections() {
+        let cases = vec![
+            (warp::reject::not_found(), ErrorCode::NotFound, 404),
+            (
+                warp::reject::custom(NoPathTokenProvided),
+                ErrorCode::NoPathToken,
+                401,
+            ),
+            (
+                warp::reject::custom(RelayRejection),
+                ErrorCode::ProxyOriginNotAllowed,
+                403,
+            ),
+            (
+                warp::reject::custom(RelayError::Timeout { source: None }),
+                ErrorCode::RelayTimeout,
+                504,
+            ),
+            (
+                warp::reject::custom(RelayError::ConnectionRefused { source: None }),
+                ErrorCode::RelayFailed,
+                502,
+            ),
+        ];
+
+        for (rejection, code, status) in cases {
+            assert_eq!(ErrorCode::of(&rejection), code);
+            assert_eq!(code.status().as_u16(), status);
+        }
+    }
+
+    #[test]
+    fn test_wants_html() {
+        assert!(wants_html(&Some("iframe".to_owned()), &None));
+        assert!(!wants_html(
+            &Some("empty".to_owned()),
+            &Some("text/html".to_owned())
+        ));
+        assert!(wants_html(
+            &None,
+            &Some("text/html,application/xhtml+xml,*/*;q=0.8".to_owned())
+        ));
+        assert!(!wants_html(&None, &Some("application/json".to_owned())));
+        assert!(!wants_html(&None, &None));
+    }
+
+    #[tokio::test]
+    async fn test_token_and_relayer_failures_are_internal_errors() {
+        let mut token_issuer = MockTokenIssuer::new();
+        token_issuer
+            .expect_issue_token()
+            .times(1)
+            .returning(|_, _| {
+                Err(TokenGenerationError::RandError {
+                    source: rand::Error::new("no entropy".to_owned()),
+                })
+            });
+        let rejection = new_token_session(&token_issuer, ".profile.name.", "https://example.com")
+            .err()
+            .unwrap();
+        assert_eq!(ErrorCode::of(&rejection), ErrorCode::TokenError);
+
+        let res = json_reply(classify(&rejection)).into_response();
+        assert_eq!(res.status(), 500);
+        let body = warp::hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["error"], "token_error");
+
+        let rejection = warp::reject::custom(RelayError::IdentityLoadError {
+            source: std::io::Error::from(std::io::ErrorKind::NotFound),
+        });
+        assert_eq!(ErrorCode::of(&rejection), ErrorCode::RelayError);
+        let res = json_reply(classify(&rejection)).into_response();
+        assert_eq!(res.status(), 500);
+    }
+
+    #[tokio::test]
+    async fn test_recover_renders_error_page_in_iframes() {
+        let mut render_engine = MockRenderer::new();
+        render_engine
+            .expect_render()
+            .withf(|template: &RenderTemplate| match &template.value {
+                TemplateValues::Error(error) => {
+                    template.name == "error"
+                        && error.status == 403
+                        && error.code == ErrorCode::PermissionDenied
+                        && error.css == Some("p { color: red; }".to_owned())
+                }
+                _ => false,
+            })
+            .times(1)
+            .return_once(|_| Ok("<p>PERMISSION DENIED</p>".to_owned()));
+
+        let routes = warp::path!("data").and_then(|| async {
+            Err::<String, _>(warp::reject::custom(PermissionDeniedRejection))
+        });
+        let filter = recover(routes, Arc::new(render_engine));
+
+        let res = warp::test::request()
+            .path("/data?css=p%20%7B%20color%3A%20red%3B%20%7D")
+            .header("sec-fetch-dest", "iframe")
+            .reply(&filter)
+            .await;
+        assert_eq!(res.status(), 403);
+        assert_eq!(res.body(), "<p>PERMISSION DENIED</p>");
+
+        let res = warp::test::request()
+            .path("/data")
+            .header("accept", "application/json")
+            .reply(&filter)
+            .await;
+        assert_eq!(res.status(), 403);
+        let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(body["error"], "permission_denied");
+        assert_eq!(body["code"], 403);
+    }
 }
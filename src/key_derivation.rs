@@ -0,0 +1,27 @@
+use sha2::{Digest, Sha256};
+use sodiumoxide::crypto::auth;
+
+/// Derives the authentication key used for one purpose from the root signing key, as
+/// SHA-256 of the purpose's context followed by the root key's bytes, so that a key
+/// derived for one purpose can't be used for another
+pub fn derive_auth_key(context: &[u8], root_key_bytes: &[u8]) -> auth::Key {
+    let mut hasher = Sha256::new();
+    hasher.update(context);
+    hasher.update(root_key_bytes);
+    let mut key_bytes = [0u8; auth::KEYBYTES];
+    key_bytes.copy_from_slice(&hasher.finalize());
+    auth::Key(key_bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::derive_auth_key;
+
+    #[test]
+    fn test_derived_keys_differ_per_context_and_root_key() {
+        let key = derive_auth_key(b"tokens", b"root key");
+        assert_eq!(key, derive_auth_key(b"tokens", b"root key"));
+        assert_ne!(key, derive_auth_key(b"audit", b"root key"));
+        assert_ne!(key, derive_auth_key(b"tokens", b"other root key"));
+    }
+}
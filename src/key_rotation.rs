@@ -0,0 +1,461 @@
+use crate::{
+    atomic_file,
+    deleter::{DeleteError, Deleter},
+    seal::seal_and_store,
+};
+use redact_crypto::{ByteAlgorithm, CryptoError, Data, Entry, IndexedStorer, State};
+use serde::{Deserialize, Serialize};
+use std::{io::ErrorKind, path::Path};
+use thiserror::Error;
+
+/// The number of entries fetched from storage at a time while looking for entries to
+/// re-seal, and the number re-sealed between saves of the rotation state
+const ROTATION_PAGE_SIZE: i64 = 100;
+
+#[derive(Error, Debug)]
+pub enum KeyRotationError {
+    #[error("Failed to read or write the key rotation state file")]
+    IoError { source: std::io::Error },
+    #[error("Failed to serialize or deserialize the key rotation state")]
+    SerializationError { source: serde_json::Error },
+    #[error("Failure happened while re-sealing entries")]
+    CryptoError { source: CryptoError },
+    #[error("Failure happened while retiring the old key")]
+    DeleteError { source: DeleteError },
+    #[error("Key '{from}' is already being rotated to '{to}'")]
+    RotationInProgress { from: String, to: String },
+    #[error("Key '{name}' has already been rotated and retired")]
+    KeyRetired { name: String },
+    #[error("Key '{name}' cannot be rotated to itself")]
+    SameKey { name: String },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum RotationStatus {
+    InProgress,
+    Complete,
+}
+
+/// The progress of rotating the symmetric key named `from` to the key named `to`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct KeyRotation {
+    pub from: String,
+    pub to: String,
+    pub from_path: String,
+    pub to_path: String,
+    /// The paths found sealed with the old key which haven't been re-sealed yet
+    #[serde(default)]
+    pub pending: Vec<String>,
+    /// How many data entries have been re-sealed with the new key so far
+    pub migrated: u64,
+    pub status: RotationStatus,
+}
+
+/// Every key rotation which has been started, persisted so that an interrupted
+/// rotation can be resumed and so that rotated keys are replaced at startup
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct RotationState {
+    pub rotations: Vec<KeyRotation>,
+}
+
+impl RotationState {
+    /// Loads the rotation state, which is empty if no rotation has ever been run
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, KeyRotationError> {
+        match std::fs::read(path) {
+            Ok(bytes) => serde_json::from_slice(&bytes)
+                .map_err(|source| KeyRotationError::SerializationError { source }),
+            Err(e) => match e.kind() {
+                ErrorKind::NotFound => Ok(RotationState::default()),
+                _ => Err(KeyRotationError::IoError { source: e }),
+            },
+        }
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), KeyRotationError> {
+        let path = path.as_ref();
+        let bytes = serde_json::to_vec_pretty(self)
+            .map_err(|source| KeyRotationError::SerializationError { source })?;
+
+        atomic_file::write(path, &bytes).map_err(|source| KeyRotationError::IoError { source })
+    }
+
+    /// The name of the key which replaces the key `name`, following chains of
+    /// rotations; new data is sealed with the new key as soon as a rotation starts
+    pub fn current_key<'a>(&'a self, name: &'a str) -> &'a str {
+        let mut current = name;
+        // Each rotation can only be followed once, which guards against cycles
+        for _ in 0..self.rotations.len() {
+            match self.rotations.iter().find(|r| r.from == current) {
+                Some(rotation) => current = &rotation.to,
+                None => break,
+            }
+        }
+        current
+    }
+}
+
+/// Re-seals every data entry sealed with an old symmetric key using a new one, then
+/// retires the old key once no entry references it any longer
+pub struct KeyRotator<H: IndexedStorer, D: Deleter> {
+    pub storer: H,
+    pub deleter: D,
+    pub state_filepath: String,
+}
+
+impl<H: IndexedStorer, D: Deleter> KeyRotator<H, D> {
+    /// Starts rotating the key `from` to the key `to`, or resumes the rotation if
+    /// it was previously interrupted
+    pub async fn rotate(
+        &self,
+        from: &str,
+        from_path: &str,
+        to: &str,
+        to_path: &str,
+    ) -> Result<KeyRotation, KeyRotationError> {
+        if from == to || from_path == to_path {
+            return Err(KeyRotationError::SameKey {
+                name: from.to_owned(),
+            });
+        }
+
+        let mut state = RotationState::load(&self.state_filepath)?;
+        let index = match state.rotations.iter().position(|r| r.from == from) {
+            Some(index) => {
+                let rotation = &state.rotations[index];
+                if rotation.status == RotationStatus::Complete {
+                    return Err(KeyRotationError::KeyRetired {
+                        name: from.to_owned(),
+                    });
+                }
+                if rotation.to != to {
+                    return Err(KeyRotationError::RotationInProgress {
+                        from: rotation.from.clone(),
+                        to: rotation.to.clone(),
+                    });
+                }
+                tracing::info!(
+                    from,
+                    to,
+                    migrated = rotation.migrated,
+                    "resuming interrupted key rotation"
+                );
+                index
+            }
+            None => {
+                state.rotations.push(KeyRotation {
+                    from: from.to_owned(),
+                    to: to.to_owned(),
+                    from_path: from_path.to_owned(),
+                    to_path: to_path.to_owned(),
+                    pending: vec![],
+                    migrated: 0,
+                    status: RotationStatus::InProgress,
+                });
+                state.save(&self.state_filepath)?;
+                state.rotations.len() - 1
+            }
+        };
+
+        // Re-seal the paths found sealed with the old key, then look again until none are
+        // found, so entries written with the old key while the rotation was running are
+        // caught too
+        loop {
+            if state.rotations[index].pending.is_empty() {
+                let pending = self.sealed_paths(&state.rotations[index].from_path).await?;
+                if pending.is_empty() {
+                    break;
+                }
+                state.rotations[index].pending = pending;
+                state.save(&self.state_filepath)?;
+            }
+            self.migrate_pending(&mut state, index).await?;
+        }
+
+        let from_path = state.rotations[index].from_path.clone();
+        self.deleter
+            .delete(&from_path)
+            .await
+            .map_err(|source| KeyRotationError::DeleteError { source })?;
+        state.rotations[index].status = RotationStatus::Complete;
+        state.save(&self.state_filepath)?;
+        Ok(state.rotations[index].clone())
+    }
+
+    /// Lists the paths of every data entry sealed with the key at `key_path`; nothing is
+    /// written while listing, so paging through storage sees every entry once
+    async fn sealed_paths(&self, key_path: &str) -> Result<Vec<String>, KeyRotationError> {
+        let mut paths = vec![];
+        let mut skip = 0;
+        loop {
+            let entries = match self
+                .storer
+                .list::<Data>(".", skip, ROTATION_PAGE_SIZE)
+                .await
+            {
+                Ok(entries) => entries,
+                Err(CryptoError::NotFound { .. }) => vec![],
+                Err(source) => return Err(KeyRotationError::CryptoError { source }),
+            };
+            if entries.is_empty() {
+                return Ok(paths);
+            }
+
+            skip += entries.len() as u64;
+            for entry in entries {
+                if is_sealed_with(&entry, key_path) {
+                    paths.push(entry.path);
+                }
+            }
+        }
+    }
+
+    /// Re-seals the pending paths with the new key, saving progress after every page;
+    /// entries which have been rewritten since they were found are left as they are
+    async fn migrate_pending(
+        &self,
+        state: &mut RotationState,
+        index: usize,
+    ) -> Result<(), KeyRotationError> {
+        while !state.rotations[index].pending.is_empty() {
+            let rotation = &state.rotations[index];
+            let page_len = rotation.pending.len().min(ROTATION_PAGE_SIZE as usize);
+            let mut page_migrated = 0;
+            for path in &rotation.pending[..page_len] {
+                let entry = match self.storer.get::<Data>(path).await {
+                    Ok(entry) => entry,
+                    Err(CryptoError::NotFound { .. }) => continue,
+                    Err(source) => return Err(KeyRotationError::CryptoError { source }),
+                };
+                if !is_sealed_with(&entry, &rotation.from_path) {
+                    continue;
+                }
+                let data = entry
+                    .take_resolve()
+                    .await
+                    .map_err(|source| KeyRotationError::CryptoError { source })?;
+                seal_and_store(&self.storer, path, &rotation.to_path, data)
+                    .await
+                    .map_err(|source| KeyRotationError::CryptoError { source })?;
+                page_migrated += 1;
+            }
+
+            let rotation = &mut state.rotations[index];
+            rotation.pending.drain(..page_len);
+            rotation.migrated += page_migrated;
+            state.save(&self.state_filepath)?;
+        }
+        Ok(())
+    }
+}
+
+/// Whether the entry is sealed with a symmetric key which references the key stored at
+/// `key_path`, as `seal_and_store` seals it
+fn is_sealed_with(entry: &Entry<Data>, key_path: &str) -> bool {
+    match &entry.value {
+        State::Sealed {
+            algorithm: ByteAlgorithm::SodiumOxideSymmetricKey(algorithm),
+            ..
+        } => matches!(&algorithm.key.value, State::Referenced { path, .. } if path == key_path),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_sealed_with, KeyRotation, KeyRotator, RotationState, RotationStatus};
+    use crate::{
+        deleter::StorerDeleter,
+        seal::seal_and_store,
+        test_utils::{temp_dir, FakeStore},
+    };
+    use redact_crypto::{Data, RedactStorer, Storer, ToEntry};
+    use std::path::{Path, PathBuf};
+    use tempfile::TempDir;
+
+    const OLD_KEY_PATH: &str = ".keys.encryption.symmetric.default.";
+    const NEW_KEY_PATH: &str = ".keys.encryption.symmetric.default2.";
+    const OTHER_KEY_PATH: &str = ".keys.encryption.symmetric.health.";
+
+    fn rotation(from: &str, to: &str, status: RotationStatus) -> KeyRotation {
+        KeyRotation {
+            from: from.to_owned(),
+            to: to.to_owned(),
+            from_path: format!(".keys.encryption.symmetric.{}.", from),
+            to_path: format!(".keys.encryption.symmetric.{}.", to),
+            pending: vec![],
+            migrated: 0,
+            status,
+        }
+    }
+
+    fn temp_state_path() -> (TempDir, PathBuf) {
+        let dir = temp_dir();
+        let path = dir.path().join("rotation.json");
+        (dir, path)
+    }
+
+    /// A store holding the old, new and an unrelated key
+    async fn store_with_keys() -> (FakeStore, RedactStorer) {
+        let store = FakeStore::default();
+        let storer = store.serve().await;
+        for key_path in &[OLD_KEY_PATH, NEW_KEY_PATH, OTHER_KEY_PATH] {
+            store.insert_key(key_path);
+        }
+        (store, storer)
+    }
+
+    fn rotator(
+        storer: &RedactStorer,
+        state_path: &Path,
+    ) -> KeyRotator<RedactStorer, StorerDeleter<RedactStorer>> {
+        KeyRotator {
+            storer: storer.clone(),
+            deleter: StorerDeleter::new(storer.clone()),
+            state_filepath: state_path.to_string_lossy().into_owned(),
+        }
+    }
+
+    async fn seal(storer: &RedactStorer, path: &str, key_path: &str) {
+        seal_and_store(storer, path, key_path, Data::String(path.to_owned()))
+            .await
+            .unwrap();
+    }
+
+    /// Asserts the entry at `path` is sealed with the key at `key_path` and still holds
+    /// its own path
+    async fn assert_sealed_with(storer: &RedactStorer, path: &str, key_path: &str) {
+        let entry = storer.get::<Data>(path).await.unwrap();
+        assert!(is_sealed_with(&entry, key_path));
+        let data = entry.take_resolve().await.unwrap();
+        assert_eq!(data.to_string(), path);
+    }
+
+    #[tokio::test]
+    async fn test_only_entries_sealed_with_the_key_are_sealed_with_it() {
+        let (_store, storer) = store_with_keys().await;
+        seal(&storer, ".profile.name.", OLD_KEY_PATH).await;
+        let sealed = storer.get::<Data>(".profile.name.").await.unwrap();
+        let unsealed = Data::String(OLD_KEY_PATH.to_owned())
+            .to_unsealed_entry(".notes.key.".to_owned())
+            .unwrap();
+
+        assert!(is_sealed_with(&sealed, OLD_KEY_PATH));
+        assert!(!is_sealed_with(&sealed, NEW_KEY_PATH));
+        assert!(!is_sealed_with(&unsealed, OLD_KEY_PATH));
+    }
+
+    #[tokio::test]
+    async fn test_rotation_reseals_entries_and_deletes_old_key() {
+        let (store, storer) = store_with_keys().await;
+        for path in &[".profile.name.", ".profile.email.", ".settings.theme."] {
+            seal(&storer, path, OLD_KEY_PATH).await;
+        }
+        seal(&storer, ".health.steps.", OTHER_KEY_PATH).await;
+        let (_dir, state_path) = temp_state_path();
+
+        let rotation = rotator(&storer, &state_path)
+            .rotate("default", OLD_KEY_PATH, "default2", NEW_KEY_PATH)
+            .await
+            .unwrap();
+
+        assert_eq!(rotation.status, RotationStatus::Complete);
+        assert_eq!(rotation.migrated, 3);
+        assert!(rotation.pending.is_empty());
+        for path in &[".profile.name.", ".profile.email.", ".settings.theme."] {
+            assert_sealed_with(&storer, path, NEW_KEY_PATH).await;
+        }
+        assert_sealed_with(&storer, ".health.steps.", OTHER_KEY_PATH).await;
+        assert_eq!(
+            store.get(OLD_KEY_PATH).unwrap()["value"]["c"]["path"],
+            ".deleted."
+        );
+        assert_eq!(
+            RotationState::load(&state_path).unwrap().rotations,
+            vec![rotation]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_interrupted_rotation_resumes_from_pending_paths() {
+        let (_store, storer) = store_with_keys().await;
+        // The rotation was interrupted after re-sealing the name, and the email was written
+        // with the old key after storage had been listed
+        seal(&storer, ".profile.name.", NEW_KEY_PATH).await;
+        for path in &[".profile.phone.", ".settings.theme.", ".profile.email."] {
+            seal(&storer, path, OLD_KEY_PATH).await;
+        }
+        let (_dir, state_path) = temp_state_path();
+        let mut interrupted = rotation("default", "default2", RotationStatus::InProgress);
+        interrupted.pending = vec![
+            ".profile.name.".to_owned(),
+            ".profile.phone.".to_owned(),
+            ".settings.theme.".to_owned(),
+        ];
+        interrupted.migrated = 1;
+        RotationState {
+            rotations: vec![interrupted],
+        }
+        .save(&state_path)
+        .unwrap();
+
+        let rotation = rotator(&storer, &state_path)
+            .rotate("default", OLD_KEY_PATH, "default2", NEW_KEY_PATH)
+            .await
+            .unwrap();
+
+        assert_eq!(rotation.status, RotationStatus::Complete);
+        assert_eq!(rotation.migrated, 4);
+        for path in &[
+            ".profile.name.",
+            ".profile.phone.",
+            ".settings.theme.",
+            ".profile.email.",
+        ] {
+            assert_sealed_with(&storer, path, NEW_KEY_PATH).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_rotation_to_another_key_is_refused_while_in_progress() {
+        let (_, storer) = store_with_keys().await;
+        let (_dir, state_path) = temp_state_path();
+        RotationState {
+            rotations: vec![rotation("default", "default2", RotationStatus::InProgress)],
+        }
+        .save(&state_path)
+        .unwrap();
+
+        assert!(rotator(&storer, &state_path)
+            .rotate("default", OLD_KEY_PATH, "health", OTHER_KEY_PATH)
+            .await
+            .is_err());
+    }
+
+    #[test]
+    fn test_current_key_follows_rotation_chain() {
+        let state = RotationState {
+            rotations: vec![
+                rotation("default", "default2", RotationStatus::Complete),
+                rotation("default2", "default3", RotationStatus::InProgress),
+            ],
+        };
+        assert_eq!(state.current_key("default"), "default3");
+        assert_eq!(state.current_key("health"), "health");
+    }
+
+    #[test]
+    fn test_state_survives_save_and_load() {
+        let (_dir, path) = temp_state_path();
+        assert_eq!(
+            RotationState::load(&path).unwrap(),
+            RotationState::default()
+        );
+
+        let state = RotationState {
+            rotations: vec![rotation("default", "default2", RotationStatus::InProgress)],
+        };
+        state.save(&path).unwrap();
+        assert_eq!(RotationState::load(&path).unwrap(), state);
+    }
+}
@@ -0,0 +1,88 @@
+use serde::Deserialize;
+
+/// Maps a data path prefix to the name of the symmetric key, configured under
+/// `keys.encryption.symmetric.<key>`, which seals data stored beneath it
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct KeyPolicy {
+    pub prefix: String,
+    pub key: String,
+}
+
+/// Chooses the storage path of the symmetric key used to seal data at a given path
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeySelector {
+    default_key_path: String,
+    policies: Vec<(String, String)>,
+}
+
+impl KeySelector {
+    /// Creates a selector which falls back to `default_key_path` for any path not
+    /// covered by a `(prefix, key path)` policy
+    pub fn new(default_key_path: String, policies: Vec<(String, String)>) -> Self {
+        KeySelector {
+            default_key_path,
+            policies,
+        }
+    }
+
+    /// The storage path of the key used for paths not covered by any policy
+    pub fn default_key_path(&self) -> &str {
+        &self.default_key_path
+    }
+
+    /// The storage path of the key for `path`; when several prefixes match, the
+    /// longest one wins so narrower policies can override broader ones
+    pub fn select(&self, path: &str) -> &str {
+        self.policies
+            .iter()
+            .filter(|(prefix, _)| path.starts_with(prefix.as_str()))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|(_, key_path)| key_path.as_str())
+            .unwrap_or(&self.default_key_path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::KeySelector;
+
+    fn selector() -> KeySelector {
+        KeySelector::new(
+            ".keys.encryption.symmetric.default.".to_owned(),
+            vec![
+                (
+                    ".health.".to_owned(),
+                    ".keys.encryption.symmetric.health.".to_owned(),
+                ),
+                (
+                    ".health.genome.".to_owned(),
+                    ".keys.encryption.symmetric.genome.".to_owned(),
+                ),
+            ],
+        )
+    }
+
+    #[test]
+    fn test_select_falls_back_to_default() {
+        assert_eq!(
+            selector().select(".profile.name."),
+            ".keys.encryption.symmetric.default."
+        );
+    }
+
+    #[test]
+    fn test_select_matches_prefix() {
+        assert_eq!(
+            selector().select(".health.bloodtype."),
+            ".keys.encryption.symmetric.health."
+        );
+    }
+
+    #[test]
+    fn test_select_prefers_longest_prefix() {
+        assert_eq!(
+            selector().select(".health.genome.sequence."),
+            ".keys.encryption.symmetric.genome."
+        );
+    }
+}
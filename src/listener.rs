@@ -0,0 +1,157 @@
+use redact_config::Configurator;
+use std::{
+    fmt::{self, Display, Formatter},
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::PathBuf,
+};
+
+/// Prefix marking a `server.address` entry as the path of a Unix domain socket
+const UNIX_SOCKET_PREFIX: &str = "unix:";
+
+/// Somewhere the server accepts connections
+#[derive(Debug, Clone, PartialEq)]
+pub enum Listener {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl Display for Listener {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Listener::Tcp(addr) => write!(f, "{}", addr),
+            Listener::Unix(path) => write!(f, "{}{}", UNIX_SOCKET_PREFIX, path.display()),
+        }
+    }
+}
+
+/// Parses a `server.address` entry, which is either an IPv4 or IPv6 address served
+/// on `port`, or `unix:<path>` for a Unix domain socket
+pub fn parse_listener(address: &str, port: u16) -> Result<Listener, String> {
+    let address = address.trim();
+    if let Some(path) = address.strip_prefix(UNIX_SOCKET_PREFIX) {
+        if path.is_empty() {
+            return Err("unix socket address has no path".to_owned());
+        }
+        return Ok(Listener::Unix(PathBuf::from(path)));
+    }
+
+    // IPv6 addresses may be written in brackets as they would be in a URL
+    let ip = address.trim_start_matches('[').trim_end_matches(']');
+    ip.parse::<IpAddr>()
+        .map(|ip| Listener::Tcp(SocketAddr::new(ip, port)))
+        .map_err(|_| {
+            format!(
+                "'{}' is not an IPv4 address, an IPv6 address or unix:<path>",
+                address
+            )
+        })
+}
+
+/// The listeners configured by `server.address`, a single address or a list of them,
+/// which defaults to the IPv4 loopback address so the client isn't reachable off-host
+pub fn get_listeners<T: Configurator>(config: &T, port: u16) -> Vec<Listener> {
+    let default = vec![Listener::Tcp(SocketAddr::new(
+        IpAddr::V4(Ipv4Addr::LOCALHOST),
+        port,
+    ))];
+    let addresses = match config.get::<Vec<String>>("server.address") {
+        Ok(addresses) => addresses,
+        Err(e) => match e {
+            // Suppress logging if server.address was simply not set
+            redact_config::ConfigError::NotFound(_) => return default,
+            _ => match config.get_str("server.address") {
+                Ok(address) => vec![address],
+                Err(e) => {
+                    tracing::warn!(
+                        error = %e,
+                        "failed to read listen addresses, defaulting to 127.0.0.1"
+                    );
+                    return default;
+                }
+            },
+        },
+    };
+
+    let listeners: Vec<Listener> = addresses
+        .iter()
+        .filter_map(|address| match parse_listener(address, port) {
+            Ok(listener) => Some(listener),
+            Err(reason) => {
+                tracing::warn!(%reason, "ignoring invalid listen address");
+                None
+            }
+        })
+        .collect();
+    if listeners.is_empty() {
+        tracing::warn!("no valid listen addresses were configured, defaulting to 127.0.0.1");
+        default
+    } else {
+        listeners
+    }
+}
+
+/// Binds a Unix domain socket which only the current user can connect to, replacing
+/// any socket left behind at the same path by a previous run
+#[cfg(unix)]
+pub fn bind_unix_socket(
+    path: &std::path::Path,
+) -> std::io::Result<tokio_stream::wrappers::UnixListenerStream> {
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => std::fs::remove_file(path)?,
+        Ok(_) => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                format!("{} exists and is not a socket", path.display()),
+            ))
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+        Err(e) => return Err(e),
+    }
+    let listener = tokio::net::UnixListener::bind(path)?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+    Ok(tokio_stream::wrappers::UnixListenerStream::new(listener))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_listener, Listener};
+    use std::{
+        net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+        path::PathBuf,
+    };
+
+    #[test]
+    fn test_parse_listener_ip_addresses() {
+        assert_eq!(
+            parse_listener("127.0.0.1", 8080),
+            Ok(Listener::Tcp(SocketAddr::new(
+                IpAddr::V4(Ipv4Addr::LOCALHOST),
+                8080
+            )))
+        );
+        assert_eq!(
+            parse_listener("[::1]", 8080),
+            Ok(Listener::Tcp(SocketAddr::new(
+                IpAddr::V6(Ipv6Addr::LOCALHOST),
+                8080
+            )))
+        );
+    }
+
+    #[test]
+    fn test_parse_listener_unix_socket() {
+        assert_eq!(
+            parse_listener("unix:/run/redact/client.sock", 8080),
+            Ok(Listener::Unix(PathBuf::from("/run/redact/client.sock")))
+        );
+        assert!(parse_listener("unix:", 8080).is_err());
+    }
+
+    #[test]
+    fn test_parse_listener_rejects_hostnames() {
+        assert!(parse_listener("localhost", 8080).is_err());
+        assert!(parse_listener("127.0.0.1:8080", 8080).is_err());
+    }
+}
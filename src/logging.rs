@@ -0,0 +1,97 @@
+//! Structured logging for the client. Spans and events only ever record paths,
+//! key paths, template names, status codes and error classifications; decrypted
+//! `Data` values and iframe tokens must never be passed to a span or event.
+
+use crate::error::ClientError;
+use redact_config::Configurator;
+use tracing::Span;
+use tracing_subscriber::EnvFilter;
+
+/// Placeholder which replaces the iframe token in logged request paths
+const REDACTED_TOKEN: &str = "<token>";
+
+/// Installs the global tracing subscriber using the `logging.level` filter directive
+/// and the `logging.format` output format, which is either `pretty` or `json`
+pub fn setup_logging<T: Configurator>(config: &T) -> Result<(), ClientError> {
+    let level = match config.get_str("logging.level") {
+        Ok(level) => level,
+        Err(redact_config::ConfigError::NotFound(_)) => "info".to_owned(),
+        Err(e) => return Err(ClientError::ConfigError { source: e }),
+    };
+    let format = match config.get_str("logging.format") {
+        Ok(format) => format,
+        Err(redact_config::ConfigError::NotFound(_)) => "pretty".to_owned(),
+        Err(e) => return Err(ClientError::ConfigError { source: e }),
+    };
+    let filter = EnvFilter::try_new(&level).map_err(|e| ClientError::InternalError {
+        source: Box::new(e),
+    })?;
+
+    let subscriber = tracing_subscriber::fmt().with_env_filter(filter);
+    match format.to_ascii_lowercase().as_ref() {
+        "json" => subscriber.json().try_init(),
+        "pretty" => subscriber.pretty().try_init(),
+        _ => {
+            return Err(ClientError::InternalError {
+                source: format!("log format '{}' is not one of 'pretty' or 'json'", format).into(),
+            })
+        }
+    }
+    .map_err(|source| ClientError::InternalError { source })
+}
+
+/// Creates the span wrapping every request handled by the server
+pub fn request_span(info: warp::trace::Info) -> Span {
+    tracing::info_span!(
+        "request",
+        method = %info.method(),
+        path = %redact_path(info.path()),
+    )
+}
+
+/// Emits one access log event per completed request
+pub fn access_log(info: warp::log::Info) {
+    tracing::info!(
+        method = %info.method(),
+        path = %redact_path(info.path()),
+        status = info.status().as_u16(),
+        elapsed_ms = info.elapsed().as_millis() as u64,
+        "request completed"
+    );
+}
+
+/// Replaces the iframe token at the end of a secure route path so it never reaches the logs
+pub fn redact_path(path: &str) -> String {
+    if !path.starts_with("/secure/") {
+        return path.to_owned();
+    }
+    let path = path.trim_end_matches('/');
+    match path.rfind('/') {
+        Some(i) if path[..i].matches('/').count() >= 2 => {
+            format!("{}/{}", &path[..i], REDACTED_TOKEN)
+        }
+        _ => path.to_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::redact_path;
+
+    #[test]
+    fn test_redact_path_removes_secure_token() {
+        assert_eq!(
+            redact_path("/secure/data/.profile.name./a1b2c3"),
+            "/secure/data/.profile.name./<token>"
+        );
+    }
+
+    #[test]
+    fn test_redact_path_leaves_other_routes() {
+        assert_eq!(
+            redact_path("/unsecure/data/.profile.name."),
+            "/unsecure/data/.profile.name."
+        );
+        assert_eq!(redact_path("/healthz"), "/healthz");
+    }
+}
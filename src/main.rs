@@ -1,338 +1,404 @@
+mod atomic_file;
+mod audit;
 mod bootstrap;
+mod compound;
+mod cors;
+mod deleter;
 mod error;
 mod error_handler;
+mod key_derivation;
+mod key_rotation;
+mod key_selector;
+mod listener;
+mod logging;
+mod metrics;
+mod permissions;
+mod relay_queue;
 mod relayer;
 mod render;
+mod rotation;
 mod routes;
+mod seal;
+mod session_store;
+#[cfg(test)]
+mod test_utils;
 pub mod token;
 
-use crate::error_handler::handle_rejection;
-use crate::relayer::MutualTLSRelayer;
-use chrono::{prelude::*, Duration};
-use pkcs8::PrivateKeyInfo;
+use crate::cors::RouteGroup;
+use crate::deleter::StorerDeleter;
+use crate::error::ClientError;
+use crate::key_rotation::KeyRotator;
+use crate::listener::Listener;
+use crate::rotation::CertificateRotator;
+use crate::routes::readyz::ReadinessChecker;
+use chrono::Duration;
 use redact_config::Configurator;
-use redact_crypto::{
-    cert::setup_cert,
-    key::sodiumoxide::{
-        SodiumOxideEd25519PublicAsymmetricKey, SodiumOxideEd25519SecretAsymmetricKey,
-        SodiumOxideSymmetricKey,
-    },
-    x509::DistinguishedName,
-    Entry, HasAlgorithmIdentifier, HasByteSource, HasPublicKey, RedactStorer, Storer,
-};
-use reqwest::Certificate;
+use redact_crypto::RedactStorer;
 use serde::Serialize;
-use std::{
-    fs::File,
-    io::{ErrorKind, Write},
-    path::Path,
-    sync::Arc,
-};
-use token::FromThreadRng;
+use std::sync::Arc;
 use warp::Filter;
-use warp_sessions::MemoryStore;
 
 #[derive(Serialize)]
 struct Healthz {}
 
-fn get_port<T: Configurator>(config: &T) -> u16 {
-    match config.get_int("server.port") {
+/// The port configured at `key`, or `default` if it isn't set or isn't a valid port
+fn get_port<T: Configurator>(config: &T, key: &str, default: u16) -> u16 {
+    match config.get_int(key) {
         Ok(port) => {
             if (1..65536).contains(&port) {
                 port as u16
             } else {
-                println!(
-                    "listen port value '{}' is not between 1 and 65535, defaulting to 8080",
-                    port
+                tracing::warn!(
+                    key,
+                    port,
+                    "port value is not between 1 and 65535, defaulting to {}",
+                    default
                 );
-                8080
+                default
             }
         }
         Err(e) => {
             match e {
-                // Suppress debug logging if server.port was simply not set
+                // Suppress debug logging if the port was simply not set
                 redact_config::ConfigError::NotFound(_) => (),
-                _ => println!("{}", e),
+                _ => tracing::warn!(
+                    key,
+                    error = %e,
+                    "failed to read port, defaulting to {}",
+                    default
+                ),
             }
-            8080
+            default
         }
     }
 }
 
+/// Reads the `--rotate-key <from> --to <to>` arguments naming the symmetric key to
+/// rotate and the key to rotate it to, failing if only one of them is given
+fn get_key_rotation_args() -> Result<Option<(String, String)>, ClientError> {
+    let args: Vec<String> = std::env::args().collect();
+    let arg_value = |name: &str| {
+        args.iter()
+            .position(|arg| arg == name)
+            .and_then(|i| args.get(i + 1))
+            .cloned()
+    };
+    match (arg_value("--rotate-key"), arg_value("--to")) {
+        (Some(from), Some(to)) => Ok(Some((from, to))),
+        (None, None) => Ok(None),
+        _ => Err(ClientError::ArgumentError {
+            message: "key rotation requires both --rotate-key <from> and --to <to>".to_owned(),
+        }),
+    }
+}
+
 #[tokio::main]
-async fn main() {
+async fn main() -> Result<(), ClientError> {
     // Extract config with a REDACT env var prefix
-    let config = redact_config::new("REDACT").unwrap();
+    let config =
+        redact_config::new("REDACT").map_err(|source| ClientError::ConfigError { source })?;
+
+    // Create the internally-used Redact storer; this is the self-storer
+    let storer_shared = Arc::new(RedactStorer::new(
+        &config
+            .get_str("storage.url")
+            .map_err(|source| ClientError::ConfigError { source })?,
+    ));
+
+    // Create the client's keys, certificates and identity bundles, then exit without
+    // starting anything else
+    if std::env::args().any(|arg| arg == "--init-only") {
+        return bootstrap::initialize(&config, storer_shared.as_ref()).await;
+    }
+
+    // Install the structured logger configured by logging.level and logging.format
+    logging::setup_logging(&config)?;
 
     // Determine port to listen on
-    let port = get_port(&config);
+    let port = get_port(&config, "server.port", 8080);
 
-    // Fetch HTML template renderer and load pre-defined templates into it
-    let render_engine = Arc::new(bootstrap::setup_html_render_engine().unwrap());
+    // Register the metrics served on the localhost-only metrics listener
+    metrics::register_metrics().map_err(|e| ClientError::InternalError {
+        source: Box::new(e),
+    })?;
+    let metrics_port = get_port(&config, "metrics.port", 9090);
 
-    // Create the internally-used Redact storer; this is the self-storer
-    let storer_shared = Arc::new(RedactStorer::new(&config.get_str("storage.url").unwrap()));
-
-    // Fetch or create the root signing key from which all other identities will be derived
-    let root_signing_key_entry: Entry<SodiumOxideEd25519SecretAsymmetricKey> =
-        bootstrap::setup_entry(&config, "keys.signing.root")
-            .await
-            .unwrap();
-    let root_signing_key = root_signing_key_entry.resolve().await.unwrap();
-
-    // Fetch or create the key that will be used for initiating client TLS connections
-    let tls_key_entry: Entry<SodiumOxideEd25519SecretAsymmetricKey> =
-        bootstrap::setup_entry(&config, "keys.signing.tls")
-            .await
-            .unwrap();
-    let tls_key = tls_key_entry.resolve().await.unwrap();
-
-    // Create the certificate for the signing key if it doesn't already exist
-    let signing_cert_o = config.get_str("certificates.signing.root.o").unwrap();
-    let signing_cert_ou = config.get_str("certificates.signing.root.ou").unwrap();
-    let signing_cert_cn = config.get_str("certificates.signing.root.cn").unwrap();
-    let signing_cert_dn = DistinguishedName {
-        o: &signing_cert_o,
-        ou: &signing_cert_ou,
-        cn: &signing_cert_cn,
-    };
-    if let Err(e) = File::open(
-        config
-            .get_str("certificates.signing.root.filepath")
-            .unwrap(),
-    ) {
-        match e.kind() {
-            ErrorKind::NotFound => {
-                let not_before = Utc::now();
-                let not_after = not_before
-                    + Duration::days(
-                        config
-                            .get_int("certificates.signing.root.expires_in")
-                            .unwrap(),
-                    );
-                let root_signing_cert = setup_cert::<_, SodiumOxideEd25519PublicAsymmetricKey>(
-                    root_signing_key,
-                    None,
-                    &signing_cert_dn,
-                    None,
-                    not_before,
-                    not_after,
-                    true,
-                    None,
-                )
-                .unwrap();
-                let path_str = &config
-                    .get_str("certificates.signing.root.filepath")
-                    .unwrap();
-
-                let path = Path::new(path_str);
-                let path_parent = path.parent();
-                if let Some(path) = path_parent {
-                    std::fs::create_dir_all(path).unwrap();
-                }
-                let mut root_signing_cert_file = File::create(path).unwrap();
-                root_signing_cert_file
-                    .write_all(b"-----BEGIN CERTIFICATE-----\n")
-                    .unwrap();
-                base64::encode(root_signing_cert)
-                    .as_bytes()
-                    .chunks(64)
-                    .for_each(|chunk| {
-                        root_signing_cert_file.write_all(chunk).unwrap();
-                        root_signing_cert_file.write_all(b"\n").unwrap();
-                    });
-                root_signing_cert_file
-                    .write_all(b"-----END CERTIFICATE-----\n")
-                    .unwrap();
-            }
-            _ => Err(e).unwrap(),
+    // Fetch HTML template renderer and load pre-defined templates into it
+    let render_engine = Arc::new(bootstrap::setup_html_render_engine().map_err(|e| {
+        ClientError::InternalError {
+            source: Box::new(e),
         }
-    }
+    })?);
 
-    // Make the TLS cert and PKCS12 file if it doesn't exist
-    let tls_cert_path_str = config.get_str("certificates.signing.tls.filepath").unwrap();
-    if let Err(e) = File::open(&tls_cert_path_str) {
-        match e.kind() {
-            ErrorKind::NotFound => {
-                let encryption_cert_o = config.get_str("certificates.signing.tls.o").unwrap();
-                let encryption_cert_ou = config.get_str("certificates.signing.tls.ou").unwrap();
-                let encryption_cert_cn = config.get_str("certificates.signing.tls.cn").unwrap();
-                let encryption_cert_dn = DistinguishedName {
-                    o: &encryption_cert_o,
-                    ou: &encryption_cert_ou,
-                    cn: &encryption_cert_cn,
-                };
-                let not_before = Utc::now();
-                let not_after = not_before
-                    + Duration::days(
-                        config
-                            .get_int("certificates.signing.tls.expires_in")
-                            .unwrap(),
-                    );
-                let tls_cert = setup_cert(
-                    root_signing_key,
-                    Some(&tls_key.public_key().unwrap()),
-                    &signing_cert_dn,
-                    Some(&encryption_cert_dn),
-                    not_before,
-                    not_after,
-                    false,
-                    None,
-                )
-                .unwrap();
-                let mut tls_cert_vec: Vec<u8> = vec![];
-                let path_str = &config.get_str("certificates.signing.tls.filepath").unwrap();
-
-                let path = Path::new(path_str);
-                let path_parent = path.parent();
-                if let Some(path) = path_parent {
-                    std::fs::create_dir_all(path).unwrap();
-                }
-                let mut tls_cert_file = File::create(path).unwrap();
-                tls_cert_vec
-                    .write_all(b"-----BEGIN CERTIFICATE-----\n")
-                    .unwrap();
-                base64::encode(tls_cert)
-                    .as_bytes()
-                    .chunks(64)
-                    .for_each(|chunk| {
-                        tls_cert_vec.write_all(chunk).unwrap();
-                        tls_cert_vec.write_all(b"\n").unwrap();
-                    });
-                tls_cert_vec
-                    .write_all(b"-----END CERTIFICATE-----\n")
-                    .unwrap();
-                tls_cert_file.write_all(&tls_cert_vec).unwrap();
-            }
-            _ => Err(e).unwrap(),
+    // Fetch or create the root signing and TLS keys, along with the certificates and
+    // client identity bundle derived from them
+    let pki = bootstrap::pki::setup_pki(&config).await?;
+    let server_identity_filepath = pki
+        .server_tls
+        .as_ref()
+        .map(|server_tls| server_tls.identity_filepath.clone());
+
+    // Setup mTLS configuration for all calls to a Redact storer
+    let storer_tls = bootstrap::setup_storer_tls(&config)?;
+
+    // Create the default encryption key and any keys assigned to path prefixes by the
+    // key policies if they don't exist
+    let key_selector =
+        Arc::new(bootstrap::setup_key_selector(&config, storer_shared.as_ref()).await?);
+
+    // Identify this client to relay servers by its root signing key, which signs every relay
+    let relay_identity =
+        bootstrap::setup_relay_identity(&config, &pki.root_signing_key_entry).await?;
+    tracing::info!(
+        user_id = relay_identity.user_id(),
+        fingerprint = %relay_identity.fingerprint(),
+        "relaying as user"
+    );
+
+    // Create a relay client which supports mutual TLS, retrying failed requests and pausing
+    // requests to hosts which keep failing
+    let relay_policy = bootstrap::setup_relay_policy(&config)?;
+    let relayer_root =
+        bootstrap::setup_ca_certs(config.get_str("relayer.tls.server.ca.filepath").ok())?;
+    let relayer = bootstrap::setup_relayer(
+        pki.identity_filepath.clone(),
+        relayer_root.as_deref(),
+        relay_identity,
+        relay_policy,
+    )?;
+
+    // Create a deleter which removes entries by overwriting them through the storer
+    let deleter = StorerDeleter::new(storer_shared.as_ref().clone());
+
+    // Rotate a symmetric key by re-sealing everything sealed with it, then exit
+    if let Some((from, to)) = get_key_rotation_args()? {
+        let from_path = bootstrap::symmetric_key_path(&config, &from)?;
+        let to_path = bootstrap::setup_symmetric_key(&config, storer_shared.as_ref(), &to).await?;
+        let rotation = KeyRotator {
+            storer: storer_shared.as_ref().clone(),
+            deleter,
+            state_filepath: bootstrap::rotation_state_filepath(&config)?,
         }
+        .rotate(&from, &from_path, &to, &to_path)
+        .await
+        .map_err(|e| ClientError::InternalError {
+            source: Box::new(e),
+        })?;
+        tracing::info!(
+            from = %rotation.from,
+            to = %rotation.to,
+            migrated = rotation.migrated,
+            "key rotation complete"
+        );
+        return Ok(());
     }
 
-    let pkcs12_path_str = &config
-        .get_str("relayer.tls.client.pkcs12.filepath")
-        .unwrap();
-    if let Err(e) = File::open(pkcs12_path_str) {
-        match e.kind() {
-            ErrorKind::NotFound => {
-                let tls_key_bs = tls_key.byte_source();
-                let mut tls_key_bytes = vec![0x04, 0x20];
-                tls_key_bytes.extend_from_slice(&tls_key_bs.get().unwrap()[0..32]);
-                let tls_key_pkcs8 =
-                    PrivateKeyInfo::new(tls_key.algorithm_identifier(), &tls_key_bytes);
-                let pkcs12_path = Path::new(pkcs12_path_str);
-                let pkcs12_path_parent = pkcs12_path.parent();
-                if let Some(path) = pkcs12_path_parent {
-                    std::fs::create_dir_all(path).unwrap();
-                }
-                let tls_cert_bytes = std::fs::read(&tls_cert_path_str).unwrap();
-                let mut pkcs12_file = File::create(pkcs12_path).unwrap();
-                pkcs12_file.write_all(&tls_cert_bytes).unwrap();
-                pkcs12_file
-                    .write_all((*(tls_key_pkcs8.to_pem(pkcs8::LineEnding::LF)).unwrap()).as_bytes())
-                    .unwrap();
-            }
-            _ => Err(e).unwrap(),
-        }
-    };
+    // Create the issuer of iframe tokens, signed with a key derived from the root signing key
+    let token_issuer =
+        Arc::new(bootstrap::setup_token_issuer(&config, &pki.root_signing_key_entry).await?);
 
-    // Setup mTLS configuration for all calls to a Redact storer
-    let pkcs12_path = config
-        .get_str("storage.tls.client.pkcs12.filepath")
-        .unwrap();
-    let server_ca_path = config
-        .get_str("storage.tls.server.ca.filepath")
+    // Open the audit log of every access to the user's data, signed with a key derived from
+    // the root signing key
+    let auditor = Arc::new(bootstrap::setup_auditor(&config, &pki.root_signing_key_entry).await?);
+    let audit_page_size = config
+        .get_int("audit.page_size")
         .ok()
-        .and_then(|path| if path.is_empty() { None } else { Some(path) });
-    redact_crypto::storage::redact::ClientTlsConfig {
-        pkcs12_path,
-        server_ca_path,
-    }
-    .make_current();
-
-    // Create the default encryption key if it doesn't exist
-    let default_encryption_key_entry: Entry<SodiumOxideSymmetricKey> =
-        bootstrap::setup_entry(&config, "keys.encryption.symmetric.default")
-            .await
-            .unwrap();
-    storer_shared
-        .create(default_encryption_key_entry)
-        .await
-        .unwrap();
+        .filter(|page_size| *page_size > 0)
+        .unwrap_or(100) as usize;
 
-    // Create a relay client which supports mutual TLS
-    let relayer_root = config
-        .get_str("relayer.tls.server.ca.filepath")
+    // Report on the storer, default key, certificates and templates the client depends on
+    let readiness_checker = ReadinessChecker {
+        storer: storer_shared.clone(),
+        default_key_path: key_selector.default_key_path().to_owned(),
+        root_cert_filepath: pki.root_cert_config.filepath.clone(),
+        tls_cert_filepath: pki.tls_cert_config.filepath.clone(),
+        render_engine: render_engine.clone(),
+        template_names: vec!["unsecure", "secure", "consent", "audit", "relays", "error"],
+    };
+
+    // Re-issue the certificates shortly before they expire and hot-swap the new identity
+    let rotation_window = config
+        .get_int("certificates.rotation.window")
         .ok()
-        .and_then(|path| match std::fs::read(path) {
-            Ok(b) => Some(vec![Certificate::from_pem(b.as_slice()).unwrap()]),
-            Err(e) => match e.kind() {
-                ErrorKind::NotFound => None,
-                _ => Err(e).unwrap(),
-            },
-        });
-    let relayer = MutualTLSRelayer::new(
-        config
-            .get_str("relayer.tls.client.pkcs12.filepath")
-            .unwrap(),
-        relayer_root.as_deref(),
-    )
-    .unwrap();
+        .filter(|window| *window > 0)
+        .unwrap_or(30);
+    let rotation_check_interval = config
+        .get_int("certificates.rotation.check_interval")
+        .ok()
+        .filter(|interval| *interval > 0)
+        .unwrap_or(3600) as u64;
+    CertificateRotator {
+        pki,
+        storer_pkcs12_path: storer_tls.pkcs12_path,
+        storer_server_ca_path: storer_tls.server_ca_path,
+        relayers: vec![relayer.clone()],
+        window: Duration::days(rotation_window),
+    }
+    .spawn(std::time::Duration::from_secs(rotation_check_interval));
 
-    // Create an in-memory session store for managing secure client sessions
-    let session_store = MemoryStore::new();
+    // Queue relays to be delivered in the background, retrying those whose destination
+    // can't be reached
+    let relay_queue = Arc::new(bootstrap::setup_relay_queue(&config).await?);
+    relay_queue
+        .as_ref()
+        .clone()
+        .spawn(relayer.clone(), auditor.clone());
 
-    // Create a token generator for generating the iframe tokens
-    let token_generator = FromThreadRng::new();
+    // Create the session store for managing secure client sessions
+    let session_store = bootstrap::setup_session_store(&config).await?;
+    let session_cookie = bootstrap::setup_session_cookie(&config)?;
 
-    // Create a CORS filter for the insecure routes that allows any origin
-    let unsecure_cors = warp::cors().allow_any_origin().allow_methods(vec!["GET"]);
-    let unsecure_cors_post = warp::cors()
-        .allow_any_origin()
-        .allow_methods(vec!["GET", "POST", "OPTIONS"])
-        .allow_headers(vec!["content-type"]);
+    // Periodically sweep expired sessions out of the session store
+    let sweep_interval = config
+        .get_int("sessions.store.sweep_interval")
+        .ok()
+        .filter(|interval| *interval > 0)
+        .unwrap_or(60) as u64;
+    let sweep_store = session_store.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(std::time::Duration::from_secs(sweep_interval));
+        loop {
+            interval.tick().await;
+            if let Err(e) = sweep_store.sweep().await {
+                tracing::error!(error = %e, "failed to sweep expired sessions");
+            }
+        }
+    });
 
-    // Create a CORS filter for the secure route that allows only localhost origin
-    let secure_cors = warp::cors()
-        .allow_origin("http://localhost:8080")
-        .allow_methods(vec!["GET", "POST"]);
+    // Create the CORS policy of each route group; "self" in a policy's origins is the
+    // client's own origin
+    let scheme = if server_identity_filepath.is_some() {
+        "https"
+    } else {
+        "http"
+    };
+    let self_origin = format!("{}://localhost:{}", scheme, port);
+    let cors_policy =
+        |group: RouteGroup| bootstrap::setup_cors_policy(&config, group, &self_origin);
 
     // Simple health-check route
     let health_route = warp::path!("healthz")
         .and(warp::get())
         .map(|| warp::reply::json(&Healthz {}))
-        .with(unsecure_cors.clone());
+        .with(warp::wrap_fn(cors::wrap(cors_policy(RouteGroup::Health)?)));
+
+    // Readiness route reporting the state of every dependency
+    let readiness_route = routes::readyz(readiness_checker).with(warp::wrap_fn(cors::wrap(
+        cors_policy(RouteGroup::Readiness)?,
+    )));
+
+    // Websites must be allowed by the user before they are shown any data
+    let permission_registry = Arc::new(permissions::StorerPermissionRegistry::new(
+        storer_shared.clone(),
+        key_selector.clone(),
+    ));
 
     // Routes called with no CSRF token, hosts iframes to routes with CSRF protection
-    let unsecure_routes = routes::unsecure(token_generator.clone(), render_engine.clone())
-        .with(warp::wrap_fn(routes::unsecure::session(
-            session_store.clone(),
-        )))
-        .with(unsecure_cors);
+    let unsecure_routes = routes::unsecure(
+        token_issuer.clone(),
+        render_engine.clone(),
+        permission_registry,
+        session_store.clone(),
+        session_cookie.clone(),
+    )
+    .with(warp::wrap_fn(routes::unsecure::session(
+        session_store.clone(),
+        session_cookie.clone(),
+    )))
+    .with(warp::wrap_fn(cors::wrap(cors_policy(
+        RouteGroup::Unsecure,
+    )?)));
 
     // Routes called with a CSRF token, only to be called by the client itself
     let secure_routes = routes::secure(
         storer_shared.clone(),
         render_engine.clone(),
-        token_generator.clone(),
-        relayer.clone(),
+        token_issuer.clone(),
+        relay_queue.clone(),
+        deleter,
+        key_selector,
+        auditor.clone(),
+        session_store.clone(),
+        session_cookie.clone(),
     )
     .with(warp::wrap_fn(routes::secure::session(
         session_store.clone(),
+        token_issuer,
+        session_cookie,
     )))
-    .with(secure_cors.clone());
+    .with(warp::wrap_fn(cors::wrap(cors_policy(RouteGroup::Secure)?)));
 
     // Routes for an external website to trigger requests from the client to itself
-    let proxy_routes = routes::proxy(relayer).with(unsecure_cors_post.clone());
-
-    // Assemble all routes into one handler
-    let routes = health_route
-        .or(unsecure_routes)
-        .or(secure_routes)
-        .or(proxy_routes)
-        .with(warp::log("routes"))
-        .recover(handle_rejection);
-
-    // Start the server
-    println!("starting server listening on ::{}", port);
-    warp::serve(routes).run(([0, 0, 0, 0], port)).await;
+    let proxy_config = bootstrap::setup_proxy_config(&config)?;
+    let proxy_routes = routes::proxy(relayer, auditor.clone(), proxy_config)
+        .with(warp::wrap_fn(cors::wrap(cors_policy(RouteGroup::Proxy)?)));
+
+    // Page listing recent accesses to the user's data, only to be viewed on the client itself
+    let audit_routes = routes::audit(auditor, render_engine.clone(), audit_page_size)
+        .with(warp::wrap_fn(cors::wrap(cors_policy(RouteGroup::Audit)?)));
+
+    // Page listing relays which haven't been delivered yet, only to be viewed on the client
+    // itself
+    let relay_routes = routes::relays(relay_queue, render_engine.clone())
+        .with(warp::wrap_fn(cors::wrap(cors_policy(RouteGroup::Relays)?)));
+
+    // Assemble all routes into one handler, showing rejected iframe requests an error page
+    let routes = error_handler::recover(
+        health_route
+            .or(readiness_route)
+            .or(unsecure_routes)
+            .or(secure_routes)
+            .or(proxy_routes)
+            .or(audit_routes)
+            .or(relay_routes),
+        render_engine,
+    )
+    .with(warp::log::custom(metrics::record_request))
+    .with(warp::log::custom(logging::access_log))
+    .with(warp::trace(logging::request_span));
+
+    // Serve metrics on the loopback interface only so they are never exposed off-host
+    tracing::info!(port = metrics_port, "starting metrics server");
+    tokio::spawn(warp::serve(metrics::route()).run(([127, 0, 0, 1], metrics_port)));
+
+    // Start a server on every configured listener, serving HTTPS over TCP with the
+    // server identity bundle if one was issued
+    let mut servers = vec![];
+    for listener in listener::get_listeners(&config, port) {
+        let server = warp::serve(routes.clone());
+        match (listener, &server_identity_filepath) {
+            (Listener::Tcp(addr), Some(identity_filepath)) => {
+                tracing::info!(%addr, "starting server with TLS");
+                servers.push(tokio::spawn(
+                    server
+                        .tls()
+                        .cert_path(identity_filepath)
+                        .key_path(identity_filepath)
+                        .run(addr),
+                ));
+            }
+            (Listener::Tcp(addr), None) => {
+                tracing::info!(%addr, "starting server");
+                servers.push(tokio::spawn(server.run(addr)));
+            }
+            #[cfg(unix)]
+            (Listener::Unix(path), _) => {
+                let incoming =
+                    listener::bind_unix_socket(&path).map_err(|e| ClientError::InternalError {
+                        source: Box::new(e),
+                    })?;
+                tracing::info!(path = %path.display(), "starting server on unix socket");
+                servers.push(tokio::spawn(server.run_incoming(incoming)));
+            }
+            #[cfg(not(unix))]
+            (Listener::Unix(path), _) => {
+                tracing::error!(
+                    path = %path.display(),
+                    "unix sockets are not supported on this platform, skipping listener"
+                );
+            }
+        }
+    }
+    futures::future::join_all(servers).await;
+    Ok(())
 }
@@ -0,0 +1,133 @@
+use lazy_static::lazy_static;
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounterVec, Opts, Registry, TextEncoder,
+};
+use redact_crypto::CryptoError;
+use warp::{Filter, Rejection, Reply};
+
+lazy_static! {
+    pub static ref REGISTRY: Registry = Registry::new();
+    pub static ref ROUTE_REQUESTS: IntCounterVec = IntCounterVec::new(
+        Opts::new(
+            "redact_client_route_requests_total",
+            "Requests handled, by route family and response status class"
+        ),
+        &["route", "status"]
+    )
+    .unwrap();
+    pub static ref SESSION_REJECTIONS: IntCounterVec = IntCounterVec::new(
+        Opts::new(
+            "redact_client_session_rejections_total",
+            "Secure route requests rejected during session token validation"
+        ),
+        &["reason"]
+    )
+    .unwrap();
+    pub static ref STORER_LATENCY: HistogramVec = HistogramVec::new(
+        HistogramOpts::new(
+            "redact_client_storer_duration_seconds",
+            "Latency of operations against the storer"
+        ),
+        &["operation"]
+    )
+    .unwrap();
+    pub static ref STORER_ERRORS: IntCounterVec = IntCounterVec::new(
+        Opts::new(
+            "redact_client_storer_errors_total",
+            "Crypto and storage errors returned to clients, by error kind"
+        ),
+        &["kind"]
+    )
+    .unwrap();
+    pub static ref RELAYS: IntCounterVec = IntCounterVec::new(
+        Opts::new(
+            "redact_client_relays_total",
+            "Relay requests sent over mutual TLS, by outcome"
+        ),
+        &["outcome"]
+    )
+    .unwrap();
+    pub static ref PROXY_REQUESTS: IntCounterVec = IntCounterVec::new(
+        Opts::new(
+            "redact_client_proxy_requests_total",
+            "Requests made through the proxy route, by outcome"
+        ),
+        &["outcome"]
+    )
+    .unwrap();
+}
+
+/// Registers every client metric with the registry served on `/metrics`
+pub fn register_metrics() -> Result<(), prometheus::Error> {
+    REGISTRY.register(Box::new(ROUTE_REQUESTS.clone()))?;
+    REGISTRY.register(Box::new(SESSION_REJECTIONS.clone()))?;
+    REGISTRY.register(Box::new(STORER_LATENCY.clone()))?;
+    REGISTRY.register(Box::new(STORER_ERRORS.clone()))?;
+    REGISTRY.register(Box::new(RELAYS.clone()))?;
+    REGISTRY.register(Box::new(PROXY_REQUESTS.clone()))?;
+    Ok(())
+}
+
+/// Serves the registered metrics in the Prometheus text format
+pub fn route() -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("metrics").and(warp::get()).map(|| {
+        let mut buffer = vec![];
+        match TextEncoder::new().encode(&REGISTRY.gather(), &mut buffer) {
+            Ok(_) => warp::reply::with_status(
+                String::from_utf8(buffer).unwrap_or_default(),
+                warp::http::StatusCode::OK,
+            ),
+            Err(e) => {
+                tracing::error!(error = %e, "failed to encode metrics");
+                warp::reply::with_status(
+                    String::new(),
+                    warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+                )
+            }
+        }
+    })
+}
+
+/// Counts every completed request against its route family
+pub fn record_request(info: warp::log::Info) {
+    let status = format!("{}xx", info.status().as_u16() / 100);
+    ROUTE_REQUESTS
+        .with_label_values(&[route_family(info.path()), &status])
+        .inc();
+}
+
+/// The first segment of the request path, limited to the known routes so that
+/// arbitrary paths can't create new label values
+fn route_family(path: &str) -> &'static str {
+    match path.trim_start_matches('/').split('/').next() {
+        Some("unsecure") => "unsecure",
+        Some("secure") => "secure",
+        Some("proxy") => "proxy",
+        Some("audit") => "audit",
+        Some("relays") => "relays",
+        Some("healthz") => "healthz",
+        Some("readyz") => "readyz",
+        _ => "other",
+    }
+}
+
+/// The name of the `CryptoError` variant, used as a low-cardinality label
+pub fn crypto_error_kind(error: &CryptoError) -> String {
+    format!("{:?}", error)
+        .chars()
+        .take_while(|c| c.is_alphanumeric() || *c == '_')
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::route_family;
+
+    #[test]
+    fn test_route_family() {
+        assert_eq!(route_family("/secure/data/.profile.name./abc"), "secure");
+        assert_eq!(route_family("/unsecure/data/.profile.name."), "unsecure");
+        assert_eq!(route_family("/proxy"), "proxy");
+        assert_eq!(route_family("/.env"), "other");
+    }
+}
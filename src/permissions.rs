@@ -0,0 +1,285 @@
+use crate::{key_selector::KeySelector, seal::seal_and_store};
+use async_trait::async_trait;
+use redact_crypto::{CryptoError, Data, Storer};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{ops::Deref, sync::Arc};
+use thiserror::Error;
+use url::Url;
+use warp::reject::Reject;
+
+/// Storage prefix under which permissions are kept; data beneath it is never served
+/// to third-party websites
+pub const PERMISSIONS_PREFIX: &str = ".permissions.";
+
+/// Origin recorded for requests whose website can't be determined
+pub const UNKNOWN_ORIGIN: &str = "null";
+
+#[derive(Error, Debug)]
+pub enum PermissionError {
+    #[error("Failure happened while reading or writing a permission")]
+    CryptoError { source: CryptoError },
+    #[error("Failed to serialize or deserialize a permission")]
+    SerializationError { source: serde_json::Error },
+    #[error("Permission stored at {path} is not a permission")]
+    MalformedPermission { path: String },
+}
+
+impl Reject for PermissionError {}
+
+/// What happens when a website requests data beneath a path prefix
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Decision {
+    /// The data is always shown
+    Allow,
+    /// The data is never shown
+    Deny,
+    /// The data is shown on the next request only, after which the user is asked again
+    Once,
+    /// The user is asked before the data is shown
+    Ask,
+}
+
+/// A decision made by the user about a website's access to the data beneath a prefix
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Permission {
+    pub origin: String,
+    pub prefix: String,
+    pub decision: Decision,
+}
+
+/// Records which websites may see which data, and enforces those decisions
+#[async_trait]
+pub trait PermissionRegistry: Clone + Send + Sync {
+    /// The decision covering `origin`'s request for `path`; the most specific prefix with
+    /// a decision wins, and a `Once` decision is used up by being returned
+    async fn decide(&self, origin: &str, path: &str) -> Result<Decision, PermissionError>;
+    async fn record(&self, permission: Permission) -> Result<(), PermissionError>;
+}
+
+#[async_trait]
+impl<U> PermissionRegistry for Arc<U>
+where
+    U: PermissionRegistry,
+{
+    async fn decide(&self, origin: &str, path: &str) -> Result<Decision, PermissionError> {
+        self.deref().decide(origin, path).await
+    }
+
+    async fn record(&self, permission: Permission) -> Result<(), PermissionError> {
+        self.deref().record(permission).await
+    }
+}
+
+/// Keeps permissions in the storer, sealed like any other data
+#[derive(Clone)]
+pub struct StorerPermissionRegistry<H: Storer> {
+    storer: Arc<H>,
+    key_selector: Arc<KeySelector>,
+}
+
+impl<H: Storer> StorerPermissionRegistry<H> {
+    pub fn new(storer: Arc<H>, key_selector: Arc<KeySelector>) -> Self {
+        StorerPermissionRegistry {
+            storer,
+            key_selector,
+        }
+    }
+
+    async fn get(&self, origin: &str, prefix: &str) -> Result<Option<Permission>, PermissionError> {
+        let path = permission_path(origin, prefix);
+        let entry = match self.storer.get::<Data>(&path).await {
+            Ok(entry) => entry,
+            Err(CryptoError::NotFound { .. }) => return Ok(None),
+            Err(source) => return Err(PermissionError::CryptoError { source }),
+        };
+        let permission: Permission = match entry
+            .take_resolve()
+            .await
+            .map_err(|source| PermissionError::CryptoError { source })?
+        {
+            Data::String(s) => serde_json::from_str(&s)
+                .map_err(|source| PermissionError::SerializationError { source })?,
+            _ => return Err(PermissionError::MalformedPermission { path }),
+        };
+
+        // Entries are addressed by a hash, so make sure this one really is for the request
+        if permission.origin == origin && permission.prefix == prefix {
+            Ok(Some(permission))
+        } else {
+            Err(PermissionError::MalformedPermission { path })
+        }
+    }
+}
+
+#[async_trait]
+impl<H: Storer> PermissionRegistry for StorerPermissionRegistry<H> {
+    #[tracing::instrument(skip(self))]
+    async fn decide(&self, origin: &str, path: &str) -> Result<Decision, PermissionError> {
+        for prefix in path_prefixes(path) {
+            match self.get(origin, &prefix).await? {
+                Some(permission) => match permission.decision {
+                    Decision::Ask => continue,
+                    Decision::Once => {
+                        self.record(Permission {
+                            decision: Decision::Ask,
+                            ..permission
+                        })
+                        .await?;
+                        return Ok(Decision::Once);
+                    }
+                    decision => return Ok(decision),
+                },
+                None => continue,
+            }
+        }
+        Ok(Decision::Ask)
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn record(&self, permission: Permission) -> Result<(), PermissionError> {
+        let path = permission_path(&permission.origin, &permission.prefix);
+        let value = serde_json::to_string(&permission)
+            .map_err(|source| PermissionError::SerializationError { source })?;
+        seal_and_store(
+            self.storer.as_ref(),
+            &path,
+            self.key_selector.select(&path),
+            Data::String(value),
+        )
+        .await
+        .map_err(|source| PermissionError::CryptoError { source })
+    }
+}
+
+/// The storage path of the permission for `origin` and `prefix`, hashed so that any
+/// origin maps onto a valid path
+pub fn permission_path(origin: &str, prefix: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(origin.as_bytes());
+    hasher.update([0u8]);
+    hasher.update(prefix.as_bytes());
+    format!("{}{:x}.", PERMISSIONS_PREFIX, hasher.finalize())
+}
+
+/// The prefixes a permission for `path` may be granted on, from the path itself up to its
+/// top-level segment; permissions are never granted on every path at once
+pub fn path_prefixes(path: &str) -> Vec<String> {
+    let segments: Vec<&str> = path.split('.').filter(|s| !s.is_empty()).collect();
+    (1..=segments.len())
+        .rev()
+        .map(|n| format!(".{}.", segments[..n].join(".")))
+        .collect()
+}
+
+/// The origin of the website making a request, taken from its `Origin` header. Browsers
+/// don't send one when loading an iframe, so the origin of the `Referer` is used instead,
+/// but only when `Sec-Fetch-Site` confirms the request was made by another website.
+pub fn request_origin(
+    origin: Option<&str>,
+    sec_fetch_site: Option<&str>,
+    referer: Option<&str>,
+) -> String {
+    let referer = match sec_fetch_site {
+        Some("cross-site") | Some("same-site") => referer,
+        _ => None,
+    };
+    origin
+        .filter(|origin| *origin != UNKNOWN_ORIGIN)
+        .or(referer)
+        .and_then(|url| Url::parse(url).ok())
+        .map(|url| url.origin())
+        .filter(|origin| origin.is_tuple())
+        .map(|origin| origin.ascii_serialization())
+        .unwrap_or_else(|| UNKNOWN_ORIGIN.to_owned())
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::{
+        path_prefixes, permission_path, request_origin, Decision, Permission, PermissionError,
+        PermissionRegistry,
+    };
+    use async_trait::async_trait;
+    use mockall::*;
+
+    mock! {
+    pub PermissionRegistry {}
+    impl Clone for PermissionRegistry {
+            fn clone(&self) -> Self;
+    }
+
+    #[async_trait]
+    impl PermissionRegistry for PermissionRegistry {
+        async fn decide(&self, origin: &str, path: &str) -> Result<Decision, PermissionError>;
+        async fn record(&self, permission: Permission) -> Result<(), PermissionError>;
+    }
+    }
+
+    #[test]
+    fn test_path_prefixes_most_specific_first() {
+        assert_eq!(
+            path_prefixes(".profile.name."),
+            vec![".profile.name.".to_owned(), ".profile.".to_owned()]
+        );
+        assert!(path_prefixes(".").is_empty());
+    }
+
+    #[test]
+    fn test_permission_path_differs_per_origin_and_prefix() {
+        let path = permission_path("https://example.com", ".profile.");
+        assert!(path.starts_with(".permissions."));
+        assert!(path.ends_with('.'));
+        assert_ne!(path, permission_path("https://example.org", ".profile."));
+        assert_ne!(
+            path,
+            permission_path("https://example.com", ".profile.name.")
+        );
+    }
+
+    #[test]
+    fn test_request_origin_from_referer() {
+        assert_eq!(
+            request_origin(
+                None,
+                Some("cross-site"),
+                Some("https://example.com/articles/1?q=2")
+            ),
+            "https://example.com"
+        );
+        assert_eq!(
+            request_origin(None, Some("cross-site"), Some("not a url")),
+            "null"
+        );
+        assert_eq!(request_origin(None, Some("cross-site"), None), "null");
+    }
+
+    #[test]
+    fn test_request_origin_prefers_origin_header() {
+        assert_eq!(
+            request_origin(
+                Some("https://example.com"),
+                Some("cross-site"),
+                Some("https://other.com/articles/1")
+            ),
+            "https://example.com"
+        );
+        assert_eq!(
+            request_origin(
+                Some("null"),
+                Some("cross-site"),
+                Some("https://example.com/articles/1")
+            ),
+            "https://example.com"
+        );
+    }
+
+    #[test]
+    fn test_request_origin_ignores_referer_not_from_another_site() {
+        let referer = Some("https://example.com/articles/1");
+        assert_eq!(request_origin(None, None, referer), "null");
+        assert_eq!(request_origin(None, Some("none"), referer), "null");
+        assert_eq!(request_origin(None, Some("same-origin"), referer), "null");
+    }
+}
@@ -0,0 +1,411 @@
+use crate::{
+    atomic_file,
+    audit::{audit, AuditEvent, Auditor, Operation},
+    relayer::Relayer,
+};
+use async_trait::async_trait;
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use std::{io::ErrorKind, ops::Deref, path::PathBuf, sync::Arc, time::Duration};
+use thiserror::Error;
+use tokio::sync::{Mutex, Notify};
+use warp::reject::Reject;
+
+#[derive(Error, Debug)]
+pub enum RelayQueueError {
+    #[error("Failed to read or write the relay queue file")]
+    IoError { source: std::io::Error },
+    #[error("Failed to serialize or deserialize the queued relays")]
+    SerializationError { source: serde_json::Error },
+}
+
+impl Reject for RelayQueueError {}
+
+/// How often the queue is checked for relays which are due, how many times a relay is
+/// attempted before it is marked as failed, and how long to wait between attempts; the
+/// wait doubles after every attempt up to `max_delay_secs`
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct RelayQueueConfig {
+    pub filepath: String,
+    pub interval: u64,
+    pub max_attempts: u32,
+    pub base_delay_secs: u64,
+    pub max_delay_secs: u64,
+}
+
+impl Default for RelayQueueConfig {
+    fn default() -> Self {
+        RelayQueueConfig {
+            filepath: "relays/queue.json".to_owned(),
+            interval: 30,
+            max_attempts: 10,
+            base_delay_secs: 30,
+            max_delay_secs: 3600,
+        }
+    }
+}
+
+impl RelayQueueConfig {
+    /// Seconds to wait after `attempts` failed attempts before the next one
+    fn backoff(&self, attempts: u32) -> i64 {
+        let exponent = attempts.saturating_sub(1);
+        self.base_delay_secs
+            .saturating_mul(1u64.checked_shl(exponent).unwrap_or(u64::MAX))
+            .min(self.max_delay_secs) as i64
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum RelayStatus {
+    /// Waiting to be delivered, or to be attempted again
+    Pending,
+    /// Attempted `max_attempts` times without success; only delivered again if replayed
+    Failed,
+}
+
+/// A relay of the data at `path` to `relay_url` which hasn't been delivered yet
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct QueuedRelay {
+    pub id: String,
+    pub path: String,
+    pub relay_url: String,
+    /// The website whose request caused the relay
+    pub origin: String,
+    pub status: RelayStatus,
+    pub attempts: u32,
+    pub enqueued_at: String,
+    pub last_attempt_at: Option<String>,
+    pub last_error: Option<String>,
+    /// Unix timestamp before which the relay isn't attempted
+    pub next_attempt_at: i64,
+}
+
+/// Holds relays until they are delivered, so that saving data doesn't depend on the relay
+/// destination being reachable
+#[async_trait]
+pub trait RelayQueue: Clone + Send + Sync {
+    async fn enqueue(
+        &self,
+        path: String,
+        relay_url: String,
+        origin: String,
+    ) -> Result<QueuedRelay, RelayQueueError>;
+    async fn list(&self) -> Result<Vec<QueuedRelay>, RelayQueueError>;
+    /// Makes a relay due immediately, with its attempts reset; `None` if there's no relay
+    /// with the ID, which is the case once it has been delivered
+    async fn replay(&self, id: String) -> Result<Option<QueuedRelay>, RelayQueueError>;
+}
+
+#[async_trait]
+impl<U> RelayQueue for Arc<U>
+where
+    U: RelayQueue,
+{
+    async fn enqueue(
+        &self,
+        path: String,
+        relay_url: String,
+        origin: String,
+    ) -> Result<QueuedRelay, RelayQueueError> {
+        self.deref().enqueue(path, relay_url, origin).await
+    }
+
+    async fn list(&self) -> Result<Vec<QueuedRelay>, RelayQueueError> {
+        self.deref().list().await
+    }
+
+    async fn replay(&self, id: String) -> Result<Option<QueuedRelay>, RelayQueueError> {
+        self.deref().replay(id).await
+    }
+}
+
+/// A relay queue kept in memory and mirrored to a JSON file after every change, so that
+/// relays which haven't been delivered survive a restart
+#[derive(Debug, Clone)]
+pub struct FileRelayQueue {
+    path: PathBuf,
+    config: RelayQueueConfig,
+    relays: Arc<Mutex<Vec<QueuedRelay>>>,
+    enqueued: Arc<Notify>,
+}
+
+impl FileRelayQueue {
+    /// Opens the queue at `config.filepath`, loading the relays left by a previous run
+    pub async fn open(config: RelayQueueConfig) -> Result<Self, RelayQueueError> {
+        let path = PathBuf::from(&config.filepath);
+        let relays = match tokio::fs::read(&path).await {
+            Ok(bytes) => serde_json::from_slice(&bytes)
+                .map_err(|source| RelayQueueError::SerializationError { source })?,
+            Err(e) => match e.kind() {
+                ErrorKind::NotFound => vec![],
+                _ => return Err(RelayQueueError::IoError { source: e }),
+            },
+        };
+
+        Ok(FileRelayQueue {
+            path,
+            config,
+            relays: Arc::new(Mutex::new(relays)),
+            enqueued: Arc::new(Notify::new()),
+        })
+    }
+
+    /// Delivers relays as they are enqueued or replayed, and every `interval` seconds
+    /// retries those which are due
+    pub fn spawn<R: Relayer + 'static, A: Auditor + 'static>(
+        self,
+        relayer: R,
+        auditor: A,
+    ) -> tokio::task::JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut interval =
+                tokio::time::interval(Duration::from_secs(self.config.interval.max(1)));
+            loop {
+                tokio::select! {
+                    _ = interval.tick() => {},
+                    _ = self.enqueued.notified() => {},
+                }
+                if let Err(e) = self.deliver_due(&relayer, &auditor).await {
+                    tracing::error!(error = %e, "failed to deliver queued relays");
+                }
+            }
+        })
+    }
+
+    /// Attempts every pending relay which is due, removing those which are delivered and
+    /// scheduling the next attempt of the others
+    pub async fn deliver_due<R: Relayer, A: Auditor>(
+        &self,
+        relayer: &R,
+        auditor: &A,
+    ) -> Result<(), RelayQueueError> {
+        let now = Utc::now().timestamp();
+        let due: Vec<QueuedRelay> = self
+            .relays
+            .lock()
+            .await
+            .iter()
+            .filter(|relay| relay.status == RelayStatus::Pending && relay.next_attempt_at <= now)
+            .cloned()
+            .collect();
+
+        // The queue isn't locked while relaying, so a slow destination doesn't hold up
+        // saving or listing
+        for relay in due {
+            let result = relayer
+                .relay(relay.path.clone(), relay.relay_url.clone())
+                .await;
+            audit(
+                auditor,
+                AuditEvent::new(&relay.origin, &relay.path, Operation::Relay, result.is_ok()),
+            )
+            .await;
+
+            let mut relays = self.relays.lock().await;
+            match result {
+                Ok(_) => relays.retain(|queued| queued.id != relay.id),
+                Err(e) => {
+                    if let Some(queued) = relays.iter_mut().find(|queued| queued.id == relay.id) {
+                        queued.attempts += 1;
+                        queued.last_attempt_at = Some(Utc::now().to_rfc3339());
+                        queued.last_error = Some(e.to_string());
+                        if queued.attempts >= self.config.max_attempts {
+                            tracing::warn!(id = %queued.id, attempts = queued.attempts, "relay failed");
+                            queued.status = RelayStatus::Failed;
+                        } else {
+                            queued.next_attempt_at =
+                                Utc::now().timestamp() + self.config.backoff(queued.attempts);
+                        }
+                    }
+                }
+            }
+            self.persist(&relays).await?;
+        }
+        Ok(())
+    }
+
+    async fn persist(&self, relays: &[QueuedRelay]) -> Result<(), RelayQueueError> {
+        let bytes = serde_json::to_vec(relays)
+            .map_err(|source| RelayQueueError::SerializationError { source })?;
+
+        atomic_file::write_async(self.path.clone(), bytes)
+            .await
+            .map_err(|source| RelayQueueError::IoError { source })
+    }
+}
+
+#[async_trait]
+impl RelayQueue for FileRelayQueue {
+    #[tracing::instrument(skip(self))]
+    async fn enqueue(
+        &self,
+        path: String,
+        relay_url: String,
+        origin: String,
+    ) -> Result<QueuedRelay, RelayQueueError> {
+        let relay = QueuedRelay {
+            id: uuid::Uuid::new_v4().to_string(),
+            path,
+            relay_url,
+            origin,
+            status: RelayStatus::Pending,
+            attempts: 0,
+            enqueued_at: Utc::now().to_rfc3339(),
+            last_attempt_at: None,
+            last_error: None,
+            next_attempt_at: Utc::now().timestamp(),
+        };
+        let mut relays = self.relays.lock().await;
+        relays.push(relay.clone());
+        self.persist(&relays).await?;
+        self.enqueued.notify_one();
+        Ok(relay)
+    }
+
+    async fn list(&self) -> Result<Vec<QueuedRelay>, RelayQueueError> {
+        Ok(self.relays.lock().await.clone())
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn replay(&self, id: String) -> Result<Option<QueuedRelay>, RelayQueueError> {
+        let mut relays = self.relays.lock().await;
+        let relay = match relays.iter_mut().find(|relay| relay.id == id) {
+            Some(relay) => {
+                relay.status = RelayStatus::Pending;
+                relay.attempts = 0;
+                relay.next_attempt_at = Utc::now().timestamp();
+                relay.clone()
+            }
+            None => return Ok(None),
+        };
+        self.persist(&relays).await?;
+        self.enqueued.notify_one();
+        Ok(Some(relay))
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::{
+        FileRelayQueue, QueuedRelay, RelayQueue, RelayQueueConfig, RelayQueueError, RelayStatus,
+    };
+    use crate::audit::{tests::expect_audits, Operation, Outcome};
+    use crate::relayer::{tests::MockRelayer, RelayError};
+    use crate::test_utils::temp_dir;
+    use async_trait::async_trait;
+    use http::StatusCode;
+    use mockall::*;
+    use tempfile::TempDir;
+
+    mock! {
+    pub RelayQueue {}
+    impl Clone for RelayQueue {
+            fn clone(&self) -> Self;
+    }
+
+    #[async_trait]
+    impl RelayQueue for RelayQueue {
+        async fn enqueue(
+            &self,
+            path: String,
+            relay_url: String,
+            origin: String,
+        ) -> Result<QueuedRelay, RelayQueueError>;
+        async fn list(&self) -> Result<Vec<QueuedRelay>, RelayQueueError>;
+        async fn replay(&self, id: String) -> Result<Option<QueuedRelay>, RelayQueueError>;
+    }
+    }
+
+    fn temp_config(max_attempts: u32) -> (TempDir, RelayQueueConfig) {
+        let dir = temp_dir();
+        let config = RelayQueueConfig {
+            filepath: dir.path().join("queue.json").to_string_lossy().into_owned(),
+            max_attempts,
+            base_delay_secs: 0,
+            ..RelayQueueConfig::default()
+        };
+        (dir, config)
+    }
+
+    #[tokio::test]
+    async fn test_queued_relays_survive_reopening_queue() {
+        let (_dir, config) = temp_config(3);
+        let queue = FileRelayQueue::open(config.clone()).await.unwrap();
+        let relay = queue
+            .enqueue(
+                ".profile.name.".to_owned(),
+                "https://relay.com/".to_owned(),
+                "https://example.com".to_owned(),
+            )
+            .await
+            .unwrap();
+
+        let queue = FileRelayQueue::open(config).await.unwrap();
+        assert_eq!(queue.list().await.unwrap(), vec![relay]);
+    }
+
+    #[tokio::test]
+    async fn test_delivered_relay_is_removed() {
+        let (_dir, config) = temp_config(3);
+        let queue = FileRelayQueue::open(config).await.unwrap();
+        queue
+            .enqueue(
+                ".profile.name.".to_owned(),
+                "https://relay.com/".to_owned(),
+                "https://example.com".to_owned(),
+            )
+            .await
+            .unwrap();
+
+        let mut relayer = MockRelayer::new();
+        relayer
+            .expect_relay()
+            .times(1)
+            .returning(|_, _| Ok(StatusCode::OK));
+        let auditor = expect_audits(Operation::Relay, vec![Outcome::Success]);
+
+        queue.deliver_due(&relayer, &auditor).await.unwrap();
+        assert!(queue.list().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_relay_fails_after_max_attempts_until_replayed() {
+        let (_dir, config) = temp_config(2);
+        let queue = FileRelayQueue::open(config).await.unwrap();
+        let relay = queue
+            .enqueue(
+                ".profile.name.".to_owned(),
+                "https://relay.com/".to_owned(),
+                "https://example.com".to_owned(),
+            )
+            .await
+            .unwrap();
+
+        let mut relayer = MockRelayer::new();
+        relayer
+            .expect_relay()
+            .times(3)
+            .returning(|_, _| Err(RelayError::ConnectionRefused { source: None }));
+        let auditor = expect_audits(Operation::Relay, vec![Outcome::Failure; 3]);
+
+        queue.deliver_due(&relayer, &auditor).await.unwrap();
+        let relays = queue.list().await.unwrap();
+        assert_eq!(relays[0].status, RelayStatus::Pending);
+        assert_eq!(relays[0].attempts, 1);
+        assert!(relays[0].last_error.is_some());
+
+        queue.deliver_due(&relayer, &auditor).await.unwrap();
+        assert_eq!(queue.list().await.unwrap()[0].status, RelayStatus::Failed);
+
+        // Failed relays are left alone until they are replayed
+        queue.deliver_due(&relayer, &auditor).await.unwrap();
+        let replayed = queue.replay(relay.id).await.unwrap().unwrap();
+        assert_eq!(replayed.status, RelayStatus::Pending);
+        assert_eq!(replayed.attempts, 0);
+        queue.deliver_due(&relayer, &auditor).await.unwrap();
+
+        assert_eq!(queue.replay("unknown".to_owned()).await.unwrap(), None);
+    }
+}
@@ -1,26 +1,138 @@
+pub mod identity;
+pub mod policy;
+
+use crate::metrics;
 use async_trait::async_trait;
-use http::StatusCode;
-use reqwest::{Certificate, Response};
+use bytes::Bytes;
+use http::{HeaderMap, Method, StatusCode};
+use identity::RelayIdentity;
+use policy::{is_retryable, AddressFilter, CircuitBreaker, RelayPolicy, Timeouts};
+use reqwest::{redirect, Certificate, ClientBuilder, RequestBuilder, Response};
 use std::collections::HashMap;
 use std::fs::File;
-use std::io::Read;
+use std::io::{ErrorKind, Read};
+use std::net::SocketAddr;
 use std::ops::Deref;
-use std::sync::Arc;
+use std::sync::{Arc, PoisonError, RwLock};
+use std::time::Duration;
 use thiserror::Error;
+use url::Url;
 use warp::reject::Reject;
 
 #[derive(Error, Debug)]
 pub enum RelayError {
     #[error("Failure happened during relay")]
     RelayRequestError { source: Option<reqwest::Error> },
+    #[error("Failed to read the client TLS identity")]
+    IdentityLoadError { source: std::io::Error },
+    #[error("Client TLS identity is not a PEM certificate and key")]
+    InvalidIdentity { source: reqwest::Error },
+    #[error("Root signing key is not an Ed25519 key")]
+    InvalidSigningKey,
+    #[error("Failed to serialize the relay request")]
+    SerializationError { source: serde_json::Error },
+    #[error("Relay destination did not respond in time")]
+    Timeout { source: Option<reqwest::Error> },
+    #[error("TLS handshake with the relay destination failed")]
+    TlsError { source: Option<reqwest::Error> },
+    #[error("Relay destination responded with status {status}")]
+    HttpStatus {
+        status: StatusCode,
+        source: Option<reqwest::Error>,
+    },
+    #[error("Relay destination refused the connection")]
+    ConnectionRefused { source: Option<reqwest::Error> },
+    #[error("Relays to {host} are failing and are paused")]
+    CircuitOpen { host: String },
+    #[error("Requests to {host} are not allowed")]
+    BlockedAddress { host: String },
+    #[error("Failed to resolve {host}")]
+    ResolveError {
+        host: String,
+        source: std::io::Error,
+    },
+}
+
+impl From<reqwest::Error> for RelayError {
+    fn from(source: reqwest::Error) -> Self {
+        if source.is_timeout() {
+            RelayError::Timeout {
+                source: Some(source),
+            }
+        } else if let Some(status) = source.status() {
+            RelayError::HttpStatus {
+                status,
+                source: Some(source),
+            }
+        } else if source.is_connect() {
+            // rustls reports handshake and certificate failures as invalid data
+            match io_error_kind(&source) {
+                Some(ErrorKind::ConnectionRefused) => RelayError::ConnectionRefused {
+                    source: Some(source),
+                },
+                Some(ErrorKind::InvalidData) => RelayError::TlsError {
+                    source: Some(source),
+                },
+                _ => RelayError::RelayRequestError {
+                    source: Some(source),
+                },
+            }
+        } else {
+            RelayError::RelayRequestError {
+                source: Some(source),
+            }
+        }
+    }
+}
+
+/// The kind of the I/O error underlying a request error, if there is one
+fn io_error_kind(error: &reqwest::Error) -> Option<ErrorKind> {
+    let mut cause = std::error::Error::source(error);
+    while let Some(error) = cause {
+        if let Some(io_error) = error.downcast_ref::<std::io::Error>() {
+            return Some(io_error.kind());
+        }
+        cause = error.source();
+    }
+    None
 }
 
 impl Reject for RelayError {}
 
+/// The most clients kept for requests to checked addresses, past which they are all
+/// dropped and built again as they're needed
+const MAX_FILTERED_CLIENTS: usize = 64;
+
+/// The host and checked address a filtered client connects to, if the host was resolved
+type ResolvedAddress = Option<(String, SocketAddr)>;
+
+/// The client TLS identity, along with the clients built with it for requests to checked
+/// addresses. The generation counts reloads, so a client built from an identity which has
+/// since been replaced isn't kept.
+#[derive(Debug)]
+struct FilteredClients {
+    tls_identity: reqwest::Identity,
+    generation: u64,
+    clients: HashMap<ResolvedAddress, reqwest::Client>,
+}
+
+/// A request made over mutual TLS on behalf of a website. If there's an address filter,
+/// the request is only made to addresses it permits and redirects aren't followed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RelayRequest {
+    pub method: Method,
+    pub url: String,
+    pub headers: HeaderMap,
+    pub body: Option<Bytes>,
+    pub address_filter: Option<AddressFilter>,
+}
+
 #[async_trait]
 pub trait Relayer: Clone + Send + Sync {
     async fn relay(&self, path: String, relay_url: String) -> Result<StatusCode, RelayError>;
-    async fn get(&self, relay_url: String) -> Result<Response, RelayError>;
+    /// Sends `request`, returning the destination's response whatever its status; only
+    /// requests whose method is idempotent are retried after a timeout or server error
+    async fn send(&self, request: RelayRequest) -> Result<Response, RelayError>;
 }
 
 #[async_trait]
@@ -32,31 +144,161 @@ where
         self.deref().relay(path, relay_url).await
     }
 
-    async fn get(&self, relay_url: String) -> Result<Response, RelayError> {
-        self.deref().get(relay_url).await
+    async fn send(&self, request: RelayRequest) -> Result<Response, RelayError> {
+        self.deref().send(request).await
     }
 }
 
 #[derive(Debug, Clone)]
 pub struct MutualTLSRelayer {
-    pub client: reqwest::Client,
+    client: Arc<RwLock<reqwest::Client>>,
+    filtered_clients: Arc<RwLock<FilteredClients>>,
+    pem_file_path: String,
+    additional_ca_certs: Option<Vec<Certificate>>,
+    identity: RelayIdentity,
+    policy: RelayPolicy,
+    circuit_breaker: CircuitBreaker,
 }
 
 impl MutualTLSRelayer {
     pub fn new(
         pem_file_path: String,
         additional_ca_certs: Option<&[Certificate]>,
+        identity: RelayIdentity,
+        policy: RelayPolicy,
     ) -> Result<MutualTLSRelayer, RelayError> {
-        // Load the client TLS certificate and key
+        let additional_ca_certs = additional_ca_certs.map(|certs| certs.to_vec());
+        let tls_identity = Self::load_tls_identity(&pem_file_path)?;
+        let client = Self::build_client(
+            &tls_identity,
+            additional_ca_certs.as_deref(),
+            &policy.timeouts,
+        )?;
+        Ok(MutualTLSRelayer {
+            client: Arc::new(RwLock::new(client)),
+            filtered_clients: Arc::new(RwLock::new(FilteredClients {
+                tls_identity,
+                generation: 0,
+                clients: HashMap::new(),
+            })),
+            pem_file_path,
+            additional_ca_certs,
+            identity,
+            circuit_breaker: CircuitBreaker::new(policy.circuit_breaker.clone()),
+            policy,
+        })
+    }
+
+    /// Re-reads the client TLS identity from disk and swaps it into the client used
+    /// for all subsequent requests, including those made by clones of this relayer
+    pub fn reload(&self) -> Result<(), RelayError> {
+        let tls_identity = Self::load_tls_identity(&self.pem_file_path)?;
+        let client = Self::build_client(
+            &tls_identity,
+            self.additional_ca_certs.as_deref(),
+            &self.policy.timeouts,
+        )?;
+        *self.client.write().unwrap_or_else(PoisonError::into_inner) = client;
+        // Filtered clients are built again with the new identity as they're needed
+        let mut filtered_clients = self
+            .filtered_clients
+            .write()
+            .unwrap_or_else(PoisonError::into_inner);
+        filtered_clients.tls_identity = tls_identity;
+        filtered_clients.generation += 1;
+        filtered_clients.clients.clear();
+        Ok(())
+    }
+
+    pub(crate) fn client(&self) -> reqwest::Client {
+        // The client is only ever swapped whole, so it's still usable if a writer panicked
+        self.client
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    /// Reads the client TLS certificate and key from disk
+    fn load_tls_identity(pem_file_path: &str) -> Result<reqwest::Identity, RelayError> {
         let mut buf = Vec::new();
         File::open(pem_file_path)
-            .unwrap()
-            .read_to_end(&mut buf)
-            .unwrap();
-        let pkcs12 = reqwest::Identity::from_pem(&buf).unwrap();
+            .and_then(|mut file| file.read_to_end(&mut buf))
+            .map_err(|source| RelayError::IdentityLoadError { source })?;
+        reqwest::Identity::from_pem(&buf).map_err(|source| RelayError::InvalidIdentity { source })
+    }
+
+    fn build_client(
+        tls_identity: &reqwest::Identity,
+        additional_ca_certs: Option<&[Certificate]>,
+        timeouts: &Timeouts,
+    ) -> Result<reqwest::Client, RelayError> {
+        Self::client_builder(tls_identity, additional_ca_certs, timeouts)
+            .build()
+            .map_err(|source| RelayError::RelayRequestError {
+                source: Some(source),
+            })
+    }
+
+    /// A client which doesn't follow redirects, connecting to the already checked address
+    /// if there's one rather than resolving the host again; clients are kept per address,
+    /// so one is only built the first time an address is requested
+    fn filtered_client(&self, resolved: ResolvedAddress) -> Result<reqwest::Client, RelayError> {
+        let (tls_identity, generation) = {
+            let filtered_clients = self
+                .filtered_clients
+                .read()
+                .unwrap_or_else(PoisonError::into_inner);
+            if let Some(client) = filtered_clients.clients.get(&resolved) {
+                return Ok(client.clone());
+            }
+            (
+                filtered_clients.tls_identity.clone(),
+                filtered_clients.generation,
+            )
+        };
+
+        // Built outside the lock, so other requests aren't held up while the TLS
+        // configuration is put together
+        let mut client_builder = Self::client_builder(
+            &tls_identity,
+            self.additional_ca_certs.as_deref(),
+            &self.policy.timeouts,
+        )
+        .redirect(redirect::Policy::none());
+        if let Some((host, addr)) = &resolved {
+            client_builder = client_builder.resolve(host, *addr);
+        }
+        let client = client_builder
+            .build()
+            .map_err(|source| RelayError::RelayRequestError {
+                source: Some(source),
+            })?;
 
+        let mut filtered_clients = self
+            .filtered_clients
+            .write()
+            .unwrap_or_else(PoisonError::into_inner);
+        // A client built with an identity that was reloaded meanwhile is used only once
+        if filtered_clients.generation == generation {
+            if filtered_clients.clients.len() >= MAX_FILTERED_CLIENTS {
+                filtered_clients.clients.clear();
+            }
+            filtered_clients.clients.insert(resolved, client.clone());
+        }
+        Ok(client)
+    }
+
+    fn client_builder(
+        tls_identity: &reqwest::Identity,
+        additional_ca_certs: Option<&[Certificate]>,
+        timeouts: &Timeouts,
+    ) -> ClientBuilder {
         // Build the relay HTTP client, adding in provided certificate as additional CA certs
-        let mut client_builder = reqwest::Client::builder().identity(pkcs12).use_rustls_tls();
+        let mut client_builder = reqwest::Client::builder()
+            .identity(tls_identity.clone())
+            .use_rustls_tls()
+            .connect_timeout(Duration::from_secs(timeouts.connect))
+            .timeout(Duration::from_secs(timeouts.request));
         if let Some(ca_certs) = additional_ca_certs {
             // In order for the additional certs to be used, the built-in root certs must be disabled
             client_builder = client_builder.tls_built_in_root_certs(false);
@@ -64,51 +306,115 @@ impl MutualTLSRelayer {
                 client_builder = client_builder.add_root_certificate(cert.clone())
             }
         }
-        Ok(MutualTLSRelayer {
-            client: client_builder.build().unwrap(),
-        })
+        client_builder
+    }
+
+    /// Sends the request built by `request`, retrying failures which `is_retryable`
+    /// allows with backoff, unless the circuit to the destination host is open. Error
+    /// statuses count as failures, but the last response is returned whatever its status.
+    async fn execute<F>(
+        &self,
+        client: reqwest::Client,
+        url: &str,
+        idempotent: bool,
+        request: F,
+    ) -> Result<Response, RelayError>
+    where
+        F: Fn(&reqwest::Client) -> RequestBuilder + Send + Sync,
+    {
+        let host = Url::parse(url)
+            .ok()
+            .and_then(|url| url.host_str().map(str::to_owned))
+            .unwrap_or_default();
+        let mut attempt = 0;
+        loop {
+            self.circuit_breaker.check(&host)?;
+            let result = request(&client).send().await.map_err(RelayError::from);
+            let status_error = result
+                .as_ref()
+                .ok()
+                .and_then(|response| response.error_for_status_ref().err())
+                .map(RelayError::from);
+            let failure = result.as_ref().err().or(status_error.as_ref());
+            self.circuit_breaker
+                .record(&host, failure.map_or(Ok(()), Err));
+
+            match failure {
+                Some(e)
+                    if attempt + 1 < self.policy.retry.max_attempts
+                        && is_retryable(e, idempotent) =>
+                {
+                    let delay = self.policy.retry.backoff(attempt);
+                    tracing::debug!(attempt, ?delay, error = %e, "retrying relay request");
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+                _ => return result,
+            }
+        }
     }
 }
 
 #[async_trait]
 impl Relayer for MutualTLSRelayer {
+    #[tracing::instrument(skip(self))]
     async fn relay(&self, path: String, relay_url: String) -> Result<StatusCode, RelayError> {
-        let mut req_body = HashMap::new();
-        req_body.insert("path", path);
-        req_body.insert("userId", "abc".to_owned());
+        let req_body = self.identity.relay_body(path, relay_url.clone())?;
 
-        self.client
-            .post(relay_url)
-            .json(&req_body)
-            .send()
-            .await
-            .and_then(|response| response.error_for_status())
-            .and_then(|response| Ok(response.status()))
-            .map_err(|source| RelayError::RelayRequestError {
-                source: Some(source),
+        let result = self
+            .execute(self.client(), &relay_url, false, |client| {
+                client.post(relay_url.as_str()).json(&req_body)
             })
+            .await
+            .and_then(|response| response.error_for_status().map_err(RelayError::from))
+            .map(|response| response.status());
+        let outcome = if result.is_ok() { "success" } else { "failure" };
+        metrics::RELAYS.with_label_values(&[outcome]).inc();
+        result
     }
 
-    async fn get(&self, relay_url: String) -> Result<Response, RelayError> {
-        self.client
-            .get(relay_url)
-            .send()
-            .await
-            .and_then(|response| response.error_for_status())
-            .map_err(|source| RelayError::RelayRequestError {
-                source: Some(source),
-            })
+    #[tracing::instrument(skip(self, request), fields(method = %request.method, url = %request.url))]
+    async fn send(&self, request: RelayRequest) -> Result<Response, RelayError> {
+        let client = match &request.address_filter {
+            Some(address_filter) => {
+                let resolved = address_filter.resolve(&request.url).await?;
+                self.filtered_client(resolved)?
+            }
+            None => self.client(),
+        };
+        self.execute(
+            client,
+            &request.url,
+            request.method.is_idempotent(),
+            |client| {
+                let builder = client
+                    .request(request.method.clone(), request.url.as_str())
+                    .headers(request.headers.clone());
+                match &request.body {
+                    Some(body) => builder.body(body.clone()),
+                    None => builder,
+                }
+            },
+        )
+        .await
     }
 }
 
 #[cfg(test)]
 pub mod tests {
-    use super::{RelayError, Relayer};
+    use super::{identity::RelayIdentity, policy::RelayPolicy, MutualTLSRelayer};
+    use super::{RelayError, RelayRequest, Relayer};
+    use crate::bootstrap::pki::{
+        setup_root_cert, setup_tls_cert, setup_tls_identity, tests::cert_config,
+    };
+    use crate::test_utils::temp_dir;
     use async_trait::async_trait;
     use http::StatusCode;
     use mockall::predicate::*;
     use mockall::*;
+    use redact_crypto::key::sodiumoxide::SodiumOxideEd25519SecretAsymmetricKey;
     use reqwest::Response;
+    use sodiumoxide::crypto::sign;
 
     mock! {
     pub Relayer {}
@@ -119,7 +425,99 @@ pub mod tests {
     #[async_trait]
     impl Relayer for MockRelayer {
         async fn relay(&self, path: String, relay_url: String) -> Result<StatusCode, RelayError>;
-        async fn get(&self, relay_url: String) -> Result<Response, RelayError>;
+        async fn send(&self, request: RelayRequest) -> Result<Response, RelayError>;
+    }
+    }
+
+    fn identity() -> RelayIdentity {
+        let (_, secret_key) = sign::gen_keypair();
+        RelayIdentity::new(None, secret_key.as_ref()).unwrap()
+    }
+
+    #[test]
+    fn test_new_without_identity_file_is_error() {
+        let dir = temp_dir();
+        let filepath = dir.path().join("identity.pem");
+        let result = MutualTLSRelayer::new(
+            filepath.to_string_lossy().into_owned(),
+            None,
+            identity(),
+            RelayPolicy::default(),
+        );
+        assert!(matches!(result, Err(RelayError::IdentityLoadError { .. })));
+    }
+
+    #[test]
+    fn test_new_with_malformed_identity_is_error() {
+        let dir = temp_dir();
+        let filepath = dir.path().join("identity.pem");
+        std::fs::write(&filepath, "not a certificate").unwrap();
+        let result = MutualTLSRelayer::new(
+            filepath.to_string_lossy().into_owned(),
+            None,
+            identity(),
+            RelayPolicy::default(),
+        );
+        assert!(matches!(result, Err(RelayError::InvalidIdentity { .. })));
     }
+
+    #[test]
+    fn test_filtered_clients_are_kept_per_address_until_reload() {
+        let dir = temp_dir();
+        let root_cert_config = cert_config(dir.path(), "root", 10);
+        let tls_cert_config = cert_config(dir.path(), "tls", 10);
+        let root_signing_key = SodiumOxideEd25519SecretAsymmetricKey::new();
+        let tls_key = SodiumOxideEd25519SecretAsymmetricKey::new();
+        let filepath = dir.path().join("identity.pem");
+        setup_root_cert(&root_signing_key, &root_cert_config).unwrap();
+        setup_tls_cert(
+            &root_signing_key,
+            &tls_key,
+            &root_cert_config,
+            &tls_cert_config,
+        )
+        .unwrap();
+        setup_tls_identity(
+            &tls_key,
+            &tls_cert_config.filepath,
+            filepath.to_str().unwrap(),
+        )
+        .unwrap();
+        let identity_pem = std::fs::read(&filepath).unwrap();
+        let relayer = MutualTLSRelayer::new(
+            filepath.to_string_lossy().into_owned(),
+            None,
+            identity(),
+            RelayPolicy::default(),
+        )
+        .unwrap();
+        let resolved = Some(("example.com".to_owned(), ([93, 184, 216, 34], 443).into()));
+        let client_count = || relayer.filtered_clients.read().unwrap().clients.len();
+
+        // The identity is read once up front, not for every address without a client
+        std::fs::remove_file(&filepath).unwrap();
+        relayer.filtered_client(resolved.clone()).unwrap();
+        relayer.filtered_client(resolved.clone()).unwrap();
+        relayer
+            .filtered_client(Some((
+                "example.com".to_owned(),
+                ([93, 184, 216, 35], 443).into(),
+            )))
+            .unwrap();
+        assert_eq!(client_count(), 2);
+
+        // A failed reload keeps the clients built with the current identity
+        assert!(matches!(
+            relayer.reload(),
+            Err(RelayError::IdentityLoadError { .. })
+        ));
+        assert_eq!(client_count(), 2);
+
+        // Reloading the identity drops the clients built with the old one
+        std::fs::write(&filepath, identity_pem).unwrap();
+        relayer.reload().unwrap();
+        assert_eq!(client_count(), 0);
+        relayer.filtered_client(resolved).unwrap();
+        assert_eq!(client_count(), 1);
     }
 }
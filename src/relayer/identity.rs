@@ -0,0 +1,203 @@
+use crate::relayer::RelayError;
+use chrono::Utc;
+use rand::{thread_rng, Rng};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sodiumoxide::crypto::sign;
+
+/// Identifies this client to the servers it relays to, signing every relay with the root
+/// signing key
+#[derive(Debug, Clone)]
+pub struct RelayIdentity {
+    user_id: String,
+    public_key: sign::PublicKey,
+    secret_key: sign::SecretKey,
+}
+
+/// The body of a relay request. The signature is made over the JSON array
+/// `[path, userId, issuedAt, relayUrl, nonce]` with the Ed25519 key whose SHA-256 is
+/// `keyFingerprint`, so it is only good for the server it was sent to, and only once.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct RelayBody {
+    pub path: String,
+    pub user_id: String,
+    pub relay_url: String,
+    pub nonce: String,
+    pub key_fingerprint: String,
+    pub public_key: String,
+    pub issued_at: i64,
+    pub signature: String,
+}
+
+impl RelayIdentity {
+    /// Creates the identity from the root signing key, identifying the user by the key's
+    /// fingerprint unless a user ID is given
+    pub fn new(user_id: Option<String>, root_key_bytes: &[u8]) -> Result<Self, RelayError> {
+        let secret_key = match root_key_bytes.len() {
+            sign::SECRETKEYBYTES => sign::SecretKey::from_slice(root_key_bytes),
+            sign::SEEDBYTES => {
+                sign::Seed::from_slice(root_key_bytes).map(|seed| sign::keypair_from_seed(&seed).1)
+            }
+            _ => None,
+        }
+        .ok_or(RelayError::InvalidSigningKey)?;
+        let public_key = secret_key.public_key();
+        let user_id = user_id
+            .filter(|user_id| !user_id.is_empty())
+            .unwrap_or_else(|| fingerprint(&public_key));
+
+        Ok(RelayIdentity {
+            user_id,
+            public_key,
+            secret_key,
+        })
+    }
+
+    pub fn user_id(&self) -> &str {
+        &self.user_id
+    }
+
+    pub fn fingerprint(&self) -> String {
+        fingerprint(&self.public_key)
+    }
+
+    /// The signed body relaying `path` to `relay_url`
+    pub fn relay_body(&self, path: String, relay_url: String) -> Result<RelayBody, RelayError> {
+        let issued_at = Utc::now().timestamp();
+        let nonce = base64::encode(thread_rng().gen::<[u8; 16]>());
+        let message = signed_message(&path, &self.user_id, issued_at, &relay_url, &nonce)?;
+        let signature = sign::sign_detached(&message, &self.secret_key);
+
+        Ok(RelayBody {
+            path,
+            user_id: self.user_id.clone(),
+            relay_url,
+            nonce,
+            key_fingerprint: self.fingerprint(),
+            public_key: base64::encode(self.public_key.as_ref()),
+            issued_at,
+            signature: base64::encode(signature.as_ref()),
+        })
+    }
+}
+
+fn fingerprint(public_key: &sign::PublicKey) -> String {
+    format!("{:x}", Sha256::digest(public_key.as_ref()))
+}
+
+fn signed_message(
+    path: &str,
+    user_id: &str,
+    issued_at: i64,
+    relay_url: &str,
+    nonce: &str,
+) -> Result<Vec<u8>, RelayError> {
+    serde_json::to_vec(&(path, user_id, issued_at, relay_url, nonce))
+        .map_err(|source| RelayError::SerializationError { source })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{fingerprint, signed_message, RelayBody, RelayIdentity};
+    use sodiumoxide::crypto::sign;
+    use std::convert::TryFrom;
+
+    const RELAY_URL: &str = "https://relay.example.com/relay";
+
+    /// Whether the body was signed by the key it carries, and that key has its fingerprint,
+    /// as checked by the server receiving the relay
+    fn verify(body: &RelayBody) -> bool {
+        let public_key = base64::decode(&body.public_key)
+            .ok()
+            .and_then(|key| sign::PublicKey::from_slice(&key));
+        let signature = base64::decode(&body.signature)
+            .ok()
+            .and_then(|signature| sign::Signature::try_from(signature.as_slice()).ok());
+        let message = signed_message(
+            &body.path,
+            &body.user_id,
+            body.issued_at,
+            &body.relay_url,
+            &body.nonce,
+        )
+        .ok();
+
+        match (public_key, signature, message) {
+            (Some(public_key), Some(signature), Some(message)) => {
+                fingerprint(&public_key) == body.key_fingerprint
+                    && sign::verify_detached(&signature, &message, &public_key)
+            }
+            _ => false,
+        }
+    }
+
+    #[test]
+    fn test_relay_body_verifies() {
+        let (_, secret_key) = sign::gen_keypair();
+        let identity = RelayIdentity::new(Some("user".to_owned()), secret_key.as_ref()).unwrap();
+        let body = identity
+            .relay_body(".profile.name.".to_owned(), RELAY_URL.to_owned())
+            .unwrap();
+
+        assert_eq!(body.user_id, "user");
+        assert_eq!(body.relay_url, RELAY_URL);
+        assert_eq!(body.key_fingerprint, identity.fingerprint());
+        assert!(verify(&body));
+    }
+
+    #[test]
+    fn test_relay_body_is_bound_to_destination_and_unique() {
+        let (_, secret_key) = sign::gen_keypair();
+        let identity = RelayIdentity::new(None, secret_key.as_ref()).unwrap();
+        let body = identity
+            .relay_body(".profile.name.".to_owned(), RELAY_URL.to_owned())
+            .unwrap();
+        let again = identity
+            .relay_body(".profile.name.".to_owned(), RELAY_URL.to_owned())
+            .unwrap();
+        assert_ne!(body.nonce, again.nonce);
+
+        let mut replayed = body.clone();
+        replayed.relay_url = "https://other.example.com/relay".to_owned();
+        assert!(!verify(&replayed));
+
+        let mut replayed = body;
+        replayed.nonce = again.nonce;
+        assert!(!verify(&replayed));
+    }
+
+    #[test]
+    fn test_altered_relay_body_does_not_verify() {
+        let (_, secret_key) = sign::gen_keypair();
+        let identity = RelayIdentity::new(None, secret_key.as_ref()).unwrap();
+        let mut body = identity
+            .relay_body(".profile.name.".to_owned(), RELAY_URL.to_owned())
+            .unwrap();
+        body.path = ".profile.email.".to_owned();
+        assert!(!verify(&body));
+
+        let (_, other_key) = sign::gen_keypair();
+        let other = RelayIdentity::new(None, other_key.as_ref()).unwrap();
+        let mut body = identity
+            .relay_body(".profile.name.".to_owned(), RELAY_URL.to_owned())
+            .unwrap();
+        body.public_key = other
+            .relay_body(".profile.name.".to_owned(), RELAY_URL.to_owned())
+            .unwrap()
+            .public_key;
+        assert!(!verify(&body));
+    }
+
+    #[test]
+    fn test_user_id_defaults_to_key_fingerprint() {
+        let seed = sign::Seed([7u8; sign::SEEDBYTES]);
+        let (_, secret_key) = sign::keypair_from_seed(&seed);
+        let from_secret_key = RelayIdentity::new(None, secret_key.as_ref()).unwrap();
+        let from_seed = RelayIdentity::new(Some("".to_owned()), &seed.0).unwrap();
+
+        assert_eq!(from_secret_key.user_id(), from_secret_key.fingerprint());
+        assert_eq!(from_secret_key.fingerprint(), from_seed.fingerprint());
+        assert!(RelayIdentity::new(None, b"short").is_err());
+    }
+}
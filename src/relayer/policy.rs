@@ -0,0 +1,462 @@
+use crate::relayer::RelayError;
+use http::StatusCode;
+use rand::{thread_rng, Rng};
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use url::{Host, Url};
+
+/// Seconds allowed to connect to a destination, and to complete a whole request
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct Timeouts {
+    pub connect: u64,
+    pub request: u64,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Timeouts {
+            connect: 5,
+            request: 30,
+        }
+    }
+}
+
+/// How many times a request is attempted, and how long to wait between attempts; the wait
+/// doubles after every attempt up to `max_delay_ms`, and a random part of it is skipped
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    pub base_delay_ms: u64,
+    pub max_delay_ms: u64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 3,
+            base_delay_ms: 200,
+            max_delay_ms: 5000,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// The wait before the attempt following attempt number `attempt`, counted from zero
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let ceiling = self
+            .base_delay_ms
+            .saturating_mul(1u64.checked_shl(attempt).unwrap_or(u64::MAX))
+            .min(self.max_delay_ms);
+        Duration::from_millis(thread_rng().gen_range(0..=ceiling))
+    }
+}
+
+/// After `failure_threshold` failures in a row, requests to a host fail immediately for
+/// `open_secs` seconds before a single request is let through to try it again
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct CircuitBreakerPolicy {
+    pub failure_threshold: u32,
+    pub open_secs: u64,
+}
+
+impl Default for CircuitBreakerPolicy {
+    fn default() -> Self {
+        CircuitBreakerPolicy {
+            failure_threshold: 5,
+            open_secs: 30,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RelayPolicy {
+    pub timeouts: Timeouts,
+    pub retry: RetryPolicy,
+    pub circuit_breaker: CircuitBreakerPolicy,
+}
+
+/// Whether a failed attempt may be tried again. A refused connection never reached the
+/// destination and a 429 or 503 asks for a retry, so those are always retried; other
+/// server errors and timeouts are only retried if the request is idempotent.
+pub fn is_retryable(error: &RelayError, idempotent: bool) -> bool {
+    match error {
+        RelayError::ConnectionRefused { .. } => true,
+        RelayError::HttpStatus { status, .. } => match *status {
+            StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE => true,
+            status => idempotent && status.is_server_error(),
+        },
+        RelayError::Timeout { .. } => idempotent,
+        _ => false,
+    }
+}
+
+/// Whether a failed attempt says something about the health of the destination host
+fn is_host_failure(error: &RelayError) -> bool {
+    match error {
+        RelayError::HttpStatus { status, .. } => status.is_server_error(),
+        RelayError::Timeout { .. }
+        | RelayError::ConnectionRefused { .. }
+        | RelayError::TlsError { .. } => true,
+        _ => false,
+    }
+}
+
+/// Which addresses a request may connect to; addresses in `allowed` are always permitted,
+/// and private, loopback and other non-public addresses are refused if `block_private`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AddressFilter {
+    pub block_private: bool,
+    pub allowed: Vec<IpAddr>,
+}
+
+impl AddressFilter {
+    pub fn permits(&self, ip: IpAddr) -> bool {
+        self.allowed.contains(&ip) || !(self.block_private && is_non_public(ip))
+    }
+
+    /// Resolves the host of `url` and checks every address it resolves to, returning the
+    /// address to connect to so the host can't be re-resolved to another address between
+    /// the check and the connection. Hosts which are IP addresses need no resolving.
+    pub async fn resolve(&self, url: &str) -> Result<Option<(String, SocketAddr)>, RelayError> {
+        let blocked = || RelayError::BlockedAddress {
+            host: url.to_owned(),
+        };
+        let url = Url::parse(url).map_err(|_| blocked())?;
+        let port = url.port_or_known_default().ok_or_else(blocked)?;
+        let ip = match url.host() {
+            Some(Host::Domain(domain)) => {
+                let addrs: Vec<SocketAddr> = tokio::net::lookup_host((domain, port))
+                    .await
+                    .map_err(|source| RelayError::ResolveError {
+                        host: domain.to_owned(),
+                        source,
+                    })?
+                    .collect();
+                return match addrs.first() {
+                    Some(addr) if addrs.iter().all(|addr| self.permits(addr.ip())) => {
+                        Ok(Some((domain.to_owned(), *addr)))
+                    }
+                    _ => Err(blocked()),
+                };
+            }
+            Some(Host::Ipv4(ip)) => IpAddr::V4(ip),
+            Some(Host::Ipv6(ip)) => IpAddr::V6(ip),
+            None => return Err(blocked()),
+        };
+        if self.permits(ip) {
+            Ok(None)
+        } else {
+            Err(blocked())
+        }
+    }
+}
+
+/// Whether `ip` is loopback, private, link-local, shared, reserved or otherwise not an
+/// address of a public host
+pub fn is_non_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_non_public_v4(ip),
+        IpAddr::V6(ip) => is_non_public_v6(ip),
+    }
+}
+
+fn is_non_public_v4(ip: Ipv4Addr) -> bool {
+    let [a, b, c, _] = ip.octets();
+    ip.is_private()
+        || ip.is_loopback()
+        || ip.is_link_local()
+        || ip.is_broadcast()
+        || ip.is_documentation()
+        || ip.is_multicast()
+        || a == 0
+        // Shared address space (100.64.0.0/10), used for carrier-grade NAT
+        || (a == 100 && (b & 0xc0) == 64)
+        // IETF protocol assignments (192.0.0.0/24) and benchmarking (198.18.0.0/15)
+        || (a == 192 && b == 0 && c == 0)
+        || (a == 198 && (b & 0xfe) == 18)
+        // Reserved for future use (240.0.0.0/4)
+        || a >= 240
+}
+
+fn is_non_public_v6(ip: Ipv6Addr) -> bool {
+    let segments = ip.segments();
+    // IPv4-mapped (::ffff:0:0/96) and IPv4-compatible addresses reach the IPv4 address
+    if segments[..5].iter().all(|segment| *segment == 0)
+        && (segments[5] == 0xffff || (segments[5] == 0 && !ip.is_loopback()))
+        && !ip.is_unspecified()
+    {
+        let [_, _, _, _, _, _, high, low] = segments;
+        let ipv4 = Ipv4Addr::new((high >> 8) as u8, high as u8, (low >> 8) as u8, low as u8);
+        return is_non_public_v4(ipv4);
+    }
+    ip.is_loopback()
+        || ip.is_unspecified()
+        || ip.is_multicast()
+        // Unique local (fc00::/7) and link-local (fe80::/10)
+        || (segments[0] & 0xfe00) == 0xfc00
+        || (segments[0] & 0xffc0) == 0xfe80
+        // Documentation (2001:db8::/32)
+        || (segments[0] == 0x2001 && segments[1] == 0x0db8)
+}
+
+/// The most hosts failures are tracked for, past which they are all forgotten
+const MAX_TRACKED_HOSTS: usize = 1024;
+
+#[derive(Debug, Default)]
+struct HostState {
+    consecutive_failures: u32,
+    open_until: Option<Instant>,
+}
+
+/// Tracks failures per destination host, shared by every clone of a relayer. Only hosts
+/// which are currently failing are tracked.
+#[derive(Debug, Clone, Default)]
+pub struct CircuitBreaker {
+    policy: CircuitBreakerPolicy,
+    hosts: Arc<Mutex<HashMap<String, HostState>>>,
+}
+
+impl CircuitBreaker {
+    pub fn new(policy: CircuitBreakerPolicy) -> Self {
+        CircuitBreaker {
+            policy,
+            hosts: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Fails if the circuit to `host` is open; once it has been open long enough, one
+    /// request is let through and the circuit stays open for everyone else meanwhile
+    pub fn check(&self, host: &str) -> Result<(), RelayError> {
+        let mut hosts = self.hosts.lock().unwrap_or_else(|e| e.into_inner());
+        let state = match hosts.get_mut(host) {
+            Some(state) => state,
+            None => return Ok(()),
+        };
+        match state.open_until {
+            Some(open_until) if Instant::now() < open_until => Err(RelayError::CircuitOpen {
+                host: host.to_owned(),
+            }),
+            Some(_) => {
+                state.open_until =
+                    Some(Instant::now() + Duration::from_secs(self.policy.open_secs));
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+
+    pub fn record(&self, host: &str, result: Result<(), &RelayError>) {
+        let mut hosts = self.hosts.lock().unwrap_or_else(|e| e.into_inner());
+        match result {
+            Err(error) if is_host_failure(error) => {
+                if !hosts.contains_key(host) && hosts.len() >= MAX_TRACKED_HOSTS {
+                    hosts.clear();
+                }
+                let state = hosts.entry(host.to_owned()).or_default();
+                state.consecutive_failures += 1;
+                if state.consecutive_failures >= self.policy.failure_threshold {
+                    tracing::warn!(host, "opening relay circuit");
+                    state.open_until =
+                        Some(Instant::now() + Duration::from_secs(self.policy.open_secs));
+                }
+            }
+            _ => {
+                hosts.remove(host);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        is_non_public, is_retryable, AddressFilter, CircuitBreaker, CircuitBreakerPolicy,
+        RetryPolicy, MAX_TRACKED_HOSTS,
+    };
+    use crate::relayer::RelayError;
+    use http::StatusCode;
+    use std::net::IpAddr;
+    use std::time::Duration;
+
+    fn status(status: StatusCode) -> RelayError {
+        RelayError::HttpStatus {
+            status,
+            source: None,
+        }
+    }
+
+    #[test]
+    fn test_backoff_grows_up_to_max_delay() {
+        let policy = RetryPolicy {
+            max_attempts: 10,
+            base_delay_ms: 100,
+            max_delay_ms: 1000,
+        };
+        for _ in 0..100 {
+            assert!(policy.backoff(0) <= Duration::from_millis(100));
+            assert!(policy.backoff(2) <= Duration::from_millis(400));
+            assert!(policy.backoff(63) <= Duration::from_millis(1000));
+            assert!(policy.backoff(64) <= Duration::from_millis(1000));
+        }
+    }
+
+    #[test]
+    fn test_only_idempotent_requests_retry_timeouts_and_server_errors() {
+        let refused = RelayError::ConnectionRefused { source: None };
+        let timeout = RelayError::Timeout { source: None };
+        assert!(is_retryable(&refused, false));
+        assert!(is_retryable(
+            &status(StatusCode::SERVICE_UNAVAILABLE),
+            false
+        ));
+        assert!(is_retryable(&status(StatusCode::TOO_MANY_REQUESTS), false));
+        assert!(!is_retryable(&timeout, false));
+        assert!(is_retryable(&timeout, true));
+        assert!(!is_retryable(&status(StatusCode::BAD_GATEWAY), false));
+        assert!(is_retryable(&status(StatusCode::BAD_GATEWAY), true));
+        assert!(!is_retryable(&status(StatusCode::NOT_FOUND), true));
+        assert!(!is_retryable(&RelayError::TlsError { source: None }, true));
+    }
+
+    #[test]
+    fn test_circuit_opens_after_consecutive_failures() {
+        let breaker = CircuitBreaker::new(CircuitBreakerPolicy {
+            failure_threshold: 2,
+            open_secs: 60,
+        });
+        let timeout = RelayError::Timeout { source: None };
+
+        breaker.record("a.com", Err(&timeout));
+        breaker.record("a.com", Ok(()));
+        breaker.record("a.com", Err(&timeout));
+        assert!(breaker.check("a.com").is_ok());
+
+        breaker.record("a.com", Err(&status(StatusCode::NOT_FOUND)));
+        breaker.record("a.com", Err(&timeout));
+        breaker.record("a.com", Err(&timeout));
+        assert!(matches!(
+            breaker.check("a.com"),
+            Err(RelayError::CircuitOpen { .. })
+        ));
+        assert!(breaker.check("b.com").is_ok());
+    }
+
+    #[test]
+    fn test_circuit_tries_host_again_once_open_period_ends() {
+        let breaker = CircuitBreaker::new(CircuitBreakerPolicy {
+            failure_threshold: 1,
+            open_secs: 0,
+        });
+        breaker.record("a.com", Err(&RelayError::Timeout { source: None }));
+        assert!(breaker.check("a.com").is_ok());
+
+        breaker.record("a.com", Ok(()));
+        assert!(breaker.check("a.com").is_ok());
+        assert!(breaker.check("a.com").is_ok());
+    }
+
+    #[test]
+    fn test_circuit_only_tracks_failing_hosts() {
+        let breaker = CircuitBreaker::new(CircuitBreakerPolicy::default());
+        let timeout = RelayError::Timeout { source: None };
+        let tracked = || breaker.hosts.lock().unwrap().len();
+
+        for index in 0..10 {
+            let host = format!("{}.example.com", index);
+            assert!(breaker.check(&host).is_ok());
+            breaker.record(&host, Ok(()));
+        }
+        assert_eq!(tracked(), 0);
+
+        breaker.record("a.com", Err(&timeout));
+        assert_eq!(tracked(), 1);
+        breaker.record("a.com", Ok(()));
+        assert_eq!(tracked(), 0);
+
+        for index in 0..MAX_TRACKED_HOSTS + 1 {
+            breaker.record(&format!("{}.example.com", index), Err(&timeout));
+        }
+        assert!(tracked() <= MAX_TRACKED_HOSTS);
+    }
+
+    #[test]
+    fn test_non_public_addresses() {
+        for ip in &[
+            "127.0.0.1",
+            "10.0.0.5",
+            "172.16.3.4",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "255.255.255.255",
+            "::1",
+            "::",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:10.0.0.5",
+            "::ffff:127.0.0.1",
+        ] {
+            assert!(is_non_public(ip.parse().unwrap()), "{}", ip);
+        }
+        for ip in &[
+            "93.184.216.34",
+            "8.8.8.8",
+            "2606:2800:220:1::1",
+            "::ffff:8.8.8.8",
+        ] {
+            assert!(!is_non_public(ip.parse().unwrap()), "{}", ip);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_address_filter_blocks_private_ip_destinations_unless_allowed() {
+        let filter = AddressFilter {
+            block_private: true,
+            allowed: vec!["192.168.1.5".parse::<IpAddr>().unwrap()],
+        };
+        assert!(matches!(
+            filter.resolve("http://10.0.0.5/admin").await,
+            Err(RelayError::BlockedAddress { .. })
+        ));
+        assert!(matches!(
+            filter.resolve("http://[::1]:8080/").await,
+            Err(RelayError::BlockedAddress { .. })
+        ));
+        assert_eq!(filter.resolve("http://192.168.1.5/").await.unwrap(), None);
+        assert_eq!(filter.resolve("https://8.8.8.8/").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_address_filter_checks_resolved_addresses() {
+        let filter = AddressFilter {
+            block_private: true,
+            allowed: vec![],
+        };
+        // Resolves without DNS, to a loopback address
+        assert!(matches!(
+            filter.resolve("http://localhost/").await,
+            Err(RelayError::BlockedAddress { .. })
+        ));
+
+        let filter = AddressFilter {
+            block_private: false,
+            allowed: vec![],
+        };
+        let (host, addr) = filter
+            .resolve("http://localhost:8080/")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(host, "localhost");
+        assert!(addr.ip().is_loopback());
+    }
+}
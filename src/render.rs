@@ -1,6 +1,10 @@
+use crate::audit::AuditEntry;
+use crate::compound::{CompoundData, CompoundLayout};
+use crate::error_handler::ErrorCode;
+use crate::relay_queue::QueuedRelay;
 use handlebars::{
-    Context, Handlebars, Helper, Output, RenderContext, RenderError as HandlebarsRenderError,
-    TemplateError as HandlebarsTemplateError,
+    html_escape, Context, Handlebars, Helper, Output, RenderContext,
+    RenderError as HandlebarsRenderError, TemplateError as HandlebarsTemplateError,
 };
 use itertools::free::join;
 use redact_crypto::{BinaryType, Data};
@@ -12,12 +16,16 @@ use strum::IntoEnumIterator;
 use thiserror::Error;
 use warp::{reject::Reject, Reply};
 
+/// Handlebars' errors are boxed, as they would otherwise make every result carrying a
+/// render error large
 #[derive(Error, Debug)]
 pub enum RenderError {
     #[error("Failure happened during render")]
-    RenderError { source: HandlebarsRenderError },
+    RenderError { source: Box<HandlebarsRenderError> },
     #[error("Failed to load template file")]
-    TemplateError { source: HandlebarsTemplateError },
+    TemplateError {
+        source: Box<HandlebarsTemplateError>,
+    },
 }
 
 impl Reject for RenderError {}
@@ -26,6 +34,10 @@ impl Reject for RenderError {}
 pub enum TemplateValues {
     Unsecure(UnsecureTemplateValues),
     Secure(SecureTemplateValues),
+    Consent(ConsentTemplateValues),
+    Audit(AuditTemplateValues),
+    Relays(RelaysTemplateValues),
+    Error(ErrorTemplateValues),
 }
 
 #[derive(Serialize, Debug, Default, PartialEq)]
@@ -34,19 +46,62 @@ pub struct UnsecureTemplateValues {
     pub css: Option<String>,
     pub edit: Option<bool>,
     pub data_type: Option<String>,
+    pub layout: Option<CompoundLayout>,
+    pub list: Option<bool>,
     pub relay_url: Option<String>,
     pub js_message: Option<String>,
     pub js_height_msg_prefix: Option<String>,
 }
 
+#[derive(Serialize, Debug, Default, PartialEq)]
+pub struct ConsentTemplateValues {
+    pub origin: String,
+    pub path: String,
+    pub prefixes: Vec<String>,
+    pub allow_always: bool,
+    pub action: String,
+    pub token: String,
+    /// Set on the prompt shown in the website's iframe, which opens the consent window
+    pub review_url: Option<String>,
+    /// Set once the decision has been recorded, which closes the consent window
+    pub decided: bool,
+}
+
+#[derive(Serialize, Debug, Default, PartialEq)]
+pub struct AuditTemplateValues {
+    pub entries: Vec<AuditEntry>,
+    pub total: u64,
+    pub intact: bool,
+    pub broken_at: Option<u64>,
+}
+
+#[derive(Serialize, Debug, Default, PartialEq)]
+pub struct RelaysTemplateValues {
+    pub relays: Vec<QueuedRelay>,
+    pub count: usize,
+    pub failed: usize,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct ErrorTemplateValues {
+    pub status: u16,
+    pub code: ErrorCode,
+    pub message: String,
+    pub css: Option<String>,
+}
+
 #[derive(Serialize, Debug, Default, PartialEq)]
 pub struct SecureTemplateValues {
     pub data: Option<Data>,
+    pub compound: Option<CompoundData>,
+    pub children: Option<Vec<String>>,
+    pub deleted: bool,
     pub path: Option<String>,
     pub token: Option<String>,
     pub css: Option<String>,
     pub edit: Option<bool>,
     pub data_type: Option<String>,
+    pub layout: Option<CompoundLayout>,
     pub relay_url: Option<String>,
     pub js_message: Option<String>,
     pub js_height_msg_prefix: Option<String>,
@@ -55,7 +110,9 @@ pub struct SecureTemplateValues {
 
 impl From<HandlebarsTemplateError> for RenderError {
     fn from(source: HandlebarsTemplateError) -> Self {
-        RenderError::TemplateError { source }
+        RenderError::TemplateError {
+            source: Box::new(source),
+        }
     }
 }
 
@@ -69,6 +126,7 @@ pub struct Rendered {
 }
 
 impl Rendered {
+    #[tracing::instrument(skip_all, fields(template = render_template.name))]
     pub fn new<E: Renderer>(
         render_engine: &E,
         render_template: RenderTemplate,
@@ -109,7 +167,7 @@ fn data_display(
                             out.write(
                                 &format!(
                                     "<video controls id=\"data-video\"><source src=\"data:{};base64, {}\"></video>",
-                                    binary.binary_type.to_string(),
+                                    binary.binary_type,
                                     binary.binary
                                 )
                             ).map_err(|e| e.into())
@@ -117,7 +175,7 @@ fn data_display(
                         _ => out.write(
                             &format!(
                                 "<img id=\"data\" src=\"data:{};base64, {}\"/>",
-                                binary.binary_type.to_string(),
+                                binary.binary_type,
                                 binary.binary
                             )
                         ).map_err(|e| e.into()),
@@ -196,6 +254,138 @@ fn data_input(
     .map_err(|e| e.into())
 }
 
+fn compound_display(
+    h: &Helper,
+    _: &Handlebars,
+    _: &Context,
+    _: &mut RenderContext,
+    out: &mut dyn Output,
+) -> Result<(), HandlebarsRenderError> {
+    let compound: CompoundData = h
+        .param(0)
+        .ok_or_else(|| {
+            HandlebarsRenderError::new("Value provided to compound_display cannot be null")
+        })
+        .and_then(|data| {
+            serde_json::value::from_value(data.value().to_owned()).map_err(|e| e.into())
+        })?;
+
+    match compound {
+        CompoundData::List(items) => {
+            out.write("<ul id=\"data\">")?;
+            for item in items {
+                out.write(&format!("<li>{}</li>", html_escape(&item.to_string())))?;
+            }
+            out.write("</ul>")
+        }
+        CompoundData::Map(items) => {
+            out.write("<dl id=\"data\">")?;
+            for (key, item) in items {
+                out.write(&format!(
+                    "<dt>{}</dt><dd>{}</dd>",
+                    html_escape(&key),
+                    html_escape(&item.to_string())
+                ))?;
+            }
+            out.write("</dl>")
+        }
+    }
+    .map_err(|e| e.into())
+}
+
+fn compound_input(
+    h: &Helper,
+    _: &Handlebars,
+    _: &Context,
+    _: &mut RenderContext,
+    out: &mut dyn Output,
+) -> Result<(), HandlebarsRenderError> {
+    let compound: CompoundData = h
+        .param(0)
+        .ok_or_else(|| {
+            HandlebarsRenderError::new("Value provided to compound_input cannot be null")
+        })
+        .and_then(|data| {
+            serde_json::value::from_value(data.value().to_owned()).map_err(|e| e.into())
+        })?;
+
+    let is_map = match &compound {
+        CompoundData::List(_) => {
+            out.write("<input type=\"hidden\" name=\"value_type\" value=\"list\">")?;
+            false
+        }
+        CompoundData::Map(_) => {
+            out.write("<input type=\"hidden\" name=\"value_type\" value=\"map\">")?;
+            true
+        }
+    };
+
+    // Render one row per existing element followed by an empty row for adding a new one
+    let mut rows: Vec<(String, Data)> = compound.children();
+    rows.push(("".to_owned(), Data::String("".to_owned())));
+    for (i, (key, item)) in rows.into_iter().enumerate() {
+        out.write("<div class=\"compound-item\">")?;
+        if is_map {
+            out.write(&format!(
+                "<input type=\"text\" class=\"key\" name=\"key.{}\" value=\"{}\">",
+                i,
+                html_escape(&key)
+            ))?;
+        }
+        let (value_type, input) = match item {
+            Data::Bool(b) => (
+                "bool",
+                format!(
+                    "<input type=\"checkbox\" class=\"checkbox\" name=\"value.{}\" value=\"true\"{}>",
+                    i,
+                    if b { " checked" } else { "" }
+                ),
+            ),
+            Data::U64(n) => (
+                "u64",
+                format!(
+                    "<input type=\"number\" class=\"number\" name=\"value.{}\" min=\"0\" value=\"{}\">",
+                    i, n
+                ),
+            ),
+            Data::I64(n) => (
+                "i64",
+                format!(
+                    "<input type=\"number\" class=\"number\" name=\"value.{}\" value=\"{}\">",
+                    i, n
+                ),
+            ),
+            Data::F64(n) => (
+                "f64",
+                format!(
+                    "<input type=\"number\" class=\"number\" name=\"value.{}\" step=\"any\" value=\"{}\">",
+                    i, n
+                ),
+            ),
+            Data::String(s) => (
+                "string",
+                format!(
+                    "<input type=\"text\" class=\"text\" name=\"value.{}\" value=\"{}\">",
+                    i,
+                    html_escape(&s)
+                ),
+            ),
+            Data::Binary(_) => {
+                return Err(HandlebarsRenderError::new(
+                    "Binary data cannot be part of a compound value",
+                ))
+            }
+        };
+        out.write(&format!(
+            "<input type=\"hidden\" name=\"value_type.{}\" value=\"{}\">",
+            i, value_type
+        ))?;
+        out.write(&input)?;
+        out.write("</div>")?;
+    }
+    Ok(())
+}
+
 pub trait Renderer {
     fn render(&self, template: RenderTemplate) -> Result<String, RenderError>;
 }
@@ -224,15 +414,24 @@ impl<'reg> HandlebarsRenderer<'reg> {
         }
         hbs.register_helper("data_input", Box::new(data_input));
         hbs.register_helper("data_display", Box::new(data_display));
+        hbs.register_helper("compound_input", Box::new(compound_input));
+        hbs.register_helper("compound_display", Box::new(compound_display));
         Ok(HandlebarsRenderer { hbs })
     }
+
+    /// Whether a template has been loaded under the given name
+    pub fn has_template(&self, name: &str) -> bool {
+        self.hbs.get_template(name).is_some()
+    }
 }
 
 impl<'reg> Renderer for HandlebarsRenderer<'reg> {
     fn render(&self, template: RenderTemplate) -> Result<String, RenderError> {
         self.hbs
             .render(template.name, &template.value)
-            .map_err(|source| RenderError::RenderError { source })
+            .map_err(|source| RenderError::RenderError {
+                source: Box::new(source),
+            })
     }
 }
 
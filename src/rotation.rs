@@ -0,0 +1,237 @@
+use crate::{
+    bootstrap::pki::{
+        cert_not_after, write_root_cert, write_server_identity, write_tls_cert, write_tls_identity,
+        Pki,
+    },
+    error::ClientError,
+    relayer::MutualTLSRelayer,
+};
+use chrono::{prelude::*, Duration};
+use redact_crypto::storage::redact::ClientTlsConfig;
+
+/// Periodically checks the root signing, TLS and server certificates and re-issues them
+/// once they come within `window` days of expiry, swapping the new client identity
+/// into the relayers and the storer TLS config without a restart. The HTTPS listener only
+/// reads the server identity when it starts, so a re-issued server certificate is served
+/// once the client is restarted.
+pub struct CertificateRotator {
+    pub pki: Pki,
+    pub storer_pkcs12_path: String,
+    pub storer_server_ca_path: Option<String>,
+    pub relayers: Vec<MutualTLSRelayer>,
+    pub window: Duration,
+}
+
+impl CertificateRotator {
+    pub fn spawn(self, check_interval: std::time::Duration) -> tokio::task::JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(check_interval);
+            loop {
+                interval.tick().await;
+                match self.rotate_if_expiring().await {
+                    Ok(Some(storer_tls_config)) => storer_tls_config.make_current(),
+                    Ok(None) => {}
+                    Err(e) => tracing::error!(error = %e, "certificate rotation failed"),
+                }
+            }
+        })
+    }
+
+    /// Re-issues the certificates if any of them is expiring, returning the storer TLS
+    /// config to make current, or `None` if nothing needed re-issuing
+    async fn rotate_if_expiring(&self) -> Result<Option<ClientTlsConfig>, ClientError> {
+        let rotate_before = Utc::now() + self.window;
+        let root_expiring = cert_not_after(&self.pki.root_cert_config.filepath)? <= rotate_before;
+        let server_expiring = match &self.pki.server_tls {
+            Some(server_tls) => cert_not_after(&server_tls.cert_config.filepath)? <= rotate_before,
+            None => false,
+        };
+        let tls_expiring = root_expiring
+            || server_expiring
+            || cert_not_after(&self.pki.tls_cert_config.filepath)? <= rotate_before;
+        if !tls_expiring {
+            return Ok(None);
+        }
+
+        let root_signing_key = self
+            .pki
+            .root_signing_key_entry
+            .resolve()
+            .await
+            .map_err(|source| ClientError::CryptoError { source })?;
+        let tls_key = self
+            .pki
+            .tls_key_entry
+            .resolve()
+            .await
+            .map_err(|source| ClientError::CryptoError { source })?;
+
+        if root_expiring {
+            tracing::info!("root signing certificate is expiring, re-issuing");
+            write_root_cert(root_signing_key, &self.pki.root_cert_config)?;
+        }
+        tracing::info!("TLS certificate is expiring, re-issuing");
+        write_tls_cert(
+            root_signing_key,
+            tls_key,
+            &self.pki.root_cert_config,
+            &self.pki.tls_cert_config,
+        )?;
+        write_tls_identity(
+            tls_key,
+            &self.pki.tls_cert_config.filepath,
+            &self.pki.identity_filepath,
+        )?;
+        if let Some(server_tls) = &self.pki.server_tls {
+            // The listener only reads its identity when it starts
+            tracing::warn!("re-issuing server certificate, restart the client for it to be served");
+            write_server_identity(root_signing_key, &self.pki.root_cert_config, server_tls)?;
+        }
+
+        // Swap the new identity into every outbound mTLS client
+        for relayer in &self.relayers {
+            relayer.reload().map_err(|e| ClientError::InternalError {
+                source: Box::new(e),
+            })?;
+        }
+        Ok(Some(ClientTlsConfig {
+            pkcs12_path: self.storer_pkcs12_path.clone(),
+            server_ca_path: self.storer_server_ca_path.clone(),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CertificateRotator;
+    use crate::{
+        bootstrap::pki::{
+            cert_not_after, setup_root_cert, setup_server_identity, setup_tls_cert,
+            setup_tls_identity, tests::cert_config, Pki, ServerTls,
+        },
+        test_utils::temp_dir,
+    };
+    use chrono::{Duration, Utc};
+    use redact_crypto::{key::sodiumoxide::SodiumOxideEd25519SecretAsymmetricKey, ToEntry};
+    use std::path::Path;
+
+    /// A rotator with a rotation window of two days over newly issued root signing, TLS
+    /// and server certificates expiring in the given number of days
+    fn rotator(dir: &Path, root_expires_in: i64, tls_expires_in: i64) -> CertificateRotator {
+        let root_signing_key = SodiumOxideEd25519SecretAsymmetricKey::new();
+        let tls_key = SodiumOxideEd25519SecretAsymmetricKey::new();
+        let root_cert_config = cert_config(dir, "root", root_expires_in);
+        let tls_cert_config = cert_config(dir, "tls", tls_expires_in);
+        let identity_filepath = dir
+            .join("keys")
+            .join("client-tls.p12.pem")
+            .to_str()
+            .unwrap()
+            .to_owned();
+        let server_tls = ServerTls {
+            cert_config: cert_config(dir, "server", tls_expires_in),
+            identity_filepath: dir
+                .join("keys")
+                .join("server-tls.pem")
+                .to_str()
+                .unwrap()
+                .to_owned(),
+        };
+
+        setup_root_cert(&root_signing_key, &root_cert_config).unwrap();
+        setup_tls_cert(
+            &root_signing_key,
+            &tls_key,
+            &root_cert_config,
+            &tls_cert_config,
+        )
+        .unwrap();
+        setup_tls_identity(&tls_key, &tls_cert_config.filepath, &identity_filepath).unwrap();
+        setup_server_identity(&root_signing_key, &root_cert_config, &server_tls).unwrap();
+
+        CertificateRotator {
+            pki: Pki {
+                root_signing_key_entry: root_signing_key
+                    .to_unsealed_entry(".keys.signing.root.".to_owned())
+                    .unwrap(),
+                tls_key_entry: tls_key
+                    .to_unsealed_entry(".keys.signing.tls.".to_owned())
+                    .unwrap(),
+                root_cert_config,
+                tls_cert_config,
+                identity_filepath: identity_filepath.clone(),
+                server_tls: Some(server_tls),
+            },
+            storer_pkcs12_path: identity_filepath,
+            storer_server_ca_path: None,
+            relayers: vec![],
+            window: Duration::days(2),
+        }
+    }
+
+    /// The contents of the root, TLS and server certificates and the client and server
+    /// identity bundles
+    fn pki_files(rotator: &CertificateRotator) -> Vec<String> {
+        let server_tls = rotator.pki.server_tls.as_ref().unwrap();
+        vec![
+            &rotator.pki.root_cert_config.filepath,
+            &rotator.pki.tls_cert_config.filepath,
+            &rotator.pki.identity_filepath,
+            &server_tls.cert_config.filepath,
+            &server_tls.identity_filepath,
+        ]
+        .into_iter()
+        .map(|filepath| std::fs::read_to_string(filepath).unwrap())
+        .collect()
+    }
+
+    #[tokio::test]
+    async fn test_certificates_far_from_expiry_are_kept() {
+        let dir = temp_dir();
+        let rotator = rotator(dir.path(), 10, 10);
+        let files = pki_files(&rotator);
+
+        assert!(rotator.rotate_if_expiring().await.unwrap().is_none());
+        assert_eq!(pki_files(&rotator), files);
+    }
+
+    #[tokio::test]
+    async fn test_expiring_certificates_are_reissued_and_made_current() {
+        let dir = temp_dir();
+        let mut rotator = rotator(dir.path(), 10, 1);
+        let files = pki_files(&rotator);
+        // Re-issued certificates last longer, so they are told apart from the expiring ones
+        rotator.pki.tls_cert_config.expires_in = 10;
+        rotator
+            .pki
+            .server_tls
+            .as_mut()
+            .unwrap()
+            .cert_config
+            .expires_in = 10;
+
+        let storer_tls_config = rotator.rotate_if_expiring().await.unwrap().unwrap();
+
+        let rotated_files = pki_files(&rotator);
+        assert_eq!(rotated_files[0], files[0]);
+        for (rotated_file, file) in rotated_files.iter().zip(&files).skip(1) {
+            assert_ne!(rotated_file, file);
+        }
+        let server_cert_filepath = &rotator
+            .pki
+            .server_tls
+            .as_ref()
+            .unwrap()
+            .cert_config
+            .filepath;
+        for filepath in &[&rotator.pki.tls_cert_config.filepath, server_cert_filepath] {
+            assert!(cert_not_after(filepath).unwrap() > Utc::now() + Duration::days(9));
+        }
+        assert!(rotated_files[2].starts_with(&rotated_files[1]));
+        assert_eq!(storer_tls_config.pkcs12_path, rotator.storer_pkcs12_path);
+        assert_eq!(
+            storer_tls_config.server_ca_path,
+            rotator.storer_server_ca_path
+        );
+    }
+}
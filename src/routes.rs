@@ -1,54 +1,161 @@
+pub mod audit;
+pub mod cookie;
 pub mod error;
 pub(crate) mod proxy;
+pub mod readyz;
+pub mod relays;
 pub mod secure;
 pub mod unsecure;
 
 use std::sync::Arc;
 
-use crate::{relayer::Relayer, render::Renderer, token::TokenGenerator};
+use crate::{
+    audit::Auditor,
+    deleter::{Deleter, DELETED_PATH},
+    key_selector::KeySelector,
+    permissions::{PermissionRegistry, PERMISSIONS_PREFIX},
+    relay_queue::RelayQueue,
+    relayer::Relayer,
+    render::Renderer,
+    routes::cookie::SessionCookieConfig,
+    token::TokenIssuer,
+};
 
-use self::error::QueryParamValidationRejection;
+use self::error::{PermissionDeniedRejection, QueryParamValidationRejection};
 pub use error::{
-    BadRequestRejection, CryptoErrorRejection, DataNotFoundRejection,
-    IframeTokensDoNotMatchRejection, SerializationRejection, SessionTokenNotFoundRejection,
+    BadRequestRejection, CryptoErrorRejection, IframeTokensDoNotMatchRejection,
+    SerializationRejection, SessionTokenNotFoundRejection,
 };
 use percent_encoding::percent_decode_str;
-use redact_crypto::Storer;
+use redact_crypto::{IndexedStorer, Storer};
 use regex::Regex;
 use serde::de::DeserializeOwned;
 use warp::{Filter, Rejection, Reply};
+use warp_sessions::{Session, SessionStore};
+
+/// The prefix of the client's keys, which seal everything else in storage
+pub const KEYS_PREFIX: &str = ".keys.";
 
-pub fn unsecure<R: Renderer + Clone + Send + Sync + 'static, T: TokenGenerator>(
-    token_generator: T,
+/// Paths holding the client's own entries, which websites may never view, edit or delete
+const RESERVED_PREFIXES: &[&str] = &[KEYS_PREFIX, PERMISSIONS_PREFIX, DELETED_PATH];
+
+/// Rejects paths under the client's keys, the user's permission decisions or the path
+/// deleted entries reference
+pub fn reject_reserved_path(path: &str) -> Result<(), Rejection> {
+    if RESERVED_PREFIXES
+        .iter()
+        .any(|prefix| path.starts_with(prefix))
+    {
+        Err(warp::reject::custom(PermissionDeniedRejection))
+    } else {
+        Ok(())
+    }
+}
+
+/// Extracts the data path from the next path segment, refusing reserved paths so that no
+/// route serving data can reach them
+pub fn non_reserved_path() -> impl Filter<Extract = (String,), Error = Rejection> + Copy {
+    warp::path::param::<String>().and_then(|path: String| async move {
+        reject_reserved_path(&path)?;
+        Ok::<_, Rejection>(path)
+    })
+}
+
+pub fn unsecure<
+    R: Renderer + Clone + Send + Sync + 'static,
+    I: TokenIssuer,
+    P: PermissionRegistry,
+    S: SessionStore,
+>(
+    token_issuer: I,
     render_engine: R,
-) -> impl Filter<Extract = (impl Reply, String, String), Error = Rejection> + Clone {
-    warp::path!("unsecure" / ..).and(unsecure::data(token_generator, render_engine))
+    registry: P,
+    session_store: S,
+    cookie_config: SessionCookieConfig,
+) -> impl Filter<Extract = (Box<dyn Reply>, String, Option<Session>), Error = Rejection> + Clone {
+    warp::path!("unsecure" / ..).and(
+        unsecure::data(
+            token_issuer.clone(),
+            render_engine.clone(),
+            registry.clone(),
+        )
+        .or(unsecure::consent(
+            token_issuer,
+            render_engine,
+            registry,
+            session_store,
+            cookie_config,
+        ))
+        .unify(),
+    )
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn secure<
-    H: Storer,
+    H: IndexedStorer,
     R: Renderer + Clone + Send + Sync + 'static,
-    T: TokenGenerator,
-    Q: Relayer,
+    I: TokenIssuer,
+    Q: RelayQueue,
+    D: Deleter,
+    A: Auditor,
+    S: SessionStore,
 >(
     storer: Arc<H>,
     render_engine: R,
-    token_generator: T,
-    relayer: Q,
-) -> impl Filter<Extract = (Box<dyn Reply>, String, Option<String>, Option<String>), Error = Rejection>
-       + Clone {
+    token_issuer: I,
+    relay_queue: Q,
+    deleter: D,
+    key_selector: Arc<KeySelector>,
+    auditor: A,
+    session_store: S,
+    cookie_config: SessionCookieConfig,
+) -> impl Filter<
+    Extract = (Box<dyn Reply>, String, Option<String>, Option<Session>),
+    Error = Rejection,
+> + Clone {
     warp::path!("secure" / ..).and(secure::data(
         storer,
         render_engine,
-        token_generator,
-        relayer,
+        token_issuer,
+        relay_queue,
+        deleter,
+        key_selector,
+        auditor,
+        session_store,
+        cookie_config,
     ))
 }
 
-pub fn proxy<R: Relayer>(
+pub fn proxy<R: Relayer, A: Auditor>(
     relayer: R,
+    auditor: A,
+    config: proxy::ProxyConfig,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("proxy").and(proxy::post(relayer, auditor, config))
+}
+
+pub fn audit<A: Auditor, R: Renderer + Clone + Send + Sync + 'static>(
+    auditor: A,
+    render_engine: R,
+    page_size: usize,
+) -> impl Filter<Extract = (Box<dyn Reply>,), Error = Rejection> + Clone {
+    warp::path!("audit").and(audit::get(auditor, render_engine, page_size))
+}
+
+pub fn relays<Q: RelayQueue, R: Renderer + Clone + Send + Sync + 'static>(
+    relay_queue: Q,
+    render_engine: R,
+) -> impl Filter<Extract = (Box<dyn Reply>,), Error = Rejection> + Clone {
+    warp::path!("relays")
+        .and(relays::get(relay_queue.clone(), render_engine))
+        .or(warp::path!("relays" / ..).and(relays::replay(relay_queue)))
+        .unify()
+}
+
+pub fn readyz<H: Storer>(
+    checker: readyz::ReadinessChecker<H>,
 ) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
-    warp::path!("proxy").and(proxy::post(relayer))
+    warp::path!("readyz").and(readyz::get(checker))
 }
 
 pub trait Validate {
@@ -62,7 +169,7 @@ pub fn validate_base64_query_param(str: Option<String>) -> Result<(), Rejection>
             .map_err(|_| warp::reject::custom(QueryParamValidationRejection))
             .and_then(|str| {
                 let base64_regex = Regex::new(r"^[A-Za-z0-9+/]+={0,2}$").unwrap();
-                match base64_regex.is_match(&str.to_string()) {
+                match base64_regex.is_match(&str) {
                     true => Ok::<_, Rejection>(()),
                     false => Err(warp::reject::custom(QueryParamValidationRejection)),
                 }
@@ -71,6 +178,22 @@ pub fn validate_base64_query_param(str: Option<String>) -> Result<(), Rejection>
     }
 }
 
+/// Whether the value of an `Accept` header asks for a JSON response rather than HTML
+pub fn accepts_json(accept: &Option<String>) -> bool {
+    accept
+        .as_ref()
+        .map(|accept| {
+            accept.split(',').any(|media_type| {
+                media_type
+                    .split(';')
+                    .next()
+                    .map(|media_type| media_type.trim().eq_ignore_ascii_case("application/json"))
+                    .unwrap_or(false)
+            })
+        })
+        .unwrap_or(false)
+}
+
 pub fn validated_query_params<T: 'static + DeserializeOwned + Send + Validate>(
 ) -> impl Filter<Extract = (T,), Error = Rejection> + Copy {
     warp::query::<T>().and_then(move |param: T| async move {
@@ -78,3 +201,18 @@ pub fn validated_query_params<T: 'static + DeserializeOwned + Send + Validate>(
         Ok::<_, Rejection>(param)
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::accepts_json;
+
+    #[test]
+    fn test_accepts_json() {
+        assert!(accepts_json(&Some("application/json".to_owned())));
+        assert!(accepts_json(&Some(
+            "text/html, application/json;q=0.9".to_owned()
+        )));
+        assert!(!accepts_json(&Some("text/html,*/*;q=0.8".to_owned())));
+        assert!(!accepts_json(&None));
+    }
+}
@@ -0,0 +1,86 @@
+use crate::{
+    audit::Auditor,
+    render::{AuditTemplateValues, RenderTemplate, Rendered, Renderer, TemplateValues},
+    routes::accepts_json,
+};
+use warp::{Filter, Rejection, Reply};
+
+/// Lists the most recent entries of the audit log; the page may only be shown by the
+/// client itself, never framed by a website
+pub fn get<A: Auditor, R: Renderer + Clone + Send + Sync + 'static>(
+    auditor: A,
+    render_engine: R,
+    page_size: usize,
+) -> impl Filter<Extract = (Box<dyn Reply>,), Error = Rejection> + Clone {
+    warp::get()
+        .and(warp::header::optional::<String>("accept"))
+        .and(warp::any().map(move || auditor.clone()))
+        .and(warp::any().map(move || render_engine.clone()))
+        .and_then(
+            move |accept: Option<String>, auditor: A, render_engine: R| async move {
+                let trail = auditor
+                    .recent(page_size)
+                    .await
+                    .map_err(warp::reject::custom)?;
+                let reply: Box<dyn Reply> = if accepts_json(&accept) {
+                    Box::new(warp::reply::json(&trail))
+                } else {
+                    Box::new(Rendered::new(
+                        &render_engine,
+                        RenderTemplate {
+                            name: "audit",
+                            value: TemplateValues::Audit(AuditTemplateValues {
+                                intact: trail.broken_at.is_none(),
+                                entries: trail.entries,
+                                total: trail.total,
+                                broken_at: trail.broken_at,
+                            }),
+                        },
+                    )?)
+                };
+                Ok::<_, Rejection>(Box::new(warp::reply::with_header(
+                    reply,
+                    "x-frame-options",
+                    "DENY",
+                )) as Box<dyn Reply>)
+            },
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::audit::{tests::MockAuditor, AuditTrail};
+    use crate::render::tests::MockRenderer;
+    use crate::routes::audit;
+    use mockall::predicate::*;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_get_json_reports_broken_chain() {
+        let mut auditor = MockAuditor::new();
+        auditor
+            .expect_recent()
+            .with(eq(10))
+            .times(1)
+            .returning(|_| {
+                Ok(AuditTrail {
+                    entries: vec![],
+                    total: 3,
+                    broken_at: Some(1),
+                })
+            });
+
+        let filter = audit::get(Arc::new(auditor), Arc::new(MockRenderer::new()), 10);
+        let res = warp::test::request()
+            .path("/")
+            .header("accept", "application/json")
+            .reply(&filter)
+            .await;
+
+        assert_eq!(res.status(), 200);
+        assert_eq!(res.headers().get("x-frame-options").unwrap(), "DENY");
+        let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(body["broken_at"], 1);
+        assert_eq!(body["total"], 3);
+    }
+}
@@ -0,0 +1,87 @@
+use serde::Deserialize;
+use warp_sessions::{CookieOptions, SameSiteCookieOption};
+
+/// The name of the cookie carrying the session ID
+const SESSION_COOKIE_NAME: &str = "sid";
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SameSite {
+    None,
+    Lax,
+    Strict,
+}
+
+impl From<SameSite> for SameSiteCookieOption {
+    fn from(same_site: SameSite) -> Self {
+        match same_site {
+            SameSite::None => SameSiteCookieOption::None,
+            SameSite::Lax => SameSiteCookieOption::Lax,
+            SameSite::Strict => SameSiteCookieOption::Strict,
+        }
+    }
+}
+
+/// Attributes of the session cookie set by the unsecure and secure routes, as found
+/// under `sessions.cookie` in the config
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct SessionCookieConfig {
+    pub secure: bool,
+    pub same_site: SameSite,
+    pub max_age: Option<u64>,
+    pub domain: Option<String>,
+}
+
+impl Default for SessionCookieConfig {
+    fn default() -> Self {
+        SessionCookieConfig {
+            secure: false,
+            same_site: SameSite::None,
+            max_age: Some(60),
+            domain: None,
+        }
+    }
+}
+
+impl SessionCookieConfig {
+    /// The options for a session cookie scoped to `path`
+    pub fn cookie_options(&self, path: Option<String>) -> CookieOptions {
+        CookieOptions {
+            cookie_name: SESSION_COOKIE_NAME,
+            cookie_value: None,
+            max_age: self.max_age,
+            domain: self.domain.clone(),
+            path,
+            secure: self.secure,
+            http_only: true,
+            same_site: Some(self.same_site.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SameSite, SessionCookieConfig};
+
+    #[test]
+    fn test_cookie_options_carry_config() {
+        let config = SessionCookieConfig {
+            secure: true,
+            same_site: SameSite::Strict,
+            max_age: Some(30),
+            domain: Some("localhost".to_owned()),
+        };
+        let options = config.cookie_options(Some("/secure/data/.profile.name./abc".to_owned()));
+
+        assert_eq!(options.cookie_name, "sid");
+        assert!(options.secure);
+        assert!(options.http_only);
+        assert_eq!(options.max_age, Some(30));
+        assert_eq!(options.domain.as_deref(), Some("localhost"));
+        assert_eq!(
+            options.path.as_deref(),
+            Some("/secure/data/.profile.name./abc")
+        );
+    }
+}
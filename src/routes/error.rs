@@ -1,3 +1,4 @@
+use crate::token::TokenVerificationError;
 use redact_crypto::CryptoError;
 use serde_json::Error as JsonSerializationError;
 use warp::reject::Reject;
@@ -11,12 +12,12 @@ pub struct IframeTokensDoNotMatchRejection;
 impl Reject for IframeTokensDoNotMatchRejection {}
 
 #[derive(Debug)]
-pub struct SessionTokenNotFoundRejection;
-impl Reject for SessionTokenNotFoundRejection {}
+pub struct InvalidTokenRejection(pub TokenVerificationError);
+impl Reject for InvalidTokenRejection {}
 
 #[derive(Debug)]
-pub struct DataNotFoundRejection;
-impl Reject for DataNotFoundRejection {}
+pub struct SessionTokenNotFoundRejection;
+impl Reject for SessionTokenNotFoundRejection {}
 
 #[derive(Debug)]
 pub struct BadRequestRejection;
@@ -35,9 +36,21 @@ pub struct RelayRejection;
 impl Reject for RelayRejection {}
 
 #[derive(Debug)]
-pub struct ProxyRejection(pub reqwest::Error);
-impl Reject for ProxyRejection {}
+pub struct ProxyResponseTooLargeRejection;
+impl Reject for ProxyResponseTooLargeRejection {}
+
+#[derive(Debug)]
+pub struct CorsForbiddenRejection;
+impl Reject for CorsForbiddenRejection {}
+
+#[derive(Debug)]
+pub struct PermissionDeniedRejection;
+impl Reject for PermissionDeniedRejection {}
 
 #[derive(Debug)]
 pub struct QueryParamValidationRejection;
 impl Reject for QueryParamValidationRejection {}
+
+#[derive(Debug)]
+pub struct CrossSiteRequestRejection;
+impl Reject for CrossSiteRequestRejection {}
@@ -1,59 +1,264 @@
+pub mod origin;
+
+use crate::audit::{audit, AuditEvent, Auditor, Operation};
 use crate::error::ClientError;
-use crate::relayer::Relayer;
-use crate::routes::error::{ProxyRejection, RelayRejection};
+use crate::metrics;
+use crate::relayer::{RelayRequest, Relayer};
+use crate::routes::error::{ProxyResponseTooLargeRejection, RelayRejection};
+use crate::routes::{BadRequestRejection, SerializationRejection};
 use addr::parser::DomainName;
 use addr::psl::List;
+use bytes::Bytes;
+use origin::OriginPolicy;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use thiserror::Error;
 use url::Url;
-use warp::http::HeaderValue;
+use warp::http::header::{self, HeaderMap, HeaderName, HeaderValue};
+use warp::http::Method;
+use warp::hyper::Body;
 use warp::{http::Response, Filter, Rejection, Reply};
 
+/// Response headers passed through when `proxy.response_headers` isn't configured;
+/// `set-cookie` is left out, as cookies passed through would be stored for the client's
+/// own origin rather than the destination's
+const DEFAULT_RESPONSE_HEADERS: &[&str] = &[
+    "cache-control",
+    "content-disposition",
+    "content-encoding",
+    "content-language",
+    "content-length",
+    "content-security-policy",
+    "content-type",
+    "etag",
+    "expires",
+    "last-modified",
+    "vary",
+];
+
+/// Headers which only apply to a single connection, and are never passed through
+const HOP_BY_HOP_HEADERS: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// Request headers a website may not set, since the client sets them itself
+const CLIENT_REQUEST_HEADERS: &[&str] = &["host", "content-length"];
+
+/// The methods a website may make requests with
+const PROXIED_METHODS: &[&str] = &["GET", "HEAD", "POST", "PUT", "PATCH", "DELETE"];
+
+/// Which destinations websites may proxy requests to, which of the destination's response
+/// headers are passed back to the website, and the largest response body passed back
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct ProxyConfig {
+    pub origin: OriginPolicy,
+    pub response_headers: Vec<String>,
+    pub max_response_bytes: u64,
+}
+
+impl Default for ProxyConfig {
+    fn default() -> Self {
+        ProxyConfig {
+            origin: OriginPolicy::default(),
+            response_headers: DEFAULT_RESPONSE_HEADERS
+                .iter()
+                .map(|name| name.to_string())
+                .collect(),
+            max_response_bytes: 16 * 1024 * 1024,
+        }
+    }
+}
+
+impl ProxyConfig {
+    fn passes_header(&self, name: &HeaderName) -> bool {
+        !HOP_BY_HOP_HEADERS.contains(&name.as_str())
+            && self
+                .response_headers
+                .iter()
+                .any(|allowed| allowed.eq_ignore_ascii_case(name.as_str()))
+    }
+}
+
+#[derive(Error, Debug)]
+enum ProxyBodyError {
+    #[error("Proxied response body exceeded {max} bytes")]
+    TooLarge { max: u64 },
+    #[error("Failed to read the proxied response body")]
+    ReadError { source: reqwest::Error },
+}
+
+/// The request to make on behalf of the website; `body` is sent as JSON
 #[derive(Deserialize, Serialize, Debug, Clone)]
 struct ProxyBodyParams {
     host_url: String,
+    #[serde(default)]
+    method: Option<String>,
+    #[serde(default)]
+    headers: HashMap<String, String>,
+    #[serde(default)]
+    body: Option<serde_json::Value>,
+}
+
+impl ProxyBodyParams {
+    /// The request to relay, if its method and headers may be proxied
+    fn relay_request(&self) -> Result<RelayRequest, Rejection> {
+        let method = self.method.as_deref().unwrap_or("GET").to_ascii_uppercase();
+        if !PROXIED_METHODS.contains(&method.as_str()) {
+            return Err(warp::reject::custom(BadRequestRejection));
+        }
+        let method = Method::from_bytes(method.as_bytes())
+            .map_err(|_| warp::reject::custom(BadRequestRejection))?;
+
+        let mut headers = HeaderMap::new();
+        for (name, value) in &self.headers {
+            let name = HeaderName::from_bytes(name.as_bytes())
+                .map_err(|_| warp::reject::custom(BadRequestRejection))?;
+            if HOP_BY_HOP_HEADERS.contains(&name.as_str())
+                || CLIENT_REQUEST_HEADERS.contains(&name.as_str())
+            {
+                return Err(warp::reject::custom(BadRequestRejection));
+            }
+            let value = HeaderValue::from_str(value)
+                .map_err(|_| warp::reject::custom(BadRequestRejection))?;
+            headers.insert(name, value);
+        }
+
+        let body = match &self.body {
+            Some(body) => {
+                if !headers.contains_key(header::CONTENT_TYPE) {
+                    headers.insert(
+                        header::CONTENT_TYPE,
+                        HeaderValue::from_static("application/json"),
+                    );
+                }
+                Some(Bytes::from(serde_json::to_vec(body).map_err(|e| {
+                    warp::reject::custom(SerializationRejection(e))
+                })?))
+            }
+            None => None,
+        };
+
+        Ok(RelayRequest {
+            method,
+            url: self.host_url.clone(),
+            headers,
+            body,
+            address_filter: None,
+        })
+    }
 }
 
-pub fn post<Q: Relayer>(
+pub fn post<Q: Relayer, A: Auditor>(
     relayer: Q,
+    auditor: A,
+    config: ProxyConfig,
 ) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let max_response_bytes = config.max_response_bytes;
+    let origin_policy = config.origin.clone();
     warp::post()
         .and(warp::filters::body::json::<ProxyBodyParams>())
         .and(warp::header::<String>("Origin"))
         .and(warp::any().map(move || relayer.clone()))
+        .and(warp::any().map(move || auditor.clone()))
+        .and(warp::any().map(move || origin_policy.clone()))
         .and_then(
-            move |body_params: ProxyBodyParams, origin_header: String, relayer: Q| async move {
-                let origin_root = parse_url_root(&origin_header)
-                    .map_err(|_| warp::reject::custom(RelayRejection))?;
-                let dest_root = parse_url_root(&body_params.host_url)
-                    .map_err(|_| warp::reject::custom(RelayRejection))?;
-
-                if dest_root != origin_root {
-                    Err(warp::reject::custom(RelayRejection))
-                } else {
-                    relayer
-                        .get(body_params.host_url)
-                        .await
-                        .map_err(|_| warp::reject::custom(RelayRejection))
+            move |body_params: ProxyBodyParams,
+                  origin_header: String,
+                  relayer: Q,
+                  auditor: A,
+                  origin_policy: OriginPolicy| async move {
+                let result = async {
+                    if let Err(e) = origin_policy.check(&origin_header, &body_params.host_url) {
+                        tracing::warn!(error = %e, "proxy destination refused");
+                        metrics::PROXY_REQUESTS
+                            .with_label_values(&["origin_mismatch"])
+                            .inc();
+                        return Err(warp::reject::custom(RelayRejection));
+                    }
+
+                    // The addresses the destination resolves to are checked, and connected
+                    // to, by the relayer
+                    let mut request = body_params.relay_request()?;
+                    request.address_filter = Some(origin_policy.address_filter());
+                    let response = relayer.send(request).await.map_err(|e| {
+                        metrics::PROXY_REQUESTS
+                            .with_label_values(&["failure"])
+                            .inc();
+                        warp::reject::custom(e)
+                    })?;
+                    // Bodies without a declared length are cut off once they exceed the
+                    // limit while streaming
+                    if response.content_length().unwrap_or(0) > max_response_bytes {
+                        metrics::PROXY_REQUESTS
+                            .with_label_values(&["too_large"])
+                            .inc();
+                        return Err(warp::reject::custom(ProxyResponseTooLargeRejection));
+                    }
+                    metrics::PROXY_REQUESTS
+                        .with_label_values(&["success"])
+                        .inc();
+                    Ok(response)
                 }
+                .await;
+
+                audit(
+                    &auditor,
+                    AuditEvent::new(
+                        &origin_header,
+                        &body_params.host_url,
+                        Operation::Proxy,
+                        result.is_ok(),
+                    ),
+                )
+                .await;
+                result
             },
         )
-        .and_then(move |response: reqwest::Response| async move {
-            Ok::<_, Rejection>(
-                Response::builder()
-                    .status(response.status())
-                    .header(
-                        "Content-Type",
-                        response
-                            .headers()
-                            .get("Content-Type")
-                            .unwrap_or(&HeaderValue::from_static("")),
-                    )
-                    .body(response.text().await.map_err(ProxyRejection)?),
-            )
+        .map(move |response: reqwest::Response| {
+            let mut builder = Response::builder().status(response.status());
+            for (name, value) in response.headers() {
+                if config.passes_header(name) {
+                    builder = builder.header(name, value);
+                }
+            }
+            builder.body(streamed_body(response, max_response_bytes))
         })
 }
 
-fn parse_url_root(url: &str) -> Result<Option<String>, ClientError> {
+/// Passes the response body through as it arrives, ending the stream with an error once
+/// more than `max_bytes` have been read
+fn streamed_body(response: reqwest::Response, max_bytes: u64) -> Body {
+    Body::wrap_stream(futures::stream::try_unfold(
+        (response, 0u64),
+        move |(mut response, read)| async move {
+            match response
+                .chunk()
+                .await
+                .map_err(|source| ProxyBodyError::ReadError { source })?
+            {
+                Some(chunk) => {
+                    let read = read + chunk.len() as u64;
+                    if read > max_bytes {
+                        tracing::warn!(max_bytes, "proxied response body is too large");
+                        return Err(ProxyBodyError::TooLarge { max: max_bytes });
+                    }
+                    Ok(Some((chunk, (response, read))))
+                }
+                None => Ok::<Option<(Bytes, _)>, ProxyBodyError>(None),
+            }
+        },
+    ))
+}
+
+pub(crate) fn parse_url_root(url: &str) -> Result<Option<String>, ClientError> {
     let origin_domain = Url::parse(url)
         .map_err(|e| ClientError::InternalError {
             source: Box::new(e),
@@ -76,11 +281,32 @@ fn parse_url_root(url: &str) -> Result<Option<String>, ClientError> {
 
 #[cfg(test)]
 mod tests {
-    use crate::relayer::{tests::MockRelayer, RelayError::RelayRequestError};
-    use crate::routes::proxy;
+    use crate::audit::{tests::expect_audits, Operation, Outcome};
+    use crate::error_handler;
+    use crate::relayer::{
+        tests::MockRelayer,
+        RelayError::{IdentityLoadError, RelayRequestError},
+        RelayRequest,
+    };
+    use crate::render::tests::MockRenderer;
+    use crate::routes::proxy::{self, origin::OriginPolicy, ProxyBodyError, ProxyConfig};
+    use bytes::Bytes;
+    use futures::StreamExt;
     use mockall::predicate::*;
     use std::sync::Arc;
-    use warp::http::HeaderValue;
+    use warp::http::{HeaderMap, HeaderValue, Method};
+    use warp::hyper::Body;
+    use warp::{Filter, Reply};
+
+    fn proxied_get(url: &str) -> RelayRequest {
+        RelayRequest {
+            method: Method::GET,
+            url: url.to_owned(),
+            headers: HeaderMap::new(),
+            body: None,
+            address_filter: Some(OriginPolicy::default().address_filter()),
+        }
+    }
 
     #[tokio::test]
     async fn test_post() {
@@ -94,12 +320,16 @@ mod tests {
 
         let mut relayer = MockRelayer::new();
         relayer
-            .expect_get()
+            .expect_send()
             .times(1)
-            .with(eq(host_url.to_owned()))
+            .with(eq(proxied_get(host_url)))
             .return_once(move |_| Ok(reqwest::Response::from(expected_response)));
 
-        let proxy = proxy::post(Arc::new(relayer));
+        let proxy = proxy::post(
+            Arc::new(relayer),
+            Arc::new(expect_audits(Operation::Proxy, vec![Outcome::Success])),
+            ProxyConfig::default(),
+        );
 
         let res = warp::test::request()
             .method("POST")
@@ -118,18 +348,263 @@ mod tests {
         );
     }
 
+    #[tokio::test]
+    async fn test_post_passes_through_status_allowed_headers_and_binary_body() {
+        let host_url = "http://host.com/image.png";
+        let body: Vec<u8> = vec![0x89, 0x50, 0x4e, 0x47, 0x00, 0xff, 0xfe];
+        let expected_response = http::response::Builder::new()
+            .header("Content-Type", "image/png")
+            .header("Cache-Control", "max-age=60")
+            .header("X-Internal", "secret")
+            .status(404)
+            .body(body.clone())
+            .unwrap();
+
+        let mut relayer = MockRelayer::new();
+        relayer
+            .expect_send()
+            .times(1)
+            .return_once(move |_| Ok(reqwest::Response::from(expected_response)));
+
+        let proxy = proxy::post(
+            Arc::new(relayer),
+            Arc::new(expect_audits(Operation::Proxy, vec![Outcome::Success])),
+            ProxyConfig::default(),
+        );
+
+        let res = warp::test::request()
+            .method("POST")
+            .path("/proxy")
+            .body(format!("{{\"host_url\":\"{}\"}}", host_url))
+            .header("Content-Type", "application/json")
+            .header("Origin", "http://host.com")
+            .reply(&proxy)
+            .await;
+
+        assert_eq!(res.status(), 404);
+        assert_eq!(res.body().as_ref(), body.as_slice());
+        assert_eq!(res.headers().get("Cache-Control").unwrap(), "max-age=60");
+        assert!(res.headers().get("X-Internal").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_post_drops_set_cookie_by_default() {
+        let host_url = "http://host.com/login";
+        let expected_response = http::response::Builder::new()
+            .header("Content-Type", "text/plain")
+            .header("Set-Cookie", "session=abc; Path=/")
+            .status(200)
+            .body("ok".to_owned())
+            .unwrap();
+
+        let mut relayer = MockRelayer::new();
+        relayer
+            .expect_send()
+            .times(1)
+            .return_once(move |_| Ok(reqwest::Response::from(expected_response)));
+
+        let proxy = proxy::post(
+            Arc::new(relayer),
+            Arc::new(expect_audits(Operation::Proxy, vec![Outcome::Success])),
+            ProxyConfig::default(),
+        );
+
+        let res = warp::test::request()
+            .method("POST")
+            .path("/proxy")
+            .body(format!("{{\"host_url\":\"{}\"}}", host_url))
+            .header("Content-Type", "application/json")
+            .header("Origin", "http://host.com")
+            .reply(&proxy)
+            .await;
+
+        assert_eq!(res.status(), 200);
+        assert_eq!(res.headers().get("Content-Type").unwrap(), "text/plain");
+        assert!(res.headers().get("Set-Cookie").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_post_rejects_response_larger_than_max() {
+        let host_url = "http://host.com/large";
+        let expected_response = http::response::Builder::new()
+            .status(200)
+            .body(vec![0u8; 11])
+            .unwrap();
+
+        let mut relayer = MockRelayer::new();
+        relayer
+            .expect_send()
+            .times(1)
+            .return_once(move |_| Ok(reqwest::Response::from(expected_response)));
+
+        let proxy = error_handler::recover(
+            proxy::post(
+                Arc::new(relayer),
+                Arc::new(expect_audits(Operation::Proxy, vec![Outcome::Failure])),
+                ProxyConfig {
+                    max_response_bytes: 10,
+                    ..ProxyConfig::default()
+                },
+            ),
+            Arc::new(MockRenderer::new()),
+        );
+
+        let res = warp::test::request()
+            .method("POST")
+            .path("/proxy")
+            .body(format!("{{\"host_url\":\"{}\"}}", host_url))
+            .header("Content-Type", "application/json")
+            .header("Origin", "http://host.com")
+            .reply(&proxy)
+            .await;
+
+        assert_eq!(res.status(), 502);
+        let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(body["error"], "proxy_response_too_large");
+    }
+
+    #[tokio::test]
+    async fn test_post_cuts_off_streamed_response_larger_than_max() {
+        // Served in chunks, so the response doesn't declare its length up front
+        let upstream = warp::any().map(|| {
+            let chunks = (0..3).map(|_| Ok::<_, std::io::Error>(Bytes::from(vec![0u8; 6])));
+            warp::http::Response::new(Body::wrap_stream(futures::stream::iter(chunks)))
+        });
+        let (addr, server) = warp::serve(upstream).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        let upstream_response = reqwest::get(format!("http://{}/", addr)).await.unwrap();
+        assert_eq!(upstream_response.content_length(), None);
+
+        let mut relayer = MockRelayer::new();
+        relayer
+            .expect_send()
+            .times(1)
+            .return_once(move |_| Ok(upstream_response));
+
+        let proxy = proxy::post(
+            Arc::new(relayer),
+            Arc::new(expect_audits(Operation::Proxy, vec![Outcome::Success])),
+            ProxyConfig {
+                max_response_bytes: 10,
+                ..ProxyConfig::default()
+            },
+        );
+
+        let res = warp::test::request()
+            .method("POST")
+            .path("/proxy")
+            .body("{\"host_url\":\"http://host.com/large\"}")
+            .header("Content-Type", "application/json")
+            .header("Origin", "http://host.com")
+            .filter(&proxy)
+            .await
+            .unwrap()
+            .into_response();
+        assert_eq!(res.status(), 200);
+
+        let mut body = res.into_body();
+        let mut received = 0;
+        let error = loop {
+            match body.next().await {
+                Some(Ok(chunk)) => received += chunk.len(),
+                Some(Err(error)) => break error,
+                None => panic!("response body was streamed in full"),
+            }
+        };
+        assert_eq!(received, 6);
+        let cause = error.into_cause().unwrap();
+        assert!(matches!(
+            cause.downcast_ref::<ProxyBodyError>(),
+            Some(ProxyBodyError::TooLarge { max: 10 })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_post_sends_method_headers_and_json_body() {
+        let host_url = "https://api.host.com/orders";
+
+        let mut relayer = MockRelayer::new();
+        relayer
+            .expect_send()
+            .times(1)
+            .withf(move |request| {
+                request.method == http::Method::PUT
+                    && request.url == host_url
+                    && request.headers.get("x-request-id").unwrap() == "42"
+                    && request.headers.get("content-type").unwrap() == "application/json"
+                    && request.body.as_deref() == Some(&b"{\"quantity\":2}"[..])
+            })
+            .return_once(|_| {
+                Ok(reqwest::Response::from(
+                    http::response::Builder::new().status(201).body("").unwrap(),
+                ))
+            });
+
+        let proxy = proxy::post(
+            Arc::new(relayer),
+            Arc::new(expect_audits(Operation::Proxy, vec![Outcome::Success])),
+            ProxyConfig::default(),
+        );
+
+        let res = warp::test::request()
+            .method("POST")
+            .path("/proxy")
+            .body(format!(
+                "{{\"host_url\":\"{}\",\"method\":\"put\",\"headers\":{{\"X-Request-Id\":\"42\"}},\"body\":{{\"quantity\":2}}}}",
+                host_url
+            ))
+            .header("Content-Type", "application/json")
+            .header("Origin", "https://www.host.com")
+            .reply(&proxy)
+            .await;
+
+        assert_eq!(res.status(), 201);
+    }
+
+    #[tokio::test]
+    async fn test_post_rejects_unproxied_method_and_client_headers() {
+        for body in &[
+            r#"{"host_url":"http://host.com/","method":"TRACE"}"#,
+            r#"{"host_url":"http://host.com/","headers":{"Host":"other.com"}}"#,
+            r#"{"host_url":"http://host.com/","headers":{"Transfer-Encoding":"chunked"}}"#,
+        ] {
+            let mut relayer = MockRelayer::new();
+            relayer.expect_send().times(0);
+
+            let proxy = proxy::post(
+                Arc::new(relayer),
+                Arc::new(expect_audits(Operation::Proxy, vec![Outcome::Failure])),
+                ProxyConfig::default(),
+            );
+
+            let res = warp::test::request()
+                .method("POST")
+                .path("/proxy")
+                .body(*body)
+                .header("Content-Type", "application/json")
+                .header("Origin", "http://host.com")
+                .filter(&proxy)
+                .await;
+            assert!(res.is_err());
+        }
+    }
+
     #[tokio::test]
     async fn test_post_relay_request_error() {
         let host_url = "http://abr.host.co.uk/proxy/session/whatever";
 
         let mut relayer = MockRelayer::new();
         relayer
-            .expect_get()
+            .expect_send()
             .times(1)
-            .with(eq(host_url.to_owned()))
+            .with(eq(proxied_get(host_url)))
             .return_once(move |_| Err(RelayRequestError { source: None }));
 
-        let proxy = proxy::post(Arc::new(relayer));
+        let proxy = proxy::post(
+            Arc::new(relayer),
+            Arc::new(expect_audits(Operation::Proxy, vec![Outcome::Failure])),
+            ProxyConfig::default(),
+        );
 
         let res = warp::test::request()
             .method("POST")
@@ -143,14 +618,52 @@ mod tests {
         assert_eq!(res.status(), 500);
     }
 
+    #[tokio::test]
+    async fn test_post_missing_identity_is_internal_error() {
+        let host_url = "http://abr.host.co.uk/proxy/session/whatever";
+
+        let mut relayer = MockRelayer::new();
+        relayer.expect_send().times(1).return_once(move |_| {
+            Err(IdentityLoadError {
+                source: std::io::Error::from(std::io::ErrorKind::NotFound),
+            })
+        });
+
+        let proxy = error_handler::recover(
+            proxy::post(
+                Arc::new(relayer),
+                Arc::new(expect_audits(Operation::Proxy, vec![Outcome::Failure])),
+                ProxyConfig::default(),
+            ),
+            Arc::new(MockRenderer::new()),
+        );
+
+        let res = warp::test::request()
+            .method("POST")
+            .path("/proxy")
+            .body(format!("{{\"host_url\":\"{}\"}}", host_url))
+            .header("Content-Type", "application/json")
+            .header("Origin", "http://host.co.uk")
+            .reply(&proxy)
+            .await;
+
+        assert_eq!(res.status(), 500);
+        let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(body["error"], "relay_error");
+    }
+
     #[tokio::test]
     async fn test_post_relay_request_different_origin() {
         let host_url = "http://host.com/proxy/session/whatever";
 
         let mut relayer = MockRelayer::new();
-        relayer.expect_get().times(0);
+        relayer.expect_send().times(0);
 
-        let proxy = proxy::post(Arc::new(relayer));
+        let proxy = proxy::post(
+            Arc::new(relayer),
+            Arc::new(expect_audits(Operation::Proxy, vec![Outcome::Failure])),
+            ProxyConfig::default(),
+        );
 
         let res = warp::test::request()
             .method("POST")
@@ -164,6 +677,29 @@ mod tests {
         assert_eq!(res.status(), 500);
     }
 
+    #[tokio::test]
+    async fn test_post_ip_origin_to_other_ip_is_refused() {
+        let mut relayer = MockRelayer::new();
+        relayer.expect_send().times(0);
+
+        let proxy = proxy::post(
+            Arc::new(relayer),
+            Arc::new(expect_audits(Operation::Proxy, vec![Outcome::Failure])),
+            ProxyConfig::default(),
+        );
+
+        let res = warp::test::request()
+            .method("POST")
+            .path("/proxy")
+            .body(r#"{"host_url":"http://10.0.0.5/admin"}"#)
+            .header("Content-Type", "application/json")
+            .header("Origin", "http://127.0.0.1")
+            .reply(&proxy)
+            .await;
+
+        assert_eq!(res.status(), 500);
+    }
+
     #[tokio::test]
     async fn test_parse_url_root() {
         let host_url = "http://www.abc.123.host.co.uk";
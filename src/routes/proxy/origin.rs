@@ -0,0 +1,201 @@
+use super::parse_url_root;
+use crate::relayer::policy::AddressFilter;
+use serde::Deserialize;
+use std::net::IpAddr;
+use thiserror::Error;
+use url::{Host, Url};
+
+#[derive(Error, Debug, PartialEq)]
+pub enum OriginError {
+    #[error("Origin or destination is not a valid URL")]
+    InvalidUrl,
+    #[error("Destination scheme {scheme} can't be proxied")]
+    UnsupportedScheme { scheme: String },
+    #[error("Destination scheme differs from the origin's")]
+    SchemeMismatch,
+    #[error("Destination port differs from the origin's")]
+    PortMismatch,
+    #[error("Destination {host} is an IP address which is not allowed")]
+    IpNotAllowed { host: String },
+    #[error("Destination is not under the origin's registrable domain")]
+    DomainMismatch,
+}
+
+/// Which destinations a website may proxy requests to. The destination must be under the
+/// website's registrable domain, or be the same IP address as the website if that address
+/// is in `allowed_ips`. Destinations which are or resolve to non-public addresses are
+/// refused if `block_private_addresses`, unless they are in `allowed_ips`.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct OriginPolicy {
+    pub match_scheme: bool,
+    pub match_port: bool,
+    pub allowed_ips: Vec<IpAddr>,
+    pub block_private_addresses: bool,
+}
+
+impl Default for OriginPolicy {
+    fn default() -> Self {
+        OriginPolicy {
+            match_scheme: true,
+            match_port: true,
+            allowed_ips: vec![],
+            block_private_addresses: true,
+        }
+    }
+}
+
+impl OriginPolicy {
+    /// Whether the website at `origin` may proxy requests to `destination`; the addresses
+    /// the destination resolves to are checked when the request is made
+    pub fn check(&self, origin: &str, destination: &str) -> Result<(), OriginError> {
+        let origin_url = Url::parse(origin).map_err(|_| OriginError::InvalidUrl)?;
+        let destination_url = Url::parse(destination).map_err(|_| OriginError::InvalidUrl)?;
+
+        let scheme = destination_url.scheme();
+        if scheme != "http" && scheme != "https" {
+            return Err(OriginError::UnsupportedScheme {
+                scheme: scheme.to_owned(),
+            });
+        }
+        if self.match_scheme && origin_url.scheme() != scheme {
+            return Err(OriginError::SchemeMismatch);
+        }
+        if self.match_port
+            && origin_url.port_or_known_default() != destination_url.port_or_known_default()
+        {
+            return Err(OriginError::PortMismatch);
+        }
+
+        match (origin_url.host(), destination_url.host()) {
+            (Some(Host::Domain(_)), Some(Host::Domain(_))) => {
+                let origin_root = parse_url_root(origin).map_err(|_| OriginError::InvalidUrl)?;
+                let destination_root =
+                    parse_url_root(destination).map_err(|_| OriginError::InvalidUrl)?;
+                match (origin_root, destination_root) {
+                    (Some(origin_root), Some(destination_root))
+                        if !origin_root.is_empty() && origin_root == destination_root =>
+                    {
+                        Ok(())
+                    }
+                    _ => Err(OriginError::DomainMismatch),
+                }
+            }
+            (origin_host, Some(destination_host)) => {
+                let ip = match &destination_host {
+                    Host::Ipv4(ip) => IpAddr::V4(*ip),
+                    Host::Ipv6(ip) => IpAddr::V6(*ip),
+                    Host::Domain(_) => return Err(OriginError::DomainMismatch),
+                };
+                if !self.allowed_ips.contains(&ip) {
+                    return Err(OriginError::IpNotAllowed {
+                        host: ip.to_string(),
+                    });
+                }
+                if origin_host != Some(destination_host) {
+                    return Err(OriginError::DomainMismatch);
+                }
+                Ok(())
+            }
+            (_, None) => Err(OriginError::DomainMismatch),
+        }
+    }
+
+    /// The addresses proxied requests may connect to
+    pub fn address_filter(&self) -> AddressFilter {
+        AddressFilter {
+            block_private: self.block_private_addresses,
+            allowed: self.allowed_ips.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{OriginError, OriginPolicy};
+
+    #[test]
+    fn test_check_allows_subdomains_of_origin_root() {
+        let policy = OriginPolicy::default();
+        assert_eq!(
+            policy.check("https://www.host.co.uk", "https://api.host.co.uk/orders"),
+            Ok(())
+        );
+        assert_eq!(
+            policy.check("https://host.co.uk", "https://other.co.uk/"),
+            Err(OriginError::DomainMismatch)
+        );
+    }
+
+    #[test]
+    fn test_check_rejects_ip_literals_unless_allowed() {
+        let policy = OriginPolicy::default();
+        assert_eq!(
+            policy.check("http://127.0.0.1", "http://10.0.0.5/admin"),
+            Err(OriginError::IpNotAllowed {
+                host: "10.0.0.5".to_owned()
+            })
+        );
+        assert_eq!(
+            policy.check("http://host.com", "http://[::1]/"),
+            Err(OriginError::IpNotAllowed {
+                host: "::1".to_owned()
+            })
+        );
+
+        let policy = OriginPolicy {
+            allowed_ips: vec!["192.168.1.5".parse().unwrap()],
+            ..OriginPolicy::default()
+        };
+        assert_eq!(
+            policy.check("http://192.168.1.5", "http://192.168.1.5/api"),
+            Ok(())
+        );
+        assert_eq!(
+            policy.check("http://192.168.1.6", "http://192.168.1.5/api"),
+            Err(OriginError::DomainMismatch)
+        );
+    }
+
+    #[test]
+    fn test_check_rejects_hosts_without_registrable_domain() {
+        let policy = OriginPolicy::default();
+        assert!(policy
+            .check("http://localhost", "http://localhost/")
+            .is_err());
+        assert!(policy.check("http://co.uk", "http://co.uk/").is_err());
+        assert_eq!(
+            policy.check("http://host.com", "file:///etc/passwd"),
+            Err(OriginError::UnsupportedScheme {
+                scheme: "file".to_owned()
+            })
+        );
+    }
+
+    #[test]
+    fn test_check_matches_scheme_and_port() {
+        let policy = OriginPolicy::default();
+        assert_eq!(
+            policy.check("https://host.com", "http://host.com/"),
+            Err(OriginError::SchemeMismatch)
+        );
+        assert_eq!(
+            policy.check("https://host.com", "https://host.com:8443/"),
+            Err(OriginError::PortMismatch)
+        );
+        assert_eq!(
+            policy.check("https://host.com:443", "https://host.com/"),
+            Ok(())
+        );
+
+        let policy = OriginPolicy {
+            match_scheme: false,
+            match_port: false,
+            ..OriginPolicy::default()
+        };
+        assert_eq!(
+            policy.check("https://host.com", "http://host.com:8080/"),
+            Ok(())
+        );
+    }
+}
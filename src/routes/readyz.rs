@@ -0,0 +1,174 @@
+use crate::{bootstrap::pki::cert_not_after, render::HandlebarsRenderer};
+use chrono::Utc;
+use redact_crypto::{CryptoError, Storer, SymmetricKey};
+use serde::Serialize;
+use std::{collections::BTreeMap, error::Error, sync::Arc};
+use warp::{http::StatusCode, Filter, Rejection, Reply};
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum CheckStatus {
+    Pass,
+    Fail,
+}
+
+/// The outcome of checking a single dependency
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct CheckResult {
+    pub status: CheckStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub days_to_expiry: Option<i64>,
+}
+
+impl CheckResult {
+    fn pass() -> Self {
+        CheckResult {
+            status: CheckStatus::Pass,
+            message: None,
+            days_to_expiry: None,
+        }
+    }
+
+    fn fail(message: String) -> Self {
+        CheckResult {
+            status: CheckStatus::Fail,
+            message: Some(message),
+            days_to_expiry: None,
+        }
+    }
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct Readiness {
+    pub status: CheckStatus,
+    pub checks: BTreeMap<&'static str, CheckResult>,
+}
+
+/// Everything the client depends on to serve requests
+#[derive(Clone)]
+pub struct ReadinessChecker<H: Storer> {
+    pub storer: Arc<H>,
+    pub default_key_path: String,
+    pub root_cert_filepath: String,
+    pub tls_cert_filepath: String,
+    pub render_engine: Arc<HandlebarsRenderer<'static>>,
+    pub template_names: Vec<&'static str>,
+}
+
+impl<H: Storer> ReadinessChecker<H> {
+    pub async fn check(&self) -> Readiness {
+        let mut checks = BTreeMap::new();
+        let (storer, default_key) = self.check_storer_and_default_key().await;
+        checks.insert("storer", storer);
+        checks.insert("default_key", default_key);
+        checks.insert(
+            "root_certificate",
+            check_certificate(&self.root_cert_filepath),
+        );
+        checks.insert(
+            "tls_certificate",
+            check_certificate(&self.tls_cert_filepath),
+        );
+        checks.insert("templates", self.check_templates());
+
+        let status = if checks.values().all(|c| c.status == CheckStatus::Pass) {
+            CheckStatus::Pass
+        } else {
+            CheckStatus::Fail
+        };
+        Readiness { status, checks }
+    }
+
+    /// Fetching the default key proves the storer is reachable over mutual TLS, and
+    /// resolving it proves the key's bytes can be read
+    async fn check_storer_and_default_key(&self) -> (CheckResult, CheckResult) {
+        match self
+            .storer
+            .get::<SymmetricKey>(&self.default_key_path)
+            .await
+        {
+            Ok(entry) => match entry.take_resolve().await {
+                Ok(_) => (CheckResult::pass(), CheckResult::pass()),
+                Err(e) => (
+                    CheckResult::pass(),
+                    CheckResult::fail(format!("default key could not be resolved: {}", e)),
+                ),
+            },
+            Err(e) => match e {
+                CryptoError::NotFound { .. } => (
+                    CheckResult::pass(),
+                    CheckResult::fail("default key was not found in storage".to_owned()),
+                ),
+                _ => (
+                    CheckResult::fail(format!("storer could not be reached: {}", e)),
+                    CheckResult::fail("default key could not be fetched".to_owned()),
+                ),
+            },
+        }
+    }
+
+    fn check_templates(&self) -> CheckResult {
+        let missing: Vec<&str> = self
+            .template_names
+            .iter()
+            .filter(|name| !self.render_engine.has_template(name))
+            .copied()
+            .collect();
+        if missing.is_empty() {
+            CheckResult::pass()
+        } else {
+            CheckResult::fail(format!("templates not loaded: {}", missing.join(", ")))
+        }
+    }
+}
+
+/// Passes while the certificate has not yet expired, reporting the days remaining
+fn check_certificate(filepath: &str) -> CheckResult {
+    match cert_not_after(filepath) {
+        Ok(not_after) => {
+            let days_to_expiry = (not_after - Utc::now()).num_days();
+            let mut result = if not_after > Utc::now() {
+                CheckResult::pass()
+            } else {
+                CheckResult::fail("certificate has expired".to_owned())
+            };
+            result.days_to_expiry = Some(days_to_expiry);
+            result
+        }
+        Err(e) => CheckResult::fail(format!(
+            "certificate could not be read: {}",
+            e.source()
+                .map(|s| s.to_string())
+                .unwrap_or_else(|| e.to_string())
+        )),
+    }
+}
+
+pub fn get<H: Storer>(
+    checker: ReadinessChecker<H>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::get()
+        .and(warp::any().map(move || checker.clone()))
+        .then(|checker: ReadinessChecker<H>| async move {
+            let readiness = checker.check().await;
+            let code = match readiness.status {
+                CheckStatus::Pass => StatusCode::OK,
+                CheckStatus::Fail => StatusCode::SERVICE_UNAVAILABLE,
+            };
+            warp::reply::with_status(warp::reply::json(&readiness), code)
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_certificate, CheckStatus};
+
+    #[test]
+    fn test_check_certificate_missing_file() {
+        let result = check_certificate("certs/does-not-exist.pem");
+        assert_eq!(result.status, CheckStatus::Fail);
+        assert_eq!(result.days_to_expiry, None);
+    }
+}
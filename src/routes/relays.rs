@@ -0,0 +1,223 @@
+use crate::{
+    relay_queue::{RelayQueue, RelayStatus},
+    render::{RelaysTemplateValues, RenderTemplate, Rendered, Renderer, TemplateValues},
+    routes::{accepts_json, error::CrossSiteRequestRejection},
+};
+use url::Url;
+use warp::{http::Uri, Filter, Rejection, Reply};
+
+/// Lists the relays which haven't been delivered yet; like the audit log, the page may
+/// only be shown by the client itself
+pub fn get<Q: RelayQueue, R: Renderer + Clone + Send + Sync + 'static>(
+    relay_queue: Q,
+    render_engine: R,
+) -> impl Filter<Extract = (Box<dyn Reply>,), Error = Rejection> + Clone {
+    warp::get()
+        .and(warp::header::optional::<String>("accept"))
+        .and(warp::any().map(move || relay_queue.clone()))
+        .and(warp::any().map(move || render_engine.clone()))
+        .and_then(
+            move |accept: Option<String>, relay_queue: Q, render_engine: R| async move {
+                let relays = relay_queue.list().await.map_err(warp::reject::custom)?;
+                let reply: Box<dyn Reply> = if accepts_json(&accept) {
+                    Box::new(warp::reply::json(&relays))
+                } else {
+                    Box::new(Rendered::new(
+                        &render_engine,
+                        RenderTemplate {
+                            name: "relays",
+                            value: TemplateValues::Relays(RelaysTemplateValues {
+                                count: relays.len(),
+                                failed: relays
+                                    .iter()
+                                    .filter(|relay| relay.status == RelayStatus::Failed)
+                                    .count(),
+                                relays,
+                            }),
+                        },
+                    )?)
+                };
+                Ok::<_, Rejection>(Box::new(warp::reply::with_header(
+                    reply,
+                    "x-frame-options",
+                    "DENY",
+                )) as Box<dyn Reply>)
+            },
+        )
+}
+
+/// Makes a relay due immediately, replying with the relay as JSON or else returning to
+/// the list of relays; only the client's own pages may replay relays
+pub fn replay<Q: RelayQueue>(
+    relay_queue: Q,
+) -> impl Filter<Extract = (Box<dyn Reply>,), Error = Rejection> + Clone {
+    warp::post()
+        .and(warp::path!(String / "replay"))
+        .and(same_origin())
+        .and(warp::header::optional::<String>("accept"))
+        .and(warp::any().map(move || relay_queue.clone()))
+        .and_then(
+            move |id: String, accept: Option<String>, relay_queue: Q| async move {
+                let relay = relay_queue
+                    .replay(id)
+                    .await
+                    .map_err(warp::reject::custom)?
+                    .ok_or_else(warp::reject::not_found)?;
+                let reply: Box<dyn Reply> = if accepts_json(&accept) {
+                    Box::new(warp::reply::json(&relay))
+                } else {
+                    Box::new(warp::redirect::see_other(Uri::from_static("/relays")))
+                };
+                Ok::<_, Rejection>(reply)
+            },
+        )
+}
+
+/// Rejects requests made by another website, as a form on any page could otherwise post
+/// to the client; CORS doesn't stop a form from being submitted
+fn same_origin() -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::header::optional::<String>("sec-fetch-site")
+        .and(warp::header::optional::<String>("origin"))
+        .and(warp::header::optional::<String>("host"))
+        .and_then(
+            |sec_fetch_site: Option<String>, origin: Option<String>, host: Option<String>| async move {
+                if is_same_origin(sec_fetch_site.as_deref(), origin.as_deref(), host.as_deref()) {
+                    Ok(())
+                } else {
+                    Err(warp::reject::custom(CrossSiteRequestRejection))
+                }
+            },
+        )
+        .untuple_one()
+}
+
+/// Whether the request came from the client's own pages, going by `Sec-Fetch-Site` or
+/// else by whether the `Origin` is the host the request was sent to. Requests with
+/// neither header weren't made by a website.
+fn is_same_origin(sec_fetch_site: Option<&str>, origin: Option<&str>, host: Option<&str>) -> bool {
+    match (sec_fetch_site, origin) {
+        (Some(sec_fetch_site), _) => sec_fetch_site == "same-origin" || sec_fetch_site == "none",
+        (None, Some(origin)) => {
+            let origin_host = Url::parse(origin).ok().and_then(|url| {
+                url.host_str().map(|origin_host| match url.port() {
+                    Some(port) => format!("{}:{}", origin_host, port),
+                    None => origin_host.to_owned(),
+                })
+            });
+            match (origin_host, host) {
+                (Some(origin_host), Some(host)) => origin_host.eq_ignore_ascii_case(host),
+                _ => false,
+            }
+        }
+        (None, None) => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::is_same_origin;
+    use crate::error_handler;
+    use crate::relay_queue::{tests::MockRelayQueue, QueuedRelay, RelayStatus};
+    use crate::render::tests::MockRenderer;
+    use crate::routes::relays;
+    use mockall::predicate::*;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_replay_unknown_relay_is_not_found() {
+        let mut relay_queue = MockRelayQueue::new();
+        relay_queue
+            .expect_replay()
+            .with(eq("unknown".to_owned()))
+            .times(1)
+            .returning(|_| Ok(None));
+
+        let filter = relays::replay(Arc::new(relay_queue));
+        let res = warp::test::request()
+            .method("POST")
+            .path("/unknown/replay")
+            .reply(&filter)
+            .await;
+        assert_eq!(res.status(), 404);
+    }
+
+    #[tokio::test]
+    async fn test_replay_form_returns_to_relays() {
+        let mut relay_queue = MockRelayQueue::new();
+        relay_queue.expect_replay().times(1).returning(|id| {
+            Ok(Some(QueuedRelay {
+                id,
+                path: ".profile.name.".to_owned(),
+                relay_url: "https://relay.com/".to_owned(),
+                origin: "https://example.com".to_owned(),
+                status: RelayStatus::Pending,
+                attempts: 0,
+                enqueued_at: "".to_owned(),
+                last_attempt_at: None,
+                last_error: None,
+                next_attempt_at: 0,
+            }))
+        });
+
+        let filter = relays::replay(Arc::new(relay_queue));
+        let res = warp::test::request()
+            .method("POST")
+            .path("/abc/replay")
+            .header("sec-fetch-site", "same-origin")
+            .header("origin", "http://localhost:8080")
+            .header("host", "localhost:8080")
+            .reply(&filter)
+            .await;
+        assert_eq!(res.status(), 303);
+        assert_eq!(res.headers().get("location").unwrap(), "/relays");
+    }
+
+    #[tokio::test]
+    async fn test_replay_from_another_website_is_forbidden() {
+        let mut relay_queue = MockRelayQueue::new();
+        relay_queue.expect_replay().times(0);
+        let filter = error_handler::recover(
+            relays::replay(Arc::new(relay_queue)),
+            Arc::new(MockRenderer::new()),
+        );
+
+        for (sec_fetch_site, origin) in &[
+            (Some("cross-site"), "https://example.com"),
+            (Some("same-site"), "http://localhost:9000"),
+            (None, "https://example.com"),
+        ] {
+            let mut req = warp::test::request()
+                .method("POST")
+                .path("/abc/replay")
+                .header("accept", "application/json")
+                .header("origin", *origin)
+                .header("host", "localhost:8080");
+            if let Some(sec_fetch_site) = sec_fetch_site {
+                req = req.header("sec-fetch-site", *sec_fetch_site);
+            }
+            let res = req.reply(&filter).await;
+            assert_eq!(res.status(), 403);
+            let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+            assert_eq!(body["error"], "cross_site_request");
+        }
+    }
+
+    #[test]
+    fn test_is_same_origin() {
+        assert!(is_same_origin(Some("same-origin"), None, None));
+        assert!(is_same_origin(Some("none"), None, None));
+        assert!(!is_same_origin(
+            Some("cross-site"),
+            Some("http://localhost:8080"),
+            Some("localhost:8080")
+        ));
+        assert!(is_same_origin(
+            None,
+            Some("http://localhost:8080"),
+            Some("localhost:8080")
+        ));
+        assert!(!is_same_origin(None, Some("null"), Some("localhost:8080")));
+        assert!(!is_same_origin(None, Some("http://localhost:8080"), None));
+        assert!(is_same_origin(None, None, Some("localhost:8080")));
+    }
+}
@@ -1,44 +1,127 @@
 pub mod data;
 
 use crate::{
-    relayer::Relayer,
+    audit::Auditor,
+    deleter::Deleter,
+    key_selector::KeySelector,
+    metrics,
+    permissions::UNKNOWN_ORIGIN,
+    relay_queue::RelayQueue,
     render::Renderer,
-    routes::error::{
-        IframeTokensDoNotMatchRejection, NoPathTokenProvided, SessionTokenNotFoundRejection,
+    routes::{
+        cookie::SessionCookieConfig,
+        error::{
+            IframeTokensDoNotMatchRejection, InvalidTokenRejection, NoPathTokenProvided,
+            SessionTokenNotFoundRejection,
+        },
     },
-    token::TokenGenerator,
+    token::TokenIssuer,
 };
-use redact_crypto::Storer;
+use redact_crypto::IndexedStorer;
 use std::sync::Arc;
+use tracing::Instrument;
 use warp::{filters::BoxedFilter, path::Peek, Filter, Rejection, Reply};
-use warp_sessions::{
-    CookieOptions, SameSiteCookieOption, Session, SessionStore, SessionWithStore, WithSession,
-};
+use warp_sessions::{Session, SessionStore, SessionWithStore, WithSession};
 
-pub fn data<H: Storer, R: Renderer + Clone + Send + 'static, T: TokenGenerator, Q: Relayer>(
+#[allow(clippy::too_many_arguments)]
+pub fn data<
+    H: IndexedStorer,
+    R: Renderer + Clone + Send + Sync + 'static,
+    I: TokenIssuer,
+    Q: RelayQueue,
+    D: Deleter,
+    A: Auditor,
+    S: SessionStore,
+>(
     storer: Arc<H>,
     render_engine: R,
-    token_generator: T,
-    relayer: Q,
-) -> impl Filter<Extract = (Box<dyn Reply>, String, Option<String>, Option<String>), Error = Rejection>
-       + Clone {
+    token_issuer: I,
+    relay_queue: Q,
+    deleter: D,
+    key_selector: Arc<KeySelector>,
+    auditor: A,
+    session_store: S,
+    cookie_config: SessionCookieConfig,
+) -> impl Filter<
+    Extract = (Box<dyn Reply>, String, Option<String>, Option<Session>),
+    Error = Rejection,
+> + Clone {
     warp::path!("data" / ..).and(
         data::get(
             storer.clone(),
             render_engine.clone(),
-            token_generator.clone(),
+            token_issuer.clone(),
+            auditor.clone(),
+            session_store.clone(),
+            cookie_config.clone(),
         )
-        .or(data::post(render_engine, token_generator, storer, relayer))
+        .or(data::post(
+            render_engine.clone(),
+            token_issuer,
+            storer.clone(),
+            relay_queue,
+            deleter.clone(),
+            key_selector,
+            auditor.clone(),
+            session_store.clone(),
+            cookie_config.clone(),
+        ))
+        .unify()
+        .or(data::delete(
+            storer,
+            render_engine,
+            deleter,
+            auditor,
+            session_store,
+            cookie_config,
+        ))
         .unify(),
     )
 }
 
-pub fn session<T, S: SessionStore>(
+/// Creates the session which must accompany the next request for `path`, holding a
+/// token issued for that path and the new session's ID along with the requesting origin
+pub fn new_token_session<I: TokenIssuer>(
+    token_issuer: &I,
+    path: &str,
+    origin: &str,
+) -> Result<(Session, String), Rejection> {
+    let mut session = Session::new();
+    let token = token_issuer
+        .issue_token(path, session.id())
+        .map_err(warp::reject::custom)?;
+    session
+        .insert("token", token.clone())
+        .map_err(|_| warp::reject())?;
+    session
+        .insert("origin", origin.to_owned())
+        .map_err(|_| warp::reject())?;
+    Ok((session, token))
+}
+
+/// The origin of the website the data was first requested by, carried from session to
+/// session as the user views and edits it
+pub fn session_origin<S: SessionStore>(
     session_store: S,
+    cookie_config: SessionCookieConfig,
+) -> impl Filter<Extract = (String,), Error = Rejection> + Clone {
+    warp_sessions::request::with_session(session_store, Some(cookie_config.cookie_options(None)))
+        .map(|session_with_store: SessionWithStore<S>| {
+            session_with_store
+                .session
+                .get::<String>("origin")
+                .unwrap_or_else(|| UNKNOWN_ORIGIN.to_owned())
+        })
+}
+
+pub fn session<T, S: SessionStore, I: TokenIssuer + 'static>(
+    session_store: S,
+    token_issuer: I,
+    cookie_config: SessionCookieConfig,
 ) -> impl Fn(T) -> BoxedFilter<(WithSession<Box<dyn Reply>>,)>
 where
     T: Filter<
-            Extract = (Box<dyn Reply>, String, Option<String>, Option<String>),
+            Extract = (Box<dyn Reply>, String, Option<String>, Option<Session>),
             Error = Rejection,
         > + Clone
         + Send
@@ -46,75 +129,93 @@ where
         + 'static,
 {
     move |filter: T| {
+        let token_issuer = token_issuer.clone();
+        let new_cookie_config = cookie_config.clone();
         warp::any()
             .and(warp::path::peek().map(|peek: Peek| {
-                if let Some(token) = peek.segments().last() {
-                    Some(token.to_owned())
-                } else {
-                    None
+                // Secure paths end with the data path followed by the token issued for it
+                let segments: Vec<&str> = peek.segments().collect();
+                match segments.as_slice() {
+                    [.., path, token] => Some((path.to_string(), token.to_string())),
+                    _ => None,
                 }
             }))
             .and(warp_sessions::request::with_session(
                 session_store.clone(),
-                Some(CookieOptions {
-                    cookie_name: "sid",
-                    cookie_value: None,
-                    max_age: Some(60),
-                    domain: None,
-                    path: None,
-                    secure: false,
-                    http_only: true,
-                    same_site: Some(SameSiteCookieOption::None),
-                }),
+                Some(cookie_config.cookie_options(None)),
             ))
+            .and(warp::any().map(move || token_issuer.clone()))
             .and_then(
-                move |token: Option<String>, session_with_store: SessionWithStore<S>| async move {
-                    let token = match token {
-                        Some(t) => t,
-                        None => return Err(warp::reject::custom(NoPathTokenProvided)),
-                    };
-                    if let Some(session_token) = session_with_store.session.get::<String>("token") {
-                        if session_token != token {
-                            Err(warp::reject::custom(IframeTokensDoNotMatchRejection))
+                move |path_and_token: Option<(String, String)>,
+                      session_with_store: SessionWithStore<S>,
+                      token_issuer: I| {
+                    async move {
+                        let (path, token) = match path_and_token {
+                            Some(path_and_token) => path_and_token,
+                            None => {
+                                tracing::debug!("no iframe token in request path");
+                                metrics::SESSION_REJECTIONS
+                                    .with_label_values(&["no_path_token"])
+                                    .inc();
+                                return Err(warp::reject::custom(NoPathTokenProvided));
+                            }
+                        };
+                        // The token must be signed, unexpired, and issued for both this data
+                        // path and the session the request arrived with
+                        if let Err(e) = token_issuer.verify_token(
+                            &token,
+                            &path,
+                            session_with_store.session.id(),
+                        ) {
+                            tracing::debug!(reason = %e, "iframe token failed verification");
+                            metrics::SESSION_REJECTIONS
+                                .with_label_values(&["invalid_token"])
+                                .inc();
+                            return Err(warp::reject::custom(InvalidTokenRejection(e)));
+                        }
+                        if let Some(session_token) =
+                            session_with_store.session.get::<String>("token")
+                        {
+                            if session_token != token {
+                                tracing::debug!("iframe token does not match session token");
+                                metrics::SESSION_REJECTIONS
+                                    .with_label_values(&["token_mismatch"])
+                                    .inc();
+                                Err(warp::reject::custom(IframeTokensDoNotMatchRejection))
+                            } else {
+                                Ok(session_with_store)
+                            }
                         } else {
-                            Ok(session_with_store)
+                            tracing::debug!("no token found in session");
+                            metrics::SESSION_REJECTIONS
+                                .with_label_values(&["token_not_found"])
+                                .inc();
+                            Err(warp::reject::custom(SessionTokenNotFoundRejection))
                         }
-                    } else {
-                        Err(warp::reject::custom(SessionTokenNotFoundRejection))
                     }
+                    .instrument(tracing::info_span!("validate_session"))
                 },
             )
             .and(filter)
+            .and(warp::any().map(move || new_cookie_config.clone()))
             .and_then(
                 |mut session_with_store: SessionWithStore<S>,
                  reply: Box<dyn Reply>,
                  old_path: String,
                  new_path: Option<String>,
-                 token: Option<String>| async move {
+                 new_session: Option<Session>,
+                 cookie_config: SessionCookieConfig| async move {
                     session_with_store.cookie_options.path = Some(old_path);
                     session_with_store.session.destroy();
 
-                    match (new_path, token) {
-                        (Some(new_path), Some(token)) => {
-                            let mut new_session = SessionWithStore::<S> {
-                                session: Session::new(),
+                    match (new_path, new_session) {
+                        (Some(new_path), Some(new_session)) => {
+                            let new_session = SessionWithStore::<S> {
+                                session: new_session,
                                 session_store: session_with_store.session_store.clone(),
-                                cookie_options: CookieOptions {
-                                    cookie_name: "sid",
-                                    cookie_value: None,
-                                    max_age: Some(60),
-                                    domain: None,
-                                    path: Some(new_path),
-                                    secure: false,
-                                    http_only: true,
-                                    same_site: Some(SameSiteCookieOption::None),
-                                },
+                                cookie_options: cookie_config.cookie_options(Some(new_path)),
                             };
 
-                            new_session
-                                .session
-                                .insert("token", token)
-                                .map_err(|_| warp::reject())?;
                             Ok::<_, Rejection>((
                                 Box::new(
                                     warp_sessions::reply::with_session(reply, session_with_store)
@@ -1,105 +1,189 @@
+pub mod delete;
 pub mod get;
 pub mod post;
 
 use bytes::buf::BufMut;
 use futures::TryStreamExt;
-use redact_crypto::{
-    BinaryData, BinaryType, CryptoError, Data, Storer, SymmetricKey, ToEntry,
-    ToSymmetricByteAlgorithm,
-};
+use redact_crypto::{BinaryData, BinaryType, CryptoError, Data, Entry, IndexedStorer, Storer};
 use std::{convert::TryFrom, sync::Arc};
 use warp::{multipart::FormData, Filter, Rejection, Reply};
+use warp_sessions::{Session, SessionStore};
 
 use crate::{
-    relayer::Relayer,
+    audit::{audit, AuditEvent, Auditor, Operation},
+    compound::{child_key, child_path, CompoundData, CompoundLayout, DataValue},
+    deleter::{is_deleted, Deleter},
+    key_selector::KeySelector,
+    metrics,
+    relay_queue::RelayQueue,
     render::Renderer,
     routes::{
-        error::RelayRejection, validated_query_params, BadRequestRejection, CryptoErrorRejection,
+        accepts_json,
+        cookie::SessionCookieConfig,
+        non_reserved_path, reject_reserved_path,
+        secure::{new_token_session, session_origin},
+        validated_query_params, BadRequestRejection, CryptoErrorRejection,
+        IframeTokensDoNotMatchRejection, SerializationRejection,
     },
-    token::TokenGenerator,
+    seal::seal_and_store,
+    token::TokenIssuer,
 };
 
-pub fn get<R: Renderer + Clone + Send + 'static, H: Storer, T: TokenGenerator>(
+/// How many entries are fetched at a time when reading a compound value laid out as child
+/// paths
+const COMPOUND_CHILDREN_PAGE_SIZE: i64 = 100;
+
+/// The most paths shown when listing the entries under a prefix
+const MAX_LISTED_PATHS: i64 = 500;
+
+pub fn get<
+    R: Renderer + Clone + Send + Sync + 'static,
+    H: IndexedStorer,
+    I: TokenIssuer,
+    A: Auditor,
+    S: SessionStore,
+>(
     storer: Arc<H>,
     render_engine: R,
-    token_generator: T,
-) -> impl Filter<Extract = (Box<dyn Reply>, String, Option<String>, Option<String>), Error = Rejection>
-       + Clone {
+    token_issuer: I,
+    auditor: A,
+    session_store: S,
+    cookie_config: SessionCookieConfig,
+) -> impl Filter<
+    Extract = (Box<dyn Reply>, String, Option<String>, Option<Session>),
+    Error = Rejection,
+> + Clone {
     warp::get()
-        .and(warp::path!(String / String))
-        .and(warp::any().map(move || token_generator.clone().generate_token().unwrap()))
+        .and(non_reserved_path())
+        .and(warp::path!(String))
         .and(validated_query_params::<get::QueryParams>())
+        .and(warp::header::optional::<String>("accept"))
+        .and(session_origin(session_store, cookie_config))
         .and(warp::any().map(move || storer.clone()))
         .and(warp::any().map(move || render_engine.clone()))
+        .and(warp::any().map(move || token_issuer.clone()))
+        .and(warp::any().map(move || auditor.clone()))
         .and_then(
             move |path: String,
                   old_token: String,
-                  new_token: String,
                   query: get::QueryParams,
+                  accept: Option<String>,
+                  origin: String,
                   storer: Arc<H>,
-                  render_engine: R| async move {
-                let data_entry = match storer.get::<Data>(&path).await {
-                    Ok(e) => Ok(Some(e)),
-                    Err(e) => match e {
-                        CryptoError::NotFound { .. } => Ok(None),
-                        _ => Err(e),
-                    },
-                }
-                .map_err(CryptoErrorRejection)?;
-
-                let data = match data_entry {
-                    Some(data_entry) => data_entry
-                        .take_resolve()
-                        .await
-                        .map_err(CryptoErrorRejection)?,
-                    None => {
-                        if let Some(data_type) = query.data_type.clone() {
-                            match data_type.to_ascii_lowercase().as_ref() {
-                                "bool" => Data::Bool(false),
-                                "u64" => Data::U64(0),
-                                "i64" => Data::I64(0),
-                                "f64" => Data::F64(0.0),
-                                "media" => Data::Binary(None),
-                                _ => Data::String("".to_owned()),
-                            }
+                  render_engine: R,
+                  token_issuer: I,
+                  auditor: A| async move {
+                let result = async {
+                    if let Some(true) = query.list {
+                        let paths = list_paths(&storer, &path, MAX_LISTED_PATHS).await?;
+                        let reply: Box<dyn Reply> = if accepts_json(&accept) {
+                            Box::new(get::json_list_reply(paths, &path))
                         } else {
-                            Data::String("".to_owned())
-                        }
+                            Box::new(get::list_reply(paths, &path, query, &render_engine)?)
+                        };
+                        return Ok::<_, Rejection>((
+                            reply,
+                            format!("/secure/data/{}/{}", &path, &old_token),
+                            None,
+                            None,
+                        ));
                     }
-                };
 
-                let new_path: Option<String> = match query.edit {
-                    Some(true) => Some(format!("/secure/data/{}/{}", &path, &new_token)),
-                    _ => None,
-                };
+                    let value = match query.data_type.as_deref().and_then(CompoundData::empty) {
+                        Some(empty) => {
+                            DataValue::Compound(match query.layout.unwrap_or_default() {
+                                CompoundLayout::Entry => {
+                                    get_compound_entry(&storer, &path, empty).await?
+                                }
+                                CompoundLayout::Children => {
+                                    get_compound_children(&storer, &path, empty).await?
+                                }
+                            })
+                        }
+                        None => DataValue::Scalar(match get_data(&storer, &path).await? {
+                            Some(data) => data,
+                            None => {
+                                if let Some(data_type) = query.data_type.clone() {
+                                    match data_type.to_ascii_lowercase().as_ref() {
+                                        "bool" => Data::Bool(false),
+                                        "u64" => Data::U64(0),
+                                        "i64" => Data::I64(0),
+                                        "f64" => Data::F64(0.0),
+                                        "media" => Data::Binary(None),
+                                        _ => Data::String("".to_owned()),
+                                    }
+                                } else {
+                                    Data::String("".to_owned())
+                                }
+                            }
+                        }),
+                    };
 
-                Ok::<_, Rejection>((
-                    Box::new(get::reply(data, &path, &new_token, query, &render_engine)?)
-                        as Box<dyn Reply>,
-                    format!("/secure/data/{}/{}", &path, &old_token),
-                    new_path,
-                    Some(new_token),
-                ))
+                    let (new_session, new_token) =
+                        new_token_session(&token_issuer, &path, &origin)?;
+                    let new_path: Option<String> = match query.edit {
+                        Some(true) => Some(format!("/secure/data/{}/{}", &path, &new_token)),
+                        _ => None,
+                    };
+
+                    let reply: Box<dyn Reply> = if accepts_json(&accept) {
+                        let token = new_path.as_ref().map(|_| new_token.as_str());
+                        Box::new(get::json_reply(value, &path, token))
+                    } else {
+                        Box::new(get::reply(value, &path, &new_token, query, &render_engine)?)
+                    };
+
+                    Ok::<_, Rejection>((
+                        reply,
+                        format!("/secure/data/{}/{}", &path, &old_token),
+                        new_path,
+                        Some(new_session),
+                    ))
+                }
+                .await;
+
+                audit(
+                    &auditor,
+                    AuditEvent::new(&origin, &path, Operation::View, result.is_ok()),
+                )
+                .await;
+                result
             },
         )
         .untuple_one()
 }
 
-pub fn post<R: Renderer + Clone + Send + 'static, T: TokenGenerator, H: Storer, Q: Relayer>(
+#[allow(clippy::too_many_arguments)]
+pub fn post<
+    R: Renderer + Clone + Send + Sync + 'static,
+    I: TokenIssuer,
+    H: IndexedStorer,
+    Q: RelayQueue,
+    D: Deleter,
+    A: Auditor,
+    S: SessionStore,
+>(
     render_engine: R,
-    token_generator: T,
+    token_issuer: I,
     storer: Arc<H>,
-    relayer: Q,
-) -> impl Filter<Extract = (Box<dyn Reply>, String, Option<String>, Option<String>), Error = Rejection>
-       + Clone {
+    relay_queue: Q,
+    deleter: D,
+    key_selector: Arc<KeySelector>,
+    auditor: A,
+    session_store: S,
+    cookie_config: SessionCookieConfig,
+) -> impl Filter<
+    Extract = (Box<dyn Reply>, String, Option<String>, Option<Session>),
+    Error = Rejection,
+> + Clone {
     warp::post()
-        .and(warp::path!(String / String))
+        .and(non_reserved_path())
+        .and(warp::path!(String))
         .and(warp::query::<post::QueryParams>())
         .and(
-            warp::filters::body::form::<post::BodyParams>()
-                .and_then(move |body: post::BodyParams| async {
-                    let path = body.path.clone();
-                    Ok::<_, Rejection>((Data::try_from(body)?, path))
+            warp::filters::body::form::<Vec<(String, String)>>()
+                .and_then(move |fields: Vec<(String, String)>| async {
+                    Ok::<_, Rejection>(post::parse_form(fields)?)
                 })
                 .or(warp::filters::multipart::form()
                     .max_length(1024 * 1024 * 16) // 16 MB
@@ -133,12 +217,8 @@ pub fn post<R: Renderer + Clone + Send + 'static, T: TokenGenerator, H: Storer,
                                             Err(_) => None,
                                         };
                                     } else if field_name == "value" {
-                                        bt = match BinaryType::try_from(
-                                            content_type.unwrap_or_default(),
-                                        ) {
-                                            Ok(binary_type) => Some(binary_type),
-                                            Err(_) => None,
-                                        };
+                                        bt = BinaryType::try_from(content_type.unwrap_or_default())
+                                            .ok();
                                         let data = x
                                             .stream()
                                             .try_fold(Vec::new(), |mut vec, data| {
@@ -161,62 +241,1035 @@ pub fn post<R: Renderer + Clone + Send + 'static, T: TokenGenerator, H: Storer,
                                 .ok_or_else(|| warp::reject::custom(BadRequestRejection))?,
                         };
                         Ok::<_, Rejection>((
-                            Data::Binary(Some(bd)),
+                            DataValue::Scalar(Data::Binary(Some(bd))),
                             path.ok_or_else(|| warp::reject::custom(BadRequestRejection))?,
                         ))
                     }))
+                .unify()
+                .or(warp::filters::body::json::<post::JsonBodyParams>()
+                    .map(|body: post::JsonBodyParams| (DataValue::Scalar(body.data), body.path)))
                 .unify(),
         )
-        .and(warp::any().map(move || token_generator.clone().generate_token().unwrap()))
+        .and(warp::header::optional::<String>("accept"))
+        .and(session_origin(session_store, cookie_config))
+        .and(warp::any().map(move || token_issuer.clone()))
         .and(warp::any().map(move || render_engine.clone()))
         .and(warp::any().map(move || storer.clone()))
-        .and(warp::any().map(move || relayer.clone()))
+        .and(warp::any().map(move || relay_queue.clone()))
+        .and(warp::any().map(move || deleter.clone()))
+        .and(warp::any().map(move || key_selector.clone()))
+        .and(warp::any().map(move || auditor.clone()))
         .and_then(
-            move |_query_data_path: String,
+            move |query_data_path: String,
                   old_token: String,
                   query: post::QueryParams,
-                  (data, path): (Data, String),
-                  new_token: String,
+                  (value, path): (DataValue, String),
+                  accept: Option<String>,
+                  origin: String,
+                  token_issuer: I,
                   render_engine: R,
                   storer: Arc<H>,
-                  relayer: Q| async move {
-                let key_entry = storer
-                    .get::<SymmetricKey>(".keys.encryption.symmetric.default.")
-                    .await
-                    .map_err(CryptoErrorRejection)?;
-                let (key, key_entry_path, _) = key_entry
-                    .take_resolve_all()
-                    .await
-                    .map_err(CryptoErrorRejection)?;
-                let algo_storer = (*storer).clone();
-                let key_algo = key
-                    .to_byte_algorithm(None, |key| async move {
-                        key.to_ref_entry(key_entry_path, algo_storer)
-                    })
-                    .await
-                    .map_err(CryptoErrorRejection)?;
-                let data_clone = data.clone();
-                let entry = data_clone
-                    .to_sealed_entry(path.clone(), key_algo)
-                    .await
-                    .map_err(CryptoErrorRejection)?;
-                storer.create(entry).await.map_err(CryptoErrorRejection)?;
+                  relay_queue: Q,
+                  deleter: D,
+                  key_selector: Arc<KeySelector>,
+                  auditor: A| async move {
+                let stored = async {
+                    // The token only authorizes writing to the path it was issued for
+                    if path != query_data_path {
+                        return Err(warp::reject::custom(IframeTokensDoNotMatchRejection));
+                    }
+                    match &value {
+                        DataValue::Scalar(data) => {
+                            let key_path = key_selector.select(&path);
+                            seal_and_store(storer.as_ref(), &path, key_path, data.clone())
+                                .await
+                                .map_err(CryptoErrorRejection)?
+                        }
+                        DataValue::Compound(compound) => match query.layout.unwrap_or_default() {
+                            CompoundLayout::Entry => {
+                                let serialized = serde_json::to_string(compound)
+                                    .map_err(|e| warp::reject::custom(SerializationRejection(e)))?;
+                                let key_path = key_selector.select(&path);
+                                seal_and_store(
+                                    storer.as_ref(),
+                                    &path,
+                                    key_path,
+                                    Data::String(serialized),
+                                )
+                                .await
+                                .map_err(CryptoErrorRejection)?
+                            }
+                            CompoundLayout::Children => {
+                                let stored: Vec<String> = list_compound_children(&storer, &path)
+                                    .await?
+                                    .into_iter()
+                                    .map(|entry| entry.path)
+                                    .collect();
+                                let elements: Vec<(String, Data)> = compound
+                                    .children()
+                                    .into_iter()
+                                    .map(|(key, data)| (child_path(&path, &key), data))
+                                    .collect();
+                                for (child, _) in &elements {
+                                    reject_reserved_path(child)?;
+                                }
+
+                                let mut children = vec![];
+                                for (child, data) in elements {
+                                    let key_path = key_selector.select(&child);
+                                    seal_and_store(storer.as_ref(), &child, key_path, data)
+                                        .await
+                                        .map_err(CryptoErrorRejection)?;
+                                    children.push(child);
+                                }
+
+                                // Elements removed from the value are deleted so they aren't
+                                // read back as part of it
+                                for stale in
+                                    stored.iter().filter(|stored| !children.contains(stored))
+                                {
+                                    deleter.delete(stale).await.map_err(warp::reject::custom)?;
+                                }
+                            }
+                        },
+                    }
+                    Ok::<_, Rejection>(())
+                }
+                .await;
+                audit(
+                    &auditor,
+                    AuditEvent::new(&origin, &path, Operation::Edit, stored.is_ok()),
+                )
+                .await;
+                stored?;
 
                 if let Some(relay_url) = query.relay_url.clone() {
-                    relayer
-                        .relay(path.clone(), relay_url)
+                    // Delivered in the background, and audited once it has been attempted
+                    relay_queue
+                        .enqueue(path.clone(), relay_url, origin.clone())
                         .await
-                        .map_err(|_| warp::reject::custom(RelayRejection))?;
+                        .map_err(warp::reject::custom)?;
                 }
 
-                let reply = post::reply(data, &path, &new_token, query, &render_engine)?;
+                let (new_session, new_token) = new_token_session(&token_issuer, &path, &origin)?;
+                let reply: Box<dyn Reply> = if accepts_json(&accept) {
+                    Box::new(get::json_reply(value, &path, Some(&new_token)))
+                } else {
+                    Box::new(post::reply(
+                        value,
+                        &path,
+                        &new_token,
+                        query,
+                        &render_engine,
+                    )?)
+                };
                 Ok::<_, Rejection>((
-                    Box::new(reply) as Box<dyn Reply>,
+                    reply,
                     format!("/secure/data/{}/{}", &path, &old_token),
                     Some(format!("/secure/data/{}/{}", &path, &new_token)),
-                    Some(new_token),
+                    Some(new_session),
                 ))
             },
         )
         .untuple_one()
 }
+
+pub fn delete<
+    R: Renderer + Clone + Send + Sync + 'static,
+    H: IndexedStorer,
+    D: Deleter,
+    A: Auditor,
+    S: SessionStore,
+>(
+    storer: Arc<H>,
+    render_engine: R,
+    deleter: D,
+    auditor: A,
+    session_store: S,
+    cookie_config: SessionCookieConfig,
+) -> impl Filter<
+    Extract = (Box<dyn Reply>, String, Option<String>, Option<Session>),
+    Error = Rejection,
+> + Clone {
+    warp::delete()
+        .and(non_reserved_path())
+        .and(warp::path!(String))
+        .and(validated_query_params::<delete::QueryParams>())
+        .and(warp::header::optional::<String>("accept"))
+        .and(session_origin(session_store, cookie_config))
+        .and(warp::any().map(move || storer.clone()))
+        .and(warp::any().map(move || render_engine.clone()))
+        .and(warp::any().map(move || deleter.clone()))
+        .and(warp::any().map(move || auditor.clone()))
+        .and_then(
+            move |path: String,
+                  old_token: String,
+                  query: delete::QueryParams,
+                  accept: Option<String>,
+                  origin: String,
+                  storer: Arc<H>,
+                  render_engine: R,
+                  deleter: D,
+                  auditor: A| async move {
+                let result = async {
+                    // A compound value laid out as child paths is removed along with its children
+                    if let Some(CompoundLayout::Children) = query.layout {
+                        let children: Vec<String> = list_compound_children(&storer, &path)
+                            .await?
+                            .into_iter()
+                            .map(|entry| entry.path)
+                            .collect();
+                        for child in &children {
+                            reject_reserved_path(child)?;
+                        }
+                        for child in &children {
+                            deleter.delete(child).await.map_err(warp::reject::custom)?;
+                        }
+                    }
+                    deleter.delete(&path).await.map_err(warp::reject::custom)?;
+
+                    let reply: Box<dyn Reply> = if accepts_json(&accept) {
+                        Box::new(delete::json_reply(&path))
+                    } else {
+                        Box::new(delete::reply(&path, query, &render_engine)?)
+                    };
+                    Ok::<_, Rejection>((
+                        reply,
+                        format!("/secure/data/{}/{}", &path, &old_token),
+                        None,
+                        None,
+                    ))
+                }
+                .await;
+
+                audit(
+                    &auditor,
+                    AuditEvent::new(&origin, &path, Operation::Delete, result.is_ok()),
+                )
+                .await;
+                result
+            },
+        )
+        .untuple_one()
+}
+
+/// Lists the paths of all data entries stored under the given prefix which haven't been
+/// deleted, leaving out the client's own entries
+#[tracing::instrument(skip(storer))]
+async fn list_paths<H: IndexedStorer>(
+    storer: &Arc<H>,
+    prefix: &str,
+    page_size: i64,
+) -> Result<Vec<String>, Rejection> {
+    let _timer = metrics::STORER_LATENCY
+        .with_label_values(&["list"])
+        .start_timer();
+    let entries = match storer.list::<Data>(prefix, 0, page_size).await {
+        Ok(entries) => Ok(entries),
+        Err(e) => match e {
+            CryptoError::NotFound { .. } => Ok(vec![]),
+            _ => Err(e),
+        },
+    }
+    .map_err(CryptoErrorRejection)?;
+
+    Ok(entries
+        .into_iter()
+        .filter(|entry| !is_deleted(&entry.value))
+        .map(|entry| entry.path)
+        .filter(|path| reject_reserved_path(path).is_ok())
+        .collect())
+}
+
+/// Fetches and resolves the data entry at the given path, if there is one
+#[tracing::instrument(skip(storer))]
+async fn get_data<H: Storer>(storer: &Arc<H>, path: &str) -> Result<Option<Data>, Rejection> {
+    let _timer = metrics::STORER_LATENCY
+        .with_label_values(&["get"])
+        .start_timer();
+    let data_entry = match storer.get::<Data>(path).await {
+        Ok(e) => Ok(Some(e)),
+        Err(e) => match e {
+            CryptoError::NotFound { .. } => Ok(None),
+            _ => Err(e),
+        },
+    }
+    .map_err(CryptoErrorRejection)?;
+
+    match data_entry {
+        Some(data_entry) if is_deleted(&data_entry.value) => Ok(None),
+        Some(data_entry) => Ok(Some(
+            data_entry
+                .take_resolve()
+                .await
+                .map_err(CryptoErrorRejection)?,
+        )),
+        None => Ok(None),
+    }
+}
+
+/// Fetches a compound value which was sealed as a single JSON-encoded string entry
+#[tracing::instrument(skip(storer, empty))]
+async fn get_compound_entry<H: Storer>(
+    storer: &Arc<H>,
+    path: &str,
+    empty: CompoundData,
+) -> Result<CompoundData, Rejection> {
+    match get_data(storer, path).await? {
+        Some(Data::String(serialized)) => serde_json::from_str(&serialized)
+            .map_err(|e| warp::reject::custom(SerializationRejection(e))),
+        Some(_) => Err(warp::reject::custom(BadRequestRejection)),
+        None => Ok(empty),
+    }
+}
+
+/// Fetches a compound value which was sealed as one entry per element under child paths
+#[tracing::instrument(skip(storer, empty))]
+async fn get_compound_children<H: IndexedStorer>(
+    storer: &Arc<H>,
+    path: &str,
+    empty: CompoundData,
+) -> Result<CompoundData, Rejection> {
+    let mut children = vec![];
+    for entry in list_compound_children(storer, path).await? {
+        if let Some(key) = child_key(path, &entry.path).map(str::to_owned) {
+            let data = entry.take_resolve().await.map_err(CryptoErrorRejection)?;
+            children.push((key, data));
+        }
+    }
+    Ok(empty.with_children(children))
+}
+
+/// Lists every element of a compound value laid out as child paths, paging through the
+/// storer until it runs out of entries. Deleted entries and grandchildren are skipped, so
+/// they never crowd out the elements themselves.
+#[tracing::instrument(skip(storer))]
+async fn list_compound_children<H: IndexedStorer>(
+    storer: &Arc<H>,
+    path: &str,
+) -> Result<Vec<Entry<Data>>, Rejection> {
+    let _timer = metrics::STORER_LATENCY
+        .with_label_values(&["list"])
+        .start_timer();
+    let mut children = vec![];
+    let mut skip = 0;
+    loop {
+        let entries = match storer
+            .list::<Data>(path, skip, COMPOUND_CHILDREN_PAGE_SIZE)
+            .await
+        {
+            Ok(entries) => Ok(entries),
+            Err(e) => match e {
+                CryptoError::NotFound { .. } => Ok(vec![]),
+                _ => Err(e),
+            },
+        }
+        .map_err(CryptoErrorRejection)?;
+        if entries.is_empty() {
+            return Ok(children);
+        }
+
+        skip += entries.len() as u64;
+        children.extend(
+            entries.into_iter().filter(|entry| {
+                !is_deleted(&entry.value) && child_key(path, &entry.path).is_some()
+            }),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        audit::{
+            tests::{expect_audits, MockAuditor},
+            Operation, Outcome,
+        },
+        deleter::{tests::MockDeleter, Deleter, StorerDeleter},
+        error_handler,
+        key_selector::KeySelector,
+        relay_queue::tests::MockRelayQueue,
+        render::{tests::MockRenderer, RenderTemplate, TemplateValues},
+        routes::{self, cookie::SessionCookieConfig},
+        test_utils::FakeStore,
+        token::{tests::MockTokenIssuer, TokenGenerationError, TokenVerificationError},
+    };
+    use mockall::predicate::*;
+    use redact_crypto::{Data, RedactStorer};
+    use std::{convert::Infallible, sync::Arc};
+    use warp::{Filter, Reply};
+    use warp_sessions::{MemoryStore, Session, SessionStore};
+
+    const KEY_PATH: &str = ".keys.encryption.symmetric.default.";
+
+    /// The secure routes behind their session check, answering rejections as the client does
+    fn secure_routes<D: Deleter + 'static>(
+        render_engine: MockRenderer,
+        storer: RedactStorer,
+        deleter: D,
+        auditor: MockAuditor,
+        token_issuer: MockTokenIssuer,
+        session_store: MemoryStore,
+    ) -> impl Filter<Extract = (Box<dyn Reply>,), Error = Infallible> + Clone {
+        let token_issuer = Arc::new(token_issuer);
+        let cookie_config = SessionCookieConfig::default();
+        error_handler::recover(
+            routes::secure(
+                Arc::new(storer),
+                Arc::new(render_engine),
+                token_issuer.clone(),
+                Arc::new(MockRelayQueue::new()),
+                deleter,
+                Arc::new(KeySelector::new(KEY_PATH.to_owned(), vec![])),
+                Arc::new(auditor),
+                session_store.clone(),
+                cookie_config.clone(),
+            )
+            .with(warp::wrap_fn(routes::secure::session(
+                session_store,
+                token_issuer,
+                cookie_config,
+            ))),
+            Arc::new(MockRenderer::new()),
+        )
+    }
+
+    /// A token issuer which accepts every token it is shown
+    fn accepting_token_issuer() -> MockTokenIssuer {
+        let mut token_issuer = MockTokenIssuer::new();
+        token_issuer
+            .expect_verify_token()
+            .returning(|_, _, _| Ok(()));
+        token_issuer
+            .expect_issue_token()
+            .returning(|_, _| Ok("next".to_owned()));
+        token_issuer
+    }
+
+    /// A token issuer which accepts every token it is shown but can't issue new ones
+    fn failing_token_issuer() -> MockTokenIssuer {
+        let mut token_issuer = MockTokenIssuer::new();
+        token_issuer
+            .expect_verify_token()
+            .returning(|_, _, _| Ok(()));
+        token_issuer.expect_issue_token().returning(|_, _| {
+            Err(TokenGenerationError::RandError {
+                source: rand::Error::new("no entropy".to_owned()),
+            })
+        });
+        token_issuer
+    }
+
+    /// Stores a session holding the token issued to the unsecure route, returning the cookie
+    /// which carries it
+    async fn session_cookie(session_store: &MemoryStore, token: &str) -> String {
+        let mut session = Session::new();
+        session.insert("token", token.to_owned()).unwrap();
+        session
+            .insert("origin", "https://example.com".to_owned())
+            .unwrap();
+        let cookie = session_store.store_session(session).await.unwrap().unwrap();
+        format!("sid={}", cookie)
+    }
+
+    #[tokio::test]
+    async fn test_delete_replaces_entry_with_deleted_reference() {
+        let store = FakeStore::default();
+        let storer = store.serve().await;
+        store.insert_data(".profile.name.", Data::String("alice".to_owned()));
+        let session_store = MemoryStore::new();
+        let cookie = session_cookie(&session_store, "abc").await;
+        let filter = secure_routes(
+            MockRenderer::new(),
+            storer.clone(),
+            StorerDeleter::new(storer),
+            expect_audits(Operation::Delete, vec![Outcome::Success]),
+            accepting_token_issuer(),
+            session_store,
+        );
+
+        let res = warp::test::request()
+            .method("DELETE")
+            .path("/secure/data/.profile.name./abc")
+            .header("cookie", cookie)
+            .header("accept", "application/json")
+            .reply(&filter)
+            .await;
+
+        assert_eq!(res.status(), 200);
+        let entry = store.get(".profile.name.").unwrap();
+        assert_eq!(entry["value"]["t"], "Referenced");
+        assert_eq!(entry["value"]["c"]["path"], ".deleted.");
+    }
+
+    #[tokio::test]
+    async fn test_delete_refuses_reserved_paths() {
+        let store = FakeStore::default();
+        let storer = store.serve().await;
+        store.insert_key(KEY_PATH);
+        let session_store = MemoryStore::new();
+        let filter = secure_routes(
+            MockRenderer::new(),
+            storer,
+            Arc::new(MockDeleter::new()),
+            // Refused before the route is reached, so nothing is audited
+            expect_audits(Operation::Delete, vec![]),
+            accepting_token_issuer(),
+            session_store.clone(),
+        );
+
+        for path in &[KEY_PATH, ".permissions.abc.", ".deleted."] {
+            let res = warp::test::request()
+                .method("DELETE")
+                .path(&format!("/secure/data/{}/abc", path))
+                .header("cookie", session_cookie(&session_store, "abc").await)
+                .header("accept", "application/json")
+                .reply(&filter)
+                .await;
+
+            assert_eq!(res.status(), 403);
+            let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+            assert_eq!(body["error"], "permission_denied");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_post_children_layout_refuses_reserved_children() {
+        let store = FakeStore::default();
+        let storer = store.serve().await;
+        store.insert_key(KEY_PATH);
+        let session_store = MemoryStore::new();
+        let cookie = session_cookie(&session_store, "abc").await;
+        let filter = secure_routes(
+            MockRenderer::new(),
+            storer,
+            Arc::new(MockDeleter::new()),
+            expect_audits(Operation::Edit, vec![Outcome::Failure]),
+            accepting_token_issuer(),
+            session_store,
+        );
+
+        let res = warp::test::request()
+            .method("POST")
+            .path("/secure/data/./abc?layout=children")
+            .header("cookie", cookie)
+            .header("accept", "application/json")
+            .header("content-type", "application/x-www-form-urlencoded")
+            .body("path=.&value_type=map&key.0=keys&value_type.0=string&value.0=x")
+            .reply(&filter)
+            .await;
+
+        assert_eq!(res.status(), 403);
+        assert!(store.get(KEY_PATH).unwrap()["value"]["t"] != "Referenced");
+    }
+
+    #[tokio::test]
+    async fn test_delete_children_layout_deletes_direct_children() {
+        let store = FakeStore::default();
+        let storer = store.serve().await;
+        store.insert_data(".profile.address.city.", Data::String("Paris".to_owned()));
+        store.insert_data(".profile.address.zip.", Data::String("75001".to_owned()));
+        store.insert_data(".profile.address.geo.lat.", Data::F64(48.86));
+        let mut deleter = MockDeleter::new();
+        for path in &[
+            ".profile.address.city.",
+            ".profile.address.zip.",
+            ".profile.address.",
+        ] {
+            deleter
+                .expect_delete()
+                .with(eq(*path))
+                .times(1)
+                .returning(|_| Ok(()));
+        }
+        let session_store = MemoryStore::new();
+        let cookie = session_cookie(&session_store, "abc").await;
+        let filter = secure_routes(
+            MockRenderer::new(),
+            storer,
+            Arc::new(deleter),
+            expect_audits(Operation::Delete, vec![Outcome::Success]),
+            accepting_token_issuer(),
+            session_store,
+        );
+
+        let res = warp::test::request()
+            .method("DELETE")
+            .path("/secure/data/.profile.address./abc?layout=children")
+            .header("cookie", cookie)
+            .header("accept", "application/json")
+            .reply(&filter)
+            .await;
+
+        assert_eq!(res.status(), 200);
+    }
+
+    #[tokio::test]
+    async fn test_delete_requires_matching_session_token() {
+        let session_store = MemoryStore::new();
+        let cookie = session_cookie(&session_store, "other").await;
+        let mut deleter = MockDeleter::new();
+        deleter.expect_delete().times(0);
+        let filter = secure_routes(
+            MockRenderer::new(),
+            FakeStore::default().serve().await,
+            Arc::new(deleter),
+            MockAuditor::new(),
+            accepting_token_issuer(),
+            session_store,
+        );
+
+        let res = warp::test::request()
+            .method("DELETE")
+            .path("/secure/data/.profile.name./abc")
+            .header("accept", "application/json")
+            .reply(&filter)
+            .await;
+        assert_eq!(res.status(), 401);
+        let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(body["error"], "session_token_not_found");
+
+        let res = warp::test::request()
+            .method("DELETE")
+            .path("/secure/data/.profile.name./abc")
+            .header("cookie", cookie)
+            .header("accept", "application/json")
+            .reply(&filter)
+            .await;
+        assert_eq!(res.status(), 401);
+        let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(body["error"], "iframe_tokens_do_not_match");
+    }
+
+    #[tokio::test]
+    async fn test_delete_rejects_invalid_token() {
+        let session_store = MemoryStore::new();
+        let cookie = session_cookie(&session_store, "abc").await;
+        let mut token_issuer = MockTokenIssuer::new();
+        token_issuer
+            .expect_verify_token()
+            .with(eq("abc"), eq(".profile.name."), always())
+            .times(1)
+            .returning(|_, _, _| Err(TokenVerificationError::Expired));
+        let mut deleter = MockDeleter::new();
+        deleter.expect_delete().times(0);
+        let filter = secure_routes(
+            MockRenderer::new(),
+            FakeStore::default().serve().await,
+            Arc::new(deleter),
+            MockAuditor::new(),
+            token_issuer,
+            session_store,
+        );
+
+        let res = warp::test::request()
+            .method("DELETE")
+            .path("/secure/data/.profile.name./abc")
+            .header("cookie", cookie)
+            .header("accept", "application/json")
+            .reply(&filter)
+            .await;
+        assert_eq!(res.status(), 401);
+        let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(body["error"], "invalid_iframe_token");
+    }
+
+    #[tokio::test]
+    async fn test_list_skips_deleted_entries() {
+        let store = FakeStore::default();
+        let storer = store.serve().await;
+        store.insert_data(".profile.name.", Data::String("alice".to_owned()));
+        store.insert_data(".profile.email.", Data::String("a@b.com".to_owned()));
+        store.insert_data(".settings.theme.", Data::String("dark".to_owned()));
+        StorerDeleter::new(storer.clone())
+            .delete(".profile.email.")
+            .await
+            .unwrap();
+        let session_store = MemoryStore::new();
+        let cookie = session_cookie(&session_store, "abc").await;
+        let mut deleter = MockDeleter::new();
+        deleter.expect_delete().times(0);
+        let filter = secure_routes(
+            MockRenderer::new(),
+            storer,
+            Arc::new(deleter),
+            expect_audits(Operation::View, vec![Outcome::Success]),
+            accepting_token_issuer(),
+            session_store,
+        );
+
+        let res = warp::test::request()
+            .path("/secure/data/.profile./abc?list=true")
+            .header("cookie", cookie)
+            .header("accept", "application/json")
+            .reply(&filter)
+            .await;
+
+        assert_eq!(res.status(), 200);
+        let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(body["path"], ".profile.");
+        assert_eq!(body["children"], serde_json::json!([".profile.name."]));
+    }
+
+    #[tokio::test]
+    async fn test_list_skips_reserved_entries() {
+        let store = FakeStore::default();
+        let storer = store.serve().await;
+        store.insert_key(KEY_PATH);
+        store.insert_data(".permissions.abc.", Data::String("allow".to_owned()));
+        store.insert_data(".profile.name.", Data::String("alice".to_owned()));
+        let session_store = MemoryStore::new();
+        let cookie = session_cookie(&session_store, "abc").await;
+        let filter = secure_routes(
+            MockRenderer::new(),
+            storer,
+            Arc::new(MockDeleter::new()),
+            expect_audits(Operation::View, vec![Outcome::Success]),
+            accepting_token_issuer(),
+            session_store,
+        );
+
+        let res = warp::test::request()
+            .path("/secure/data/./abc?list=true")
+            .header("cookie", cookie)
+            .header("accept", "application/json")
+            .reply(&filter)
+            .await;
+
+        assert_eq!(res.status(), 200);
+        let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(body["children"], serde_json::json!([".profile.name."]));
+    }
+
+    #[tokio::test]
+    async fn test_get_refuses_reserved_paths() {
+        let store = FakeStore::default();
+        let storer = store.serve().await;
+        store.insert_data(".permissions.abc.", Data::String("allow".to_owned()));
+        let session_store = MemoryStore::new();
+        let filter = secure_routes(
+            MockRenderer::new(),
+            storer,
+            Arc::new(MockDeleter::new()),
+            // Refused before the route is reached, so nothing is audited
+            expect_audits(Operation::View, vec![]),
+            accepting_token_issuer(),
+            session_store.clone(),
+        );
+
+        for query in &["", "?list=true"] {
+            let res = warp::test::request()
+                .path(&format!("/secure/data/.permissions./abc{}", query))
+                .header("cookie", session_cookie(&session_store, "abc").await)
+                .header("accept", "application/json")
+                .reply(&filter)
+                .await;
+
+            assert_eq!(res.status(), 403);
+            let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+            assert_eq!(body["error"], "permission_denied");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_post_children_layout_deletes_removed_elements() {
+        let store = FakeStore::default();
+        let storer = store.serve().await;
+        store.insert_key(KEY_PATH);
+        for (index, tag) in ["a", "b", "c"].iter().enumerate() {
+            store.insert_data(
+                &format!(".profile.tags.{}.", index),
+                Data::String(tag.to_string()),
+            );
+        }
+        let session_store = MemoryStore::new();
+        let cookie = session_cookie(&session_store, "abc").await;
+        let filter = secure_routes(
+            MockRenderer::new(),
+            storer.clone(),
+            StorerDeleter::new(storer),
+            expect_audits(Operation::Edit, vec![Outcome::Success]),
+            accepting_token_issuer(),
+            session_store,
+        );
+
+        let res = warp::test::request()
+            .method("POST")
+            .path("/secure/data/.profile.tags./abc?layout=children")
+            .header("cookie", cookie)
+            .header("accept", "application/json")
+            .header("content-type", "application/x-www-form-urlencoded")
+            .body("path=.profile.tags.&value_type=list&value.0=z&value_type.0=string")
+            .reply(&filter)
+            .await;
+
+        assert_eq!(res.status(), 200);
+        assert_eq!(
+            store.get(".profile.tags.0.").unwrap()["value"]["t"],
+            "Sealed"
+        );
+        for stale in &[".profile.tags.1.", ".profile.tags.2."] {
+            assert_eq!(store.get(stale).unwrap()["value"]["c"]["path"], ".deleted.");
+        }
+    }
+
+    /// Stores a map laid out as child paths with more elements than fit in one page of a
+    /// list, behind deleted elements and grandchildren which sort before most of them
+    async fn insert_large_children_map(store: &FakeStore, storer: &RedactStorer) {
+        for index in 0..150 {
+            let child = format!(".profile.tags.{:03}.", index);
+            store.insert_data(&child, Data::U64(index));
+            if index < 30 {
+                StorerDeleter::new(storer.clone())
+                    .delete(&child)
+                    .await
+                    .unwrap();
+            } else if index < 60 {
+                store.insert_data(&format!("{}note.", child), Data::Bool(true));
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_get_children_layout_reads_every_page_of_children() {
+        let store = FakeStore::default();
+        let storer = store.serve().await;
+        insert_large_children_map(&store, &storer).await;
+        let session_store = MemoryStore::new();
+        let cookie = session_cookie(&session_store, "abc").await;
+        let filter = secure_routes(
+            MockRenderer::new(),
+            storer,
+            Arc::new(MockDeleter::new()),
+            expect_audits(Operation::View, vec![Outcome::Success]),
+            accepting_token_issuer(),
+            session_store,
+        );
+
+        let res = warp::test::request()
+            .path("/secure/data/.profile.tags./abc?data_type=map&layout=children")
+            .header("cookie", cookie)
+            .header("accept", "application/json")
+            .reply(&filter)
+            .await;
+
+        assert_eq!(res.status(), 200);
+        let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        let children = body["compound"]["c"].as_object().unwrap();
+        assert_eq!(children.len(), 120);
+        assert!(children.contains_key("030"));
+        assert!(children.contains_key("149"));
+    }
+
+    #[tokio::test]
+    async fn test_delete_children_layout_deletes_every_page_of_children() {
+        let store = FakeStore::default();
+        let storer = store.serve().await;
+        insert_large_children_map(&store, &storer).await;
+        let session_store = MemoryStore::new();
+        let cookie = session_cookie(&session_store, "abc").await;
+        let filter = secure_routes(
+            MockRenderer::new(),
+            storer.clone(),
+            StorerDeleter::new(storer),
+            expect_audits(Operation::Delete, vec![Outcome::Success]),
+            accepting_token_issuer(),
+            session_store,
+        );
+
+        let res = warp::test::request()
+            .method("DELETE")
+            .path("/secure/data/.profile.tags./abc?layout=children")
+            .header("cookie", cookie)
+            .header("accept", "application/json")
+            .reply(&filter)
+            .await;
+
+        assert_eq!(res.status(), 200);
+        for index in 30..150 {
+            let child = format!(".profile.tags.{:03}.", index);
+            assert_eq!(
+                store.get(&child).unwrap()["value"]["c"]["path"],
+                ".deleted."
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_post_rejects_body_path_other_than_url_path() {
+        let store = FakeStore::default();
+        let storer = store.serve().await;
+        store.insert_key(KEY_PATH);
+        let session_store = MemoryStore::new();
+        let cookie = session_cookie(&session_store, "abc").await;
+        let filter = secure_routes(
+            MockRenderer::new(),
+            storer,
+            Arc::new(MockDeleter::new()),
+            expect_audits(Operation::Edit, vec![Outcome::Failure]),
+            accepting_token_issuer(),
+            session_store,
+        );
+
+        let res = warp::test::request()
+            .method("POST")
+            .path("/secure/data/.profile.name./abc")
+            .header("cookie", cookie)
+            .header("accept", "application/json")
+            .json(&serde_json::json!({
+                "path": ".profile.email.",
+                "data": {"String": "mallory@example.com"}
+            }))
+            .reply(&filter)
+            .await;
+
+        assert_eq!(res.status(), 401);
+        let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(body["error"], "iframe_tokens_do_not_match");
+        assert!(store.get(".profile.email.").is_none());
+        assert!(store.get(".profile.name.").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_get_returns_json_when_accepted_and_html_by_default() {
+        let store = FakeStore::default();
+        let storer = store.serve().await;
+        store.insert_data(".profile.name.", Data::String("alice".to_owned()));
+        let mut render_engine = MockRenderer::new();
+        render_engine
+            .expect_render()
+            .withf(|template: &RenderTemplate| match &template.value {
+                TemplateValues::Secure(secure) => {
+                    template.name == "secure"
+                        && secure.data == Some(Data::String("alice".to_owned()))
+                }
+                _ => false,
+            })
+            .times(1)
+            .return_once(|_| Ok("<p>alice</p>".to_owned()));
+        let session_store = MemoryStore::new();
+        let filter = secure_routes(
+            render_engine,
+            storer,
+            Arc::new(MockDeleter::new()),
+            expect_audits(Operation::View, vec![Outcome::Success; 2]),
+            accepting_token_issuer(),
+            session_store.clone(),
+        );
+
+        let res = warp::test::request()
+            .path("/secure/data/.profile.name./abc?edit=true")
+            .header("cookie", session_cookie(&session_store, "abc").await)
+            .header("accept", "application/json")
+            .reply(&filter)
+            .await;
+        assert_eq!(res.status(), 200);
+        assert_eq!(res.headers()["content-type"], "application/json");
+        let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(body["path"], ".profile.name.");
+        assert_eq!(body["data"], serde_json::json!({"String": "alice"}));
+        assert_eq!(body["token"], "next");
+
+        let res = warp::test::request()
+            .path("/secure/data/.profile.name./abc")
+            .header("cookie", session_cookie(&session_store, "abc").await)
+            .header("accept", "text/html,application/xhtml+xml,*/*;q=0.8")
+            .reply(&filter)
+            .await;
+        assert_eq!(res.status(), 200);
+        assert_eq!(res.headers()["content-type"], "text/html; charset=utf-8");
+        assert_eq!(res.body(), "<p>alice</p>");
+    }
+
+    #[tokio::test]
+    async fn test_post_accepts_json_body_and_returns_json() {
+        let store = FakeStore::default();
+        let storer = store.serve().await;
+        store.insert_key(KEY_PATH);
+        let session_store = MemoryStore::new();
+        let cookie = session_cookie(&session_store, "abc").await;
+        let filter = secure_routes(
+            MockRenderer::new(),
+            storer.clone(),
+            Arc::new(MockDeleter::new()),
+            expect_audits(Operation::Edit, vec![Outcome::Success]),
+            accepting_token_issuer(),
+            session_store,
+        );
+
+        let res = warp::test::request()
+            .method("POST")
+            .path("/secure/data/.profile.age./abc")
+            .header("cookie", cookie)
+            .header("accept", "application/json")
+            .json(&serde_json::json!({"path": ".profile.age.", "data": {"U64": 42}}))
+            .reply(&filter)
+            .await;
+
+        assert_eq!(res.status(), 200);
+        let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(body["data"], serde_json::json!({"U64": 42}));
+        assert_eq!(body["token"], "next");
+        let stored = redact_crypto::Storer::get::<Data>(&storer, ".profile.age.")
+            .await
+            .unwrap();
+        assert_eq!(stored.take_resolve().await.unwrap(), Data::U64(42));
+    }
+
+    #[tokio::test]
+    async fn test_get_token_generation_failure_is_internal_error() {
+        let store = FakeStore::default();
+        let storer = store.serve().await;
+        store.insert_data(".profile.name.", Data::String("alice".to_owned()));
+        let session_store = MemoryStore::new();
+        let filter = secure_routes(
+            MockRenderer::new(),
+            storer,
+            Arc::new(MockDeleter::new()),
+            expect_audits(Operation::View, vec![Outcome::Failure]),
+            failing_token_issuer(),
+            session_store.clone(),
+        );
+
+        let res = warp::test::request()
+            .path("/secure/data/.profile.name./abc")
+            .header("cookie", session_cookie(&session_store, "abc").await)
+            .header("accept", "application/json")
+            .reply(&filter)
+            .await;
+
+        assert_eq!(res.status(), 500);
+        let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(body["error"], "token_error");
+    }
+
+    #[tokio::test]
+    async fn test_post_token_generation_failure_is_internal_error() {
+        let store = FakeStore::default();
+        let storer = store.serve().await;
+        store.insert_key(KEY_PATH);
+        let session_store = MemoryStore::new();
+        let cookie = session_cookie(&session_store, "abc").await;
+        let filter = secure_routes(
+            MockRenderer::new(),
+            storer,
+            Arc::new(MockDeleter::new()),
+            expect_audits(Operation::Edit, vec![Outcome::Success]),
+            failing_token_issuer(),
+            session_store,
+        );
+
+        let res = warp::test::request()
+            .method("POST")
+            .path("/secure/data/.profile.age./abc")
+            .header("cookie", cookie)
+            .header("accept", "application/json")
+            .json(&serde_json::json!({"path": ".profile.age.", "data": {"U64": 42}}))
+            .reply(&filter)
+            .await;
+
+        assert_eq!(res.status(), 500);
+        let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(body["error"], "token_error");
+    }
+}
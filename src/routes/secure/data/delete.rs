@@ -0,0 +1,60 @@
+use crate::{
+    compound::CompoundLayout,
+    render::{
+        RenderError, RenderTemplate, Rendered, Renderer, SecureTemplateValues, TemplateValues,
+    },
+    routes::{validate_base64_query_param, Validate},
+};
+use serde::{Deserialize, Serialize};
+use warp::{Rejection, Reply};
+
+#[derive(Deserialize, Serialize)]
+pub struct QueryParams {
+    pub css: Option<String>,
+    pub layout: Option<CompoundLayout>,
+    pub js_message: Option<String>,
+    pub js_height_msg_prefix: Option<String>,
+}
+
+impl Validate for QueryParams {
+    fn validate(&self) -> Result<(), Rejection> {
+        validate_base64_query_param(self.js_message.clone())?;
+        validate_base64_query_param(self.js_height_msg_prefix.clone())?;
+        Ok::<_, Rejection>(())
+    }
+}
+
+/// The JSON representation of a deleted entry returned to clients which accept JSON
+#[derive(Serialize)]
+pub struct JsonReply {
+    pub path: String,
+    pub deleted: bool,
+}
+
+pub fn json_reply(path: &str) -> impl Reply + 'static {
+    warp::reply::json(&JsonReply {
+        path: path.to_owned(),
+        deleted: true,
+    })
+}
+
+pub fn reply<R: Renderer>(
+    path: &str,
+    query: QueryParams,
+    render_engine: &R,
+) -> Result<impl Reply, RenderError> {
+    Rendered::new(
+        render_engine,
+        RenderTemplate {
+            name: "secure",
+            value: TemplateValues::Secure(SecureTemplateValues {
+                deleted: true,
+                path: Some(path.to_owned()),
+                css: query.css,
+                js_message: query.js_message,
+                js_height_msg_prefix: query.js_height_msg_prefix,
+                ..Default::default()
+            }),
+        },
+    )
+}
@@ -1,4 +1,5 @@
 use crate::{
+    compound::{CompoundData, CompoundLayout, DataValue},
     render::{
         RenderError, RenderTemplate, Rendered, Renderer, SecureTemplateValues, TemplateValues,
     },
@@ -13,6 +14,8 @@ pub struct QueryParams {
     pub css: Option<String>,
     pub edit: Option<bool>,
     pub data_type: Option<String>,
+    pub layout: Option<CompoundLayout>,
+    pub list: Option<bool>,
     pub relay_url: Option<String>,
     pub js_message: Option<String>,
     pub js_height_msg_prefix: Option<String>,
@@ -26,15 +29,43 @@ impl Validate for QueryParams {
     }
 }
 
-pub fn reply<'a, R: Renderer>(
-    data: Data,
+/// The JSON representation of a data entry returned to clients which accept JSON
+#[derive(Serialize)]
+pub struct JsonReply {
+    pub path: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub data: Option<Data>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub compound: Option<CompoundData>,
+    pub token: Option<String>,
+}
+
+pub fn json_reply(value: DataValue, path: &str, token: Option<&str>) -> impl Reply + 'static {
+    let (data, compound) = match value {
+        DataValue::Scalar(data) => (Some(data), None),
+        DataValue::Compound(compound) => (None, Some(compound)),
+    };
+    warp::reply::json(&JsonReply {
+        path: path.to_owned(),
+        data,
+        compound,
+        token: token.map(str::to_owned),
+    })
+}
+
+pub fn reply<R: Renderer>(
+    value: DataValue,
     path: &str,
     token: &str,
     query: QueryParams,
-    render_engine: &'a R,
+    render_engine: &R,
 ) -> Result<impl Reply + 'static, RenderError> {
+    let (data, compound) = match value {
+        DataValue::Scalar(data) => (Some(data), None),
+        DataValue::Compound(compound) => (None, Some(compound)),
+    };
     let is_binary_data = match data {
-        Data::Binary(_) => true,
+        Some(Data::Binary(_)) => true,
         _ => query.data_type == Some("media".to_owned()),
     };
 
@@ -43,12 +74,16 @@ pub fn reply<'a, R: Renderer>(
         RenderTemplate {
             name: "secure",
             value: TemplateValues::Secure(SecureTemplateValues {
-                data: Some(data),
+                data,
+                compound,
+                children: None,
+                deleted: false,
                 path: Some(path.to_owned()),
                 token: Some(token.to_owned()),
                 css: query.css,
                 edit: query.edit,
                 data_type: query.data_type,
+                layout: query.layout,
                 relay_url: query.relay_url,
                 js_message: query.js_message,
                 js_height_msg_prefix: query.js_height_msg_prefix,
@@ -57,3 +92,39 @@ pub fn reply<'a, R: Renderer>(
         },
     )
 }
+
+/// The JSON representation of the entry paths stored under a prefix
+#[derive(Serialize)]
+pub struct JsonListReply {
+    pub path: String,
+    pub children: Vec<String>,
+}
+
+pub fn json_list_reply(children: Vec<String>, path: &str) -> impl Reply + 'static {
+    warp::reply::json(&JsonListReply {
+        path: path.to_owned(),
+        children,
+    })
+}
+
+pub fn list_reply<R: Renderer>(
+    children: Vec<String>,
+    path: &str,
+    query: QueryParams,
+    render_engine: &R,
+) -> Result<impl Reply + 'static, RenderError> {
+    Rendered::new(
+        render_engine,
+        RenderTemplate {
+            name: "secure",
+            value: TemplateValues::Secure(SecureTemplateValues {
+                children: Some(children),
+                path: Some(path.to_owned()),
+                css: query.css,
+                js_message: query.js_message,
+                js_height_msg_prefix: query.js_height_msg_prefix,
+                ..Default::default()
+            }),
+        },
+    )
+}
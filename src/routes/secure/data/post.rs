@@ -1,10 +1,11 @@
-use std::convert::TryFrom;
+use std::{collections::BTreeMap, convert::TryFrom};
 
 use redact_crypto::Data;
 use serde::{Deserialize, Serialize};
 use warp::Reply;
 
 use crate::{
+    compound::{CompoundData, CompoundLayout, DataValue},
     render::{
         RenderError, RenderTemplate, Rendered, Renderer, SecureTemplateValues, TemplateValues,
     },
@@ -16,6 +17,7 @@ pub struct QueryParams {
     pub css: Option<String>,
     pub edit: Option<bool>,
     pub data_type: Option<String>,
+    pub layout: Option<CompoundLayout>,
     pub relay_url: Option<String>,
     pub js_message: Option<String>,
     pub js_height_msg_prefix: Option<String>,
@@ -28,6 +30,12 @@ pub struct BodyParams {
     pub value_type: String,
 }
 
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct JsonBodyParams {
+    pub path: String,
+    pub data: Data,
+}
+
 impl TryFrom<BodyParams> for Data {
     type Error = BadRequestRejection;
 
@@ -47,24 +55,119 @@ impl TryFrom<BodyParams> for Data {
     }
 }
 
+/// The fields submitted for a single element of a compound value, named
+/// `key.<n>`, `value.<n>` and `value_type.<n>` in the form
+#[derive(Default)]
+struct CompoundFormRow {
+    key: Option<String>,
+    value: Option<String>,
+    value_type: Option<String>,
+}
+
+/// Parses a submitted form into the value it describes and the path it should be stored at.
+/// Scalar values use the `value` and `value_type` fields, while compound values use a
+/// `value_type` of `list` or `map` and one set of numbered fields per element.
+pub fn parse_form(
+    fields: Vec<(String, String)>,
+) -> Result<(DataValue, String), BadRequestRejection> {
+    let mut path = None;
+    let mut value = None;
+    let mut value_type = None;
+    let mut rows: BTreeMap<usize, CompoundFormRow> = BTreeMap::new();
+
+    for (name, field) in fields {
+        match name.split_once('.') {
+            None => match name.as_ref() {
+                "path" => path = Some(field),
+                "value" => value = Some(field),
+                "value_type" => value_type = Some(field),
+                _ => (),
+            },
+            Some((name, index)) => {
+                let row = rows
+                    .entry(index.parse::<usize>().or(Err(BadRequestRejection))?)
+                    .or_default();
+                match name {
+                    "key" => row.key = Some(field),
+                    "value" => row.value = Some(field),
+                    "value_type" => row.value_type = Some(field),
+                    _ => (),
+                }
+            }
+        }
+    }
+
+    let path = path.ok_or(BadRequestRejection)?;
+    let value_type = value_type.ok_or(BadRequestRejection)?;
+    let value = match CompoundData::empty(&value_type) {
+        None => DataValue::Scalar(Data::try_from(BodyParams {
+            path: path.clone(),
+            value,
+            value_type,
+        })?),
+        Some(empty) => {
+            let is_map = matches!(empty, CompoundData::Map(_));
+            let mut children = vec![];
+            for (index, row) in rows {
+                let row_value_type = row.value_type.ok_or(BadRequestRejection)?;
+                let key = match row.key {
+                    Some(key) if is_map => key.trim().to_owned(),
+                    _ => index.to_string(),
+                };
+
+                // Rows left empty, such as the one offered for adding an element, are skipped
+                let is_empty = if is_map {
+                    key.is_empty()
+                } else {
+                    row_value_type != "bool" && row.value.as_deref().unwrap_or("").is_empty()
+                };
+                if is_empty {
+                    continue;
+                }
+                if key.contains('.') {
+                    return Err(BadRequestRejection);
+                }
+
+                let data = Data::try_from(BodyParams {
+                    path: path.clone(),
+                    value: row.value,
+                    value_type: row_value_type,
+                })?;
+                children.push((key, data));
+            }
+            DataValue::Compound(empty.with_children(children))
+        }
+    };
+
+    Ok((value, path))
+}
+
 pub fn reply<R: Renderer>(
-    data: Data,
+    value: DataValue,
     path: &str,
     token: &str,
     query: QueryParams,
     render_engine: &R,
 ) -> Result<impl Reply, RenderError> {
+    let (data, compound) = match value {
+        DataValue::Scalar(data) => (Some(data), None),
+        DataValue::Compound(compound) => (None, Some(compound)),
+    };
     Rendered::new(
         render_engine,
         RenderTemplate {
             name: "secure",
             value: TemplateValues::Secure(SecureTemplateValues {
-                data: Some(data),
+                data,
+                compound,
+                children: None,
+                deleted: false,
                 path: Some(path.to_owned()),
                 token: Some(token.to_owned()),
                 css: query.css,
                 edit: query.edit,
                 data_type: query.data_type,
+                layout: query.layout,
                 relay_url: query.relay_url,
                 js_message: query.js_message,
                 js_height_msg_prefix: query.js_height_msg_prefix,
@@ -73,3 +176,88 @@ pub fn reply<R: Renderer>(
         },
     )
 }
+
+#[cfg(test)]
+mod tests {
+    use super::parse_form;
+    use crate::compound::{CompoundData, DataValue};
+    use redact_crypto::Data;
+
+    fn fields(fields: &[(&str, &str)]) -> Vec<(String, String)> {
+        fields
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_parse_form_scalar() {
+        let (value, path) = parse_form(fields(&[
+            ("path", ".profile.age."),
+            ("value_type", "u64"),
+            ("value", "30"),
+        ]))
+        .unwrap();
+        assert_eq!(path, ".profile.age.");
+        assert_eq!(value, DataValue::Scalar(Data::U64(30)));
+    }
+
+    #[test]
+    fn test_parse_form_map_skips_empty_rows() {
+        let (value, _) = parse_form(fields(&[
+            ("path", ".profile.address."),
+            ("value_type", "map"),
+            ("key.0", "street"),
+            ("value_type.0", "string"),
+            ("value.0", "Main St"),
+            ("key.1", "verified"),
+            ("value_type.1", "bool"),
+            ("key.2", ""),
+            ("value_type.2", "string"),
+            ("value.2", ""),
+        ]))
+        .unwrap();
+        match value {
+            DataValue::Compound(CompoundData::Map(map)) => {
+                assert_eq!(map.len(), 2);
+                assert_eq!(map["street"], Data::String("Main St".to_owned()));
+                assert_eq!(map["verified"], Data::Bool(false));
+            }
+            _ => panic!("expected a map"),
+        }
+    }
+
+    #[test]
+    fn test_parse_form_list_keeps_order() {
+        let (value, _) = parse_form(fields(&[
+            ("path", ".profile.nicknames."),
+            ("value_type", "list"),
+            ("value_type.1", "string"),
+            ("value.1", "second"),
+            ("value_type.0", "string"),
+            ("value.0", "first"),
+            ("value_type.2", "string"),
+            ("value.2", ""),
+        ]))
+        .unwrap();
+        assert_eq!(
+            value,
+            DataValue::Compound(CompoundData::List(vec![
+                Data::String("first".to_owned()),
+                Data::String("second".to_owned()),
+            ]))
+        );
+    }
+
+    #[test]
+    fn test_parse_form_rejects_dotted_map_keys() {
+        let res = parse_form(fields(&[
+            ("path", ".profile.address."),
+            ("value_type", "map"),
+            ("key.0", "street.name"),
+            ("value_type.0", "string"),
+            ("value.0", "Main St"),
+        ]));
+        assert!(res.is_err());
+    }
+}
@@ -1,50 +1,73 @@
+pub mod consent;
 pub mod data;
 
-use crate::{render::Renderer, token::TokenGenerator};
-use warp::{filters::BoxedFilter, Filter, Rejection, Reply};
-use warp_sessions::{
-    CookieOptions, SameSiteCookieOption, SessionStore, SessionWithStore, WithSession,
+use crate::{
+    permissions::PermissionRegistry, render::Renderer, routes::cookie::SessionCookieConfig,
+    token::TokenIssuer,
 };
+use warp::{filters::BoxedFilter, Filter, Rejection, Reply};
+use warp_sessions::{Session, SessionStore, SessionWithStore, WithSession};
+
+pub fn data<I: TokenIssuer, R: Renderer + Clone + Send + Sync + 'static, P: PermissionRegistry>(
+    token_issuer: I,
+    render_engine: R,
+    registry: P,
+) -> impl Filter<Extract = (Box<dyn Reply>, String, Option<Session>), Error = Rejection> + Clone {
+    warp::path!("data" / ..).and(data::get(token_issuer, render_engine, registry))
+}
 
-pub fn data<T: TokenGenerator, R: Renderer + Clone + Send + Sync + 'static>(
-    token_generator: T,
+pub fn consent<
+    I: TokenIssuer,
+    R: Renderer + Clone + Send + Sync + 'static,
+    P: PermissionRegistry,
+    S: SessionStore,
+>(
+    token_issuer: I,
     render_engine: R,
-) -> impl Filter<Extract = (impl Reply, String, String), Error = Rejection> + Clone {
-    warp::path!("data" / ..).and(data::get(token_generator, render_engine))
+    registry: P,
+    session_store: S,
+    cookie_config: SessionCookieConfig,
+) -> impl Filter<Extract = (Box<dyn Reply>, String, Option<Session>), Error = Rejection> + Clone {
+    consent::get(token_issuer.clone(), render_engine.clone())
+        .or(consent::post(
+            token_issuer,
+            render_engine,
+            registry,
+            session_store,
+            cookie_config,
+        ))
+        .unify()
 }
 
 pub fn session<T, S: SessionStore, R: Reply + 'static>(
     session_store: S,
+    cookie_config: SessionCookieConfig,
 ) -> impl Fn(T) -> BoxedFilter<(WithSession<R>,)>
 where
-    T: Filter<Extract = (R, String, String), Error = Rejection> + Clone + Send + Sync + 'static,
+    T: Filter<Extract = (R, String, Option<Session>), Error = Rejection>
+        + Clone
+        + Send
+        + Sync
+        + 'static,
 {
     move |filter: T| {
         warp::any()
             .and(filter)
             .and(warp_sessions::request::with_session(
                 session_store.clone(),
-                Some(CookieOptions {
-                    cookie_name: "sid",
-                    cookie_value: None,
-                    max_age: Some(60),
-                    domain: None,
-                    path: None,
-                    secure: false,
-                    http_only: true,
-                    same_site: Some(SameSiteCookieOption::None),
-                }),
+                Some(cookie_config.cookie_options(None)),
             ))
             .and_then(
                 |reply: R,
                  path: String,
-                 token: String,
+                 session: Option<Session>,
                  mut session_with_store: SessionWithStore<S>| async move {
-                    session_with_store
-                        .session
-                        .insert("token", token)
-                        .map_err(|_| warp::reject())?;
-                    session_with_store.cookie_options.path = Some(path);
+                    // The token was issued for this session's ID, so it replaces whichever
+                    // session the request arrived with
+                    if let Some(session) = session {
+                        session_with_store.session = session;
+                        session_with_store.cookie_options.path = Some(path);
+                    }
                     Ok::<_, Rejection>((reply, session_with_store))
                 },
             )
@@ -0,0 +1,595 @@
+use serde::{Deserialize, Serialize};
+use url::form_urlencoded;
+use warp::{Filter, Rejection, Reply};
+use warp_sessions::{Session, SessionError, SessionStore, SessionWithStore};
+
+use crate::{
+    metrics,
+    permissions::{path_prefixes, Decision, Permission, PermissionRegistry, UNKNOWN_ORIGIN},
+    render::{
+        ConsentTemplateValues, RenderError, RenderTemplate, Rendered, Renderer, TemplateValues,
+    },
+    routes::{
+        cookie::SessionCookieConfig,
+        error::{InvalidTokenRejection, PermissionDeniedRejection},
+        secure::new_token_session,
+        BadRequestRejection, IframeTokensDoNotMatchRejection, SessionTokenNotFoundRejection,
+    },
+    token::TokenIssuer,
+};
+
+/// The session ID review tokens are issued for; the consent window is a new top-level
+/// page, so it can't rely on any cookie the website's iframe was given
+const REVIEW_SESSION_ID: &str = "consent-review";
+
+#[derive(Deserialize, Debug)]
+pub struct BodyParams {
+    pub origin: String,
+    pub prefix: String,
+    pub decision: Decision,
+    pub token: String,
+}
+
+/// Carried from the prompt in the website's iframe to the consent window it opens
+#[derive(Deserialize, Serialize, Debug)]
+pub struct ReviewParams {
+    pub origin: String,
+    pub token: String,
+}
+
+/// What a consent token is issued for, binding it to both the website and the data path
+pub fn token_subject(origin: &str, path: &str) -> String {
+    format!("consent:{}:{}", origin, path)
+}
+
+/// What a review token is issued for, which opens the consent window for the website and
+/// the data path
+fn review_token_subject(origin: &str, path: &str) -> String {
+    format!("consent-review:{}:{}", origin, path)
+}
+
+/// Where the consent window for `path` is opened and its form is posted
+pub fn action_path(path: &str) -> String {
+    format!("/unsecure/consent/{}", path)
+}
+
+/// Renders the prompt shown in the website's iframe when the user hasn't decided whether
+/// the website at `origin` may see `path`. The decision is only ever made in a window of
+/// its own, which the website can't frame, cover or style.
+pub fn prompt<I: TokenIssuer, R: Renderer>(
+    origin: &str,
+    path: &str,
+    token_issuer: &I,
+    render_engine: &R,
+) -> Result<impl Reply + 'static, Rejection> {
+    let token = token_issuer
+        .issue_token(&review_token_subject(origin, path), REVIEW_SESSION_ID)
+        .map_err(warp::reject::custom)?;
+    let review_query = form_urlencoded::Serializer::new(String::new())
+        .append_pair("origin", origin)
+        .append_pair("token", &token)
+        .finish();
+    Ok(Rendered::new(
+        render_engine,
+        RenderTemplate {
+            name: "consent",
+            value: TemplateValues::Consent(ConsentTemplateValues {
+                origin: origin.to_owned(),
+                path: path.to_owned(),
+                review_url: Some(format!("{}?{}", action_path(path), review_query)),
+                ..ConsentTemplateValues::default()
+            }),
+        },
+    )?)
+}
+
+/// Renders the page asking the user whether the website at `origin` may see `path`
+fn reply<R: Renderer>(
+    origin: &str,
+    path: &str,
+    token: &str,
+    render_engine: &R,
+) -> Result<impl Reply + 'static, RenderError> {
+    Rendered::new(
+        render_engine,
+        RenderTemplate {
+            name: "consent",
+            value: TemplateValues::Consent(ConsentTemplateValues {
+                origin: origin.to_owned(),
+                path: path.to_owned(),
+                prefixes: path_prefixes(path),
+                allow_always: origin != UNKNOWN_ORIGIN,
+                action: action_path(path),
+                token: token.to_owned(),
+                ..ConsentTemplateValues::default()
+            }),
+        },
+    )
+}
+
+/// Keeps the consent window from being framed, so a website can't trick the user into
+/// clicking through it
+fn deny_framing(reply: impl Reply + 'static) -> Box<dyn Reply> {
+    Box::new(warp::reply::with_header(
+        warp::reply::with_header(reply, "x-frame-options", "DENY"),
+        "content-security-policy",
+        "frame-ancestors 'none'",
+    ))
+}
+
+/// Opens the consent window from the prompt, with a session holding the token its form
+/// must be posted with
+pub fn get<I: TokenIssuer, R: Renderer + Clone + Send + Sync>(
+    token_issuer: I,
+    render_engine: R,
+) -> impl Filter<Extract = (Box<dyn Reply>, String, Option<Session>), Error = Rejection> + Clone {
+    warp::get()
+        .and(warp::path!("consent" / String))
+        .and(warp::query::<ReviewParams>())
+        .and(warp::any().map(move || token_issuer.clone()))
+        .and(warp::any().map(move || render_engine.clone()))
+        .and_then(
+            |path: String, params: ReviewParams, token_issuer: I, render_engine: R| async move {
+                token_issuer
+                    .verify_token(
+                        &params.token,
+                        &review_token_subject(&params.origin, &path),
+                        REVIEW_SESSION_ID,
+                    )
+                    .map_err(|e| {
+                        metrics::SESSION_REJECTIONS
+                            .with_label_values(&["invalid_token"])
+                            .inc();
+                        warp::reject::custom(InvalidTokenRejection(e))
+                    })?;
+
+                let (session, token) = new_token_session(
+                    &token_issuer,
+                    &token_subject(&params.origin, &path),
+                    &params.origin,
+                )?;
+                let reply = reply(&params.origin, &path, &token, &render_engine)?;
+                Ok::<_, Rejection>((deny_framing(reply), action_path(&path), Some(session)))
+            },
+        )
+        .untuple_one()
+}
+
+/// Records the user's decision from the consent window, then closes the window and
+/// reloads the website's iframe, which is now served as the user decided
+pub fn post<
+    I: TokenIssuer,
+    R: Renderer + Clone + Send + Sync,
+    P: PermissionRegistry,
+    S: SessionStore,
+>(
+    token_issuer: I,
+    render_engine: R,
+    registry: P,
+    session_store: S,
+    cookie_config: SessionCookieConfig,
+) -> impl Filter<Extract = (Box<dyn Reply>, String, Option<Session>), Error = Rejection> + Clone {
+    warp::post()
+        .and(warp::path!("consent" / String))
+        .and(warp::body::form::<BodyParams>())
+        .and(warp_sessions::request::with_session(
+            session_store,
+            Some(cookie_config.cookie_options(None)),
+        ))
+        .and(warp::any().map(move || token_issuer.clone()))
+        .and(warp::any().map(move || render_engine.clone()))
+        .and(warp::any().map(move || registry.clone()))
+        .and_then(
+            |path: String,
+             body: BodyParams,
+             session_with_store: SessionWithStore<S>,
+             token_issuer: I,
+             render_engine: R,
+             registry: P| async move {
+                verify_consent_token(&token_issuer, &session_with_store.session, &path, &body)?;
+                validate_decision(&path, &body)?;
+
+                // The consent token is used up, so its session is destroyed before the
+                // decision is recorded and the same form can't be posted again
+                session_with_store
+                    .session_store
+                    .destroy_session(session_with_store.session)
+                    .await
+                    .map_err(|source| {
+                        warp::reject::custom(SessionError::DestroyError { source })
+                    })?;
+
+                registry
+                    .record(Permission {
+                        origin: body.origin.clone(),
+                        prefix: body.prefix.clone(),
+                        decision: body.decision,
+                    })
+                    .await
+                    .map_err(warp::reject::custom)?;
+                tracing::info!(
+                    origin = %body.origin,
+                    prefix = %body.prefix,
+                    decision = ?body.decision,
+                    "recorded website permission"
+                );
+
+                let reply = Rendered::new(
+                    &render_engine,
+                    RenderTemplate {
+                        name: "consent",
+                        value: TemplateValues::Consent(ConsentTemplateValues {
+                            origin: body.origin,
+                            path: path.clone(),
+                            decided: true,
+                            ..ConsentTemplateValues::default()
+                        }),
+                    },
+                )?;
+                Ok::<_, Rejection>((deny_framing(reply), action_path(&path), None))
+            },
+        )
+        .untuple_one()
+}
+
+/// The form must carry the token issued with the consent page, for the same website and
+/// path, to the session that page was served to, so other websites can't grant themselves
+fn verify_consent_token<I: TokenIssuer>(
+    token_issuer: &I,
+    session: &Session,
+    path: &str,
+    body: &BodyParams,
+) -> Result<(), Rejection> {
+    let rejection = match session.get::<String>("token") {
+        Some(session_token) if session_token == body.token => None,
+        Some(_) => Some((
+            "token_mismatch",
+            warp::reject::custom(IframeTokensDoNotMatchRejection),
+        )),
+        None => Some((
+            "token_not_found",
+            warp::reject::custom(SessionTokenNotFoundRejection),
+        )),
+    };
+    if let Some((reason, rejection)) = rejection {
+        tracing::debug!(reason, "consent token does not match session");
+        metrics::SESSION_REJECTIONS
+            .with_label_values(&[reason])
+            .inc();
+        return Err(rejection);
+    }
+    token_issuer
+        .verify_token(
+            &body.token,
+            &token_subject(&body.origin, path),
+            session.id(),
+        )
+        .map_err(|e| {
+            metrics::SESSION_REJECTIONS
+                .with_label_values(&["invalid_token"])
+                .inc();
+            warp::reject::custom(InvalidTokenRejection(e))
+        })
+}
+
+fn validate_decision(path: &str, body: &BodyParams) -> Result<(), Rejection> {
+    if !path_prefixes(path).contains(&body.prefix) {
+        return Err(warp::reject::custom(BadRequestRejection));
+    }
+    match body.decision {
+        Decision::Ask => Err(warp::reject::custom(BadRequestRejection)),
+        // A website without a known origin can't be recognised again, so it can't be
+        // allowed permanently
+        Decision::Allow if body.origin == UNKNOWN_ORIGIN => {
+            Err(warp::reject::custom(PermissionDeniedRejection))
+        }
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{validate_decision, BodyParams};
+    use crate::{
+        error_handler,
+        permissions::{tests::MockPermissionRegistry, Decision, Permission},
+        render::{tests::MockRenderer, RenderTemplate, TemplateValues},
+        routes::{self, cookie::SessionCookieConfig},
+        token::{tests::MockTokenIssuer, TokenVerificationError},
+    };
+    use mockall::predicate::*;
+    use std::{convert::Infallible, sync::Arc};
+    use warp::{Filter, Reply};
+    use warp_sessions::{MemoryStore, Session, SessionStore};
+
+    fn unsecure_routes(
+        token_issuer: MockTokenIssuer,
+        render_engine: MockRenderer,
+        registry: MockPermissionRegistry,
+        session_store: MemoryStore,
+    ) -> impl Filter<Extract = (Box<dyn Reply>,), Error = Infallible> + Clone {
+        let cookie_config = SessionCookieConfig::default();
+        error_handler::recover(
+            routes::unsecure(
+                Arc::new(token_issuer),
+                Arc::new(render_engine),
+                Arc::new(registry),
+                session_store.clone(),
+                cookie_config.clone(),
+            )
+            .with(warp::wrap_fn(routes::unsecure::session(
+                session_store,
+                cookie_config,
+            ))),
+            Arc::new(MockRenderer::new()),
+        )
+    }
+
+    fn assert_cannot_be_framed(res: &warp::http::Response<warp::hyper::body::Bytes>) {
+        assert_eq!(res.headers()["x-frame-options"], "DENY");
+        assert_eq!(
+            res.headers()["content-security-policy"],
+            "frame-ancestors 'none'"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_undecided_website_is_shown_prompt_opening_consent_window() {
+        let mut token_issuer = MockTokenIssuer::new();
+        token_issuer
+            .expect_issue_token()
+            .with(
+                eq("consent-review:https://example.com:.profile.name."),
+                eq("consent-review"),
+            )
+            .times(1)
+            .returning(|_, _| Ok("review".to_owned()));
+        let mut registry = MockPermissionRegistry::new();
+        registry
+            .expect_decide()
+            .with(eq("https://example.com"), eq(".profile.name."))
+            .returning(|_, _| Ok(Decision::Ask));
+        let mut render_engine = MockRenderer::new();
+        render_engine
+            .expect_render()
+            .withf(|template: &RenderTemplate| match &template.value {
+                TemplateValues::Consent(consent) => {
+                    consent.review_url.as_deref()
+                        == Some(
+                            "/unsecure/consent/.profile.name.?origin=https%3A%2F%2Fexample.com&token=review",
+                        )
+                        && consent.token.is_empty()
+                        && consent.prefixes.is_empty()
+                }
+                _ => false,
+            })
+            .times(1)
+            .returning(|_| Ok("<button>Review request</button>".to_owned()));
+        let session_store = MemoryStore::new();
+        let filter = unsecure_routes(token_issuer, render_engine, registry, session_store.clone());
+
+        let res = warp::test::request()
+            .path("/unsecure/data/.profile.name.")
+            .header("sec-fetch-site", "cross-site")
+            .header("referer", "https://example.com/profile")
+            .reply(&filter)
+            .await;
+
+        assert_eq!(res.status(), 200);
+        assert_eq!(res.body(), "<button>Review request</button>");
+        assert!(res.headers().get("set-cookie").is_none());
+        assert_eq!(session_store.count().await, 0);
+    }
+
+    #[tokio::test]
+    async fn test_consent_window_cannot_be_framed() {
+        let mut token_issuer = MockTokenIssuer::new();
+        token_issuer
+            .expect_verify_token()
+            .with(
+                eq("review"),
+                eq("consent-review:https://example.com:.profile.name."),
+                eq("consent-review"),
+            )
+            .times(1)
+            .returning(|_, _, _| Ok(()));
+        token_issuer
+            .expect_issue_token()
+            .withf(|subject, _| subject == "consent:https://example.com:.profile.name.")
+            .times(1)
+            .returning(|_, _| Ok("form".to_owned()));
+        let mut render_engine = MockRenderer::new();
+        render_engine
+            .expect_render()
+            .withf(|template: &RenderTemplate| match &template.value {
+                TemplateValues::Consent(consent) => {
+                    consent.token == "form"
+                        && consent.action == "/unsecure/consent/.profile.name."
+                        && consent.allow_always
+                        && consent.review_url.is_none()
+                }
+                _ => false,
+            })
+            .times(1)
+            .returning(|_| Ok("<form></form>".to_owned()));
+        let filter = unsecure_routes(
+            token_issuer,
+            render_engine,
+            MockPermissionRegistry::new(),
+            MemoryStore::new(),
+        );
+
+        let res = warp::test::request()
+            .path("/unsecure/consent/.profile.name.?origin=https%3A%2F%2Fexample.com&token=review")
+            .reply(&filter)
+            .await;
+
+        assert_eq!(res.status(), 200);
+        assert_eq!(res.body(), "<form></form>");
+        assert_cannot_be_framed(&res);
+        assert!(res.headers()["set-cookie"]
+            .to_str()
+            .unwrap()
+            .contains("Path=/unsecure/consent/.profile.name."));
+    }
+
+    #[tokio::test]
+    async fn test_consent_window_requires_review_token() {
+        let mut token_issuer = MockTokenIssuer::new();
+        token_issuer
+            .expect_verify_token()
+            .returning(|_, _, _| Err(TokenVerificationError::InvalidSignature));
+        let filter = unsecure_routes(
+            token_issuer,
+            MockRenderer::new(),
+            MockPermissionRegistry::new(),
+            MemoryStore::new(),
+        );
+
+        let res = warp::test::request()
+            .path("/unsecure/consent/.profile.name.?origin=https%3A%2F%2Fexample.com&token=forged")
+            .reply(&filter)
+            .await;
+
+        assert_eq!(res.status(), 401);
+    }
+
+    #[tokio::test]
+    async fn test_post_records_decision_and_closes_consent_window() {
+        let session_store = MemoryStore::new();
+        let mut session = Session::new();
+        session.insert("token", "form".to_owned()).unwrap();
+        let session_id = session.id().to_owned();
+        let cookie = session_store.store_session(session).await.unwrap().unwrap();
+        let mut token_issuer = MockTokenIssuer::new();
+        token_issuer
+            .expect_verify_token()
+            .withf(move |token, subject, id| {
+                token == "form"
+                    && subject == "consent:https://example.com:.profile.name."
+                    && id == session_id
+            })
+            .times(1)
+            .returning(|_, _, _| Ok(()));
+        let mut registry = MockPermissionRegistry::new();
+        registry
+            .expect_record()
+            .with(eq(Permission {
+                origin: "https://example.com".to_owned(),
+                prefix: ".profile.".to_owned(),
+                decision: Decision::Allow,
+            }))
+            .times(1)
+            .returning(|_| Ok(()));
+        let mut render_engine = MockRenderer::new();
+        render_engine
+            .expect_render()
+            .withf(|template: &RenderTemplate| match &template.value {
+                TemplateValues::Consent(consent) => consent.decided,
+                _ => false,
+            })
+            .times(1)
+            .returning(|_| Ok("<script>window.close();</script>".to_owned()));
+        let filter = unsecure_routes(token_issuer, render_engine, registry, session_store);
+
+        let res = warp::test::request()
+            .method("POST")
+            .path("/unsecure/consent/.profile.name.")
+            .header("cookie", format!("sid={}", cookie))
+            .header("content-type", "application/x-www-form-urlencoded")
+            .body("origin=https%3A%2F%2Fexample.com&prefix=.profile.&decision=allow&token=form")
+            .reply(&filter)
+            .await;
+
+        assert_eq!(res.status(), 200);
+        assert_eq!(res.body(), "<script>window.close();</script>");
+        assert_cannot_be_framed(&res);
+    }
+
+    #[tokio::test]
+    async fn test_post_cannot_be_replayed() {
+        let session_store = MemoryStore::new();
+        let mut session = Session::new();
+        session.insert("token", "form".to_owned()).unwrap();
+        let cookie = session_store.store_session(session).await.unwrap().unwrap();
+        let mut token_issuer = MockTokenIssuer::new();
+        token_issuer
+            .expect_verify_token()
+            .times(1)
+            .returning(|_, _, _| Ok(()));
+        let mut registry = MockPermissionRegistry::new();
+        registry.expect_record().times(1).returning(|_| Ok(()));
+        let mut render_engine = MockRenderer::new();
+        render_engine
+            .expect_render()
+            .times(1)
+            .returning(|_| Ok("<script>window.close();</script>".to_owned()));
+        let filter = unsecure_routes(token_issuer, render_engine, registry, session_store.clone());
+        let post = || {
+            warp::test::request()
+                .method("POST")
+                .path("/unsecure/consent/.profile.name.")
+                .header("cookie", format!("sid={}", cookie))
+                .header("content-type", "application/x-www-form-urlencoded")
+                .body("origin=https%3A%2F%2Fexample.com&prefix=.profile.&decision=allow&token=form")
+        };
+
+        let first = post().reply(&filter).await;
+        let second = post().reply(&filter).await;
+
+        assert_eq!(first.status(), 200);
+        assert_eq!(second.status(), 401);
+        assert_eq!(session_store.count().await, 0);
+    }
+
+    fn body(origin: &str, prefix: &str, decision: Decision) -> BodyParams {
+        BodyParams {
+            origin: origin.to_owned(),
+            prefix: prefix.to_owned(),
+            decision,
+            token: "token".to_owned(),
+        }
+    }
+
+    #[test]
+    fn test_validate_decision_accepts_path_prefixes() {
+        assert!(validate_decision(
+            ".profile.name.",
+            &body("https://example.com", ".profile.", Decision::Allow)
+        )
+        .is_ok());
+        assert!(validate_decision(
+            ".profile.name.",
+            &body("https://example.com", ".profile.name.", Decision::Deny)
+        )
+        .is_ok());
+    }
+
+    #[test]
+    fn test_validate_decision_rejects_unrelated_prefix() {
+        assert!(validate_decision(
+            ".profile.name.",
+            &body("https://example.com", ".health.", Decision::Allow)
+        )
+        .is_err());
+        assert!(validate_decision(
+            ".profile.name.",
+            &body("https://example.com", ".", Decision::Allow)
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_validate_decision_only_allows_unknown_origin_once() {
+        assert!(validate_decision(
+            ".profile.name.",
+            &body("null", ".profile.name.", Decision::Allow)
+        )
+        .is_err());
+        assert!(validate_decision(
+            ".profile.name.",
+            &body("null", ".profile.name.", Decision::Once)
+        )
+        .is_ok());
+    }
+}
@@ -1,27 +1,230 @@
 use warp::{Filter, Rejection, Reply};
+use warp_sessions::Session;
 
-use crate::{render::Renderer, routes::validated_query_params, token::TokenGenerator};
+use crate::{
+    permissions::{request_origin, Decision, PermissionRegistry},
+    render::Renderer,
+    routes::{
+        error::PermissionDeniedRejection, non_reserved_path, secure::new_token_session,
+        unsecure::consent, validated_query_params,
+    },
+    token::TokenIssuer,
+};
 
 pub mod get;
 
-pub fn get<T: TokenGenerator, R: Renderer + Clone + Send + Sync>(
-    token_generator: T,
+pub fn get<I: TokenIssuer, R: Renderer + Clone + Send + Sync, P: PermissionRegistry>(
+    token_issuer: I,
     render_engine: R,
-) -> impl Filter<Extract = (impl Reply, String, String), Error = Rejection> + Clone {
+    registry: P,
+) -> impl Filter<Extract = (Box<dyn Reply>, String, Option<Session>), Error = Rejection> + Clone {
     warp::get()
-        .and(warp::path!(String))
-        .and(warp::any().map(move || token_generator.clone().generate_token().unwrap()))
+        .and(non_reserved_path())
+        .and(warp::path::end())
         .and(validated_query_params::<get::QueryParams>())
+        .and(warp::header::optional::<String>("origin"))
+        .and(warp::header::optional::<String>("sec-fetch-site"))
+        .and(warp::header::optional::<String>("referer"))
+        .and(warp::any().map(move || token_issuer.clone()))
         .and(warp::any().map(move || render_engine.clone()))
+        .and(warp::any().map(move || registry.clone()))
         .and_then(
-            |path: String, token: String, query: get::QueryParams, render_engine: R| async move {
-                let secure_path = format!("/secure/data/{}/{}", &path, &token);
-                Ok::<_, Rejection>((
-                    get::reply(&secure_path, query, &render_engine)?,
-                    secure_path,
-                    token,
-                ))
+            |path: String,
+             query: get::QueryParams,
+             origin: Option<String>,
+             sec_fetch_site: Option<String>,
+             referer: Option<String>,
+             token_issuer: I,
+             render_engine: R,
+             registry: P| async move {
+                let origin = request_origin(
+                    origin.as_deref(),
+                    sec_fetch_site.as_deref(),
+                    referer.as_deref(),
+                );
+                respond(
+                    &origin,
+                    path,
+                    query,
+                    &token_issuer,
+                    &render_engine,
+                    &registry,
+                )
+                .await
             },
         )
         .untuple_one()
 }
+
+/// Serves the data at `path` to the website at `origin` if the user has allowed it, asks
+/// the user if they haven't decided yet, and refuses if they have denied it
+async fn respond<I: TokenIssuer, R: Renderer, P: PermissionRegistry>(
+    origin: &str,
+    path: String,
+    query: get::QueryParams,
+    token_issuer: &I,
+    render_engine: &R,
+    registry: &P,
+) -> Result<(Box<dyn Reply>, String, Option<Session>), Rejection> {
+    match registry
+        .decide(origin, &path)
+        .await
+        .map_err(warp::reject::custom)?
+    {
+        Decision::Allow | Decision::Once => {
+            let (session, token) = new_token_session(token_issuer, &path, origin)?;
+            let secure_path = format!("/secure/data/{}/{}", &path, &token);
+            Ok((
+                Box::new(get::reply(&secure_path, query, render_engine)?),
+                secure_path,
+                Some(session),
+            ))
+        }
+        Decision::Deny => {
+            tracing::debug!(origin, "website was denied access to data");
+            Err(warp::reject::custom(PermissionDeniedRejection))
+        }
+        // The prompt holds no token of its own, so no session is stored for it
+        Decision::Ask => Ok((
+            Box::new(consent::prompt(origin, &path, token_issuer, render_engine)?),
+            consent::action_path(&path),
+            None,
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        error_handler,
+        permissions::{tests::MockPermissionRegistry, Decision},
+        render::{tests::MockRenderer, RenderTemplate, TemplateValues},
+        routes::{self, cookie::SessionCookieConfig},
+        token::{tests::MockTokenIssuer, TokenGenerationError},
+    };
+    use std::sync::Arc;
+    use warp::Filter;
+    use warp_sessions::MemoryStore;
+
+    #[tokio::test]
+    async fn test_get_serves_html_even_when_json_is_accepted() {
+        let mut token_issuer = MockTokenIssuer::new();
+        token_issuer
+            .expect_issue_token()
+            .returning(|_, _| Ok("abc".to_owned()));
+        let mut registry = MockPermissionRegistry::new();
+        registry
+            .expect_decide()
+            .returning(|_, _| Ok(Decision::Allow));
+        let mut render_engine = MockRenderer::new();
+        render_engine
+            .expect_render()
+            .withf(|template: &RenderTemplate| match &template.value {
+                TemplateValues::Unsecure(unsecure) => {
+                    template.name == "unsecure"
+                        && unsecure.path == "/secure/data/.profile.name./abc"
+                }
+                _ => false,
+            })
+            .times(2)
+            .returning(|_| Ok("<iframe></iframe>".to_owned()));
+        let session_store = MemoryStore::new();
+        let cookie_config = SessionCookieConfig::default();
+        let filter = routes::unsecure(
+            Arc::new(token_issuer),
+            Arc::new(render_engine),
+            Arc::new(registry),
+            session_store.clone(),
+            cookie_config.clone(),
+        )
+        .with(warp::wrap_fn(routes::unsecure::session(
+            session_store,
+            cookie_config,
+        )));
+
+        for accept in &["text/html,*/*;q=0.8", "application/json"] {
+            let res = warp::test::request()
+                .path("/unsecure/data/.profile.name.")
+                .header("accept", *accept)
+                .header("referer", "https://example.com/profile")
+                .header("sec-fetch-site", "cross-site")
+                .reply(&filter)
+                .await;
+            assert_eq!(res.status(), 200);
+            assert_eq!(res.headers()["content-type"], "text/html; charset=utf-8");
+            assert_eq!(res.body(), "<iframe></iframe>");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_get_token_generation_failure_is_internal_error() {
+        let mut token_issuer = MockTokenIssuer::new();
+        token_issuer.expect_issue_token().returning(|_, _| {
+            Err(TokenGenerationError::RandError {
+                source: rand::Error::new("no entropy".to_owned()),
+            })
+        });
+        let mut registry = MockPermissionRegistry::new();
+        registry
+            .expect_decide()
+            .returning(|_, _| Ok(Decision::Allow));
+        let session_store = MemoryStore::new();
+        let cookie_config = SessionCookieConfig::default();
+        let filter = error_handler::recover(
+            routes::unsecure(
+                Arc::new(token_issuer),
+                Arc::new(MockRenderer::new()),
+                Arc::new(registry),
+                session_store.clone(),
+                cookie_config.clone(),
+            )
+            .with(warp::wrap_fn(routes::unsecure::session(
+                session_store,
+                cookie_config,
+            ))),
+            Arc::new(MockRenderer::new()),
+        );
+
+        let res = warp::test::request()
+            .path("/unsecure/data/.profile.name.")
+            .header("accept", "application/json")
+            .header("origin", "https://example.com")
+            .reply(&filter)
+            .await;
+
+        assert_eq!(res.status(), 500);
+        let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(body["error"], "token_error");
+    }
+
+    #[tokio::test]
+    async fn test_get_refuses_keys() {
+        let session_store = MemoryStore::new();
+        let cookie_config = SessionCookieConfig::default();
+        let filter = error_handler::recover(
+            routes::unsecure(
+                Arc::new(MockTokenIssuer::new()),
+                Arc::new(MockRenderer::new()),
+                Arc::new(MockPermissionRegistry::new()),
+                session_store.clone(),
+                cookie_config.clone(),
+            )
+            .with(warp::wrap_fn(routes::unsecure::session(
+                session_store,
+                cookie_config,
+            ))),
+            Arc::new(MockRenderer::new()),
+        );
+
+        let res = warp::test::request()
+            .path("/unsecure/data/.keys.encryption.symmetric.default.")
+            .header("accept", "application/json")
+            .header("origin", "https://example.com")
+            .reply(&filter)
+            .await;
+
+        assert_eq!(res.status(), 403);
+        let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(body["error"], "permission_denied");
+    }
+}
@@ -1,4 +1,5 @@
 use crate::{
+    compound::CompoundLayout,
     render::{
         RenderError, RenderTemplate, Rendered, Renderer, TemplateValues, UnsecureTemplateValues,
     },
@@ -12,6 +13,8 @@ pub struct QueryParams {
     css: Option<String>,
     edit: Option<bool>,
     data_type: Option<String>,
+    layout: Option<CompoundLayout>,
+    list: Option<bool>,
     relay_url: Option<String>,
     js_message: Option<String>,
     js_height_msg_prefix: Option<String>,
@@ -39,6 +42,8 @@ pub fn reply<R: Renderer>(
                 css: query.css,
                 edit: query.edit,
                 data_type: query.data_type,
+                layout: query.layout,
+                list: query.list,
                 relay_url: query.relay_url,
                 js_height_msg_prefix: query.js_height_msg_prefix,
                 js_message: query.js_message,
@@ -0,0 +1,27 @@
+use crate::metrics;
+use redact_crypto::{CryptoError, Data, Storer, SymmetricKey, ToEntry, ToSymmetricByteAlgorithm};
+
+/// Seals the data with the symmetric key at `key_path` and stores it at the given path; the
+/// sealed entry references the key by its path so reads resolve it without the selector
+#[tracing::instrument(skip(storer, data))]
+pub async fn seal_and_store<H: Storer>(
+    storer: &H,
+    path: &str,
+    key_path: &str,
+    data: Data,
+) -> Result<(), CryptoError> {
+    let _timer = metrics::STORER_LATENCY
+        .with_label_values(&["create"])
+        .start_timer();
+    let key_entry = storer.get::<SymmetricKey>(key_path).await?;
+    let (key, key_entry_path, _) = key_entry.take_resolve_all().await?;
+    let algo_storer = storer.clone();
+    let key_algo = key
+        .to_byte_algorithm(None, |key| async move {
+            key.to_ref_entry(key_entry_path, algo_storer)
+        })
+        .await?;
+    let entry = data.to_sealed_entry(path.to_owned(), key_algo).await?;
+    storer.create(entry).await?;
+    Ok(())
+}
@@ -0,0 +1,427 @@
+use crate::atomic_file;
+use async_session::{async_trait, Session, SessionStore};
+use serde::{Deserialize, Serialize};
+use sodiumoxide::crypto::secretbox;
+use std::{
+    collections::HashMap,
+    convert::TryInto,
+    fmt::{self, Debug},
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use thiserror::Error;
+use tokio::{io::AsyncWriteExt, sync::RwLock};
+use warp_sessions::MemoryStore;
+
+/// Every change in the session store file is prefixed with its length as a big-endian u32
+const RECORD_LENGTH_BYTES: usize = 4;
+
+#[derive(Error, Debug)]
+pub enum SessionStoreError {
+    #[error("Failed to read or write the session store file")]
+    IoError { source: std::io::Error },
+    #[error("Failed to serialize or deserialize the stored sessions")]
+    SerializationError { source: serde_json::Error },
+    #[error("Session store key must be exactly {} bytes", secretbox::KEYBYTES)]
+    InvalidKey,
+    #[error("Session store file could not be decrypted with the provided key")]
+    DecryptionError,
+}
+
+/// The session store selected through the `sessions.store.type` config value
+#[derive(Debug, Clone)]
+pub enum ClientSessionStore {
+    Memory(MemoryStore),
+    File(FileSessionStore),
+}
+
+impl ClientSessionStore {
+    /// Removes all expired sessions from the underlying store
+    pub async fn sweep(&self) -> async_session::Result {
+        match self {
+            ClientSessionStore::Memory(store) => store.cleanup().await,
+            ClientSessionStore::File(store) => Ok(store.sweep().await?),
+        }
+    }
+}
+
+#[async_trait]
+impl SessionStore for ClientSessionStore {
+    async fn load_session(&self, cookie_value: String) -> async_session::Result<Option<Session>> {
+        match self {
+            ClientSessionStore::Memory(store) => store.load_session(cookie_value).await,
+            ClientSessionStore::File(store) => store.load_session(cookie_value).await,
+        }
+    }
+
+    async fn store_session(&self, session: Session) -> async_session::Result<Option<String>> {
+        match self {
+            ClientSessionStore::Memory(store) => store.store_session(session).await,
+            ClientSessionStore::File(store) => store.store_session(session).await,
+        }
+    }
+
+    async fn destroy_session(&self, session: Session) -> async_session::Result {
+        match self {
+            ClientSessionStore::Memory(store) => store.destroy_session(session).await,
+            ClientSessionStore::File(store) => store.destroy_session(session).await,
+        }
+    }
+
+    async fn clear_store(&self) -> async_session::Result {
+        match self {
+            ClientSessionStore::Memory(store) => store.clear_store().await,
+            ClientSessionStore::File(store) => store.clear_store().await,
+        }
+    }
+}
+
+/// A change to the stored sessions, as appended to the session store file
+#[derive(Serialize, Deserialize)]
+enum Change {
+    Store(Session),
+    Destroy(String),
+    Clear,
+}
+
+/// The sessions in the store, and how many changes the file holds to arrive at them
+struct Sessions {
+    live: HashMap<String, Session>,
+    changes: usize,
+}
+
+impl Sessions {
+    fn apply(&mut self, change: Change) {
+        match change {
+            Change::Store(session) => {
+                self.live.insert(session.id().to_owned(), session);
+            }
+            Change::Destroy(id) => {
+                self.live.remove(&id);
+            }
+            Change::Clear => self.live.clear(),
+        }
+        self.changes += 1;
+    }
+}
+
+/// A session store which keeps all sessions in memory and appends every change to a file
+/// on disk, each encrypted with a symmetric key. The file is rewritten with only the live
+/// sessions when expired sessions are swept.
+#[derive(Clone)]
+pub struct FileSessionStore {
+    path: PathBuf,
+    key: secretbox::Key,
+    sessions: Arc<RwLock<Sessions>>,
+}
+
+impl Debug for FileSessionStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FileSessionStore")
+            .field("path", &self.path)
+            .finish()
+    }
+}
+
+impl FileSessionStore {
+    /// Opens the session store at the given path, loading any sessions that were
+    /// persisted by a previous run of the client
+    pub fn new<P: AsRef<Path>>(path: P, key: &[u8]) -> Result<Self, SessionStoreError> {
+        let key = secretbox::Key::from_slice(key).ok_or(SessionStoreError::InvalidKey)?;
+        let path = path.as_ref().to_path_buf();
+        let mut sessions = Sessions {
+            live: HashMap::new(),
+            changes: 0,
+        };
+        let bytes = match std::fs::read(&path) {
+            Ok(bytes) => bytes,
+            Err(e) => match e.kind() {
+                ErrorKind::NotFound => vec![],
+                _ => return Err(SessionStoreError::IoError { source: e }),
+            },
+        };
+
+        let mut rest = bytes.as_slice();
+        while rest.len() >= RECORD_LENGTH_BYTES {
+            let (length, record) = rest.split_at(RECORD_LENGTH_BYTES);
+            let length = u32::from_be_bytes(length.try_into().unwrap()) as usize;
+            if record.len() < length {
+                break;
+            }
+            sessions.apply(Self::decrypt(&record[..length], &key)?);
+            rest = &record[length..];
+        }
+        if !rest.is_empty() {
+            // The last change was cut off while being appended, so it is dropped before
+            // anything else is appended after it
+            std::fs::OpenOptions::new()
+                .write(true)
+                .open(&path)
+                .and_then(|file| file.set_len((bytes.len() - rest.len()) as u64))
+                .map_err(|source| SessionStoreError::IoError { source })?;
+        }
+
+        Ok(FileSessionStore {
+            path,
+            key,
+            sessions: Arc::new(RwLock::new(sessions)),
+        })
+    }
+
+    /// Removes all expired sessions, rewriting the file without the changes which no
+    /// longer matter
+    pub async fn sweep(&self) -> Result<(), SessionStoreError> {
+        let mut sessions = self.sessions.write().await;
+        sessions.live.retain(|_, session| !session.is_expired());
+        if sessions.changes > sessions.live.len() {
+            self.compact(&mut sessions).await?;
+        }
+        Ok(())
+    }
+
+    fn decrypt(record: &[u8], key: &secretbox::Key) -> Result<Change, SessionStoreError> {
+        if record.len() < secretbox::NONCEBYTES {
+            return Err(SessionStoreError::DecryptionError);
+        }
+        let (nonce, ciphertext) = record.split_at(secretbox::NONCEBYTES);
+        let nonce =
+            secretbox::Nonce::from_slice(nonce).ok_or(SessionStoreError::DecryptionError)?;
+        let plaintext = secretbox::open(ciphertext, &nonce, key)
+            .map_err(|_| SessionStoreError::DecryptionError)?;
+        serde_json::from_slice(&plaintext)
+            .map_err(|source| SessionStoreError::SerializationError { source })
+    }
+
+    /// The change encrypted and prefixed with its length, ready to be appended
+    fn encrypt(&self, change: &Change) -> Result<Vec<u8>, SessionStoreError> {
+        let plaintext = serde_json::to_vec(change)
+            .map_err(|source| SessionStoreError::SerializationError { source })?;
+        let nonce = secretbox::gen_nonce();
+        let ciphertext = secretbox::seal(&plaintext, &nonce, &self.key);
+
+        let length = (secretbox::NONCEBYTES + ciphertext.len()) as u32;
+        let mut record = length.to_be_bytes().to_vec();
+        record.extend_from_slice(nonce.as_ref());
+        record.extend(ciphertext);
+        Ok(record)
+    }
+
+    async fn append(
+        &self,
+        sessions: &mut Sessions,
+        change: Change,
+    ) -> Result<(), SessionStoreError> {
+        let record = self.encrypt(&change)?;
+        if let Some(parent) = self.path.parent() {
+            tokio::fs::create_dir_all(parent)
+                .await
+                .map_err(|source| SessionStoreError::IoError { source })?;
+        }
+        let mut file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .await
+            .map_err(|source| SessionStoreError::IoError { source })?;
+        file.write_all(&record)
+            .await
+            .map_err(|source| SessionStoreError::IoError { source })?;
+        file.sync_data()
+            .await
+            .map_err(|source| SessionStoreError::IoError { source })?;
+
+        sessions.apply(change);
+        Ok(())
+    }
+
+    async fn compact(&self, sessions: &mut Sessions) -> Result<(), SessionStoreError> {
+        let mut bytes = vec![];
+        for session in sessions.live.values() {
+            bytes.extend(self.encrypt(&Change::Store(session.clone()))?);
+        }
+
+        atomic_file::write_async(self.path.clone(), bytes)
+            .await
+            .map_err(|source| SessionStoreError::IoError { source })?;
+
+        sessions.changes = sessions.live.len();
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl SessionStore for FileSessionStore {
+    async fn load_session(&self, cookie_value: String) -> async_session::Result<Option<Session>> {
+        let id = Session::id_from_cookie_value(&cookie_value)?;
+        Ok(self
+            .sessions
+            .read()
+            .await
+            .live
+            .get(&id)
+            .cloned()
+            .and_then(Session::validate))
+    }
+
+    async fn store_session(&self, session: Session) -> async_session::Result<Option<String>> {
+        let mut sessions = self.sessions.write().await;
+        self.append(&mut sessions, Change::Store(session.clone()))
+            .await?;
+
+        session.reset_data_changed();
+        Ok(session.into_cookie_value())
+    }
+
+    async fn destroy_session(&self, session: Session) -> async_session::Result {
+        let mut sessions = self.sessions.write().await;
+        if sessions.live.contains_key(session.id()) {
+            self.append(&mut sessions, Change::Destroy(session.id().to_owned()))
+                .await?;
+        }
+        Ok(())
+    }
+
+    async fn clear_store(&self) -> async_session::Result {
+        let mut sessions = self.sessions.write().await;
+        Ok(self.append(&mut sessions, Change::Clear).await?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FileSessionStore, SessionStoreError};
+    use crate::test_utils::temp_dir;
+    use async_session::{Session, SessionStore};
+    use sodiumoxide::crypto::secretbox;
+    use std::path::PathBuf;
+    use tempfile::TempDir;
+
+    fn temp_store_path() -> (TempDir, PathBuf) {
+        let dir = temp_dir();
+        let path = dir.path().join("sessions.enc");
+        (dir, path)
+    }
+
+    #[tokio::test]
+    async fn test_sessions_survive_reopening_store() {
+        let (_dir, path) = temp_store_path();
+        let key = secretbox::gen_key();
+
+        let store = FileSessionStore::new(&path, key.as_ref()).unwrap();
+        let mut session = Session::new();
+        session.insert("token", "abc").unwrap();
+        let cookie_value = store.store_session(session).await.unwrap().unwrap();
+
+        let reopened_store = FileSessionStore::new(&path, key.as_ref()).unwrap();
+        let session = reopened_store
+            .load_session(cookie_value)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(session.get::<String>("token"), Some("abc".to_owned()));
+    }
+
+    #[tokio::test]
+    async fn test_reopening_store_with_wrong_key() {
+        let (_dir, path) = temp_store_path();
+
+        let store = FileSessionStore::new(&path, secretbox::gen_key().as_ref()).unwrap();
+        store.store_session(Session::new()).await.unwrap();
+
+        match FileSessionStore::new(&path, secretbox::gen_key().as_ref()) {
+            Err(SessionStoreError::DecryptionError) => (),
+            _ => panic!("expected the store to fail decryption"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sweep_removes_expired_sessions() {
+        let (_dir, path) = temp_store_path();
+        let store = FileSessionStore::new(&path, secretbox::gen_key().as_ref()).unwrap();
+
+        let mut session = Session::new();
+        session.set_expiry(chrono::Utc::now() - chrono::Duration::seconds(1));
+        let cookie_value = store.store_session(session).await.unwrap().unwrap();
+        store.sweep().await.unwrap();
+
+        assert!(store.sessions.read().await.live.is_empty());
+        assert!(store.load_session(cookie_value).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_changes_are_appended_and_compacted_by_sweep() {
+        let (_dir, path) = temp_store_path();
+        let key = secretbox::gen_key();
+        let store = FileSessionStore::new(&path, key.as_ref()).unwrap();
+
+        let kept_cookie_value = store.store_session(Session::new()).await.unwrap().unwrap();
+        let first_length = std::fs::metadata(&path).unwrap().len();
+        let destroyed_cookie_value = store.store_session(Session::new()).await.unwrap().unwrap();
+        let destroyed_session = store
+            .load_session(destroyed_cookie_value.clone())
+            .await
+            .unwrap()
+            .unwrap();
+        store.destroy_session(destroyed_session).await.unwrap();
+        let appended_length = std::fs::metadata(&path).unwrap().len();
+        assert!(appended_length > 2 * first_length);
+
+        store.sweep().await.unwrap();
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), first_length);
+
+        let reopened_store = FileSessionStore::new(&path, key.as_ref()).unwrap();
+        assert!(reopened_store
+            .load_session(kept_cookie_value)
+            .await
+            .unwrap()
+            .is_some());
+        assert!(reopened_store
+            .load_session(destroyed_cookie_value)
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_change_cut_off_while_appending_is_dropped() {
+        let (_dir, path) = temp_store_path();
+        let key = secretbox::gen_key();
+        let store = FileSessionStore::new(&path, key.as_ref()).unwrap();
+        let cookie_value = store.store_session(Session::new()).await.unwrap().unwrap();
+        let length = std::fs::metadata(&path).unwrap().len();
+        store.store_session(Session::new()).await.unwrap();
+        std::fs::OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(length + 10)
+            .unwrap();
+
+        let reopened_store = FileSessionStore::new(&path, key.as_ref()).unwrap();
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), length);
+        let later_cookie_value = reopened_store
+            .store_session(Session::new())
+            .await
+            .unwrap()
+            .unwrap();
+
+        let reopened_store = FileSessionStore::new(&path, key.as_ref()).unwrap();
+        for cookie_value in [cookie_value, later_cookie_value] {
+            assert!(reopened_store
+                .load_session(cookie_value)
+                .await
+                .unwrap()
+                .is_some());
+        }
+    }
+
+    #[test]
+    fn test_invalid_key_length() {
+        let (_dir, path) = temp_store_path();
+        match FileSessionStore::new(path, &[0u8; 4]) {
+            Err(SessionStoreError::InvalidKey) => (),
+            _ => panic!("expected an invalid key error"),
+        }
+    }
+}
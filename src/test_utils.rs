@@ -0,0 +1,132 @@
+//! Helpers shared by the tests of several modules
+
+use percent_encoding::percent_decode_str;
+use redact_crypto::{
+    key::sodiumoxide::SodiumOxideSymmetricKey, Data, RedactStorer, StorableType, ToEntry,
+};
+use serde_json::Value;
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, Mutex},
+};
+use tempfile::TempDir;
+use warp::{http::StatusCode, Filter};
+
+/// An in-memory stand-in for a redact-store server, which keeps the JSON of every entry
+/// posted to it by path and answers the gets and lists of a `RedactStorer`
+#[derive(Clone, Default)]
+pub struct FakeStore {
+    entries: Arc<Mutex<BTreeMap<String, Value>>>,
+}
+
+impl FakeStore {
+    /// Serves the store on an ephemeral localhost port for the rest of the test, returning
+    /// a storer which talks to it
+    pub async fn serve(&self) -> RedactStorer {
+        let create_store = self.clone();
+        let create = warp::post()
+            .and(warp::path::end())
+            .and(warp::body::json())
+            .map(move |entry: Value| {
+                let path = entry["path"].as_str().unwrap_or_default().to_owned();
+                create_store.entries.lock().unwrap().insert(path, entry);
+                warp::reply()
+            });
+        let get_store = self.clone();
+        let get = warp::get()
+            .and(warp::path::tail())
+            .and(warp::query::<HashMap<String, String>>())
+            .map(
+                move |tail: warp::path::Tail, query: HashMap<String, String>| {
+                    let path = percent_decode_str(tail.as_str())
+                        .decode_utf8_lossy()
+                        .into_owned();
+                    get_store.reply(&path, &query)
+                },
+            );
+
+        let (addr, server) = warp::serve(create.or(get)).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        RedactStorer::new(&format!("http://{}", addr))
+    }
+
+    fn reply(&self, path: &str, query: &HashMap<String, String>) -> Box<dyn warp::Reply> {
+        let index = query
+            .get("index")
+            .and_then(|index| serde_json::from_str::<Value>(index).ok())
+            .map(|index| index["c"]["builder"].clone());
+        let matches_index = |entry: &Value| match &index {
+            Some(index) => is_subset(index, &entry["builder"]),
+            None => true,
+        };
+        let entries = self.entries.lock().unwrap();
+
+        match (query.get("skip"), query.get("page_size")) {
+            (Some(skip), Some(page_size)) => {
+                let skip = skip.parse().unwrap_or(0);
+                let page_size = page_size.parse::<usize>().unwrap_or(usize::MAX);
+                let listed: Vec<&Value> = entries
+                    .iter()
+                    .filter(|(entry_path, entry)| {
+                        entry_path.starts_with(path) && matches_index(entry)
+                    })
+                    .map(|(_, entry)| entry)
+                    .skip(skip)
+                    .take(page_size)
+                    .collect();
+                Box::new(warp::reply::json(&listed))
+            }
+            _ => match entries.get(path).filter(|entry| matches_index(entry)) {
+                Some(entry) => Box::new(warp::reply::json(entry)),
+                None => Box::new(StatusCode::NOT_FOUND),
+            },
+        }
+    }
+
+    /// The JSON of the entry stored at `path`
+    pub fn get(&self, path: &str) -> Option<Value> {
+        self.entries.lock().unwrap().get(path).cloned()
+    }
+
+    /// Stores the entry as it would be posted by a storer
+    pub fn insert<T: StorableType>(&self, entry: redact_crypto::Entry<T>) {
+        let path = entry.path.clone();
+        let entry = serde_json::to_value(&entry).unwrap();
+        self.entries.lock().unwrap().insert(path, entry);
+    }
+
+    /// Stores unsealed data at `path`
+    pub fn insert_data(&self, path: &str, data: Data) {
+        self.insert(data.to_unsealed_entry(path.to_owned()).unwrap());
+    }
+
+    /// Stores a new unsealed symmetric key at `path`
+    pub fn insert_key(&self, path: &str) {
+        self.insert(
+            SodiumOxideSymmetricKey::new()
+                .to_unsealed_entry(path.to_owned())
+                .unwrap(),
+        );
+    }
+}
+
+/// A new, empty directory under the system's temporary directory, removed along with
+/// everything in it when the returned guard is dropped
+pub fn temp_dir() -> TempDir {
+    tempfile::Builder::new()
+        .prefix("redact-client-")
+        .tempdir()
+        .unwrap()
+}
+
+/// Whether every field of `pattern` is present in `value` with the same value
+fn is_subset(pattern: &Value, value: &Value) -> bool {
+    match (pattern, value) {
+        (Value::Object(pattern), Value::Object(value)) => pattern.iter().all(|(key, field)| {
+            value
+                .get(key)
+                .is_some_and(|value_field| is_subset(field, value_field))
+        }),
+        _ => pattern == value,
+    }
+}
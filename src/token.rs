@@ -1,18 +1,45 @@
+use crate::key_derivation::derive_auth_key;
+use chrono::{Duration, Utc};
 use rand::{thread_rng, Rng};
+use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
+use sodiumoxide::crypto::auth;
 use std::ops::Deref;
 use std::sync::{Arc, RwLock};
 use thiserror::Error;
 use warp::reject::Reject;
 
+/// Domain separation for the token signing key derived from the root signing key
+const TOKEN_KEY_CONTEXT: &[u8] = b"redact-client iframe token signing key";
+
 #[derive(Error, Debug)]
 pub enum TokenGenerationError {
     #[error("Failed to generate cryptographically secure random bytes")]
     RandError { source: rand::Error },
+    #[error("Random number generator is unusable after a panic while generating a token")]
+    RngPoisoned,
+    #[error("Failed to serialize the token claims")]
+    SerializationError { source: serde_json::Error },
 }
 
 impl Reject for TokenGenerationError {}
 
+#[derive(Error, Debug, PartialEq)]
+pub enum TokenVerificationError {
+    #[error("Token is not a well-formed signed token")]
+    Malformed,
+    #[error("Token signature is invalid")]
+    InvalidSignature,
+    #[error("Token was issued for a different data path")]
+    PathMismatch,
+    #[error("Token was issued for a different session")]
+    SessionMismatch,
+    #[error("Token has expired")]
+    Expired,
+}
+
+impl Reject for TokenVerificationError {}
+
 pub trait TokenGenerator: Clone + Send + Sync {
     fn generate_token(&self) -> Result<String, TokenGenerationError>;
 }
@@ -26,11 +53,41 @@ where
     }
 }
 
+/// Issues iframe tokens bound to a data path, a session ID and an expiry, and
+/// verifies presented tokens against the request they arrive with
+pub trait TokenIssuer: Clone + Send + Sync {
+    fn issue_token(&self, path: &str, session_id: &str) -> Result<String, TokenGenerationError>;
+    fn verify_token(
+        &self,
+        token: &str,
+        path: &str,
+        session_id: &str,
+    ) -> Result<(), TokenVerificationError>;
+}
+
+impl<T> TokenIssuer for Arc<T>
+where
+    T: TokenIssuer,
+{
+    fn issue_token(&self, path: &str, session_id: &str) -> Result<String, TokenGenerationError> {
+        self.deref().issue_token(path, session_id)
+    }
+
+    fn verify_token(
+        &self,
+        token: &str,
+        path: &str,
+        session_id: &str,
+    ) -> Result<(), TokenVerificationError> {
+        self.deref().verify_token(token, path, session_id)
+    }
+}
+
 pub struct FromCustomRng<T: Rng + Send + Sync> {
     rand_source: Arc<RwLock<T>>,
 }
 
-#[derive(Clone)]
+#[derive(Clone, Default)]
 pub struct FromThreadRng;
 
 impl<T: Rng + Send + Sync> Clone for FromCustomRng<T> {
@@ -47,7 +104,7 @@ impl<T: Rng + Send + Sync> TokenGenerator for FromCustomRng<T> {
         let mut random_bytes: [u8; 32] = [0; 32];
         self.rand_source
             .write()
-            .unwrap()
+            .map_err(|_| TokenGenerationError::RngPoisoned)?
             .try_fill(&mut random_bytes)
             .map_err(|source| TokenGenerationError::RandError { source })?;
 
@@ -93,9 +150,99 @@ impl FromThreadRng {
     }
 }
 
+/// The claims carried by a signed token
+#[derive(Serialize, Deserialize)]
+struct TokenClaims {
+    #[serde(rename = "p")]
+    path: String,
+    #[serde(rename = "s")]
+    session_id: String,
+    #[serde(rename = "e")]
+    expires_at: i64,
+    #[serde(rename = "n")]
+    nonce: String,
+}
+
+/// Issues tokens of the form `<claims>.<tag>`, both URL-safe base64, where the tag is an
+/// HMAC-SHA-512-256 of the JSON claims under a key derived from the root signing key
+#[derive(Clone)]
+pub struct SignedTokenIssuer<T: TokenGenerator> {
+    nonce_source: T,
+    key: auth::Key,
+    lifetime: Duration,
+}
+
+impl<T: TokenGenerator> SignedTokenIssuer<T> {
+    pub fn new(nonce_source: T, root_key_bytes: &[u8], lifetime: Duration) -> Self {
+        SignedTokenIssuer {
+            nonce_source,
+            key: derive_auth_key(TOKEN_KEY_CONTEXT, root_key_bytes),
+            lifetime,
+        }
+    }
+}
+
+impl<T: TokenGenerator> TokenIssuer for SignedTokenIssuer<T> {
+    fn issue_token(&self, path: &str, session_id: &str) -> Result<String, TokenGenerationError> {
+        let claims = TokenClaims {
+            path: path.to_owned(),
+            session_id: session_id.to_owned(),
+            expires_at: (Utc::now() + self.lifetime).timestamp(),
+            nonce: self.nonce_source.generate_token()?,
+        };
+        let payload = serde_json::to_vec(&claims)
+            .map_err(|source| TokenGenerationError::SerializationError { source })?;
+        let tag = auth::authenticate(&payload, &self.key);
+
+        Ok(format!(
+            "{}.{}",
+            base64::encode_config(&payload, base64::URL_SAFE_NO_PAD),
+            base64::encode_config(tag.as_ref(), base64::URL_SAFE_NO_PAD)
+        ))
+    }
+
+    fn verify_token(
+        &self,
+        token: &str,
+        path: &str,
+        session_id: &str,
+    ) -> Result<(), TokenVerificationError> {
+        let (payload, tag) = token
+            .split_once('.')
+            .ok_or(TokenVerificationError::Malformed)?;
+        let payload = base64::decode_config(payload, base64::URL_SAFE_NO_PAD)
+            .map_err(|_| TokenVerificationError::Malformed)?;
+        let tag = base64::decode_config(tag, base64::URL_SAFE_NO_PAD)
+            .ok()
+            .and_then(|tag| auth::Tag::from_slice(&tag))
+            .ok_or(TokenVerificationError::Malformed)?;
+
+        // The signature is checked before the claims are even parsed
+        if !auth::verify(&tag, &payload, &self.key) {
+            return Err(TokenVerificationError::InvalidSignature);
+        }
+        let claims: TokenClaims =
+            serde_json::from_slice(&payload).map_err(|_| TokenVerificationError::Malformed)?;
+
+        if claims.path != path {
+            Err(TokenVerificationError::PathMismatch)
+        } else if claims.session_id != session_id {
+            Err(TokenVerificationError::SessionMismatch)
+        } else if claims.expires_at <= Utc::now().timestamp() {
+            Err(TokenVerificationError::Expired)
+        } else {
+            Ok(())
+        }
+    }
+}
+
 #[cfg(test)]
 pub mod tests {
-    use crate::token::{FromCustomRng, FromThreadRng, TokenGenerationError, TokenGenerator};
+    use crate::token::{
+        FromCustomRng, FromThreadRng, SignedTokenIssuer, TokenGenerationError, TokenGenerator,
+        TokenIssuer, TokenVerificationError,
+    };
+    use chrono::Duration;
     use mockall::predicate::*;
     use mockall::*;
     use rand::{prelude::*, Error, RngCore};
@@ -111,6 +258,22 @@ pub mod tests {
     }
     }
 
+    mock! {
+    pub TokenIssuer {}
+    impl TokenIssuer for TokenIssuer {
+            fn issue_token(&self, path: &str, session_id: &str) -> Result<String, TokenGenerationError>;
+            fn verify_token(
+                &self,
+                token: &str,
+                path: &str,
+                session_id: &str,
+            ) -> Result<(), TokenVerificationError>;
+    }
+    impl Clone for TokenIssuer {
+            fn clone(&self) -> Self;
+    }
+    }
+
     mock! {
     FailingRng {}
     impl RngCore for FailingRng {
@@ -150,6 +313,21 @@ pub mod tests {
         let _ = token_generator.generate_token().unwrap();
     }
 
+    #[test]
+    fn test_token_generation_with_poisoned_rng_is_error() {
+        let token_generator = FromCustomRng::new(Pcg64::seed_from_u64(1));
+        let rand_source = token_generator.rand_source.clone();
+        let _ = std::thread::spawn(move || {
+            let _guard = rand_source.write().unwrap();
+            panic!("panicked while holding the rng");
+        })
+        .join();
+        assert!(matches!(
+            token_generator.generate_token(),
+            Err(TokenGenerationError::RngPoisoned)
+        ));
+    }
+
     #[test]
     fn test_converting_token_generation_error_to_warp_rejection() {
         let rand_err = Error::new("some random error".to_string());
@@ -162,4 +340,59 @@ pub mod tests {
         let rng = FromCustomRng::new(Pcg64::seed_from_u64(1));
         let _ = rng.clone();
     }
+
+    fn issuer(lifetime: Duration) -> SignedTokenIssuer<FromThreadRng> {
+        SignedTokenIssuer::new(FromThreadRng::new(), &[7u8; 64], lifetime)
+    }
+
+    #[test]
+    fn test_signed_token_verifies_for_its_path_and_session() {
+        let issuer = issuer(Duration::seconds(60));
+        let token = issuer.issue_token(".profile.name.", "session-a").unwrap();
+        assert_eq!(
+            issuer.verify_token(&token, ".profile.name.", "session-a"),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn test_signed_token_rejects_other_path_and_session() {
+        let issuer = issuer(Duration::seconds(60));
+        let token = issuer.issue_token(".profile.name.", "session-a").unwrap();
+        assert_eq!(
+            issuer.verify_token(&token, ".profile.email.", "session-a"),
+            Err(TokenVerificationError::PathMismatch)
+        );
+        assert_eq!(
+            issuer.verify_token(&token, ".profile.name.", "session-b"),
+            Err(TokenVerificationError::SessionMismatch)
+        );
+    }
+
+    #[test]
+    fn test_signed_token_rejects_expired_token() {
+        let issuer = issuer(Duration::seconds(-1));
+        let token = issuer.issue_token(".profile.name.", "session-a").unwrap();
+        assert_eq!(
+            issuer.verify_token(&token, ".profile.name.", "session-a"),
+            Err(TokenVerificationError::Expired)
+        );
+    }
+
+    #[test]
+    fn test_signed_token_rejects_other_signing_key() {
+        let token = issuer(Duration::seconds(60))
+            .issue_token(".profile.name.", "session-a")
+            .unwrap();
+        let other_issuer =
+            SignedTokenIssuer::new(FromThreadRng::new(), &[8u8; 64], Duration::seconds(60));
+        assert_eq!(
+            other_issuer.verify_token(&token, ".profile.name.", "session-a"),
+            Err(TokenVerificationError::InvalidSignature)
+        );
+        assert_eq!(
+            other_issuer.verify_token("not-a-token", ".profile.name.", "session-a"),
+            Err(TokenVerificationError::Malformed)
+        );
+    }
 }